@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use prost::Message;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use proto::strapper;
+
+use crate::output;
+
+// The last advertisement the server accepted, so a restart with unchanged
+// state doesn't rewrite every record. Anything unreadable, undecodable or
+// older than `max_age` is ignored (with a warning) and we advertise as usual.
+pub async fn load(path: &Path, max_age: Duration) -> Option<strapper::NodeAdvertisement> {
+    let meta = match tokio::fs::metadata(path).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            output::warning(format_args!(
+                "ignoring state cache {}: {}",
+                path.display(),
+                e
+            ));
+            return None;
+        }
+    };
+    let age = meta
+        .modified()
+        .ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .unwrap_or_default();
+    if age > max_age {
+        output::warning(format_args!(
+            "ignoring state cache {}: {} seconds old",
+            path.display(),
+            age.as_secs()
+        ));
+        return None;
+    }
+
+    match tokio::fs::read(path)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|b| Ok(strapper::NodeAdvertisement::decode(b.as_slice())?))
+    {
+        Ok(adv) => Some(adv),
+        Err(e) => {
+            output::warning(format_args!(
+                "ignoring state cache {}: {}",
+                path.display(),
+                e
+            ));
+            None
+        }
+    }
+}
+
+pub async fn store(path: &Path, advertisement: &strapper::NodeAdvertisement) -> Result<()> {
+    let mut buf = Vec::with_capacity(advertisement.encoded_len());
+    advertisement.encode(&mut buf)?;
+
+    // Write-then-rename so a crash mid-write can't leave a truncated cache.
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, &buf)
+        .await
+        .with_context(|| format!("error writing {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("error renaming to {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+use client::Target;
+use proto::beacon;
+
+use crate::output;
+
+// Listens on `port` for a beacon signed with `key` and returns the endpoint
+// the first good one names, or None if none arrives within `timeout`.
+// Beacons that fail to verify are logged and skipped.
+pub async fn find(port: u16, key: &[u8], timeout: Duration) -> Result<Option<Target>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+        .await
+        .with_context(|| format!("error listening for beacons on port {}", port))?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = [0; 1500];
+    loop {
+        let (len, from) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            Ok(r) => r.context("error receiving a beacon")?,
+            Err(_) => return Ok(None),
+        };
+        match check(&buf[..len], key) {
+            Ok(target) => return Ok(Some(target)),
+            Err(e) => output::warning(format_args!("ignoring beacon from {}: {}", from, e)),
+        }
+    }
+}
+
+fn check(packet: &[u8], key: &[u8]) -> Result<Target, String> {
+    let beacon = beacon::decode(packet, key)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if now.abs_diff(beacon.timestamp) > beacon::MAX_AGE_SECS {
+        return Err(format!(
+            "timestamp {} is more than {} seconds from ours ({})",
+            beacon.timestamp,
+            beacon::MAX_AGE_SECS,
+            now
+        ));
+    }
+    beacon
+        .endpoint
+        .parse()
+        .map_err(|e| format!("invalid endpoint '{}': {}", beacon.endpoint, e))
+}
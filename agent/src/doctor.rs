@@ -0,0 +1,456 @@
+use anyhow::Result;
+use client::Target;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use proto::strapper;
+
+use crate::{hostname, netns, output, poll, wireguard, Opt};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Outcome {
+    Pass,
+    // Worth knowing, but the agent would run.
+    Warn,
+    // The agent would fail, or advertise nothing useful.
+    Fail,
+    // Left out because a check it needs failed.
+    Skip,
+}
+
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+fn check(name: impl Into<String>, outcome: Outcome, message: impl Into<String>) -> Check {
+    Check {
+        name: name.into(),
+        outcome,
+        message: message.into(),
+    }
+}
+
+// What `doctor` runs: the steps the agent takes on startup, each on its own
+// so one failing doesn't hide the rest, going through the same functions
+// run_advertise does. Returns whether none failed.
+pub async fn run(opt: &Opt) -> Result<bool> {
+    let mut checks = vec![];
+
+    let source = &opt.hostname_source;
+    let hostname = match source.read().await {
+        Ok(hostname) => {
+            checks.push(check("hostname source", Outcome::Pass, source.to_string()));
+            Ok(hostname)
+        }
+        // What the agent would do too, but it'd be under the wrong name.
+        Err(e) if source.is_cloud() => {
+            checks.push(check(
+                "hostname source",
+                Outcome::Warn,
+                format!(
+                    "{:#}; the agent would fall back to the kernel's hostname",
+                    e
+                ),
+            ));
+            hostname::read_kernel().await
+        }
+        Err(e) => Err(e),
+    };
+    match hostname {
+        Ok(hostname) => {
+            checks.push(hostname_check(&hostname));
+            let fqdn = crate::read_fqdn(&hostname).await;
+            checks.push(if fqdn.is_empty() {
+                check(
+                    "fqdn",
+                    Outcome::Warn,
+                    "neither the kernel's domainname nor resolv.conf names a domain; fine unless the server publishes by FQDN",
+                )
+            } else {
+                check("fqdn", Outcome::Pass, fqdn)
+            });
+        }
+        Err(e) => checks.push(check("hostname", Outcome::Fail, format!("{:#}", e))),
+    }
+
+    checks.push(match crate::read_labels(opt).await {
+        Ok(labels) if labels.is_empty() => check("labels", Outcome::Pass, "none set"),
+        Ok(labels) => check(
+            "labels",
+            Outcome::Pass,
+            labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        Err(e) => check("labels", Outcome::Fail, format!("{:#}", e)),
+    });
+
+    checks.extend(interface_checks(opt).await);
+
+    if let Some(path) = &opt.auth_token_file {
+        checks.push(match client::read_token_file(path) {
+            Ok((_, true)) => check(
+                "auth token",
+                Outcome::Warn,
+                format!("{} is world-readable; chmod o-r it", path.display()),
+            ),
+            Ok(_) => check("auth token", Outcome::Pass, path.display().to_string()),
+            Err(e) => check("auth token", Outcome::Fail, format!("{:#}", e)),
+        });
+    }
+
+    match crate::discover_target(opt).await {
+        Ok(target) => checks.push(endpoint_check(opt, &target, true).await),
+        Err(e) => checks.push(check("endpoint", Outcome::Fail, format!("{:#}", e))),
+    }
+    for target in opt.also_endpoint.iter() {
+        checks.push(endpoint_check(opt, target, false).await);
+    }
+
+    checks.push(notify_check());
+
+    output::doctor(&checks);
+    Ok(!checks.iter().any(|c| matches!(c.outcome, Outcome::Fail)))
+}
+
+fn hostname_check(hostname: &str) -> Check {
+    if hostname.is_empty() || hostname == "(none)" {
+        return check(
+            "hostname",
+            Outcome::Fail,
+            "the kernel has no hostname; set one with hostnamectl",
+        );
+    }
+    let valid = hostname.split('.').all(|l| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if !valid {
+        return check(
+            "hostname",
+            Outcome::Fail,
+            format!(
+                "'{}' isn't a valid DNS name, so no records can be published under it",
+                hostname
+            ),
+        );
+    }
+    if hostname == "localhost" || hostname.starts_with("localhost.") {
+        return check(
+            "hostname",
+            Outcome::Warn,
+            format!(
+                "'{}' is the default hostname, which every new machine shares",
+                hostname
+            ),
+        );
+    }
+    check("hostname", Outcome::Pass, hostname)
+}
+
+async fn interface_checks(opt: &Opt) -> Vec<Check> {
+    let mut checks = vec![];
+    let netlink = match opt.poll_interval_secs {
+        Some(_) => {
+            checks.push(check(
+                "netlink",
+                Outcome::Skip,
+                "polling /proc/net (--poll-interval-secs)",
+            ));
+            None
+        }
+        None => match crate::open_netlink(opt) {
+            Ok(netlink) => Some(netlink),
+            Err(e) if !opt.require_netlink && opt.netns.is_none() => {
+                checks.push(check(
+                    "netlink",
+                    Outcome::Warn,
+                    format!(
+                        "{:#}; the agent will poll /proc/net instead (--require-netlink to fail)",
+                        e
+                    ),
+                ));
+                None
+            }
+            Err(e) => {
+                return vec![
+                    check(
+                        "netlink",
+                        Outcome::Fail,
+                        format!("{:#}; the agent needs a rtnetlink socket", e),
+                    ),
+                    check("addresses", Outcome::Skip, "needs netlink"),
+                ];
+            }
+        },
+    };
+
+    let mut state = crate::new_state(opt, strapper::NodeAdvertisement::default());
+    let listed = match netlink {
+        Some((connection, handle, _)) => {
+            let connection = tokio::spawn(connection);
+            checks.push(check(
+                "netlink",
+                Outcome::Pass,
+                match &opt.netns {
+                    Some(ns) => format!("connected in network namespace {}", ns),
+                    None => "connected".to_owned(),
+                },
+            ));
+            let listed = crate::process_ifaces(&handle, &mut state).await;
+            connection.abort();
+            listed
+        }
+        None => poll::load(&mut state),
+    };
+    if let Err(e) = listed {
+        checks.push(check("addresses", Outcome::Fail, format!("{:#}", e)));
+        return checks;
+    }
+
+    let interfaces = state.interfaces();
+    let addrs: Vec<IpAddr> = interfaces
+        .iter()
+        .flat_map(|i| i.ipaddr.iter().filter_map(|a| a.parse().ok()))
+        .collect();
+    let count = addrs.len();
+    checks.push(if count == 0 {
+        check(
+            "addresses",
+            if opt.allow_empty_advertisement {
+                Outcome::Warn
+            } else {
+                Outcome::Fail
+            },
+            format!(
+                "none to advertise on {} interfaces; check --exclude-ifaces, --address-scope and --address-family",
+                interfaces.len()
+            ),
+        )
+    } else if count < opt.min_addresses {
+        check(
+            "addresses",
+            if opt.require_min_addresses {
+                Outcome::Fail
+            } else {
+                Outcome::Warn
+            },
+            format!(
+                "{} to advertise, fewer than --min-addresses {}",
+                count, opt.min_addresses
+            ),
+        )
+    } else {
+        check(
+            "addresses",
+            Outcome::Pass,
+            format!(
+                "{} to advertise on {} interfaces",
+                count,
+                interfaces.iter().filter(|i| !i.ipaddr.is_empty()).count()
+            ),
+        )
+    });
+
+    let family = state.filter().family;
+    if family.v6() {
+        checks.push(if addrs.iter().any(|a| a.is_ipv6() && a.is_global()) {
+            check("ipv6", Outcome::Pass, "global address found")
+        } else {
+            check(
+                "ipv6",
+                Outcome::Warn,
+                "no global IPv6 address found on any non-excluded interface; check RA/DHCPv6",
+            )
+        });
+    }
+    if family.v4() {
+        checks.push(if addrs.iter().any(IpAddr::is_ipv4) {
+            check("ipv4", Outcome::Pass, "address found")
+        } else {
+            check(
+                "ipv4",
+                Outcome::Warn,
+                "no IPv4 address found on any non-excluded interface; check DHCP",
+            )
+        });
+    }
+
+    let wireguard: Vec<u32> = interfaces
+        .iter()
+        .filter(|i| i.kind == "wireguard")
+        .map(|i| i.index)
+        .collect();
+    if !wireguard.is_empty() && !opt.skip_wireguard_keys {
+        let netns = opt.netns.as_deref().map(netns::resolve);
+        checks.push(match wireguard::public_keys(netns.as_deref(), &wireguard) {
+            Ok(keys) if keys.len() == wireguard.len() => check(
+                "wireguard",
+                Outcome::Pass,
+                format!("public keys read for {} interfaces", keys.len()),
+            ),
+            Ok(keys) => check(
+                "wireguard",
+                Outcome::Warn,
+                format!(
+                    "{} of {} interfaces have no key configured",
+                    wireguard.len() - keys.len(),
+                    wireguard.len()
+                ),
+            ),
+            Err(e) => check(
+                "wireguard",
+                Outcome::Warn,
+                format!(
+                    "{:#}; their keys won't be advertised (--skip-wireguard-keys to not try)",
+                    e
+                ),
+            ),
+        });
+    }
+    checks
+}
+
+// Whether anything listens at the target, short of speaking gRPC to it.
+async fn reach(target: &Target) -> Result<(), String> {
+    let uri = match target {
+        Target::Unix(path) => {
+            return tokio::net::UnixStream::connect(path)
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("unix socket connect failed: {}", e));
+        }
+        Target::Uri(uri) => uri,
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| "endpoint has no host".to_owned())?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+    let addrs: Vec<_> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .collect();
+    let mut error = format!("{} resolved to no addresses", host);
+    for addr in addrs {
+        match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => error = format!("TCP connect to {} failed: {}", addr, e),
+            Err(_) => {
+                error = format!(
+                    "TCP connect to {} timed out after {}s",
+                    addr,
+                    CONNECT_TIMEOUT.as_secs()
+                )
+            }
+        }
+    }
+    Err(error)
+}
+
+async fn endpoint_check(opt: &Opt, target: &Target, primary: bool) -> Check {
+    let name = if primary { "endpoint" } else { "also-endpoint" };
+    // The agent carries on without the extra ones.
+    let failed = if primary {
+        Outcome::Fail
+    } else {
+        Outcome::Warn
+    };
+    if let Err(e) = reach(target).await {
+        return check(name, failed, format!("{}: {}", target, e));
+    }
+    let connected = match target {
+        Target::Unix(_) => "unix socket connect ok",
+        Target::Uri(_) => "TCP connect ok",
+    };
+    if let Target::Uri(uri) = target {
+        if uri.scheme_str() == Some("https") {
+            return check(
+                name,
+                failed,
+                format!(
+                    "{}: {}, but this agent is built without TLS, so use http://",
+                    target, connected
+                ),
+            );
+        }
+    }
+
+    let status = async {
+        let mut client = crate::authenticated_client(opt, target).await?;
+        client.status().await
+    };
+    match tokio::time::timeout(STATUS_TIMEOUT, status).await {
+        Ok(Ok(status)) => check(
+            name,
+            Outcome::Pass,
+            format!(
+                "{}: {}, server {} answered",
+                target, connected, status.version
+            ),
+        ),
+        Ok(Err(e)) => check(
+            name,
+            failed,
+            format!(
+                "{}: {}, gRPC status request failed: {:#}",
+                target, connected, e
+            ),
+        ),
+        Err(_) => check(
+            name,
+            failed,
+            format!(
+                "{}: {}, but no gRPC answer within {}s; is it a strapper server?",
+                target,
+                connected,
+                STATUS_TIMEOUT.as_secs()
+            ),
+        ),
+    }
+}
+
+// Abstract sockets (@name) can't be looked for.
+fn notify_check() -> Check {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        None => check(
+            "systemd",
+            Outcome::Warn,
+            "NOTIFY_SOCKET isn't set; after its first advertisement the agent waits for systemd to take READY before it watches for changes, so run it from a Type=notify unit",
+        ),
+        Some(path) if path.to_string_lossy().starts_with('@') => check(
+            "systemd",
+            Outcome::Pass,
+            format!("NOTIFY_SOCKET is {}", path.to_string_lossy()),
+        ),
+        Some(path) if Path::new(&path).exists() => check(
+            "systemd",
+            Outcome::Pass,
+            format!("NOTIFY_SOCKET is {}", path.to_string_lossy()),
+        ),
+        Some(path) => check(
+            "systemd",
+            Outcome::Fail,
+            format!(
+                "NOTIFY_SOCKET is {}, which doesn't exist",
+                path.to_string_lossy()
+            ),
+        ),
+    }
+}
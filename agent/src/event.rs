@@ -0,0 +1,80 @@
+use anyhow::Result;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use rtnetlink::packet::{rtnl, NetlinkMessage, NetlinkPayload};
+use rtnetlink::sys::SocketAddr;
+use tokio::task::JoinHandle;
+
+use crate::StreamEnded;
+
+// The kernel changes the event loop acts on, taken out of rtnl's nesting so
+// the loop can be fed them from something other than a netlink socket.
+pub enum Event {
+    NewAddress(rtnl::address::AddressMessage),
+    DelAddress(rtnl::address::AddressMessage),
+    NewLink(rtnl::link::LinkMessage),
+    DelLink(rtnl::link::LinkMessage),
+    NewRoute(rtnl::route::RouteMessage),
+    DelRoute(rtnl::route::RouteMessage),
+}
+
+impl Event {
+    // None for messages the loop has no use for.
+    pub fn from_netlink(message: NetlinkMessage<rtnl::RtnlMessage>) -> Option<Event> {
+        let inner = match message.payload {
+            NetlinkPayload::InnerMessage(inner) => inner,
+            _ => return None,
+        };
+        match inner {
+            rtnl::RtnlMessage::NewAddress(addr) => Some(Event::NewAddress(addr)),
+            rtnl::RtnlMessage::DelAddress(addr) => Some(Event::DelAddress(addr)),
+            rtnl::RtnlMessage::NewLink(link) => Some(Event::NewLink(link)),
+            rtnl::RtnlMessage::DelLink(link) => Some(Event::DelLink(link)),
+            rtnl::RtnlMessage::NewRoute(route) => Some(Event::NewRoute(route)),
+            rtnl::RtnlMessage::DelRoute(route) => Some(Event::DelRoute(route)),
+            _ => None,
+        }
+    }
+}
+
+// Ends with a StreamEnded error when whatever feeds it goes away, which
+// restarts the agent's loop (or, with --exit-on-stream-end, the agent).
+pub type Events = BoxStream<'static, Result<Event>>;
+
+// A netlink subscription's events, until either its message stream or its
+// connection task ends.
+pub fn netlink(
+    messages: impl Stream<Item = (NetlinkMessage<rtnl::RtnlMessage>, SocketAddr)>
+        + Unpin
+        + Send
+        + 'static,
+    connection: JoinHandle<()>,
+) -> Events {
+    let events = messages.filter_map(|(message, _)| future::ready(Event::from_netlink(message)));
+    stream::unfold(Some((events, connection)), |open| async move {
+        let (mut events, mut connection) = open?;
+        let reason = tokio::select! {
+            e = events.next() => match e {
+                Some(event) => return Some((Ok(event), Some((events, connection)))),
+                None => "netlink event stream ended".to_owned(),
+            },
+            r = &mut connection => match r {
+                Ok(()) => "netlink connection closed".to_owned(),
+                Err(e) => format!("netlink connection task failed: {}", e),
+            },
+        };
+        Some((Err(StreamEnded(reason).into()), None))
+    })
+    .boxed()
+}
+
+// Never returns without events, as when polling.
+pub async fn next(events: &mut Option<Events>) -> Result<Event> {
+    match events {
+        Some(events) => match events.next().await {
+            Some(event) => event,
+            None => Err(StreamEnded("event stream ended".to_owned()).into()),
+        },
+        None => future::pending().await,
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::io;
+
+// What kind of failure ended the agent, for scripts to tell apart by exit
+// code:
+//
+//   1  internal: anything not below, and a doctor check failing
+//   2  config: bad arguments, label or token files, --otlp-endpoint
+//   3  network: a socket the agent needs couldn't be bound
+//   4  netlink: no netlink socket, or not allowed one
+//   5  upstream: the server couldn't be reached, or refused what was sent
+//
+// Errors are classified by what's in their chain; where that can't tell,
+// the failing call attaches a category as context.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Category {
+    Internal,
+    Config,
+    Network,
+    Netlink,
+    Upstream,
+}
+
+impl Category {
+    pub fn code(self) -> i32 {
+        match self {
+            Category::Internal => 1,
+            Category::Config => 2,
+            Category::Network => 3,
+            Category::Netlink => 4,
+            Category::Upstream => 5,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Internal => "internal",
+            Category::Config => "config",
+            Category::Network => "network",
+            Category::Netlink => "netlink",
+            Category::Upstream => "upstream",
+        }
+    }
+
+    // What to look at first, if there's anything to say.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Category::Internal => None,
+            Category::Config => Some("see --help for the options involved"),
+            Category::Network => Some("is another process using the port?"),
+            Category::Netlink => Some("are you running with CAP_NET_ADMIN?"),
+            Category::Upstream => Some("is the server at --endpoint up and accepting this node?"),
+        }
+    }
+}
+
+// As context, so the category reads as part of the message.
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Internal => "internal error",
+            Category::Config => "invalid configuration",
+            Category::Network => "network unavailable",
+            Category::Netlink => "netlink unavailable",
+            Category::Upstream => "server unavailable",
+        })
+    }
+}
+
+pub fn classify(error: &anyhow::Error) -> Category {
+    if let Some(category) = error.downcast_ref::<Category>() {
+        return *category;
+    }
+    for cause in error.chain() {
+        if cause.is::<client::AdvertiseError>()
+            || cause.is::<tonic::Status>()
+            || cause.is::<tonic::transport::Error>()
+        {
+            return Category::Upstream;
+        }
+        if cause.is::<rtnetlink::Error>() {
+            return Category::Netlink;
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            match e.kind() {
+                io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => {
+                    return Category::Network
+                }
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::TimedOut => return Category::Upstream,
+                _ => {}
+            }
+        }
+    }
+    Category::Internal
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+    use std::io;
+
+    use super::{classify, Category};
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        anyhow::Error::new(io::Error::from(kind))
+    }
+
+    #[test]
+    fn by_chain() {
+        let status = || tonic::Status::unavailable("down");
+        assert_eq!(classify(&status().into()), Category::Upstream);
+        let refused = client::AdvertiseError {
+            request_id: "r".to_owned(),
+            status: tonic::Status::permission_denied("not this node"),
+        };
+        assert_eq!(
+            classify(&anyhow::Error::new(refused).context("error advertising")),
+            Category::Upstream
+        );
+        assert_eq!(
+            classify(&anyhow::Error::new(rtnetlink::Error::RequestFailed).context("dump")),
+            Category::Netlink
+        );
+
+        for kind in &[io::ErrorKind::AddrInUse, io::ErrorKind::AddrNotAvailable] {
+            assert_eq!(classify(&io_error(*kind)), Category::Network, "{:?}", kind);
+        }
+        for kind in &[
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+        ] {
+            assert_eq!(classify(&io_error(*kind)), Category::Upstream, "{:?}", kind);
+        }
+        // Found however deep it sits.
+        let deep = io_error(io::ErrorKind::AddrInUse)
+            .context("error binding")
+            .context("error starting");
+        assert_eq!(classify(&deep), Category::Network);
+
+        assert_eq!(
+            classify(&io_error(io::ErrorKind::NotFound)),
+            Category::Internal
+        );
+        assert_eq!(classify(&anyhow!("something else")), Category::Internal);
+    }
+
+    #[test]
+    fn by_context() {
+        // The category a call attaches wins over what's under it, at any depth.
+        let e = io_error(io::ErrorKind::NotFound).context(Category::Config);
+        assert_eq!(classify(&e), Category::Config);
+        let e = Err::<(), _>(io_error(io::ErrorKind::PermissionDenied))
+            .context(Category::Netlink)
+            .context("error opening netlink")
+            .unwrap_err();
+        assert_eq!(classify(&e), Category::Netlink);
+        let e = Err::<(), _>(tonic::Status::unavailable("down"))
+            .context(Category::Config)
+            .unwrap_err();
+        assert_eq!(classify(&e), Category::Config);
+        assert!(format!("{:#}", e).starts_with("invalid configuration: "));
+    }
+
+    #[test]
+    fn codes() {
+        let all = [
+            Category::Internal,
+            Category::Config,
+            Category::Network,
+            Category::Netlink,
+            Category::Upstream,
+        ];
+        let codes: Vec<i32> = all.iter().map(|c| c.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5]);
+        let names: Vec<&str> = all.iter().map(|c| c.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["internal", "config", "network", "netlink", "upstream"]
+        );
+        assert!(all
+            .iter()
+            .all(|c| c.hint().is_some() == (*c != Category::Internal)));
+    }
+}
@@ -0,0 +1,678 @@
+use anyhow::anyhow;
+use ipnet::IpNet;
+use regex::Regex;
+use rtnetlink::packet::rtnl;
+use rtnetlink::packet::rtnl::constants::{
+    RT_SCOPE_HOST, RT_SCOPE_LINK, RT_SCOPE_SITE, RT_SCOPE_UNIVERSE,
+};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// The widest kernel address scope (ifa_scope) to advertise. Scopes are ordered
+// universe < site < link < host, so each setting admits everything narrower
+// than it in that list.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressScope {
+    Universe,
+    Site,
+    Link,
+    All,
+}
+
+impl FromStr for AddressScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "universe" => Ok(AddressScope::Universe),
+            "site" => Ok(AddressScope::Site),
+            "link" => Ok(AddressScope::Link),
+            "all" => Ok(AddressScope::All),
+            _ => Err(anyhow!(
+                "unknown address scope '{}' (expected universe, site, link or all)",
+                s
+            )),
+        }
+    }
+}
+
+impl AddressScope {
+    fn max(&self) -> u8 {
+        match self {
+            AddressScope::Universe => RT_SCOPE_UNIVERSE,
+            AddressScope::Site => RT_SCOPE_SITE,
+            AddressScope::Link => RT_SCOPE_LINK,
+            AddressScope::All => u8::MAX,
+        }
+    }
+}
+
+// Which IP versions get dumped, subscribed to and advertised, for
+// single-stack networks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressFamily {
+    V4,
+    V6,
+    Both,
+}
+
+impl FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(AddressFamily::V4),
+            "v6" => Ok(AddressFamily::V6),
+            "both" => Ok(AddressFamily::Both),
+            _ => Err(anyhow!(
+                "unknown address family '{}' (expected v4, v6 or both)",
+                s
+            )),
+        }
+    }
+}
+
+impl AddressFamily {
+    pub fn v4(&self) -> bool {
+        *self != AddressFamily::V6
+    }
+
+    pub fn v6(&self) -> bool {
+        *self != AddressFamily::V4
+    }
+
+    fn accepts(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(_) => self.v4(),
+            IpAddr::V6(_) => self.v6(),
+        }
+    }
+}
+
+// The address flags, as given on the command line. AddressPolicy turns them
+// into rules.
+#[derive(Clone, Debug)]
+pub struct AddressOptions {
+    pub family: AddressFamily,
+    pub scope: AddressScope,
+    pub include_ula: bool,
+    pub include_link_local: bool,
+    pub include_v4_cgnat: bool,
+    // Matched against IPv4 alias labels; unlabelled addresses match "".
+    pub only_labels: Option<Regex>,
+    pub exclude_labels: Option<Regex>,
+    // Addresses advertised as VIPs; see AddressPolicy::is_vip.
+    pub vips: Vec<IpNet>,
+}
+
+// One step of an AddressPolicy. Each either decides or passes the address
+// on to the next.
+#[derive(Clone, Debug)]
+pub enum AddressRule {
+    RejectOtherFamily(AddressFamily),
+    RejectUnlessLabel(Regex),
+    RejectLabel(Regex),
+    // Link-local addresses carry link scope, so opting into them has to get
+    // them past a narrower scope limit too; `max_scope` is at least link.
+    AcceptLinkLocal { max_scope: u8 },
+    RejectScopeAbove(AddressScope),
+    AcceptGlobal,
+    AcceptV4Private,
+    AcceptUla,
+    AcceptV4Cgnat,
+}
+
+impl AddressRule {
+    fn evaluate(&self, scope: u8, addr: &IpAddr, label: &str) -> Option<Decision> {
+        let decided = match self {
+            AddressRule::RejectOtherFamily(family) => !family.accepts(addr),
+            AddressRule::RejectUnlessLabel(r) => !r.is_match(label),
+            AddressRule::RejectLabel(r) => r.is_match(label),
+            AddressRule::AcceptLinkLocal { max_scope } => {
+                is_link_local(addr) && scope <= *max_scope
+            }
+            AddressRule::RejectScopeAbove(max) => scope > max.max(),
+            AddressRule::AcceptGlobal => match addr {
+                IpAddr::V6(a) => a.is_global(),
+                IpAddr::V4(a) => a.is_global(),
+            },
+            AddressRule::AcceptV4Private => matches!(addr, IpAddr::V4(a) if a.is_private()),
+            AddressRule::AcceptUla => matches!(addr, IpAddr::V6(a) if a.is_unique_local()),
+            AddressRule::AcceptV4Cgnat => matches!(addr, IpAddr::V4(a) if a.is_shared()),
+        };
+        if !decided {
+            return None;
+        }
+        let rule = self.to_string();
+        Some(match self {
+            AddressRule::AcceptLinkLocal { .. }
+            | AddressRule::AcceptGlobal
+            | AddressRule::AcceptV4Private
+            | AddressRule::AcceptUla
+            | AddressRule::AcceptV4Cgnat => Decision::Accept(rule),
+            _ => Decision::Reject(rule),
+        })
+    }
+}
+
+impl std::fmt::Display for AddressRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AddressRule::RejectOtherFamily(family) => {
+                write!(f, "not in --address-family {:?}", family)
+            }
+            AddressRule::RejectUnlessLabel(r) => {
+                write!(f, "label doesn't match --only-labels {}", r)
+            }
+            AddressRule::RejectLabel(r) => write!(f, "label matches --exclude-labels {}", r),
+            AddressRule::AcceptLinkLocal { .. } => {
+                write!(f, "link-local, with --include-link-local")
+            }
+            AddressRule::RejectScopeAbove(max) => write!(f, "outside --address-scope {:?}", max),
+            AddressRule::AcceptGlobal => write!(f, "globally routable"),
+            AddressRule::AcceptV4Private => write!(f, "IPv4 private"),
+            AddressRule::AcceptUla => write!(f, "IPv6 unique local, with --include-ula"),
+            AddressRule::AcceptV4Cgnat => {
+                write!(f, "IPv4 shared address space, with --include-v4-cgnat")
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Decision {
+    // Both carry the rule that decided.
+    Accept(String),
+    Reject(String),
+}
+
+// Decides which kernel addresses are worth advertising at all, by running
+// them through ordered rules until one decides; an address no rule accepts
+// is rejected. Adds and deletes go through the same policy so a filtered
+// address never shows up as a change.
+#[derive(Clone, Debug)]
+pub struct AddressPolicy {
+    pub family: AddressFamily,
+    rules: Vec<AddressRule>,
+    vips: Vec<IpNet>,
+}
+
+impl AddressPolicy {
+    pub fn from_options(options: AddressOptions) -> AddressPolicy {
+        let mut rules = vec![AddressRule::RejectOtherFamily(options.family)];
+        if let Some(r) = options.only_labels {
+            rules.push(AddressRule::RejectUnlessLabel(r));
+        }
+        if let Some(r) = options.exclude_labels {
+            rules.push(AddressRule::RejectLabel(r));
+        }
+        if options.include_link_local {
+            rules.push(AddressRule::AcceptLinkLocal {
+                max_scope: options.scope.max().max(RT_SCOPE_LINK),
+            });
+        }
+        rules.push(AddressRule::RejectScopeAbove(options.scope));
+        rules.push(AddressRule::AcceptGlobal);
+        rules.push(AddressRule::AcceptV4Private);
+        if options.include_ula {
+            rules.push(AddressRule::AcceptUla);
+        }
+        if options.include_v4_cgnat {
+            rules.push(AddressRule::AcceptV4Cgnat);
+        }
+        AddressPolicy {
+            family: options.family,
+            rules,
+            vips: options.vips,
+        }
+    }
+
+    // Whether `addr` is one of the --vip addresses, which keepalived (or
+    // the like) moves between nodes. It's still subject to the rules; this
+    // only marks it, so the server publishes it under vip: remappers.
+    pub fn is_vip(&self, addr: &IpAddr) -> bool {
+        self.vips.iter().any(|net| net.contains(addr))
+    }
+
+    pub fn evaluate(&self, scope: u8, addr: &IpAddr, label: &str) -> Decision {
+        self.rules
+            .iter()
+            .find_map(|r| r.evaluate(scope, addr, label))
+            .unwrap_or_else(|| Decision::Reject(
+                    "not global or private (see --include-ula, --include-link-local and --include-v4-cgnat)".to_owned(),
+                ))
+    }
+
+    pub fn accepts(&self, scope: u8, addr: &IpAddr, label: &str) -> bool {
+        matches!(self.evaluate(scope, addr, label), Decision::Accept(_))
+    }
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V6(a) => a.is_unicast_link_local(),
+        IpAddr::V4(a) => a.is_link_local(),
+    }
+}
+
+// The scope the kernel would give `addr`, for explaining a hypothetical one.
+pub fn default_scope(addr: &IpAddr) -> u8 {
+    if addr.is_loopback() {
+        RT_SCOPE_HOST
+    } else if is_link_local(addr) {
+        RT_SCOPE_LINK
+    } else {
+        RT_SCOPE_UNIVERSE
+    }
+}
+
+// A --vip: a network, or a bare address standing for just itself.
+pub fn parse_vip(s: &str) -> anyhow::Result<IpNet> {
+    if let Ok(addr) = s.parse::<IpAddr>() {
+        return Ok(IpNet::from(addr));
+    }
+    s.parse()
+        .map_err(|_| anyhow!("invalid VIP '{}' (expected an address or a CIDR)", s))
+}
+
+// Decides which links get advertised.
+pub struct LinkFilter {
+    pub exclude: Vec<Regex>,
+    pub skip_macless: bool,
+    pub include_slaves: bool,
+}
+
+impl LinkFilter {
+    pub fn excludes_name(&self, name: &str) -> bool {
+        self.exclude.iter().any(|r| r.is_match(name))
+    }
+
+    // Bond and bridge members carry the master's MAC and only briefly hold
+    // addresses during failover, so they're noise unless asked for.
+    pub fn excludes_slave(&self, l: &rtnl::link::LinkMessage) -> bool {
+        !self.include_slaves
+            && l.nlas
+                .iter()
+                .any(|nla| matches!(nla, rtnl::link::nlas::Nla::Master(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rtnetlink::packet::rtnl::constants::{
+        RT_SCOPE_HOST, RT_SCOPE_LINK, RT_SCOPE_SITE, RT_SCOPE_UNIVERSE,
+    };
+
+    use regex::Regex;
+    use std::net::IpAddr;
+
+    use super::{
+        is_link_local, AddressFamily, AddressOptions, AddressPolicy, AddressScope, Decision,
+    };
+
+    fn policy(scope: AddressScope, include_link_local: bool) -> AddressPolicy {
+        AddressPolicy::from_options(AddressOptions {
+            family: AddressFamily::Both,
+            scope,
+            include_ula: true,
+            include_link_local,
+            include_v4_cgnat: false,
+            only_labels: None,
+            exclude_labels: None,
+            vips: vec![],
+        })
+    }
+
+    // Scope and value disagreeing either way: the kernel's scope has to
+    // pass --address-scope and the value the usual checks.
+    #[test]
+    fn scope_and_value_disagree() {
+        use AddressScope::*;
+        let cases: &[(AddressScope, bool, u8, &str, bool)] = &[
+            (Universe, false, RT_SCOPE_UNIVERSE, "8.8.8.8", true),
+            // Global addresses with link scope, for anycast.
+            (Universe, false, RT_SCOPE_LINK, "8.8.8.8", false),
+            (Universe, false, RT_SCOPE_LINK, "2606:4700::1", false),
+            (Link, false, RT_SCOPE_LINK, "8.8.8.8", true),
+            // Host scope on a dummy interface.
+            (Universe, false, RT_SCOPE_HOST, "10.0.0.1", false),
+            (Link, false, RT_SCOPE_HOST, "10.0.0.1", false),
+            (All, false, RT_SCOPE_HOST, "10.0.0.1", true),
+            (Universe, false, RT_SCOPE_SITE, "fd00::1", false),
+            (Site, false, RT_SCOPE_SITE, "fd00::1", true),
+            // Universe scope doesn't make these worth advertising.
+            (Universe, false, RT_SCOPE_UNIVERSE, "127.0.0.1", false),
+            (All, false, RT_SCOPE_UNIVERSE, "fe80::1", false),
+            (All, false, RT_SCOPE_UNIVERSE, "169.254.0.1", false),
+            (All, false, RT_SCOPE_HOST, "::1", false),
+            // --include-link-local takes link-local values at link scope,
+            // and nothing else for being at link scope.
+            (Universe, true, RT_SCOPE_LINK, "fe80::1", true),
+            (Universe, true, RT_SCOPE_UNIVERSE, "fe80::1", true),
+            (Universe, true, RT_SCOPE_HOST, "fe80::1", false),
+            (Universe, true, RT_SCOPE_LINK, "10.0.0.1", false),
+        ];
+        for &(scope, link_local, kernel_scope, addr, accepted) in cases {
+            let p = policy(scope, link_local);
+            assert_eq!(
+                p.accepts(kernel_scope, &addr.parse().unwrap(), ""),
+                accepted,
+                "{} at scope {} under {:?}, --include-link-local {}",
+                addr,
+                kernel_scope,
+                scope,
+                link_local
+            );
+        }
+    }
+
+    #[test]
+    fn scope_rejection_is_explained() {
+        let p = policy(AddressScope::Universe, false);
+        assert_eq!(
+            p.evaluate(RT_SCOPE_LINK, &"8.8.8.8".parse().unwrap(), ""),
+            Decision::Reject("outside --address-scope Universe".to_owned())
+        );
+    }
+
+    // AddressFilter::accepts and accepts_label as they were before the
+    // rules, kept as the reference the policy has to agree with.
+    fn flags_accept(o: &AddressOptions, scope: u8, addr: &IpAddr, label: &str) -> bool {
+        let label_ok = o.only_labels.as_ref().is_none_or(|r| r.is_match(label))
+            && !o.exclude_labels.as_ref().is_some_and(|r| r.is_match(label));
+        if !label_ok || !o.family.accepts(addr) {
+            return false;
+        }
+        let link_local = o.include_link_local && is_link_local(addr);
+        let max_scope = if link_local {
+            o.scope.max().max(RT_SCOPE_LINK)
+        } else {
+            o.scope.max()
+        };
+        if scope > max_scope {
+            return false;
+        }
+        match addr {
+            IpAddr::V6(a) => a.is_global() || (o.include_ula && a.is_unique_local()) || link_local,
+            IpAddr::V4(a) => {
+                a.is_private()
+                    || a.is_global()
+                    || (o.include_v4_cgnat && a.is_shared())
+                    || link_local
+            }
+        }
+    }
+
+    // Every combination of the flags, over addresses of every kind the
+    // rules tell apart, at every scope, with and without a label.
+    #[test]
+    fn same_as_the_flags() {
+        let addrs: Vec<IpAddr> = [
+            "8.8.8.8",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "169.254.0.1",
+            "127.0.0.1",
+            "192.0.2.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "2606:4700::1",
+            "fd00::1",
+            "fc00::1",
+            "fe80::1",
+            "::1",
+            "::",
+            "2001:db8::1",
+            "ff02::1",
+            "::ffff:10.0.0.1",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let scopes = [
+            RT_SCOPE_UNIVERSE,
+            RT_SCOPE_SITE,
+            RT_SCOPE_LINK,
+            RT_SCOPE_HOST,
+        ];
+        let labels = ["", "eth0", "eth0:vip", "eth0:tmp"];
+        let label_filters: [(Option<&str>, Option<&str>); 4] = [
+            (None, None),
+            (Some(":vip$"), None),
+            (None, Some(":tmp$")),
+            (Some("^eth0"), Some(":vip$")),
+        ];
+        let bools = [false, true];
+
+        let mut checked = 0;
+        for &family in &[AddressFamily::V4, AddressFamily::V6, AddressFamily::Both] {
+            for &scope in &[
+                AddressScope::Universe,
+                AddressScope::Site,
+                AddressScope::Link,
+                AddressScope::All,
+            ] {
+                for &include_ula in &bools {
+                    for &include_link_local in &bools {
+                        for &include_v4_cgnat in &bools {
+                            for (only, exclude) in &label_filters {
+                                let options = AddressOptions {
+                                    family,
+                                    scope,
+                                    include_ula,
+                                    include_link_local,
+                                    include_v4_cgnat,
+                                    only_labels: only.map(|r| Regex::new(r).unwrap()),
+                                    exclude_labels: exclude.map(|r| Regex::new(r).unwrap()),
+                                    vips: vec![],
+                                };
+                                let policy = AddressPolicy::from_options(options.clone());
+                                for addr in &addrs {
+                                    for &kernel_scope in &scopes {
+                                        for label in &labels {
+                                            assert_eq!(
+                                                policy.accepts(kernel_scope, addr, label),
+                                                flags_accept(&options, kernel_scope, addr, label),
+                                                "{} at scope {} labelled '{}' under {:?}",
+                                                addr,
+                                                kernel_scope,
+                                                label,
+                                                options
+                                            );
+                                            checked += 1;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(checked, 3 * 4 * 8 * 4 * 20 * 4 * 4);
+    }
+
+    // The spec: for an address at the scope the kernel gives it, which
+    // rule decides under the flags set. Changes to the policy change this
+    // table.
+    #[test]
+    fn deciding_rules() {
+        struct Flags {
+            family: AddressFamily,
+            scope: AddressScope,
+            ula: bool,
+            link_local: bool,
+            cgnat: bool,
+            only: Option<&'static str>,
+            exclude: Option<&'static str>,
+        }
+        const DEFAULTS: Flags = Flags {
+            family: AddressFamily::Both,
+            scope: AddressScope::Universe,
+            ula: false,
+            link_local: false,
+            cgnat: false,
+            only: None,
+            exclude: None,
+        };
+        const NOT_PUBLIC: &str =
+            "not global or private (see --include-ula, --include-link-local and --include-v4-cgnat)";
+        let accept = |rule: &str| Decision::Accept(rule.to_owned());
+        let reject = |rule: &str| Decision::Reject(rule.to_owned());
+
+        let cases = vec![
+            (DEFAULTS, "8.8.8.8", "", accept("globally routable")),
+            (DEFAULTS, "2606:4700::1", "", accept("globally routable")),
+            (DEFAULTS, "10.0.0.1", "", accept("IPv4 private")),
+            (DEFAULTS, "192.168.1.1", "eth0:1", accept("IPv4 private")),
+            (DEFAULTS, "fd00::1", "", reject(NOT_PUBLIC)),
+            (
+                Flags {
+                    ula: true,
+                    ..DEFAULTS
+                },
+                "fd00::1",
+                "",
+                accept("IPv6 unique local, with --include-ula"),
+            ),
+            (DEFAULTS, "100.64.0.1", "", reject(NOT_PUBLIC)),
+            (
+                Flags {
+                    cgnat: true,
+                    ..DEFAULTS
+                },
+                "100.64.0.1",
+                "",
+                accept("IPv4 shared address space, with --include-v4-cgnat"),
+            ),
+            (
+                DEFAULTS,
+                "fe80::1",
+                "",
+                reject("outside --address-scope Universe"),
+            ),
+            (
+                DEFAULTS,
+                "169.254.0.1",
+                "",
+                reject("outside --address-scope Universe"),
+            ),
+            (
+                Flags {
+                    link_local: true,
+                    ..DEFAULTS
+                },
+                "fe80::1",
+                "",
+                accept("link-local, with --include-link-local"),
+            ),
+            (
+                DEFAULTS,
+                "127.0.0.1",
+                "",
+                reject("outside --address-scope Universe"),
+            ),
+            (
+                Flags {
+                    scope: AddressScope::All,
+                    ..DEFAULTS
+                },
+                "127.0.0.1",
+                "",
+                reject(NOT_PUBLIC),
+            ),
+            (DEFAULTS, "192.0.2.1", "", reject(NOT_PUBLIC)),
+            (DEFAULTS, "2001:db8::1", "", reject(NOT_PUBLIC)),
+            (
+                Flags {
+                    family: AddressFamily::V6,
+                    ..DEFAULTS
+                },
+                "10.0.0.1",
+                "",
+                reject("not in --address-family V6"),
+            ),
+            (
+                Flags {
+                    family: AddressFamily::V4,
+                    ..DEFAULTS
+                },
+                "2606:4700::1",
+                "",
+                reject("not in --address-family V4"),
+            ),
+            // The family goes first, whatever else would reject it.
+            (
+                Flags {
+                    family: AddressFamily::V4,
+                    exclude: Some(".*"),
+                    ..DEFAULTS
+                },
+                "fe80::1",
+                "",
+                reject("not in --address-family V4"),
+            ),
+            (
+                Flags {
+                    only: Some(":vip$"),
+                    ..DEFAULTS
+                },
+                "10.0.0.1",
+                "eth0",
+                reject("label doesn't match --only-labels :vip$"),
+            ),
+            (
+                Flags {
+                    only: Some(":vip$"),
+                    ..DEFAULTS
+                },
+                "10.0.0.1",
+                "eth0:vip",
+                accept("IPv4 private"),
+            ),
+            (
+                Flags {
+                    exclude: Some(":tmp$"),
+                    ..DEFAULTS
+                },
+                "10.0.0.1",
+                "eth0:tmp",
+                reject("label matches --exclude-labels :tmp$"),
+            ),
+            // Labels are checked before link-local is let in.
+            (
+                Flags {
+                    link_local: true,
+                    exclude: Some("^eth0$"),
+                    ..DEFAULTS
+                },
+                "169.254.0.1",
+                "eth0",
+                reject("label matches --exclude-labels ^eth0$"),
+            ),
+        ];
+        for (flags, addr, label, decision) in cases {
+            let policy = AddressPolicy::from_options(AddressOptions {
+                family: flags.family,
+                scope: flags.scope,
+                include_ula: flags.ula,
+                include_link_local: flags.link_local,
+                include_v4_cgnat: flags.cgnat,
+                only_labels: flags.only.map(|r| Regex::new(r).unwrap()),
+                exclude_labels: flags.exclude.map(|r| Regex::new(r).unwrap()),
+                vips: vec![],
+            });
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(
+                policy.evaluate(super::default_scope(&addr), &addr, label),
+                decision,
+                "{} labelled '{}'",
+                addr,
+                label
+            );
+        }
+    }
+}
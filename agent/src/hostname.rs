@@ -0,0 +1,347 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use hyper::{Body, Client, Method, Request};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::output;
+
+// Both providers answer on the link-local address, which saves depending on
+// DNS being up yet for metadata.google.internal.
+const EC2_METADATA: &str = "http://169.254.169.254/latest";
+const GCE_METADATA: &str = "http://169.254.169.254/computeMetadata/v1";
+
+// For each request to the metadata service; it answers in milliseconds when
+// it's there at all.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long an IMDSv2 token lasts; it's only used for the one request.
+const EC2_TOKEN_TTL_SECS: u32 = 60;
+
+// Where the name the agent advertises comes from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HostnameSource {
+    Kernel,
+    File(PathBuf),
+    Ec2,
+    Gce,
+    Static(String),
+}
+
+impl FromStr for HostnameSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            ensure!(!path.is_empty(), "file: needs a path");
+            return Ok(HostnameSource::File(PathBuf::from(path)));
+        }
+        if let Some(name) = s.strip_prefix("static:") {
+            ensure!(!name.is_empty(), "static: needs a name");
+            return Ok(HostnameSource::Static(name.to_owned()));
+        }
+        match s {
+            "kernel" => Ok(HostnameSource::Kernel),
+            "ec2" => Ok(HostnameSource::Ec2),
+            "gce" => Ok(HostnameSource::Gce),
+            _ => Err(anyhow!(
+                "unknown hostname source '{}' (expected kernel, file:<path>, ec2, gce or static:<name>)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HostnameSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostnameSource::Kernel => write!(f, "kernel"),
+            HostnameSource::File(path) => write!(f, "file:{}", path.display()),
+            HostnameSource::Ec2 => write!(f, "ec2"),
+            HostnameSource::Gce => write!(f, "gce"),
+            HostnameSource::Static(name) => write!(f, "static:{}", name),
+        }
+    }
+}
+
+impl HostnameSource {
+    pub fn is_cloud(&self) -> bool {
+        matches!(self, HostnameSource::Ec2 | HostnameSource::Gce)
+    }
+
+    // The name, or why the source couldn't give one.
+    pub async fn read(&self) -> Result<String> {
+        match self {
+            HostnameSource::Kernel => read_kernel().await,
+            HostnameSource::File(path) => Ok(tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("error reading hostname from {}", path.display()))?
+                .trim()
+                .to_owned()),
+            HostnameSource::Ec2 => read_ec2(EC2_METADATA)
+                .await
+                .context("error reading hostname from EC2 metadata"),
+            HostnameSource::Gce => read_gce(GCE_METADATA)
+                .await
+                .context("error reading hostname from GCE metadata"),
+            HostnameSource::Static(name) => Ok(name.clone()),
+        }
+    }
+
+    // read, except that the cloud sources fall back to the kernel's
+    // hostname, so an agent on an instance whose metadata service is
+    // unreachable still comes up under some name.
+    pub async fn resolve(&self) -> Result<String> {
+        match self.read().await {
+            Err(e) if self.is_cloud() => {
+                output::warning(format_args!("{:#}; using the kernel's hostname", e));
+                read_kernel().await
+            }
+            r => r,
+        }
+    }
+}
+
+pub async fn read_kernel() -> Result<String> {
+    Ok(tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+        .await
+        .context("error reading hostname")?
+        .trim_end()
+        .to_owned())
+}
+
+// IMDSv2: a session token from a PUT, then the GET with it. local-hostname
+// is the VPC's name for the instance, which every instance has, unlike the
+// public one.
+async fn read_ec2(base: &str) -> Result<String> {
+    let token = fetch(
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("{}/api/token", base))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", EC2_TOKEN_TTL_SECS)
+            .body(Body::empty())?,
+    )
+    .await
+    .context("error getting a metadata token")?;
+    fetch(
+        Request::builder()
+            .uri(format!("{}/meta-data/local-hostname", base))
+            .header("X-aws-ec2-metadata-token", token)
+            .body(Body::empty())?,
+    )
+    .await
+}
+
+async fn read_gce(base: &str) -> Result<String> {
+    fetch(
+        Request::builder()
+            .uri(format!("{}/instance/hostname", base))
+            .header("Metadata-Flavor", "Google")
+            .body(Body::empty())?,
+    )
+    .await
+}
+
+// The trimmed body of a successful response.
+async fn fetch(request: Request<Body>) -> Result<String> {
+    let uri = request.uri().clone();
+    let response = tokio::time::timeout(METADATA_TIMEOUT, Client::new().request(request))
+        .await
+        .map_err(|_| anyhow!("{} didn't answer within {:?}", uri, METADATA_TIMEOUT))?
+        .with_context(|| format!("error requesting {}", uri))?;
+    ensure!(
+        response.status().is_success(),
+        "{} answered {}",
+        uri,
+        response.status()
+    );
+    let body = tokio::time::timeout(
+        METADATA_TIMEOUT,
+        hyper::body::to_bytes(response.into_body()),
+    )
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "{} didn't finish answering within {:?}",
+            uri,
+            METADATA_TIMEOUT
+        )
+    })?
+    .with_context(|| format!("error reading {}", uri))?;
+    let name = String::from_utf8(body.to_vec())
+        .with_context(|| format!("{} answered with something other than text", uri))?
+        .trim()
+        .to_owned();
+    ensure!(!name.is_empty(), "{} answered with no name", uri);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::{read_ec2, read_gce, read_kernel, HostnameSource};
+
+    #[test]
+    fn parse() {
+        for s in &[
+            "kernel",
+            "file:/etc/hostname",
+            "ec2",
+            "gce",
+            "static:node-1",
+        ] {
+            let source: HostnameSource = s.parse().unwrap();
+            assert_eq!(source.to_string(), *s);
+        }
+        assert!("ec2".parse::<HostnameSource>().unwrap().is_cloud());
+        assert!(!"static:a".parse::<HostnameSource>().unwrap().is_cloud());
+        for s in &["", "file:", "static:", "azure", "Kernel"] {
+            assert!(s.parse::<HostnameSource>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_sources() {
+        let kernel = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        assert_eq!(
+            HostnameSource::Kernel.read().await.unwrap(),
+            kernel.trim_end()
+        );
+        assert_eq!(read_kernel().await.unwrap(), kernel.trim_end());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "  node-7.example.com ").unwrap();
+        let source = HostnameSource::File(file.path().to_owned());
+        assert_eq!(source.read().await.unwrap(), "node-7.example.com");
+        assert_eq!(source.resolve().await.unwrap(), "node-7.example.com");
+
+        let missing = HostnameSource::File("/nonexistent/hostname".into());
+        let e = missing.resolve().await.unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("/nonexistent/hostname"),
+            "{:#}",
+            e
+        );
+
+        let source = HostnameSource::Static("node-8".to_owned());
+        assert_eq!(source.read().await.unwrap(), "node-8");
+    }
+
+    // What a metadata service was asked: the method, the path and the
+    // headers the providers require.
+    type Asked = Arc<Mutex<Vec<(Method, String, Option<String>)>>>;
+
+    // A metadata service at the returned base URL that answers every
+    // request with `answer`, given the method and path.
+    async fn metadata(
+        header: &'static str,
+        answer: fn(&Method, &str) -> (StatusCode, &'static str),
+    ) -> (String, Asked) {
+        let asked = Asked::default();
+        let log = asked.clone();
+        let server =
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+                let log = log.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let (status, body) = answer(request.method(), request.uri().path());
+                        log.lock().unwrap().push((
+                            request.method().clone(),
+                            request.uri().path().to_owned(),
+                            request
+                                .headers()
+                                .get(header)
+                                .map(|v| v.to_str().unwrap().to_owned()),
+                        ));
+                        let mut response = Response::new(Body::from(body));
+                        *response.status_mut() = status;
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            }));
+        let base = format!("http://{}/latest", server.local_addr());
+        tokio::spawn(server);
+        (base, asked)
+    }
+
+    #[tokio::test]
+    async fn ec2() {
+        // The token goes from the PUT's answer into the GET's header.
+        let (base, asked) = metadata("x-aws-ec2-metadata-token", |method, path| {
+            match (method, path) {
+                (&Method::PUT, "/latest/api/token") => (StatusCode::OK, "token-1"),
+                (&Method::GET, "/latest/meta-data/local-hostname") => {
+                    (StatusCode::OK, "ip-10-0-0-1.ec2.internal\n")
+                }
+                _ => (StatusCode::NOT_FOUND, ""),
+            }
+        })
+        .await;
+        assert_eq!(read_ec2(&base).await.unwrap(), "ip-10-0-0-1.ec2.internal");
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec![
+                (Method::PUT, "/latest/api/token".to_owned(), None),
+                (
+                    Method::GET,
+                    "/latest/meta-data/local-hostname".to_owned(),
+                    Some("token-1".to_owned())
+                ),
+            ]
+        );
+
+        // No token, no name.
+        let (base, asked) = metadata("x-aws-ec2-metadata-token", |_, _| {
+            (StatusCode::FORBIDDEN, "")
+        })
+        .await;
+        let e = read_ec2(&base).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("metadata token"), "{:#}", e);
+        assert!(format!("{:#}", e).contains("403"), "{:#}", e);
+        assert_eq!(asked.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gce() {
+        let (base, asked) = metadata("metadata-flavor", |_, path| match path {
+            "/latest/instance/hostname" => (StatusCode::OK, "vm-1.c.project.internal"),
+            _ => (StatusCode::NOT_FOUND, ""),
+        })
+        .await;
+        assert_eq!(read_gce(&base).await.unwrap(), "vm-1.c.project.internal");
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec![(
+                Method::GET,
+                "/latest/instance/hostname".to_owned(),
+                Some("Google".to_owned())
+            )]
+        );
+
+        // An empty answer isn't a name.
+        let (base, _) = metadata("metadata-flavor", |_, _| (StatusCode::OK, " \n")).await;
+        let e = read_gce(&base).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("no name"), "{:#}", e);
+
+        let (base, _) = metadata("metadata-flavor", |_, _| (StatusCode::NOT_FOUND, "")).await;
+        let e = read_gce(&base).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("404"), "{:#}", e);
+    }
+
+    // A service that takes the connection and never answers runs into the
+    // timeout instead of holding the agent's startup.
+    #[tokio::test(start_paused = true)]
+    async fn unanswered() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/latest", listener.local_addr().unwrap());
+        let e = read_gce(&base).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("didn't answer"), "{:#}", e);
+        drop(listener);
+    }
+}
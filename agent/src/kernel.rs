@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use futures_util::TryStreamExt;
+use rtnetlink::packet::rtnl;
+use rtnetlink::IpVersion;
+
+// What the event loop asks the kernel for beyond its events: the dumps it
+// builds the state from, and rebuilds parts of it from when events can't be
+// trusted. Netlink answers them in the agent; tests answer them from a
+// kernel of their own making.
+#[tonic::async_trait(?Send)]
+pub trait Kernel {
+    async fn links(&self) -> Result<Vec<rtnl::link::LinkMessage>>;
+
+    // Of one family (AF_INET or AF_INET6), only on the interfaces `keep`
+    // takes.
+    async fn addresses(
+        &self,
+        family: u8,
+        keep: &dyn Fn(u32) -> bool,
+    ) -> Result<Vec<rtnl::address::AddressMessage>>;
+
+    // What one interface has right now.
+    async fn interface_addresses(&self, index: u32) -> Result<Vec<rtnl::address::AddressMessage>>;
+
+    async fn routes(&self, version: IpVersion) -> Result<Vec<rtnl::route::RouteMessage>>;
+}
+
+#[tonic::async_trait(?Send)]
+impl Kernel for rtnetlink::Handle {
+    async fn links(&self) -> Result<Vec<rtnl::link::LinkMessage>> {
+        self.link()
+            .get()
+            .execute()
+            .try_collect()
+            .await
+            .context("error listing interfaces")
+    }
+
+    // Only collects, so both families can be dumped at once; addresses on
+    // interfaces left out are dropped as they arrive.
+    async fn addresses(
+        &self,
+        family: u8,
+        keep: &dyn Fn(u32) -> bool,
+    ) -> Result<Vec<rtnl::address::AddressMessage>> {
+        let mut ret = Vec::new();
+        let mut message = self.address().get();
+        message.message_mut().header.family = family;
+        let mut addrs = message.execute();
+        while let Some(addr) = addrs.try_next().await.context("address lookup failed")? {
+            if keep(addr.header.index) {
+                ret.push(addr);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn interface_addresses(&self, index: u32) -> Result<Vec<rtnl::address::AddressMessage>> {
+        self.address()
+            .get()
+            .set_link_index_filter(index)
+            .execute()
+            .try_collect()
+            .await
+            .context("address lookup failed")
+    }
+
+    async fn routes(&self, version: IpVersion) -> Result<Vec<rtnl::route::RouteMessage>> {
+        self.route()
+            .get(version)
+            .execute()
+            .try_collect()
+            .await
+            .context("route lookup failed")
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use proto::strapper;
+
+use crate::output;
+
+// Links that don't track carrier (loopback, many virtual ones) say unknown.
+fn is_up(i: &strapper::Interface) -> bool {
+    !matches!(
+        strapper::OperState::from_i32(i.oper_state),
+        Some(strapper::OperState::Down)
+            | Some(strapper::OperState::LowerLayerDown)
+            | Some(strapper::OperState::NotPresent)
+            | Some(strapper::OperState::Dormant)
+    )
+}
+
+struct Link {
+    name: String,
+    // Whether its addresses are advertised, and the oper state advertised
+    // with them; both stay put for as long as a transition is pending.
+    advertised: bool,
+    oper_state: i32,
+    // What the kernel last said.
+    current: i32,
+    // When the link, having stayed in the other state, flips.
+    pending: Option<Instant>,
+}
+
+// What --link-down-hold-secs and --link-up-hold-secs make of link state: a
+// link's addresses are withdrawn once it has been down for the one, and
+// advertised again once it has been back up for the other. A link that goes
+// back within the hold cancels the change outright, so a flapping link
+// stays as it was rather than churning DNS.
+pub struct LinkHolds {
+    down_hold: Duration,
+    up_hold: Duration,
+    links: HashMap<u32, Link>,
+}
+
+pub enum HoldStatus<'a> {
+    Advertised(&'a str),
+    Withdrawn(&'a str),
+    // The name, whether it's going up, and how long until it does.
+    Pending(&'a str, bool, Duration),
+}
+
+impl LinkHolds {
+    pub fn new(down_hold: Duration, up_hold: Duration) -> LinkHolds {
+        LinkHolds {
+            down_hold,
+            up_hold,
+            links: HashMap::new(),
+        }
+    }
+
+    // Starts or cancels transitions for what the kernel now says, returning
+    // whether any link flipped. Links seen for the first time (at startup,
+    // say) take their state at once.
+    pub fn observe(&mut self, interfaces: &[strapper::Interface]) -> bool {
+        let now = Instant::now();
+        self.links
+            .retain(|index, _| interfaces.iter().any(|i| i.index == *index));
+        for i in interfaces {
+            let up = is_up(i);
+            let link = self.links.entry(i.index).or_insert_with(|| Link {
+                name: i.name.clone(),
+                advertised: up,
+                oper_state: i.oper_state,
+                current: i.oper_state,
+                pending: None,
+            });
+            link.name = i.name.clone();
+            link.current = i.oper_state;
+            if up == link.advertised {
+                if link.pending.take().is_some() {
+                    output::info(format_args!(
+                        "{} is {} again, leaving its addresses as they were",
+                        link.name,
+                        if up { "up" } else { "down" }
+                    ));
+                }
+                link.oper_state = i.oper_state;
+            } else if link.pending.is_none() {
+                let hold = if up { self.up_hold } else { self.down_hold };
+                link.pending = Some(now + hold);
+                if !hold.is_zero() {
+                    output::info(format_args!(
+                        "{} went {}, {} its addresses in {}s if it stays that way",
+                        link.name,
+                        if up { "up" } else { "down" },
+                        if up { "advertising" } else { "withdrawing" },
+                        hold.as_secs()
+                    ));
+                }
+            }
+        }
+        self.advance()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.links.values().filter_map(|l| l.pending).min()
+    }
+
+    // Carries out the transitions that have waited out their hold,
+    // returning whether there were any.
+    pub fn advance(&mut self) -> bool {
+        let now = Instant::now();
+        let mut flipped = false;
+        for link in self.links.values_mut() {
+            if link.pending.is_some_and(|at| at <= now) {
+                link.pending = None;
+                link.advertised = !link.advertised;
+                link.oper_state = link.current;
+                flipped = true;
+                output::info(format_args!(
+                    "{} {}'s addresses",
+                    if link.advertised {
+                        "advertising"
+                    } else {
+                        "withdrawing"
+                    },
+                    link.name
+                ));
+            }
+        }
+        flipped
+    }
+
+    // Brings an advertisement of what the kernel says to what the holds
+    // allow.
+    pub fn apply(&self, adv: &mut strapper::NodeAdvertisement) {
+        for i in adv.interfaces.iter_mut() {
+            let link = match self.links.get(&i.index) {
+                Some(l) => l,
+                None => continue,
+            };
+            i.oper_state = link.oper_state;
+            if !link.advertised {
+                i.ipaddr.clear();
+                i.address_info.clear();
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<HoldStatus<'_>> {
+        let now = Instant::now();
+        let mut links: Vec<(&u32, &Link)> = self.links.iter().collect();
+        links.sort_by_key(|(index, _)| **index);
+        links
+            .into_iter()
+            .map(|(_, l)| match l.pending {
+                Some(at) => {
+                    HoldStatus::Pending(&l.name, !l.advertised, at.saturating_duration_since(now))
+                }
+                None if l.advertised => HoldStatus::Advertised(&l.name),
+                None => HoldStatus::Withdrawn(&l.name),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proto::strapper;
+
+    use super::{HoldStatus, LinkHolds};
+
+    const HOLD: Duration = Duration::from_secs(10);
+
+    fn kernel(up: bool) -> strapper::NodeAdvertisement {
+        let oper_state = if up {
+            strapper::OperState::Up
+        } else {
+            strapper::OperState::Down
+        };
+        strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![strapper::Interface {
+                name: "eth0".to_owned(),
+                index: 2,
+                oper_state: oper_state as i32,
+                ipaddr: vec!["2001:db8::1".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    // The main loop, as far as holds go: what the kernel says, through the
+    // holds, advertised whenever it differs from what was last advertised.
+    struct Agent {
+        holds: LinkHolds,
+        kernel: strapper::NodeAdvertisement,
+        advertised: Vec<strapper::NodeAdvertisement>,
+    }
+
+    impl Agent {
+        fn new(up: bool) -> Agent {
+            let mut agent = Agent {
+                holds: LinkHolds::new(HOLD, HOLD),
+                kernel: kernel(up),
+                advertised: vec![],
+            };
+            agent.holds.observe(&agent.kernel.interfaces);
+            agent.advertise();
+            agent
+        }
+
+        fn advertise(&mut self) {
+            let mut adv = self.kernel.clone();
+            self.holds.apply(&mut adv);
+            if self.advertised.last() != Some(&adv) {
+                self.advertised.push(adv);
+            }
+        }
+
+        fn carrier(&mut self, up: bool) {
+            self.kernel = kernel(up);
+            self.holds.observe(&self.kernel.interfaces);
+            self.advertise();
+        }
+
+        // Time passing, with the hold deadlines firing as they come up.
+        async fn wait(&mut self, d: Duration) {
+            let until = tokio::time::Instant::now() + d;
+            while let Some(at) = self.holds.next_deadline().filter(|at| *at <= until) {
+                tokio::time::sleep_until(at).await;
+                self.holds.advance();
+                self.advertise();
+            }
+            tokio::time::sleep_until(until).await;
+        }
+
+        fn last(&self) -> &strapper::Interface {
+            &self.advertised.last().unwrap().interfaces[0]
+        }
+    }
+
+    #[tokio::test]
+    async fn flapping_then_down() {
+        tokio::time::pause();
+        let mut agent = Agent::new(true);
+        assert_eq!(agent.advertised.len(), 1);
+
+        // Bad optics: every few seconds, never for as long as the hold.
+        for _ in 0..10 {
+            agent.carrier(false);
+            agent.wait(Duration::from_secs(3)).await;
+            agent.carrier(true);
+            agent.wait(Duration::from_secs(2)).await;
+        }
+        assert_eq!(agent.advertised.len(), 1, "{:?}", agent.advertised);
+
+        // Then it goes for good.
+        agent.carrier(false);
+        agent.wait(HOLD - Duration::from_secs(1)).await;
+        assert_eq!(agent.advertised.len(), 1);
+        agent.wait(Duration::from_secs(1)).await;
+        assert_eq!(agent.advertised.len(), 2, "{:?}", agent.advertised);
+        assert!(agent.last().ipaddr.is_empty());
+        assert_eq!(agent.last().oper_state, strapper::OperState::Down as i32);
+
+        agent.wait(HOLD * 5).await;
+        assert_eq!(agent.advertised.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn flapping_then_up() {
+        tokio::time::pause();
+        let mut agent = Agent::new(false);
+        assert!(agent.last().ipaddr.is_empty());
+
+        for _ in 0..10 {
+            agent.carrier(true);
+            agent.wait(Duration::from_secs(9)).await;
+            agent.carrier(false);
+            agent.wait(Duration::from_secs(1)).await;
+        }
+        agent.carrier(true);
+        agent.wait(HOLD).await;
+        assert_eq!(agent.advertised.len(), 2, "{:?}", agent.advertised);
+        assert_eq!(agent.last().ipaddr, ["2001:db8::1"]);
+        assert_eq!(agent.last().oper_state, strapper::OperState::Up as i32);
+    }
+
+    #[tokio::test]
+    async fn pending_in_status() {
+        tokio::time::pause();
+        let mut agent = Agent::new(true);
+        match agent.holds.status()[..] {
+            [HoldStatus::Advertised("eth0")] => {}
+            _ => panic!("eth0 not advertised"),
+        }
+        agent.carrier(false);
+        agent.wait(Duration::from_secs(4)).await;
+        match agent.holds.status()[..] {
+            // The paused clock rounds sleeps up to the millisecond.
+            [HoldStatus::Pending("eth0", false, left)] => assert!(
+                left <= Duration::from_secs(6) && left > Duration::from_secs(5),
+                "{:?}",
+                left
+            ),
+            _ => panic!("eth0 not pending down"),
+        }
+        agent.wait(Duration::from_secs(6)).await;
+        match agent.holds.status()[..] {
+            [HoldStatus::Withdrawn("eth0")] => {}
+            _ => panic!("eth0 not withdrawn"),
+        }
+    }
+
+    // Without holds, link state goes straight through; a link seen for the
+    // first time takes its state at once, whatever the holds.
+    #[tokio::test]
+    async fn zero_hold_and_new_links() {
+        tokio::time::pause();
+        let mut holds = LinkHolds::new(Duration::ZERO, Duration::ZERO);
+        assert!(!holds.observe(&kernel(true).interfaces));
+        assert!(holds.observe(&kernel(false).interfaces));
+        assert!(holds.observe(&kernel(true).interfaces));
+        assert_eq!(holds.next_deadline(), None);
+
+        let mut holds = LinkHolds::new(HOLD, HOLD);
+        assert!(!holds.observe(&kernel(false).interfaces));
+        let mut adv = kernel(false);
+        holds.apply(&mut adv);
+        assert!(adv.interfaces[0].ipaddr.is_empty());
+
+        // Gone and back is a new link.
+        holds.observe(&[]);
+        assert!(!holds.observe(&kernel(true).interfaces));
+        let mut adv = kernel(true);
+        holds.apply(&mut adv);
+        assert_eq!(adv.interfaces[0].ipaddr, ["2001:db8::1"]);
+    }
+}
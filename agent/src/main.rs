@@ -1,294 +1,1993 @@
 #![feature(ip)]
 
+mod cache;
+mod discover;
+mod doctor;
+mod event;
+mod exit;
+mod filter;
+mod hostname;
+mod kernel;
+mod linkhold;
+mod netns;
+mod notices;
+mod otel;
+mod output;
+mod poll;
+mod readvertise;
+mod routes;
+mod runtime;
+mod select;
+mod state;
+mod supervise;
+#[cfg(test)]
+mod testing;
+mod upstream;
+mod wireguard;
+
 use structopt::StructOpt;
 
 use anyhow::{anyhow, Context, Result};
-use futures_util::{StreamExt, TryStreamExt};
 use regex::Regex;
-use rtnetlink::constants::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV6_IFADDR, RTMGRP_LINK};
+use rtnetlink::constants::{
+    RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK,
+};
 use rtnetlink::packet::rtnl;
 use rtnetlink::sys::SocketAddr;
-use std::convert::TryInto;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use rtnetlink::IpVersion;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{oneshot, watch};
+use tonic::transport::Endpoint;
 
-use proto::strapper::{self, node_state_service_client::NodeStateServiceClient};
+use client::{RetryPolicy, StrapperClient, Target};
+use event::{Event, Events};
+use exit::Category;
+use filter::{AddressFamily, AddressOptions, AddressPolicy, AddressScope, LinkFilter};
+use hostname::HostnameSource;
+use kernel::Kernel;
+use linkhold::LinkHolds;
+use output::OutputFormat;
+use proto::{canonical, delta, strapper};
+use select::SelectionPolicy;
+use state::{AdvertisementState, LinkUpdate};
+use upstream::{Advertiser, Clients, ReadyRequires, Upstream};
 
-#[derive(StructOpt)]
+#[derive(StructOpt, Clone)]
 struct Opt {
     #[structopt(default_value = "http://leader.infra.ibj.io:55555", long, short)]
-    endpoint: tonic::transport::Uri,
+    endpoint: Target,
 
-    #[structopt(long)]
+    // More servers to advertise to alongside --endpoint, e.g. one feeding an
+    // inventory rather than DNS. Each is tried on its own, so one being down
+    // doesn't hold up the others.
+    #[structopt(long, number_of_values = 1)]
+    also_endpoint: Vec<Target>,
+
+    // Which servers must have accepted the first advertisement before
+    // systemd hears we're ready: all, any or primary (--endpoint).
+    #[structopt(default_value = "primary", long)]
+    ready_requires: ReadyRequires,
+
+    // Where the advertised hostname comes from: kernel, file:<path> (read
+    // and trimmed), ec2 or gce (the instance metadata service, falling back
+    // to the kernel's if it doesn't answer) or static:<name>.
+    #[structopt(default_value = "kernel", long)]
+    hostname_source: HostnameSource,
+
+    // Like every repeatable flag, one value per use, so a subcommand after
+    // it isn't taken for another value.
+    #[structopt(long, number_of_values = 1)]
     exclude_ifaces: Vec<Regex>,
+
+    #[structopt(default_value = "all", long)]
+    address_policy: SelectionPolicy,
+
+    #[structopt(default_value = "universe", long)]
+    address_scope: AddressScope,
+
+    // Advertise IPv6 unique local (fc00::/7) addresses. The server only
+    // publishes addresses inside a remapper's net, so make sure remappers for
+    // public zones don't cover ULA space.
+    #[structopt(long)]
+    include_ula: bool,
+
+    // Advertise link-local (fe80::/10, 169.254.0.0/16) addresses, regardless
+    // of --address-scope. Remappers decide whether they get published.
+    #[structopt(long)]
+    include_link_local: bool,
+
+    // Advertise IPv4 shared address space (100.64.0.0/10, carrier-grade
+    // NAT). Remappers decide whether they get published.
+    #[structopt(long)]
+    include_v4_cgnat: bool,
+
+    #[structopt(long)]
+    skip_macless_ifaces: bool,
+
+    #[structopt(long)]
+    include_slave_ifaces: bool,
+
+    #[structopt(default_value = "30", long)]
+    keepalive_secs: u64,
+
+    #[structopt(default_value = "10", long)]
+    keepalive_timeout_secs: u64,
+
+    #[structopt(long)]
+    auth_token_file: Option<PathBuf>,
+
+    #[structopt(default_value = "true", long, parse(try_from_str))]
+    exit_on_stream_end: bool,
+
+    #[structopt(long)]
+    netns: Option<String>,
+
+    // Also advertise every namespace under /var/run/netns, as it comes and
+    // goes, each as a node named by --netns-hostname-fmt ({hostname} being
+    // this node's name, {netns} the namespace's).
+    #[structopt(long, conflicts_with_all = &["netns", "poll-interval-secs"])]
+    all_netns: bool,
+
+    #[structopt(default_value = "{hostname}--{netns}", long)]
+    netns_hostname_fmt: String,
+
+    #[structopt(long)]
+    state_cache: Option<PathBuf>,
+
+    #[structopt(default_value = "86400", long)]
+    state_cache_max_age_secs: u64,
+
+    #[structopt(long)]
+    force_initial_advertise: bool,
+
+    #[structopt(default_value = "human", long)]
+    output: OutputFormat,
+
+    #[structopt(default_value = "0", long)]
+    min_addresses: usize,
+
+    #[structopt(default_value = "30", long)]
+    initial_settle_timeout_secs: u64,
+
+    #[structopt(long)]
+    require_min_addresses: bool,
+
+    #[structopt(long)]
+    allow_empty_advertisement: bool,
+
+    #[structopt(default_value = "both", long)]
+    address_family: AddressFamily,
+
+    #[structopt(default_value = "10", long)]
+    retry_max_tries: u32,
+
+    #[structopt(default_value = "1", long)]
+    retry_base_delay_secs: u64,
+
+    #[structopt(default_value = "60", long)]
+    retry_max_delay_secs: u64,
+
+    #[structopt(long)]
+    retry_max_elapsed_secs: Option<u64>,
+
+    #[structopt(long)]
+    status: bool,
+
+    // Prints which rule of the address flags given decides on this address
+    // (taken as unlabelled, with the scope the kernel would give it), then
+    // exits.
+    #[structopt(long)]
+    explain_address: Option<IpAddr>,
+
+    #[structopt(long)]
+    only_labels: Option<Regex>,
+
+    #[structopt(long)]
+    exclude_labels: Option<Regex>,
+
+    // Addresses (or networks of them) that move between nodes, like
+    // keepalived VIPs. The node holding one advertises it marked as a VIP,
+    // which only the server's vip: remappers publish. Repeatable.
+    #[structopt(long = "vip", parse(try_from_str = filter::parse_vip))]
+    vips: Vec<ipnet::IpNet>,
+
+    #[structopt(default_value = "200", long)]
+    event_debounce_ms: u64,
+
+    // Where to export a trace of each advertisement over OTLP; the server
+    // adds its side to the same trace. Needs a build with the otel feature.
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+
+    // The share of advertisements traced.
+    #[structopt(default_value = "1.0", long)]
+    trace_sample_ratio: f64,
+
+    // Read interfaces and addresses from /proc/net and /sys/class/net this
+    // often instead of watching netlink. Unset, the agent only polls (every
+    // 10 seconds) if it can't open a netlink socket.
+    #[structopt(long, parse(try_from_str = parse_poll_interval), conflicts_with_all = &["require-netlink", "netns"])]
+    poll_interval_secs: Option<u64>,
+
+    // Fail instead of falling back to polling when netlink is unavailable.
+    #[structopt(long)]
+    require_netlink: bool,
+
+    // Withdraw a link's addresses once it has been down (no carrier) this
+    // long; unset, they're advertised whatever state the link is in.
+    #[structopt(long)]
+    link_down_hold_secs: Option<u64>,
+
+    // How long a withdrawn link must be back up before its addresses are
+    // advertised again; 0 when unset.
+    #[structopt(long, requires = "link-down-hold-secs")]
+    link_up_hold_secs: Option<u64>,
+
+    // Don't read wireguard interfaces' public keys, which takes
+    // CAP_NET_ADMIN.
+    #[structopt(long)]
+    skip_wireguard_keys: bool,
+
+    // How often wireguard keys are read again to catch a rotation, which
+    // nothing on netlink announces; 0 only reads them as links appear.
+    #[structopt(default_value = "60", long, conflicts_with = "skip-wireguard-keys")]
+    wireguard_key_interval_secs: u64,
+
+    // Also advertise this often when nothing changed. The primary server can
+    // suggest another interval, taken within --readvertise-min-secs and
+    // --readvertise-max-secs.
+    #[structopt(long)]
+    readvertise_interval_secs: Option<u64>,
+
+    #[structopt(default_value = "20", long)]
+    readvertise_jitter_percent: u32,
+
+    #[structopt(default_value = "60", long)]
+    readvertise_min_secs: u64,
+
+    #[structopt(default_value = "3600", long)]
+    readvertise_max_secs: u64,
+
+    // Past this many interfaces an advertisement is most likely container
+    // churn that --exclude-ifaces should be keeping out.
+    #[structopt(default_value = "256", long)]
+    warn_interfaces: usize,
+
+    // Past this many changes, an advertisement's log line counts them
+    // rather than listing them.
+    #[structopt(default_value = "20", long)]
+    log_changes_max: usize,
+
+    #[structopt(default_value = "4194304", long)]
+    max_message_bytes: usize,
+
+    // Always send the whole advertisement instead of the changes since the
+    // last one.
+    #[structopt(long)]
+    full_advertise_only: bool,
+
+    // Listen for a server beacon (see the server's --announce) before
+    // falling back to --endpoint, for networks where its name doesn't
+    // resolve yet.
+    #[structopt(long)]
+    discover: bool,
+
+    #[structopt(default_value = "55556", long)]
+    discover_port: u16,
+
+    #[structopt(default_value = "15", long)]
+    discover_timeout_secs: u64,
+
+    #[structopt(long)]
+    discover_key_file: Option<PathBuf>,
+
+    #[structopt(default_value = "current", long)]
+    runtime: runtime::Flavor,
+
+    #[structopt(long)]
+    worker_threads: Option<usize>,
+
+    // <name>:<proto>:<port>, e.g. node-exporter:tcp:9100, for the server to
+    // publish as an SRV record pointing at this node.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_service))]
+    service: Vec<strapper::Service>,
+
+    // key=value, e.g. role=ingress, for remappers on the server to select
+    // on (label:role=ingress:) or name records after ({label:role}).
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_label))]
+    label: Vec<(String, String)>,
+
+    // More labels, one key=value a line, with # starting a comment;
+    // --label overrides any given here. Read once at startup.
+    #[structopt(long)]
+    labels_file: Option<PathBuf>,
+
+    // For maintenance: the server keeps the node's records in the zone but
+    // disabled, so they aren't served, until an advertisement without it.
+    #[structopt(long)]
+    advertise_disabled: bool,
+
+    // Asks the server to publish the node's records with at most this TTL,
+    // e.g. ahead of a planned move.
+    #[structopt(long, parse(try_from_str = parse_ttl_override))]
+    ttl_override: Option<u32>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-async fn read_hostname() -> Result<String> {
-    Ok(tokio::fs::read_to_string("/proc/sys/kernel/hostname")
-        .await
-        .context("error reading hostname")?
-        .trim_end()
-        .to_owned())
+#[derive(StructOpt, Clone)]
+enum Command {
+    // Checks what the agent needs to run with the options given (netlink,
+    // the hostname, addresses to advertise, the endpoints, systemd), prints
+    // how each went and exits non-zero if any failed.
+    Doctor,
 }
 
-fn iface_for<'a>(
-    v: &'a mut Vec<strapper::Interface>,
-    addr: &rtnl::address::AddressMessage,
-) -> Option<&'a mut strapper::Interface> {
-    for i in v {
-        if i.index == addr.header.index {
-            return Some(i);
-        }
+fn parse_label(s: &str) -> Result<(String, String)> {
+    proto::labels::parse(s).map_err(|e| anyhow!(e))
+}
+
+fn parse_ttl_override(s: &str) -> Result<u32> {
+    let ttl: u32 = s.parse()?;
+    if ttl == 0 {
+        return Err(anyhow!("--ttl-override must be at least 1"));
     }
-    None
+    Ok(ttl)
 }
 
-fn add_addr(
-    v: &mut Vec<strapper::Interface>,
-    addr: &rtnl::address::AddressMessage,
-) -> Result<bool> {
-    process_addr_message(v, addr, |i, a| {
-        for ea in &i.ipaddr {
-            if ea == &a {
-                return false;
+fn parse_poll_interval(s: &str) -> Result<u64> {
+    let secs: u64 = s.parse()?;
+    if secs == 0 {
+        return Err(anyhow!("--poll-interval-secs must be at least 1"));
+    }
+    Ok(secs)
+}
+
+async fn read_labels(opt: &Opt) -> Result<BTreeMap<String, String>> {
+    let mut labels = BTreeMap::new();
+    if let Some(path) = &opt.labels_file {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("error reading {}", path.display()))?;
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
             }
+            let (key, value) =
+                parse_label(line).with_context(|| format!("{}:{}", path.display(), n + 1))?;
+            labels.insert(key, value);
         }
+    }
+    labels.extend(opt.label.iter().cloned());
+    if labels.len() > proto::labels::MAX_LABELS {
+        return Err(anyhow!(
+            "{} labels, over the {} allowed",
+            labels.len(),
+            proto::labels::MAX_LABELS
+        ));
+    }
+    Ok(labels)
+}
+
+fn is_service_label(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('-') && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
 
-        i.ipaddr.push(a);
-        true
+fn parse_service(s: &str) -> Result<strapper::Service> {
+    let mut parts = s.splitn(3, ':');
+    let (name, protocol, port) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(protocol), Some(port)) => (name, protocol, port),
+        _ => return Err(anyhow!("service '{}' isn't <name>:<proto>:<port>", s)),
+    };
+    for label in [name, protocol].iter() {
+        if !is_service_label(label) {
+            return Err(anyhow!(
+                "'{}' in service '{}' isn't a DNS label (leave out the leading underscore)",
+                label,
+                s
+            ));
+        }
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow!("invalid port in service '{}'", s))?;
+    if port == 0 {
+        return Err(anyhow!("invalid port in service '{}'", s));
+    }
+    Ok(strapper::Service {
+        name: name.to_ascii_lowercase(),
+        protocol: protocol.to_ascii_lowercase(),
+        port: port.into(),
     })
 }
 
-fn del_addr(
-    v: &mut Vec<strapper::Interface>,
-    addr: &rtnl::address::AddressMessage,
-) -> Result<bool> {
-    process_addr_message(v, addr, |i, a| {
-        let mut r = false;
-        i.ipaddr.retain(|v| if v != &a {
-            true
-        } else {
-            r = true;
-            false
-        });
-        r
+// The netlink event stream or its connection task went away, so we can no
+// longer see address changes.
+#[derive(Debug)]
+struct StreamEnded(String);
+
+impl std::fmt::Display for StreamEnded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StreamEnded {}
+
+// The hostname if it's already qualified, otherwise joined with the
+// kernel's domainname or, failing that, resolv.conf's search domain. Empty
+// when neither names one.
+async fn read_fqdn(hostname: &str) -> String {
+    if hostname.contains('.') {
+        return hostname.to_owned();
+    }
+    let domain = tokio::fs::read_to_string("/proc/sys/kernel/domainname")
+        .await
+        .map(|d| d.trim().to_owned())
+        .unwrap_or_default();
+    // The kernel reports an unset domain as "(none)".
+    let domain = if domain.is_empty() || domain == "(none)" {
+        tokio::fs::read_to_string("/etc/resolv.conf")
+            .await
+            .map(|c| resolv_domain(&c))
+            .unwrap_or_default()
+    } else {
+        domain
+    };
+    let domain = domain.trim_matches('.');
+    if domain.is_empty() {
+        return String::new();
+    }
+    format!("{}.{}", hostname, domain)
+}
+
+// The first search domain; domain and search override each other, so the
+// last such line wins.
+fn resolv_domain(conf: &str) -> String {
+    conf.lines()
+        .rev()
+        .find_map(|l| {
+            let mut words = l.split_whitespace();
+            match words.next()? {
+                "domain" | "search" => Some(words.next().unwrap_or_default()),
+                _ => None,
+            }
+        })
+        .unwrap_or_default()
+        .to_owned()
+}
+
+async fn process_ifaces(kernel: &dyn Kernel, state: &mut AdvertisementState) -> Result<()> {
+    let started = std::time::Instant::now();
+    let links = kernel.links().await?;
+    state.add_links(&links)?;
+
+    let family = state.filter().family;
+    let (v6, v4) = tokio::try_join!(
+        list_addresses_for_af(kernel, libc::AF_INET6 as u8, family.v6(), state),
+        list_addresses_for_af(kernel, libc::AF_INET as u8, family.v4(), state),
+    )?;
+    for addr in v6.iter().chain(v4.iter()) {
+        state.apply_new_address(addr)?;
+    }
+
+    output::info(format_args!(
+        "enumerated {} interfaces and {} addresses in {} ms",
+        state.interfaces().len(),
+        state.address_count(),
+        started.elapsed().as_millis()
+    ));
+    Ok(())
+}
+
+async fn list_default_routes(
+    kernel: &dyn Kernel,
+    ifaces: &[strapper::Interface],
+    family: AddressFamily,
+) -> Result<Vec<strapper::Route>> {
+    let mut ret = Vec::new();
+    let versions = [(IpVersion::V6, family.v6()), (IpVersion::V4, family.v4())];
+    for version in versions
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(v, _)| v.clone())
+    {
+        for route in kernel.routes(version).await? {
+            routes::add_route(&mut ret, ifaces, &route);
+        }
+    }
+    Ok(ret)
+}
+
+// Addresses on interfaces `state` doesn't track are left out.
+async fn list_addresses_for_af(
+    kernel: &dyn Kernel,
+    af: u8,
+    enabled: bool,
+    state: &AdvertisementState,
+) -> Result<Vec<rtnl::address::AddressMessage>> {
+    if !enabled {
+        return Ok(Vec::new());
+    }
+    kernel.addresses(af, &|index| state.tracks(index)).await
+}
+
+// A restart alone isn't a change worth re-advertising, nor is the kernel
+// listing things in another order.
+fn same_advertisement(a: &strapper::NodeAdvertisement, b: &strapper::NodeAdvertisement) -> bool {
+    canonical::canonical_hash(a) == canonical::canonical_hash(b)
+}
+
+// The HTTP/2 ping interval and how long to wait for the answer.
+fn keepalive(opt: &Opt) -> (Duration, Duration) {
+    (
+        Duration::from_secs(opt.keepalive_secs),
+        Duration::from_secs(opt.keepalive_timeout_secs),
+    )
+}
+
+fn endpoint(opt: &Opt, target: &Target) -> Endpoint {
+    let (interval, timeout) = keepalive(opt);
+    target
+        .endpoint()
+        .http2_keep_alive_interval(interval)
+        .keep_alive_timeout(timeout)
+        .keep_alive_while_idle(true)
+}
+
+fn address_policy(opt: &Opt) -> AddressPolicy {
+    AddressPolicy::from_options(AddressOptions {
+        family: opt.address_family,
+        scope: opt.address_scope,
+        include_ula: opt.include_ula,
+        include_link_local: opt.include_link_local,
+        include_v4_cgnat: opt.include_v4_cgnat,
+        only_labels: opt.only_labels.clone(),
+        exclude_labels: opt.exclude_labels.clone(),
+        vips: opt.vips.clone(),
     })
 }
 
-fn process_addr_message<F>(
-    v: &mut Vec<strapper::Interface>,
-    addr: &rtnl::address::AddressMessage,
-    f: F,
-) -> Result<bool>
-where
-    F: Fn(&mut strapper::Interface, String) -> bool,
-{
-    let iface = match iface_for(v, addr) {
-        Some(v) => v,
-        None => return Ok(false),
+fn retry_policy(opt: &Opt) -> RetryPolicy {
+    RetryPolicy {
+        max_tries: opt.retry_max_tries,
+        base_delay: Duration::from_secs(opt.retry_base_delay_secs),
+        max_delay: Duration::from_secs(opt.retry_max_delay_secs),
+        max_elapsed: opt.retry_max_elapsed_secs.map(Duration::from_secs),
+    }
+}
+
+// TCP channels connect lazily, but tonic can only build a channel over a
+// custom connector by connecting, so unix sockets retry here until the server
+// is up.
+// Only unix sockets can fail here; URIs connect lazily.
+async fn connect(opt: &Opt, target: &Target) -> Result<StrapperClient> {
+    let mut backoff = runtime::backoff(retry_policy(opt));
+    loop {
+        let e = match StrapperClient::connect_target(target, endpoint(opt, target)).await {
+            Ok(client) => return Ok(client),
+            Err(e) => e,
+        };
+        let try_cnt = backoff.attempt();
+        let wait = match backoff.next_delay() {
+            Some(wait) => wait,
+            None => return Err(e),
+        };
+        output::retry(&format!("connecting to {}", target), &e, try_cnt, wait);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// --endpoint, or with --discover whatever the first good beacon names.
+async fn discover_target(opt: &Opt) -> Result<Target> {
+    if !opt.discover {
+        return Ok(opt.endpoint.clone());
+    }
+    let path = opt
+        .discover_key_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("--discover needs --discover-key-file"))
+        .context(Category::Config)?;
+    let (key, world_readable) = client::read_token_file(path).context(Category::Config)?;
+    if world_readable {
+        output::warning(format_args!(
+            "discover key file {} is world-readable",
+            path.display()
+        ));
+    }
+
+    output::info(format_args!(
+        "listening for a server beacon on port {}",
+        opt.discover_port
+    ));
+    let timeout = Duration::from_secs(opt.discover_timeout_secs);
+    match discover::find(opt.discover_port, key.as_bytes(), timeout).await? {
+        Some(target) => {
+            output::info(format_args!("discovered server at {}", target));
+            Ok(target)
+        }
+        None => {
+            output::warning(format_args!(
+                "no server beacon within {} seconds, using --endpoint {}",
+                opt.discover_timeout_secs, opt.endpoint
+            ));
+            Ok(opt.endpoint.clone())
+        }
+    }
+}
+
+async fn authenticated_client(opt: &Opt, target: &Target) -> Result<StrapperClient> {
+    let client = connect(opt, target)
+        .await?
+        .with_max_message_size(opt.max_message_bytes);
+    let path = match &opt.auth_token_file {
+        Some(path) => path,
+        None => return Ok(client),
     };
+    let (token, world_readable) = client::read_token_file(path).context(Category::Config)?;
+    if world_readable {
+        output::warning(format_args!(
+            "auth token file {} is world-readable",
+            path.display()
+        ));
+    }
+    client.with_auth_token(&token)
+}
+
+async fn print_status(opt: &Opt) -> Result<()> {
+    let mut client = authenticated_client(opt, &discover_target(opt).await?).await?;
+    let status = client
+        .status()
+        .await
+        .context("error getting server status")?;
+    output::status(&status);
+    match opt.hostname_source.resolve().await {
+        Ok(hostname) => output::info(format_args!(
+            "this node: {} (from {})",
+            hostname, opt.hostname_source
+        )),
+        Err(e) => output::warning(format_args!(
+            "this node's hostname ({}): {:#}",
+            opt.hostname_source, e
+        )),
+    }
+    // What the server last said to the agent running with this --state-cache.
+    if let Some(path) = notices::path(opt) {
+        match notices::load(&path).await {
+            Ok(Some((server, said))) if !said.is_empty() => {
+                for n in said.iter() {
+                    output::notice(&server, n);
+                }
+            }
+            Ok(_) => output::info("no notices from the server"),
+            Err(e) => output::warning(format_args!("{:#}", e)),
+        }
+    }
+    Ok(())
+}
 
-    let mut changes = false;
+// Past --warn-interfaces, what to tell the operator before sending it.
+fn size_warning(opt: &Opt, advertisement: &strapper::NodeAdvertisement) -> Option<String> {
+    if advertisement.interfaces.len() <= opt.warn_interfaces {
+        return None;
+    }
+    Some(format!(
+        "advertising {} interfaces (more than --warn-interfaces {}); if they're container veths or similar, leave them out with --exclude-ifaces",
+        advertisement.interfaces.len(),
+        opt.warn_interfaces
+    ))
+}
 
-    for nla in addr.nlas.iter() {
-        if let rtnl::address::nlas::Nla::Address(addr) = nla {
-            let addrstr = if addr.len() == 16 {
-                let a: [u8; 16] = addr.as_slice().try_into().unwrap();
-                let addr = Ipv6Addr::from(a);
-                if !addr.is_global() {
-                    continue;
+// Once `upstream` has accepted something, only the changes since are sent,
+// unless the server has lost it or --full-advertise-only is set.
+async fn try_advertise(
+    opt: &Opt,
+    upstream: &mut Upstream,
+    advertisement: &strapper::NodeAdvertisement,
+) -> Result<strapper::AdvertiseResult> {
+    if let Some(warning) = size_warning(opt, advertisement) {
+        output::warning(warning);
+    }
+    let client = match &mut upstream.client {
+        Some(client) => client,
+        None => {
+            let client = match &upstream.clients {
+                Some(clients) => clients.get(opt, &upstream.target).await?,
+                None => authenticated_client(opt, &upstream.target).await?,
+            };
+            upstream.client.insert(client)
+        }
+    };
+    let target = &upstream.target;
+    let what = format!("advertise to {}", target);
+    let delta = upstream
+        .accepted
+        .as_ref()
+        .filter(|_| !opt.full_advertise_only)
+        .and_then(|b| Some((b, delta::diff(b, advertisement)?)))
+        .map(|(b, ops)| strapper::AdvertisementDelta {
+            hostname: b.hostname.clone(),
+            agent_start_time: advertisement.agent_start_time,
+            base_sequence: b.sequence,
+            sequence: advertisement.sequence,
+            observed_ms: advertisement.observed_ms,
+            ops,
+        });
+    if let Some(delta) = delta {
+        let sent = client
+            .advertise_delta_with_retry(&delta, &retry_policy(opt), |e, try_cnt, wait| {
+                output::retry(&what, e, try_cnt, wait);
+                otel::retry(e, try_cnt, wait);
+            })
+            .await;
+        match sent {
+            Ok(result) => {
+                output::info(format_args!(
+                    "delta of {} changes accepted by {}, now at {} (request {})",
+                    delta.ops.len(),
+                    target,
+                    canonical::short_hash(advertisement),
+                    result.request_id
+                ));
+                if upstream.primary {
+                    store_cache(opt, advertisement).await;
                 }
-                addr.to_string()
-            } else if addr.len() == 4 {
-                let a: [u8; 4] = addr.as_slice().try_into().unwrap();
-                let addr = Ipv4Addr::from(a);
-                if !(addr.is_private() || addr.is_global()) {
-                    continue;
+                return Ok(result);
+            }
+            Err(e) => match e.downcast_ref::<client::AdvertiseError>() {
+                Some(e) if e.resync_required() => {
+                    output::info(format_args!(
+                        "{}: {} (request {}), sending a full advertisement",
+                        target,
+                        e.status.message(),
+                        e.request_id
+                    ));
                 }
-                addr.to_string()
-            } else {
-                return Err(anyhow!("non-recognized address format"));
-            };
+                _ => return Err(e),
+            },
+        }
+    }
 
-            if f(iface, addrstr) {
-                changes = true;
-            }
+    let result = client
+        .advertise_with_retry(advertisement, &retry_policy(opt), |e, try_cnt, wait| {
+            output::retry(&what, e, try_cnt, wait);
+            otel::retry(e, try_cnt, wait);
+        })
+        .await?;
+    output::info(format_args!(
+        "advertisement {} accepted by {} (request {})",
+        canonical::short_hash(advertisement),
+        target,
+        result.request_id
+    ));
+    if upstream.primary {
+        store_cache(opt, advertisement).await;
+    }
+    Ok(result)
+}
+
+// A discovered server may have moved, so once advertising to it fails we
+// listen for a beacon again and, if it names somewhere else, retry there.
+async fn advertise(
+    opt: &Opt,
+    upstream: &mut Upstream,
+    advertisement: &strapper::NodeAdvertisement,
+) -> Result<strapper::AdvertiseResult> {
+    let e = match traced_advertise(opt, upstream, advertisement).await {
+        Ok(result) => return Ok(result),
+        Err(e) if !opt.discover || !upstream.primary => return Err(e),
+        Err(e) => e,
+    };
+    output::warning(format_args!(
+        "advertising to {} failed ({:#}), listening for a new beacon",
+        upstream.target, e
+    ));
+    let found = discover_target(opt).await?;
+    if found.to_string() == upstream.target.to_string() {
+        return Err(e);
+    }
+    upstream.client = Some(authenticated_client(opt, &found).await?);
+    upstream.target = found;
+    traced_advertise(opt, upstream, advertisement).await
+}
+
+async fn traced_advertise(
+    opt: &Opt,
+    upstream: &mut Upstream,
+    advertisement: &strapper::NodeAdvertisement,
+) -> Result<strapper::AdvertiseResult> {
+    let attributes = vec![
+        ("strapper.target", upstream.target.to_string()),
+        ("strapper.hostname", advertisement.hostname.clone()),
+        ("strapper.sequence", advertisement.sequence.to_string()),
+    ];
+    otel::traced(
+        "advertise",
+        attributes,
+        try_advertise(opt, upstream, advertisement),
+    )
+    .await
+}
+
+async fn store_cache(opt: &Opt, advertisement: &strapper::NodeAdvertisement) {
+    if let Some(path) = &opt.state_cache {
+        if let Err(e) = cache::store(path, advertisement).await {
+            output::warning(format_args!("unable to update state cache: {:#}", e));
+        }
+    }
+}
+
+// The notify socket is written with a blocking send, so it goes to the
+// blocking pool rather than stalling whichever thread polls us.
+async fn advertise_ready() -> Result<()> {
+    output::info("notifying systemd of 'ready' state...");
+    loop {
+        let notified = tokio::task::spawn_blocking(|| {
+            systemd::daemon::notify(false, [(systemd::daemon::STATE_READY, "1")].iter())
+        })
+        .await??;
+        if notified {
+            return Ok(());
         }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
+}
+
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
-    Ok(changes)
+enum Source {
+    // Events, and netlink dumps (or in tests, a kernel's worth of them)
+    // to go with them.
+    Netlink(Box<dyn Kernel>),
+    // Netlink is unavailable (or --poll-interval-secs asked for this), so
+    // the state is read again from /proc/net every interval.
+    Poll(Duration),
+}
+
+impl Source {
+    fn poll_interval(&self) -> Option<Duration> {
+        match self {
+            Source::Netlink(_) => None,
+            Source::Poll(interval) => Some(*interval),
+        }
+    }
 }
 
-fn add_iface_if_not_exists_and_not_excluded(
-    v: &mut Vec<strapper::Interface>,
-    exclude_ifaces: &Vec<Regex>,
-    l: &rtnl::link::LinkMessage,
-) -> Result<bool> {
-    let mut i_name = None;
-    let mut i_perm_mac = None;
+// Feeds netlink messages into an AdvertisementState, doing the dumps that
+// some changes call for, or when polling, rebuilds it every interval.
+struct Tracker {
+    source: Source,
+    state: AdvertisementState,
+    // Interfaces with address events since the last resync.
+    touched: HashSet<u32>,
+    holds: Option<LinkHolds>,
+    wireguard: Option<wireguard::Keys>,
+}
 
-    for nla in l.nlas.iter() {
-        match nla {
-            rtnl::link::nlas::Nla::IfName(name) => {
-                for r in exclude_ifaces {
-                    if r.is_match(name) {
-                        return Ok(false);
-                    }
-                }
+impl Tracker {
+    // What gets advertised: the kernel's view, less what the link holds
+    // keep back.
+    fn advertisement(&self) -> strapper::NodeAdvertisement {
+        let mut adv = self.state.advertisement().clone();
+        if let Some(holds) = &self.holds {
+            holds.apply(&mut adv);
+        }
+        adv
+    }
 
-                for iface in v.iter() {
-                    if &iface.name == name {
-                        return Ok(false);
+    // After anything that changes the state.
+    fn observe_links(&mut self) {
+        if let Some(holds) = &mut self.holds {
+            holds.observe(self.state.interfaces());
+        }
+    }
+
+    fn next_hold(&self) -> Option<tokio::time::Instant> {
+        self.holds.as_ref()?.next_deadline()
+    }
+
+    async fn process(&mut self, event: Event) -> Result<bool> {
+        let kernel = match &self.source {
+            Source::Netlink(kernel) => kernel.as_ref(),
+            Source::Poll(_) => return Ok(false),
+        };
+        let state = &mut self.state;
+        match event {
+            Event::NewAddress(addr) => {
+                self.touched.insert(addr.header.index);
+                state.apply_new_address(&addr)
+            }
+            Event::DelAddress(addr) => {
+                self.touched.insert(addr.header.index);
+                state.apply_del_address(&addr)
+            }
+            Event::NewLink(link) => {
+                let update = state.apply_link(&link).unwrap_or_else(|e| {
+                    output::warning(format_args!(
+                        "ignoring link update for index {}: {}",
+                        link.header.index, e
+                    ));
+                    LinkUpdate::Unchanged
+                });
+                match update {
+                    LinkUpdate::Unchanged => Ok(false),
+                    LinkUpdate::Changed => Ok(true),
+                    LinkUpdate::Added(index) => {
+                        for addr in kernel.interface_addresses(index).await? {
+                            state.apply_new_address(&addr)?;
+                        }
+                        if let Some(keys) = &mut self.wireguard {
+                            keys.refresh(state).await;
+                        }
+                        let routes =
+                            list_default_routes(kernel, state.interfaces(), state.filter().family)
+                                .await?;
+                        state.set_default_routes(routes);
+                        Ok(true)
                     }
                 }
-                i_name = Some(name);
             }
-            rtnl::link::nlas::Nla::Address(addr) => {
-                let mac = eui48::MacAddress::from_bytes(&addr)?;
-                i_perm_mac = Some(mac.to_hex_string());
+            Event::DelLink(link) => {
+                self.touched.remove(&link.header.index);
+                Ok(state.apply_del_link(&link))
             }
-            _ => {}
+            Event::NewRoute(route) => Ok(state.apply_new_route(&route)),
+            Event::DelRoute(route) => Ok(state.apply_del_route(&route)),
+        }
+    }
+
+    // Events for one address can arrive out of order (a quick delete and
+    // re-add), so before advertising, the addresses of every interface that
+    // saw events are rebuilt from a fresh dump.
+    async fn resync_addresses(&mut self) -> Result<()> {
+        let kernel = match &self.source {
+            Source::Netlink(kernel) => kernel.as_ref(),
+            Source::Poll(_) => return Ok(()),
         };
+        for index in std::mem::take(&mut self.touched) {
+            if !self.state.tracks(index) {
+                continue;
+            }
+            let dump = kernel.interface_addresses(index).await?;
+            let before = match self.state.replace_addresses(index, &dump)? {
+                Some(before) => before,
+                None => continue,
+            };
+
+            if let Some(iface) = self.state.interface(index) {
+                if iface.ipaddr != before {
+                    output::warning(format_args!(
+                        "addresses on {} were {:?} after events but {:?} in the kernel, using the kernel's",
+                        iface.name, before, iface.ipaddr
+                    ));
+                }
+            }
+        }
+        Ok(())
     }
 
-    i_name
-        .zip(i_perm_mac)
-        .map(|(name, mac)| strapper::Interface {
-            name: name.clone(),
-            mac,
-            ipaddr: Vec::new(),
-            index: l.header.index,
-        })
-        .map(|iface| {
-            v.push(iface);
-            true
-        })
-        .ok_or(anyhow!("name or mac is unexpectedly missing"))
+    // Throws away everything the events built up and starts again from a
+    // full dump, as on startup.
+    async fn resync_all(&mut self) -> Result<()> {
+        self.touched.clear();
+        let kernel = match &self.source {
+            Source::Netlink(kernel) => kernel.as_ref(),
+            Source::Poll(_) => return self.poll().await.map(|_| ()),
+        };
+        self.state.reset();
+        process_ifaces(kernel, &mut self.state).await?;
+        let routes =
+            list_default_routes(kernel, self.state.interfaces(), self.state.filter().family)
+                .await?;
+        self.state.set_default_routes(routes);
+        self.refresh_wireguard_keys().await;
+        Ok(())
+    }
+
+    // Reads everything again when polling. Returns whether anything changed.
+    async fn poll(&mut self) -> Result<bool> {
+        let before = self.state.advertisement().clone();
+        poll::load(&mut self.state)?;
+        self.refresh_wireguard_keys().await;
+        Ok(*self.state.advertisement() != before)
+    }
+
+    // Returns whether any key changed.
+    async fn refresh_wireguard_keys(&mut self) -> bool {
+        match &mut self.wireguard {
+            Some(keys) => keys.refresh(&mut self.state).await,
+            None => false,
+        }
+    }
 }
 
-async fn process_ifaces(
-    handle: &rtnetlink::Handle,
-    ignore_ifaces: &Vec<Regex>,
-) -> Result<Vec<strapper::Interface>> {
-    let mut ret = Vec::new();
-    let mut interfaces = handle.link().get().execute();
+// A netlink connection in --netns, if given, subscribed to link changes and
+// to address and route changes of the families advertised.
+#[allow(clippy::type_complexity)]
+fn open_netlink(
+    opt: &Opt,
+) -> Result<(
+    rtnetlink::proto::Connection<rtnl::RtnlMessage>,
+    rtnetlink::Handle,
+    impl futures_util::Stream<
+            Item = (
+                rtnetlink::packet::NetlinkMessage<rtnl::RtnlMessage>,
+                SocketAddr,
+            ),
+        > + Unpin,
+)> {
+    let (mut connection, handle, messages) = match &opt.netns {
+        Some(ns) => netns::in_netns(&netns::resolve(ns), rtnetlink::new_connection)?,
+        None => rtnetlink::new_connection()?,
+    };
 
-    while let Some(r) = interfaces
-        .try_next()
-        .await
-        .context("error listing interfaces")?
-    {
-        add_iface_if_not_exists_and_not_excluded(&mut ret, &ignore_ifaces, &r)?;
+    let mut groups = RTMGRP_LINK;
+    if opt.address_family.v4() {
+        groups |= RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE;
     }
+    if opt.address_family.v6() {
+        groups |= RTMGRP_IPV6_IFADDR | RTMGRP_IPV6_ROUTE;
+    }
+    let addr = SocketAddr::new(0, groups);
 
-    list_addresses_for_af(handle, libc::AF_INET6 as u8, &mut ret).await?;
-    list_addresses_for_af(handle, libc::AF_INET as u8, &mut ret).await?;
+    connection.socket_mut().bind(&addr)?;
+    Ok((connection, handle, messages))
+}
 
-    Ok(ret)
+// Empty but for `base`, to fill in with process_ifaces.
+fn new_state(opt: &Opt, base: strapper::NodeAdvertisement) -> AdvertisementState {
+    let links = LinkFilter {
+        exclude: opt.exclude_ifaces.clone(),
+        skip_macless: opt.skip_macless_ifaces,
+        include_slaves: opt.include_slave_ifaces,
+    };
+    AdvertisementState::new(links, address_policy(opt), opt.address_policy, base)
 }
 
-async fn list_addresses_for_af(handle: &rtnetlink::Handle, af: u8, r: &mut Vec<strapper::Interface>) -> Result<()> {
-    let mut message = handle.address().get();
-    message.message_mut().header.family = af;
-    let mut addrs = message.execute();
-    while let Some(addr) = addrs.try_next().await.context("address lookup failed")? {
-        add_addr(r, &addr)?;
+// `sequence` outlives a restart after the netlink stream ends, so the server
+// keeps seeing it climb for as long as this process (and start time) lives.
+async fn run_advertise(
+    opt: &Opt,
+    started: u64,
+    sequence: &mut u64,
+    clients: Option<&Clients>,
+) -> Result<()> {
+    // Installed first so neither kills the agent while it starts up.
+    let signals = DebugSignals::install()?;
+
+    let netlink = match opt.poll_interval_secs {
+        Some(_) => None,
+        None => match open_netlink(opt).context(Category::Netlink) {
+            Ok(netlink) => Some(netlink),
+            // Nothing under /proc/net or /sys/class/net is the other
+            // namespace's.
+            Err(e) if !opt.require_netlink && opt.netns.is_none() => {
+                output::warning(format_args!(
+                    "unable to open a netlink socket ({:#}), falling back to polling; --require-netlink makes this fatal",
+                    e
+                ));
+                None
+            }
+            Err(e) => return Err(e),
+        },
+    };
+    let (source, mut events) = match netlink {
+        Some((connection, handle, messages)) => (
+            Source::Netlink(Box::new(handle)),
+            Some(event::netlink(messages, tokio::spawn(connection))),
+        ),
+        None => {
+            let interval = opt
+                .poll_interval_secs
+                .map(Duration::from_secs)
+                .unwrap_or(FALLBACK_POLL_INTERVAL);
+            (Source::Poll(interval), None)
+        }
+    };
+
+    // The primary comes first; ReadyRequires relies on it.
+    let mut upstreams = vec![Upstream::new(discover_target(opt).await?, true)];
+    upstreams.extend(
+        opt.also_endpoint
+            .iter()
+            .map(|t| Upstream::new(t.clone(), false)),
+    );
+    for u in upstreams.iter_mut() {
+        u.clients = clients.cloned();
     }
-    Ok(())
+    let hostname = opt.hostname_source.resolve().await?;
+    output::info(format_args!(
+        "hostname {} (from {})",
+        hostname, opt.hostname_source
+    ));
+    let mut state = new_state(
+        opt,
+        strapper::NodeAdvertisement {
+            fqdn: read_fqdn(&hostname).await,
+            hostname,
+            agent_version: env!("CARGO_PKG_VERSION").to_owned(),
+            agent_start_time: started,
+            services: opt.service.clone(),
+            labels: read_labels(opt).await.context(Category::Config)?,
+            disabled: opt.advertise_disabled,
+            ttl_override: opt.ttl_override,
+            ..Default::default()
+        },
+    );
+    match &source {
+        Source::Netlink(kernel) => {
+            process_ifaces(kernel.as_ref(), &mut state).await?;
+            let default_routes =
+                list_default_routes(kernel.as_ref(), state.interfaces(), state.filter().family)
+                    .await?;
+            state.set_default_routes(default_routes);
+        }
+        Source::Poll(_) => {
+            poll::load(&mut state)?;
+            output::info(format_args!(
+                "read {} interfaces and {} addresses from /proc/net",
+                state.interfaces().len(),
+                state.address_count()
+            ));
+        }
+    }
+    let mut tracker = Tracker {
+        source,
+        state,
+        touched: HashSet::new(),
+        holds: opt.link_down_hold_secs.map(|down| {
+            LinkHolds::new(
+                Duration::from_secs(down),
+                Duration::from_secs(opt.link_up_hold_secs.unwrap_or(0)),
+            )
+        }),
+        wireguard: wireguard::Keys::new(opt),
+    };
+    tracker.refresh_wireguard_keys().await;
+    tracker.observe_links();
+    output::startup(&tracker.advertisement());
+    output::source(tracker.source.poll_interval());
+    let poll_interval = tracker.source.poll_interval();
+    let next_poll = || Some(tokio::time::Instant::now() + poll_interval?);
+    let mut poll_at = next_poll();
+
+    // Early in boot we can beat DHCP; hold the first advertisement (and
+    // READY) until enough addresses show up or we give up waiting.
+    if tracker.state.address_count() < opt.min_addresses {
+        output::info(format_args!(
+            "only {} of {} required addresses, waiting up to {} seconds for more",
+            tracker.state.address_count(),
+            opt.min_addresses,
+            opt.initial_settle_timeout_secs
+        ));
+        let deadline =
+            tokio::time::Instant::now() + Duration::from_secs(opt.initial_settle_timeout_secs);
+        while tracker.state.address_count() < opt.min_addresses {
+            tokio::select! {
+                e = event::next(&mut events) => { tracker.process(e?).await?; }
+                _ = readvertise::until(poll_at) => {
+                    poll_at = next_poll();
+                    tracker.poll().await?;
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        if tracker.state.address_count() < opt.min_addresses {
+            if opt.require_min_addresses {
+                return Err(anyhow!(
+                    "only {} of {} required addresses after {} seconds",
+                    tracker.state.address_count(),
+                    opt.min_addresses,
+                    opt.initial_settle_timeout_secs
+                ));
+            }
+            output::warning(format_args!(
+                "only {} of {} required addresses after {} seconds, advertising anyway",
+                tracker.state.address_count(),
+                opt.min_addresses,
+                opt.initial_settle_timeout_secs
+            ));
+        }
+    }
+
+    let cached = match &opt.state_cache {
+        Some(p) => cache::load(p, Duration::from_secs(opt.state_cache_max_age_secs)).await,
+        None => None,
+    };
+    tracker.observe_links();
+    *sequence += 1;
+    tracker.state.set_sequence(*sequence);
+    // The first advertisement carries whatever the lifetimes are now.
+    tracker.state.take_refreshed();
+    // The cache only says what the primary holds; the other servers always
+    // get the initial advertisement.
+    match cached {
+        Some(c)
+            if !opt.force_initial_advertise && same_advertisement(&c, &tracker.advertisement()) =>
+        {
+            output::info("state matches the cached advertisement, skipping initial advertisement");
+            upstreams[0].accepted = Some(tracker.advertisement());
+        }
+        _ => {}
+    }
+
+    advertise_changes(
+        opt,
+        tracker,
+        events,
+        upstreams,
+        sequence,
+        signals,
+        advertise_ready(),
+    )
+    .await
 }
 
-async fn advertise(
-    endpoint: &tonic::transport::Uri,
-    advertisement: &strapper::NodeAdvertisement,
-) -> Result<()> {
-    let mut client = NodeStateServiceClient::connect(endpoint.clone()).await?;
-    client.advertise(advertisement.clone()).await?;
-    Ok(())
+// SIGUSR1 forces a full resync and advertisement, SIGUSR2 logs what would be
+// advertised; both are for debugging without a restart.
+struct DebugSignals {
+    usr1: Signal,
+    usr2: Signal,
 }
 
-async fn try_advertise(
-    endpoint: &tonic::transport::Uri,
-    advertisement: &strapper::NodeAdvertisement,
+impl DebugSignals {
+    fn install() -> Result<DebugSignals> {
+        Ok(DebugSignals {
+            usr1: signal(SignalKind::user_defined1()).context("error listening for SIGUSR1")?,
+            usr2: signal(SignalKind::user_defined2()).context("error listening for SIGUSR2")?,
+        })
+    }
+}
+
+// Sends the tracker's first advertisement to every upstream, and from then
+// on every change to it, until `events` ends or the primary gives up.
+// `notify_ready` tells systemd once the upstreams ReadyRequires asks for
+// have the first. Everything it waits on is an event, an upstream or
+// tokio::time, so tests run it under a paused clock.
+async fn advertise_changes(
+    opt: &Opt,
+    mut tracker: Tracker,
+    mut events: Option<Events>,
+    upstreams: Vec<impl Advertiser>,
+    sequence: &mut u64,
+    signals: DebugSignals,
+    notify_ready: impl std::future::Future<Output = Result<()>>,
 ) -> Result<()> {
-    for try_cnt in 0..10 {
-        match advertise(endpoint, advertisement).await {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                let next_try = 2_u64.pow(try_cnt);
-                println!(
-                    "advertise failed ({}, try {}), trying again in {} seconds",
-                    e, try_cnt, next_try
-                );
-                tokio::time::sleep(tokio::time::Duration::from_secs(next_try)).await;
+    let DebugSignals { mut usr1, mut usr2 } = signals;
+    let poll_interval = tracker.source.poll_interval();
+    let next_poll = || Some(tokio::time::Instant::now() + poll_interval?);
+    let mut poll_at = next_poll();
+    let mut last_advertised = tracker.advertisement();
+    let (latest, latest_rx) = watch::channel(tracker.advertisement());
+    // Only the primary's suggested interval counts.
+    let (hints, suggested) = watch::channel(0);
+    let mut hints = Some(hints);
+    let mut accepted = vec![];
+    let upstreams = futures_util::future::try_join_all(upstreams.into_iter().map(|u| {
+        let (ready, first) = oneshot::channel();
+        accepted.push(first);
+        let hints = if u.primary() { hints.take() } else { None };
+        upstream::run(u, opt, latest_rx.clone(), ready, hints)
+    }));
+    drop(latest_rx);
+    let mut schedule = readvertise::Schedule::new(opt);
+
+    let events = async {
+        opt.ready_requires.wait(accepted).await;
+        notify_ready.await?;
+        output::info("Waiting for address updates.");
+
+        let debounce = Duration::from_millis(opt.event_debounce_ms);
+        let mut next_readvertise = || {
+            let s = schedule.as_mut()?;
+            Some(tokio::time::Instant::now() + s.next(*suggested.borrow()))
+        };
+        let mut readvertise_at = next_readvertise();
+        let wireguard_interval = Some(Duration::from_secs(opt.wireguard_key_interval_secs))
+            .filter(|i| tracker.wireguard.is_some() && !i.is_zero());
+        let next_wireguard_check = || Some(tokio::time::Instant::now() + wireguard_interval?);
+        let mut wireguard_check_at = next_wireguard_check();
+        loop {
+            let timed = tokio::select! {
+                e = event::next(&mut events) => {
+                    let mut has_changes = tracker.process(e?).await?;
+
+                    // Take in the rest of a burst before checking it against the kernel.
+                    while let Ok(e) = tokio::time::timeout(debounce, event::next(&mut events)).await {
+                        has_changes |= tracker.process(e?).await?;
+                    }
+                    if has_changes {
+                        tracker.resync_addresses().await?;
+                        tracker.observe_links();
+                    } else {
+                        tracker.touched.clear();
+                    }
+
+                    let refreshed = tracker.state.take_refreshed();
+                    if !has_changes || (!refreshed && same_advertisement(&last_advertised, &tracker.advertisement())) {
+                        continue;
+                    }
+                    false
+                }
+                _ = readvertise::until(poll_at) => {
+                    poll_at = next_poll();
+                    if !tracker.poll().await? {
+                        continue;
+                    }
+                    // As with a burst of events, wait for the state to hold
+                    // still for the debounce before advertising it.
+                    loop {
+                        tokio::time::sleep(debounce).await;
+                        if !tracker.poll().await? {
+                            break;
+                        }
+                    }
+                    tracker.observe_links();
+                    if same_advertisement(&last_advertised, &tracker.advertisement()) {
+                        continue;
+                    }
+                    false
+                }
+                _ = readvertise::until(tracker.next_hold()) => {
+                    let holds = tracker.holds.as_mut().expect("hold deadline without holds");
+                    if !holds.advance() || same_advertisement(&last_advertised, &tracker.advertisement()) {
+                        continue;
+                    }
+                    false
+                }
+                _ = readvertise::until(wireguard_check_at) => {
+                    wireguard_check_at = next_wireguard_check();
+                    if !tracker.refresh_wireguard_keys().await || same_advertisement(&last_advertised, &tracker.advertisement()) {
+                        continue;
+                    }
+                    output::info("wireguard keys changed");
+                    false
+                }
+                _ = usr1.recv() => {
+                    output::info("SIGUSR1: resyncing from the kernel and advertising");
+                    tracker.resync_all().await?;
+                    tracker.observe_links();
+                    false
+                }
+                _ = usr2.recv() => {
+                    output::dump(&tracker.advertisement());
+                    output::source(tracker.source.poll_interval());
+                    if let Some(holds) = &tracker.holds {
+                        output::holds(&holds.status());
+                    }
+                    continue;
+                }
+                _ = readvertise::until(readvertise_at) => true,
+            };
+            // Whatever sent, or decided against sending, the current state
+            // restarts the wait.
+            readvertise_at = next_readvertise();
+
+            // Losing every address at once is more often a transient (interface
+            // bounce, DHCP renew gone wrong) than the node really going dark.
+            let empty = tracker
+                .advertisement()
+                .interfaces
+                .iter()
+                .all(|i| i.ipaddr.is_empty());
+            if opt.min_addresses > 0 && empty && !opt.allow_empty_advertisement {
+                output::warning("all addresses are gone; not advertising an empty node without --allow-empty-advertisement");
+                continue;
+            }
+
+            *sequence += 1;
+            tracker.state.set_sequence(*sequence);
+            let advertisement = tracker.advertisement();
+            if timed {
+                output::info(format_args!(
+                    "re-advertising {}",
+                    canonical::short_hash(&advertisement)
+                ));
+            } else {
+                output::change(&last_advertised, &advertisement, opt.log_changes_max);
+            }
+            last_advertised = advertisement.clone();
+            if latest.send(advertisement).is_err() {
+                // Every upstream has stopped listening.
+                break;
             }
         }
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::select! {
+        r = events => r,
+        r = upstreams => r.map(|_| ()),
     }
-
-    Err(anyhow!("advertise exceeded tries"))
 }
 
-fn advertise_ready() -> Result<()> {
-    println!("notifying systemd of 'ready' state...");
-    while !systemd::daemon::notify(false, [(systemd::daemon::STATE_READY, "1")].iter())? {
-        std::thread::sleep(std::time::Duration::from_secs(1));
+async fn shutdown_signal() {
+    let mut term = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            output::warning(format_args!("unable to listen for SIGTERM: {}", e));
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
+    output::info("shutting down");
+}
 
-    Ok(())
+// Exits with the code of the error's category; see exit.rs.
+fn main() {
+    let opt = match Opt::from_args_safe() {
+        Ok(opt) => opt,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            std::process::exit(Category::Config.code());
+        }
+        // --help and --version.
+        Err(e) => e.exit(),
+    };
+    output::init(opt.output);
+    if let Err(e) = run(opt) {
+        let category = exit::classify(&e);
+        output::error(&e, category);
+        std::process::exit(category.code());
+    }
 }
 
-async fn run_advertise(opt: &Opt) -> Result<()> {
-    let (mut connection, handle, mut messages) = rtnetlink::new_connection()?;
+fn run(opt: Opt) -> Result<()> {
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
 
-    let addr = SocketAddr::new(0, RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR);
+    let rt = runtime::build(opt.runtime, opt.worker_threads)?;
+    {
+        let _runtime = rt.enter();
+        otel::init(opt.otlp_endpoint.as_deref(), opt.trace_sample_ratio)
+            .context(Category::Config)?;
+    }
 
-    connection.socket_mut().bind(&addr)?;
+    if opt.status {
+        return rt.block_on(print_status(&opt));
+    }
 
-    tokio::spawn(connection);
-    let (hostname, ifaces) = tokio::try_join!(
-        read_hostname(),
-        process_ifaces(&handle, &opt.exclude_ifaces)
-    )?;
+    if let Some(Command::Doctor) = opt.command {
+        if !rt.block_on(doctor::run(&opt))? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(addr) = opt.explain_address {
+        let scope = filter::default_scope(&addr);
+        output::explain(
+            &addr,
+            scope,
+            &address_policy(&opt).evaluate(scope, &addr, ""),
+        );
+        return Ok(());
+    }
+
+    if opt.all_netns {
+        supervise::check_hostname_fmt(&opt.netns_hostname_fmt).context(Category::Config)?;
+        let r = rt.block_on(async {
+            tokio::select! {
+                r = supervise::run(&opt, started) => r,
+                _ = shutdown_signal() => Ok(()),
+            }
+        });
+        otel::shutdown(&rt);
+        return r;
+    }
 
-    println!("{}: {:?}", hostname, ifaces);
+    let mut sequence = 0;
+    loop {
+        // Dropping run_advertise on a signal also cuts short any backoff
+        // sleep it's in.
+        let run = async {
+            tokio::select! {
+                r = run_advertise(&opt, started, &mut sequence, None) => r,
+                _ = shutdown_signal() => Ok(()),
+            }
+        };
+        match rt.block_on(run) {
+            Err(e) if !opt.exit_on_stream_end && e.is::<StreamEnded>() => {
+                output::warning(format_args!("{}, starting over", e));
+                rt.block_on(tokio::time::sleep(Duration::from_secs(1)));
+            }
+            r => {
+                otel::shutdown(&rt);
+                return r;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use structopt::StructOpt;
 
-    let mut advertisement = strapper::NodeAdvertisement {
-        hostname,
-        interfaces: ifaces,
+    use futures_util::stream::{self, StreamExt};
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    use proto::strapper;
+
+    use super::{
+        address_policy, advertise_changes, advertise_ready, keepalive, new_state, process_ifaces,
+        runtime, same_advertisement, size_warning, DebugSignals, Opt, Source, Tracker,
     };
+    use crate::event::Event;
+    use crate::filter;
+    use crate::testing::{address, link, FakeKernel};
+    use crate::upstream::Advertiser;
+
+    fn opt(args: &[&str]) -> Opt {
+        Opt::from_iter_safe(std::iter::once("agent").chain(args.iter().copied())).unwrap()
+    }
 
-    try_advertise(&opt.endpoint, &advertisement).await?;
-    advertise_ready()?;
+    #[test]
+    fn keepalive_flags() {
+        assert_eq!(
+            keepalive(&opt(&[])),
+            (Duration::from_secs(30), Duration::from_secs(10))
+        );
+        assert_eq!(
+            keepalive(&opt(&[
+                "--keepalive-secs",
+                "7",
+                "--keepalive-timeout-secs",
+                "3"
+            ])),
+            (Duration::from_secs(7), Duration::from_secs(3))
+        );
+        assert!(Opt::from_iter_safe(&["agent", "--keepalive-secs", "-1"]).is_err());
+    }
 
-    println!("Waiting for address updates.");
+    // A representative of everything the --include-* flags are about, and
+    // the addresses they should leave alone.
+    const ADDRESSES: &[&str] = &[
+        "8.8.8.8",
+        "2606:4700::1",
+        "10.0.0.1",
+        "fd00::1",
+        "fe80::1",
+        "169.254.0.1",
+        "100.64.0.1",
+        "127.0.0.1",
+        "::1",
+    ];
 
-    while let Some((message, _)) = messages.next().await {
-        let has_changes = if let rtnetlink::packet::NetlinkPayload::InnerMessage(i) = message.payload {
-            match i {
-                rtnl::RtnlMessage::NewAddress(addr) => {
-                    add_addr(&mut advertisement.interfaces, &addr)
-                },
-                rtnl::RtnlMessage::DelAddress(addr) => {
-                    del_addr(&mut advertisement.interfaces, &addr)
+    fn accepted(args: &[&str]) -> Vec<&'static str> {
+        let policy = address_policy(&opt(args));
+        ADDRESSES
+            .iter()
+            .copied()
+            .filter(|a| {
+                let addr = a.parse().unwrap();
+                policy.accepts(filter::default_scope(&addr), &addr, "")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn include_flags() {
+        let base = ["8.8.8.8", "2606:4700::1", "10.0.0.1"];
+        assert_eq!(accepted(&[]), base);
+        assert_eq!(
+            accepted(&["--include-ula"]),
+            ["8.8.8.8", "2606:4700::1", "10.0.0.1", "fd00::1"]
+        );
+        assert_eq!(
+            accepted(&["--include-link-local"]),
+            [
+                "8.8.8.8",
+                "2606:4700::1",
+                "10.0.0.1",
+                "fe80::1",
+                "169.254.0.1"
+            ]
+        );
+        assert_eq!(
+            accepted(&["--include-v4-cgnat"]),
+            ["8.8.8.8", "2606:4700::1", "10.0.0.1", "100.64.0.1"]
+        );
+        assert_eq!(
+            accepted(&[
+                "--include-ula",
+                "--include-link-local",
+                "--include-v4-cgnat"
+            ]),
+            &ADDRESSES[..7]
+        );
+    }
+
+    // The other family's addresses never make it into the state, so they
+    // never count as a change to advertise, coming or going.
+    #[tokio::test]
+    async fn other_family_is_no_change() {
+        let opt = opt(&["--address-family", "v4"]);
+        let (connection, handle, _) = rtnetlink::new_connection().unwrap();
+        tokio::spawn(connection);
+        let mut tracker = Tracker {
+            source: Source::Netlink(Box::new(handle)),
+            state: new_state(
+                &opt,
+                strapper::NodeAdvertisement {
+                    hostname: "node".to_owned(),
+                    ..Default::default()
                 },
-                _ => Ok(false)
-            }?
-        } else { false };
+            ),
+            touched: HashSet::new(),
+            holds: None,
+            wireguard: None,
+        };
+        tracker
+            .state
+            .add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1]))
+            .unwrap();
+        let before = tracker.advertisement();
+
+        let v6 = || address(1, "2606:4700::1");
+        assert!(!tracker.process(Event::NewAddress(v6())).await.unwrap());
+        assert!(!tracker.process(Event::DelAddress(v6())).await.unwrap());
+        assert_eq!(tracker.advertisement(), before);
+
+        assert!(tracker
+            .process(Event::NewAddress(address(1, "10.0.0.1")))
+            .await
+            .unwrap());
+        assert!(!tracker.process(Event::NewAddress(v6())).await.unwrap());
+        assert_eq!(tracker.state.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+    }
+
+    // Thousands of container veths: warned about at --warn-interfaces, and
+    // still sent, since it's the server that draws the line.
+    #[test]
+    fn many_interfaces_warned() {
+        let advertisement = |n: u32| strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: (0..n)
+                .map(|i| strapper::Interface {
+                    name: format!("veth{}", i),
+                    index: i + 2,
+                    ipaddr: vec![format!("fd00::{:x}", i)],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let defaults = opt(&[]);
+        let warning = size_warning(&defaults, &advertisement(5000)).expect("a warning");
+        assert!(warning.contains("5000 interfaces"), "{}", warning);
+        assert!(warning.contains("--warn-interfaces 256"), "{}", warning);
+        assert!(warning.contains("--exclude-ifaces"), "{}", warning);
+        assert_eq!(size_warning(&defaults, &advertisement(256)), None);
 
-        if has_changes {
-            println!("Advertising address changes: {:?}", advertisement);
-            try_advertise(&opt.endpoint, &advertisement).await?;
+        let raised = opt(&["--warn-interfaces", "5000"]);
+        assert_eq!(size_warning(&raised, &advertisement(5000)), None);
+        assert!(size_warning(&raised, &advertisement(5001)).is_some());
+    }
+
+    // Outside systemd the notify never lands, so advertise_ready keeps
+    // retrying; on the one thread of the current runtime, everything else
+    // has to keep running while it waits.
+    #[test]
+    fn ready_retries_without_blocking() {
+        std::env::remove_var("NOTIFY_SOCKET");
+        let rt = runtime::build(runtime::Flavor::Current, None).unwrap();
+        let longest = rt.block_on(async {
+            let ready = tokio::spawn(advertise_ready());
+            let started = std::time::Instant::now();
+            let mut last = started;
+            let mut longest = Duration::default();
+            while started.elapsed() < Duration::from_millis(1500) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                longest = longest.max(last.elapsed());
+                last = std::time::Instant::now();
+            }
+            assert!(!ready.is_finished());
+            ready.abort();
+            longest
+        });
+        // A blocking second-long retry would hold up the loop for all of it.
+        assert!(longest < Duration::from_millis(500), "{:?}", longest);
+    }
+
+    // Stands in for a server: fails the next `down` advertisements as an
+    // unreachable one would, and takes the rest. Clones share everything,
+    // so the test keeps one while the loop sends to another.
+    #[derive(Clone, Default)]
+    struct FakeServer {
+        down: Rc<Cell<u32>>,
+        attempts: Rc<RefCell<Vec<Instant>>>,
+        taken: Rc<RefCell<Vec<(Instant, strapper::NodeAdvertisement)>>>,
+    }
+
+    impl FakeServer {
+        fn taken(&self) -> Vec<strapper::NodeAdvertisement> {
+            self.taken.borrow().iter().map(|(_, a)| a.clone()).collect()
+        }
+
+        fn addresses(&self) -> Vec<Vec<String>> {
+            self.taken()
+                .iter()
+                .map(|a| a.interfaces.iter().flat_map(|i| i.ipaddr.clone()).collect())
+                .collect()
         }
     }
-    Ok(())
-}
 
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
+    #[tonic::async_trait(?Send)]
+    impl Advertiser for FakeServer {
+        fn name(&self) -> String {
+            "fake".to_owned()
+        }
+
+        fn primary(&self) -> bool {
+            true
+        }
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_io()
-        .build()?;
+        fn accepted(&self) -> Option<u64> {
+            self.taken.borrow().last().map(|(_, a)| a.sequence)
+        }
 
-    rt.block_on(run_advertise(&opt))?;
+        async fn advertise(
+            &mut self,
+            _: &Opt,
+            advertisement: &strapper::NodeAdvertisement,
+        ) -> anyhow::Result<strapper::AdvertiseResult> {
+            let now = Instant::now();
+            self.attempts.borrow_mut().push(now);
+            if self.down.get() > 0 {
+                self.down.set(self.down.get() - 1);
+                return Err(anyhow::anyhow!("connection refused"));
+            }
+            self.taken.borrow_mut().push((now, advertisement.clone()));
+            Ok(Default::default())
+        }
+    }
 
-    Ok(())
+    // The agent's loop over a fake kernel with eth0 and one address, events
+    // from `events` and advertisements to `server`, already past systemd's
+    // READY. Never ends by itself; tests race it against what they do.
+    async fn agent(
+        opt: &Opt,
+        kernel: &FakeKernel,
+        events: mpsc::UnboundedReceiver<Event>,
+        server: &FakeServer,
+    ) {
+        kernel.add_link(2, "eth0", [2, 0, 0, 0, 0, 1]);
+        kernel.add_address(2, "2606:4700::1");
+        let mut state = new_state(
+            opt,
+            strapper::NodeAdvertisement {
+                hostname: "node".to_owned(),
+                ..Default::default()
+            },
+        );
+        process_ifaces(kernel, &mut state).await.unwrap();
+        let mut sequence = 1;
+        state.set_sequence(sequence);
+        let tracker = Tracker {
+            source: Source::Netlink(Box::new(kernel.clone())),
+            state,
+            touched: HashSet::new(),
+            holds: None,
+            wireguard: None,
+        };
+        let events = stream::unfold(events, |mut rx| async move {
+            let event = rx.recv().await?;
+            Some((Ok(event), rx))
+        })
+        .boxed();
+        let r = advertise_changes(
+            opt,
+            tracker,
+            Some(events),
+            vec![server.clone()],
+            &mut sequence,
+            DebugSignals::install().unwrap(),
+            async { Ok(()) },
+        )
+        .await;
+        panic!("the loop ended: {:?}", r);
+    }
+
+    // With the clock paused, sleeping lets everything else run until it's
+    // waiting on time, then moves the clock on.
+    async fn advance(secs: f64) {
+        tokio::time::sleep(Duration::from_secs_f64(secs)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_advertised_once() {
+        let opt = opt(&[]);
+        let (kernel, server) = (FakeKernel::default(), FakeServer::default());
+        let (events, rx) = mpsc::unbounded_channel();
+        let script = async {
+            advance(1.0).await;
+            assert_eq!(server.addresses(), [["2606:4700::1"]]);
+
+            // Five adds, each within the 200ms debounce of the last.
+            for i in 2..=6 {
+                events
+                    .send(kernel.add_address(2, &format!("2606:4700::{}", i)))
+                    .unwrap();
+                advance(0.1).await;
+            }
+            advance(10.0).await;
+            let taken = server.taken();
+            assert_eq!(taken.len(), 2, "{:?}", server.addresses());
+            assert_eq!(
+                taken[1].interfaces[0].ipaddr,
+                [
+                    "2606:4700::1",
+                    "2606:4700::2",
+                    "2606:4700::3",
+                    "2606:4700::4",
+                    "2606:4700::5",
+                    "2606:4700::6"
+                ]
+            );
+            assert!(taken[1].sequence > taken[0].sequence);
+            // Sent once the burst had been quiet for the debounce.
+            let (first, _) = server.taken.borrow()[0];
+            let (at, _) = server.taken.borrow()[1];
+            let after = at - first;
+            assert!(
+                after >= Duration::from_millis(1600) && after < Duration::from_millis(1700),
+                "{:?}",
+                after
+            );
+
+            // An add and delete of the same address cancel out.
+            events.send(kernel.add_address(2, "2606:4700::7")).unwrap();
+            events.send(kernel.del_address(2, "2606:4700::7")).unwrap();
+            advance(10.0).await;
+            assert_eq!(server.taken().len(), 2);
+        };
+        tokio::select! {
+            _ = agent(&opt, &kernel, rx, &server) => {}
+            _ = script => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_down_then_back() {
+        // Otherwise giving up on the primary ends the agent.
+        let opt = opt(&["--ready-requires", "any"]);
+        let (kernel, server) = (FakeKernel::default(), FakeServer::default());
+        server.down.set(3);
+        let (events, rx) = mpsc::unbounded_channel();
+        let script = async {
+            // Three failed rounds, backing off 1, 2 and 4 seconds at most.
+            advance(30.0).await;
+            let attempts = server.attempts.borrow().clone();
+            assert_eq!(attempts.len(), 4, "{:?}", attempts);
+            for (n, pair) in attempts.windows(2).enumerate() {
+                let cap = Duration::from_secs(1 << n) + Duration::from_millis(1);
+                assert!(
+                    pair[1] - pair[0] <= cap,
+                    "{:?} after {} failures",
+                    pair[1] - pair[0],
+                    n + 1
+                );
+            }
+            assert_eq!(server.addresses(), [["2606:4700::1"]]);
+
+            // Back up, it takes the next change straight away.
+            events.send(kernel.add_address(2, "2606:4700::2")).unwrap();
+            advance(1.0).await;
+            assert_eq!(server.attempts.borrow().len(), 5);
+            assert_eq!(
+                server.addresses(),
+                [vec!["2606:4700::1"], vec!["2606:4700::1", "2606:4700::2"]]
+            );
+        };
+        tokio::select! {
+            _ = agent(&opt, &kernel, rx, &server) => {}
+            _ = script => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn readvertise_after_quiet() {
+        let opt = opt(&[
+            "--readvertise-interval-secs",
+            "60",
+            "--readvertise-jitter-percent",
+            "0",
+        ]);
+        let (kernel, server) = (FakeKernel::default(), FakeServer::default());
+        let (events, rx) = mpsc::unbounded_channel();
+        let script = async {
+            advance(30.0).await;
+            events.send(kernel.add_address(2, "2606:4700::2")).unwrap();
+            // The change at 30s put off the re-advertisement due at 60s.
+            advance(45.0).await;
+            assert_eq!(server.taken().len(), 2);
+            advance(20.0).await;
+            let taken = server.taken();
+            assert_eq!(taken.len(), 3);
+            assert!(same_advertisement(&taken[1], &taken[2]));
+            assert!(taken[2].sequence > taken[1].sequence);
+            // From then on, every 60s while nothing changes.
+            advance(60.0).await;
+            assert_eq!(server.taken().len(), 4);
+            let times: Vec<_> = server.taken.borrow().iter().map(|(at, _)| *at).collect();
+            assert!(times[3] - times[2] >= Duration::from_secs(60));
+            assert!(times[3] - times[2] < Duration::from_secs(61));
+        };
+        tokio::select! {
+            _ = agent(&opt, &kernel, rx, &server) => {}
+            _ = script => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn link_added_and_removed() {
+        let opt = opt(&[]);
+        let (kernel, server) = (FakeKernel::default(), FakeServer::default());
+        let (events, rx) = mpsc::unbounded_channel();
+        let script = async {
+            advance(1.0).await;
+            // A new link's addresses come from a dump, not its own events.
+            kernel.add_address(3, "2606:4700::3:1");
+            events
+                .send(kernel.add_link(3, "eth1", [2, 0, 0, 0, 0, 2]))
+                .unwrap();
+            advance(1.0).await;
+            let taken = server.taken();
+            assert_eq!(taken.len(), 2);
+            assert_eq!(
+                taken[1]
+                    .interfaces
+                    .iter()
+                    .map(|i| (i.name.as_str(), i.ipaddr.clone()))
+                    .collect::<Vec<_>>(),
+                [
+                    ("eth0", vec!["2606:4700::1".to_owned()]),
+                    ("eth1", vec!["2606:4700::3:1".to_owned()])
+                ]
+            );
+
+            events.send(kernel.del_link(3)).unwrap();
+            advance(1.0).await;
+            let taken = server.taken();
+            assert_eq!(taken.len(), 3);
+            assert_eq!(taken[2].interfaces.len(), 1);
+            assert_eq!(taken[2].interfaces[0].name, "eth0");
+        };
+        tokio::select! {
+            _ = agent(&opt, &kernel, rx, &server) => {}
+            _ = script => {}
+        }
+    }
+
+    // Backoffs run on tokio's clock, so under a paused one their elapsed
+    // time is what the sleeps took, not what the machine did.
+    #[tokio::test(start_paused = true)]
+    async fn backoff_on_tokio_time() {
+        let mut backoff = runtime::backoff(client::RetryPolicy {
+            max_tries: u32::MAX,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            max_elapsed: Some(Duration::from_secs(3600)),
+        });
+        tokio::time::advance(Duration::from_secs(3599)).await;
+        assert!(backoff.next_delay().unwrap() <= Duration::from_secs(1));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(backoff.next_delay(), None);
+    }
 }
@@ -0,0 +1,51 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+// `ip netns` names live under /var/run/netns; anything with a slash is taken
+// as a path, e.g. /proc/<pid>/ns/net.
+pub fn resolve(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        PathBuf::from(netns)
+    } else {
+        Path::new("/var/run/netns").join(netns)
+    }
+}
+
+fn setns(ns: &File, what: &str) -> Result<()> {
+    if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EPERM) => anyhow!("entering {} requires CAP_SYS_ADMIN", what),
+            Some(libc::EINVAL) => anyhow!("{} is not a network namespace", what),
+            _ => anyhow!("error entering {}: {}", what, e),
+        });
+    }
+    Ok(())
+}
+
+// Runs `f` with this thread in the namespace at `path`, then switches back.
+// Sockets keep the namespace they were created in, so a netlink socket made
+// here watches the target namespace while everything else (gRPC, hostname)
+// stays in ours.
+pub fn in_netns<T, F>(path: &Path, f: F) -> Result<T>
+where
+    F: FnOnce() -> std::io::Result<T>,
+{
+    let target = File::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            anyhow!("network namespace {} does not exist", path.display())
+        }
+        _ => anyhow!("error opening network namespace {}: {}", path.display(), e),
+    })?;
+    let original = File::open("/proc/thread-self/ns/net")
+        .context("error opening current network namespace")?;
+
+    setns(&target, &format!("network namespace {}", path.display()))?;
+    let result = f();
+    setns(&original, "the original network namespace")
+        .context("unable to return to the original network namespace")?;
+
+    Ok(result?)
+}
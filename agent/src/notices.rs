@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+use proto::{notice, strapper};
+
+use crate::{output, Opt};
+
+// What one server last said about our advertisements (see proto::notice),
+// passed on as it changes: a notice is logged the first time it's given,
+// not again for as long as every answer repeats it, and the primary's
+// latest goes in systemd's STATUS=, for `systemctl status` to show. The
+// primary's are also kept at `path`, for --status to show.
+pub struct Notices {
+    primary: bool,
+    path: Option<PathBuf>,
+    current: Vec<strapper::Notice>,
+}
+
+impl Notices {
+    pub fn new(primary: bool, path: Option<PathBuf>) -> Notices {
+        Notices {
+            primary,
+            path,
+            current: vec![],
+        }
+    }
+
+    // `notices` is everything the server said this time, so anything left
+    // out of it has been dealt with.
+    pub async fn update(&mut self, server: &str, notices: Vec<strapper::Notice>) {
+        for n in notices.iter().filter(|n| !self.current.contains(n)) {
+            output::notice(server, n);
+        }
+        if self.primary && notices != self.current {
+            let status = notices
+                .last()
+                .map(|n| format!("server says: {}", n.message))
+                .unwrap_or_default();
+            set_status(status).await;
+            if let Some(path) = &self.path {
+                if let Err(e) = store(path, server, &notices).await {
+                    output::warning(format_args!("unable to keep notices: {:#}", e));
+                }
+            }
+        }
+        self.current = notices;
+    }
+}
+
+// Everything the server said in refusing, which is its notice if it gave
+// one and nothing otherwise. None for an error that never got an answer
+// from it, which says nothing about whether what it last said still holds.
+pub fn from_error(e: &anyhow::Error) -> Option<Vec<strapper::Notice>> {
+    let refused = e
+        .chain()
+        .find_map(|c| c.downcast_ref::<client::AdvertiseError>())?;
+    Some(notice::from_status(&refused.status).into_iter().collect())
+}
+
+// Next to the state cache, the only place the agent keeps anything.
+pub fn path(opt: &Opt) -> Option<PathBuf> {
+    Some(opt.state_cache.as_ref()?.with_extension("notices"))
+}
+
+async fn store(path: &Path, server: &str, notices: &[strapper::Notice]) -> Result<()> {
+    let kept = json!({
+        "server": server,
+        "notices": notices.iter().map(|n| json!({
+            "severity": n.severity,
+            "code": n.code,
+            "message": n.message,
+        })).collect::<Vec<_>>(),
+    });
+    let tmp = path.with_extension("tmp-notices");
+    tokio::fs::write(&tmp, kept.to_string())
+        .await
+        .with_context(|| format!("error writing {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("error renaming to {}", path.display()))?;
+    Ok(())
+}
+
+// The server's name and what it last said, or None if it hasn't said
+// anything yet.
+pub async fn load(path: &Path) -> Result<Option<(String, Vec<strapper::Notice>)>> {
+    let kept = match tokio::fs::read(path).await {
+        Ok(kept) => kept,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("error reading {}", path.display())),
+    };
+    let kept: Value = serde_json::from_slice(&kept)
+        .with_context(|| format!("error parsing {}", path.display()))?;
+    let server = kept["server"].as_str().unwrap_or_default().to_owned();
+    let notices = kept["notices"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|n| strapper::Notice {
+            severity: n["severity"].as_i64().unwrap_or_default() as i32,
+            code: n["code"].as_str().unwrap_or_default().to_owned(),
+            message: n["message"].as_str().unwrap_or_default().to_owned(),
+        })
+        .collect();
+    Ok(Some((server, notices)))
+}
+
+// Blocking, as advertise_ready is. Outside systemd there's no one to tell,
+// and a STATUS= that doesn't make it is no reason to stop advertising.
+async fn set_status(status: String) {
+    let notified = tokio::task::spawn_blocking(move || {
+        systemd::daemon::notify(
+            false,
+            [(systemd::daemon::STATE_STATUS, status.as_str())].iter(),
+        )
+    })
+    .await;
+    if let Ok(Err(e)) = notified {
+        output::warning(format_args!("unable to set systemd status: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::{notice, strapper};
+
+    use super::{from_error, load, Notices};
+
+    fn refused(status: tonic::Status) -> anyhow::Error {
+        anyhow::Error::new(client::AdvertiseError {
+            request_id: "r".to_owned(),
+            status,
+        })
+        .context("error advertising")
+    }
+
+    #[test]
+    fn said_in_refusing() {
+        let status = notice::refusal(
+            tonic::Code::InvalidArgument,
+            notice::HOSTNAME_INVALID,
+            "hostname 'web_01' contains invalid character '_'".to_owned(),
+        );
+        let said = from_error(&refused(status)).unwrap();
+        assert_eq!(said.len(), 1);
+        assert_eq!(said[0].code, notice::HOSTNAME_INVALID);
+        assert_eq!(
+            said[0].message,
+            "hostname 'web_01' contains invalid character '_'"
+        );
+
+        // A refusal without one says there's nothing to act on.
+        let said = from_error(&refused(tonic::Status::permission_denied("no")));
+        assert_eq!(said, Some(vec![]));
+        // A server that never answered says nothing at all, so what it last
+        // said stands and isn't logged again when it says it once more.
+        assert_eq!(from_error(&anyhow::anyhow!("connection refused")), None);
+        assert_eq!(from_error(&tonic::Status::unavailable("down").into()), None);
+    }
+
+    #[tokio::test]
+    async fn kept_for_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.notices");
+        assert!(load(&path).await.unwrap().is_none());
+
+        let said = vec![notice::warning(
+            notice::NO_REMAPPER_MATCH,
+            "no remapper takes 192.0.2.1".to_owned(),
+        )];
+        // Only the primary's are kept.
+        let mut secondary = Notices::new(false, Some(path.clone()));
+        secondary.update("secondary", said.clone()).await;
+        assert!(load(&path).await.unwrap().is_none());
+
+        let mut primary = Notices::new(true, Some(path.clone()));
+        primary.update("primary", said.clone()).await;
+        assert_eq!(
+            load(&path).await.unwrap(),
+            Some(("primary".to_owned(), said))
+        );
+        primary.update("primary", vec![]).await;
+        assert_eq!(
+            load(&path).await.unwrap(),
+            Some(("primary".to_owned(), Vec::<strapper::Notice>::new()))
+        );
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(load(&path).await.is_err());
+    }
+}
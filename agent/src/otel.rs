@@ -0,0 +1,137 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+// Traces are only exported from builds with the otel feature, which is off
+// by default for the dependencies it pulls in. Without it everything here
+// does nothing, and --otlp-endpoint is refused rather than ignored.
+#[cfg(feature = "otel")]
+mod imp {
+    use anyhow::Result;
+    use opentelemetry::global;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::{self as sdktrace, Sampler};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::trace::{FutureExt, Span, StatusCode, TraceContextExt, Tracer};
+    use opentelemetry::{Context, KeyValue};
+    use std::future::Future;
+
+    const TRACER: &str = "strapper-agent";
+
+    // Needs to be called inside the runtime, which the exporter runs on.
+    pub fn init(endpoint: Option<&str>, sample_ratio: f64) -> Result<()> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(
+                sdktrace::config()
+                    .with_sampler(Sampler::TraceIdRatioBased(sample_ratio))
+                    .with_resource(Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        "strapper-agent",
+                    )])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(())
+    }
+
+    // The exporter's shutdown blocks until what's been recorded is sent,
+    // which takes the runtime running to do.
+    pub fn shutdown(rt: &tokio::runtime::Runtime) {
+        let _ = rt.block_on(tokio::task::spawn_blocking(
+            global::shutdown_tracer_provider,
+        ));
+    }
+
+    pub struct Active(Context);
+
+    pub fn start(name: &'static str, attributes: Vec<(&'static str, String)>) -> Active {
+        let span = global::tracer(TRACER).start(name);
+        for (key, value) in attributes {
+            span.set_attribute(KeyValue::new(key, value));
+        }
+        Active(Context::current_with_span(span))
+    }
+
+    pub async fn run<T>(active: &Active, fut: impl Future<Output = T>) -> T {
+        fut.with_context(active.0.clone()).await
+    }
+
+    pub fn finish(active: Active, error: Option<String>) {
+        if let Some(e) = error {
+            active.0.span().set_status(StatusCode::Error, e);
+        }
+        active.0.span().end();
+    }
+
+    pub fn event(name: &'static str, attributes: Vec<(&'static str, String)>) {
+        Context::current().span().add_event(
+            name.to_owned(),
+            attributes
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value))
+                .collect(),
+        );
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod imp {
+    use anyhow::{ensure, Result};
+    use std::future::Future;
+
+    pub fn init(endpoint: Option<&str>, _sample_ratio: f64) -> Result<()> {
+        ensure!(
+            endpoint.is_none(),
+            "--otlp-endpoint needs an agent built with the otel feature"
+        );
+        Ok(())
+    }
+
+    pub fn shutdown(_rt: &tokio::runtime::Runtime) {}
+
+    pub struct Active;
+
+    pub fn start(_name: &'static str, _attributes: Vec<(&'static str, String)>) -> Active {
+        Active
+    }
+
+    pub async fn run<T>(_active: &Active, fut: impl Future<Output = T>) -> T {
+        fut.await
+    }
+
+    pub fn finish(_active: Active, _error: Option<String>) {}
+
+    pub fn event(_name: &'static str, _attributes: Vec<(&'static str, String)>) {}
+}
+
+pub use imp::{init, shutdown};
+
+// Runs `fut` as a trace of its own, marked failed if `fut` fails. The
+// client sends the trace along with each request made under it.
+pub async fn traced<T, E: Display>(
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let trace = imp::start(name, attributes);
+    let result = imp::run(&trace, fut).await;
+    imp::finish(trace, result.as_ref().err().map(|e| format!("{:#}", e)));
+    result
+}
+
+// Records a failed attempt on the trace the caller is running in.
+pub fn retry(error: impl Display, attempt: u32, wait: Duration) {
+    imp::event(
+        "retry",
+        vec![
+            ("error", error.to_string()),
+            ("attempt", attempt.to_string()),
+            ("wait_secs", wait.as_secs().to_string()),
+        ],
+    )
+}
@@ -0,0 +1,444 @@
+use anyhow::anyhow;
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+use proto::{changes, notice, strapper};
+
+use crate::doctor::{Check, Outcome};
+use crate::exit::Category;
+use crate::filter::Decision;
+use crate::linkhold::HoldStatus;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!(
+                "unknown output format '{}' (expected human or json)",
+                s
+            )),
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn init(format: OutputFormat) {
+    JSON.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+// One line per event, with `ts` and `event` always present so log pipelines
+// can key on them.
+fn emit(event: &str, fields: Value) {
+    println!("{}", line(event, fields));
+}
+
+fn line(event: &str, fields: Value) -> Value {
+    let mut line = json!({
+        "ts": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        "event": event,
+    });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    line
+}
+
+pub fn info(message: impl Display) {
+    if is_json() {
+        emit("info", json!({ "message": message.to_string() }));
+    } else {
+        println!("{}", message);
+    }
+}
+
+pub fn warning(message: impl Display) {
+    if is_json() {
+        emit("warning", json!({ "message": message.to_string() }));
+    } else {
+        println!("warning: {}", message);
+    }
+}
+
+pub fn startup(adv: &strapper::NodeAdvertisement) {
+    if is_json() {
+        emit("startup", adv.to_json());
+    } else {
+        println!("{}", adv);
+    }
+}
+
+// Where changes come from: netlink events or, with `poll_interval`, reading
+// /proc/net and /sys/class/net. At startup and on SIGUSR2.
+pub fn source(poll_interval: Option<Duration>) {
+    if is_json() {
+        emit(
+            "source",
+            json!({
+                "mode": if poll_interval.is_some() { "poll" } else { "netlink" },
+                "poll_interval_secs": poll_interval.map(|i| i.as_secs()),
+            }),
+        );
+    } else {
+        match poll_interval {
+            Some(i) => println!("polling /proc/net every {}s for changes", i.as_secs()),
+            None => println!("watching netlink for changes"),
+        }
+    }
+}
+
+// What changed since `last`, past `max` changes only counted.
+pub fn change(last: &strapper::NodeAdvertisement, adv: &strapper::NodeAdvertisement, max: usize) {
+    if is_json() {
+        emit("change", change_fields(last, adv));
+    } else {
+        let changes = changes::changes(last, adv);
+        println!("advertising changes: {}", changes::describe(&changes, max));
+    }
+}
+
+fn change_fields(last: &strapper::NodeAdvertisement, adv: &strapper::NodeAdvertisement) -> Value {
+    let mut fields = adv.to_json();
+    if let Value::Object(fields) = &mut fields {
+        let changes = changes::changes(last, adv);
+        fields.insert(
+            "changes".to_owned(),
+            json!(changes.iter().map(|c| c.to_string()).collect::<Vec<_>>()),
+        );
+    }
+    fields
+}
+
+// Always JSON, so it can be pasted straight into a bug report.
+pub fn dump(adv: &strapper::NodeAdvertisement) {
+    if is_json() {
+        emit("dump", adv.to_json());
+    } else {
+        println!("current advertisement: {}", adv.to_json());
+    }
+}
+
+fn hold<'a>(h: &HoldStatus<'a>) -> (&'a str, &'static str, Option<Duration>) {
+    match *h {
+        HoldStatus::Advertised(name) => (name, "advertised", None),
+        HoldStatus::Withdrawn(name) => (name, "withdrawn", None),
+        HoldStatus::Pending(name, true, wait) => (name, "advertising", Some(wait)),
+        HoldStatus::Pending(name, false, wait) => (name, "withdrawing", Some(wait)),
+    }
+}
+
+// Something `server` said about the advertisement; see proto::notice.
+pub fn notice(server: &str, n: &strapper::Notice) {
+    if is_json() {
+        emit(
+            "notice",
+            json!({
+                "server": server,
+                "severity": notice::severity_name(n),
+                "code": n.code,
+                "message": n.message,
+            }),
+        );
+    } else {
+        let message = format!("{} says: {} ({})", server, n.message, n.code);
+        match notice::severity(n) {
+            strapper::NoticeSeverity::Info => println!("{}", message),
+            strapper::NoticeSeverity::Warning => println!("warning: {}", message),
+            strapper::NoticeSeverity::Error => println!("error: {}", message),
+        }
+    }
+}
+
+// Each link's standing under --link-down-hold-secs, on SIGUSR2.
+pub fn holds(holds: &[HoldStatus]) {
+    if is_json() {
+        emit(
+            "link_holds",
+            json!({
+                "links": holds.iter().map(|h| {
+                    let (name, state, wait) = hold(h);
+                    json!({
+                        "name": name,
+                        "state": state,
+                        "pending_secs": wait.map(|w| w.as_secs_f64()),
+                    })
+                }).collect::<Vec<_>>(),
+            }),
+        );
+    } else {
+        let links: Vec<String> = holds
+            .iter()
+            .map(|h| match hold(h) {
+                (name, state, None) => format!("{} {}", name, state),
+                (name, state, Some(wait)) => format!("{} {} in {}s", name, state, wait.as_secs()),
+            })
+            .collect();
+        println!("link holds: {}", links.join(", "));
+    }
+}
+
+pub fn explain(addr: &IpAddr, scope: u8, decision: &Decision) {
+    let (accepted, rule) = match decision {
+        Decision::Accept(rule) => (true, rule),
+        Decision::Reject(rule) => (false, rule),
+    };
+    if is_json() {
+        emit(
+            "explain",
+            json!({
+                "address": addr.to_string(),
+                "scope": scope,
+                "accepted": accepted,
+                "rule": rule,
+            }),
+        );
+    } else {
+        let verdict = if accepted {
+            "advertised"
+        } else {
+            "not advertised"
+        };
+        println!("{} (scope {}): {}: {}", addr, scope, verdict, rule);
+    }
+}
+
+pub fn status(s: &strapper::ServerStatus) {
+    let pause = s.write_pause.clone().unwrap_or_default();
+    let role = match strapper::Role::from_i32(s.role) {
+        Some(strapper::Role::Standby) => "standby",
+        _ => "primary",
+    };
+    let writer_lock = match strapper::WriterLock::from_i32(s.writer_lock) {
+        Some(strapper::WriterLock::Held) => "held",
+        Some(strapper::WriterLock::Waiting) => "waiting",
+        Some(strapper::WriterLock::Lost) => "lost",
+        _ => "unused",
+    };
+    if is_json() {
+        emit(
+            "status",
+            json!({
+                "version": s.version,
+                "git_hash": s.git_hash,
+                "uptime_secs": s.uptime_secs,
+                "nodes": s.nodes,
+                "remappers": s.remappers,
+                "pdns_endpoint": s.pdns_endpoint,
+                "advertise_succeeded": s.advertise_succeeded,
+                "advertise_failed": s.advertise_failed,
+                "pdns_applied": s.pdns_applied,
+                "pdns_failures": s.pdns_failures,
+                "write_pause": {
+                    "all": pause.all,
+                    "zones": pause.zones,
+                    "held_writes": pause.held_writes,
+                },
+                "role": role,
+                "missing_zones": s.missing_zones,
+                "writer_lock": writer_lock,
+            }),
+        );
+        return;
+    }
+
+    let version = if s.git_hash.is_empty() {
+        s.version.clone()
+    } else {
+        format!("{} ({})", s.version, s.git_hash)
+    };
+    let rows = [
+        ("version", version),
+        (
+            "uptime",
+            humantime::format_duration(Duration::from_secs(s.uptime_secs)).to_string(),
+        ),
+        ("role", role.to_owned()),
+        ("writer lock", writer_lock.to_owned()),
+        ("nodes", s.nodes.to_string()),
+        ("remappers", s.remappers.to_string()),
+        ("pdns endpoint", s.pdns_endpoint.clone()),
+        (
+            "advertisements",
+            format!(
+                "{} ok, {} failed",
+                s.advertise_succeeded, s.advertise_failed
+            ),
+        ),
+        (
+            "pdns updates",
+            format!("{} ok, {} failed", s.pdns_applied, s.pdns_failures),
+        ),
+        (
+            "writes paused",
+            match (pause.all, pause.zones.is_empty()) {
+                (false, true) => "no".to_owned(),
+                (true, _) => format!("all zones, {} held", pause.held_writes),
+                (false, false) => format!("{}, {} held", pause.zones.join(", "), pause.held_writes),
+            },
+        ),
+        (
+            "missing zones",
+            if s.missing_zones.is_empty() {
+                "none".to_owned()
+            } else {
+                s.missing_zones.join(", ")
+            },
+        ),
+    ];
+    for (name, value) in rows.iter() {
+        println!("{:<16}{}", name, value);
+    }
+}
+
+fn outcome(outcome: &Outcome) -> &'static str {
+    match outcome {
+        Outcome::Pass => "pass",
+        Outcome::Warn => "warn",
+        Outcome::Fail => "fail",
+        Outcome::Skip => "skip",
+    }
+}
+
+pub fn doctor(checks: &[Check]) {
+    if is_json() {
+        emit(
+            "doctor",
+            json!({
+                "ok": !checks.iter().any(|c| matches!(c.outcome, Outcome::Fail)),
+                "checks": checks.iter().map(|c| json!({
+                    "name": c.name,
+                    "result": outcome(&c.outcome),
+                    "message": c.message,
+                })).collect::<Vec<_>>(),
+            }),
+        );
+        return;
+    }
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for c in checks {
+        println!(
+            "{:<width$}  {:<4}  {}",
+            c.name,
+            outcome(&c.outcome),
+            c.message,
+            width = width
+        );
+    }
+}
+
+// `what` is the operation being retried, e.g. "advertise".
+pub fn retry(what: &str, error: impl Display, attempt: u32, wait: Duration) {
+    if is_json() {
+        emit(
+            "retry",
+            json!({
+                "operation": what,
+                "error": error.to_string(),
+                "attempt": attempt,
+                "wait_secs": wait.as_secs(),
+            }),
+        );
+    } else {
+        println!(
+            "{} failed ({}, try {}), trying again in {} seconds",
+            what,
+            error,
+            attempt,
+            wait.as_secs()
+        );
+    }
+}
+
+// The error the agent exits with, as its last line.
+pub fn error(error: &anyhow::Error, category: Category) {
+    if is_json() {
+        emit(
+            "error",
+            json!({
+                "error": format!("{:#}", error),
+                "category": category.as_str(),
+                "exit_code": category.code(),
+                "hint": category.hint(),
+            }),
+        );
+    } else {
+        eprintln!("error ({}): {:#}", category.as_str(), error);
+        if let Some(hint) = category.hint() {
+            eprintln!("hint: {}", hint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::strapper;
+    use serde_json::Value;
+
+    use super::{change_fields, line};
+
+    // Log pipelines key on these names; changing one breaks them, so it
+    // should take changing testdata/change_event.json too.
+    #[test]
+    fn change_event_schema() {
+        let iface = |ipaddr: Vec<&str>| strapper::Interface {
+            name: "eth0".to_owned(),
+            index: 2,
+            mac: "52:54:00:12:34:56".to_owned(),
+            ipaddr: ipaddr.into_iter().map(str::to_owned).collect(),
+            address_info: vec![strapper::AddressInfo {
+                address: "10.0.0.1".to_owned(),
+                preferred_lifetime: u32::MAX,
+                valid_lifetime: u32::MAX,
+                ..Default::default()
+            }],
+            mtu: 1500,
+            oper_state: strapper::OperState::Up as i32,
+            ..Default::default()
+        };
+        let adv = |ipaddr| strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            fqdn: "node.example.com".to_owned(),
+            sequence: 7,
+            agent_version: "0.1.0".to_owned(),
+            interfaces: vec![iface(ipaddr)],
+            default_routes: vec![strapper::Route {
+                gateway: "10.0.0.254".to_owned(),
+                metric: 100,
+                index: 2,
+            }],
+            ..Default::default()
+        };
+        let last = adv(vec!["10.0.0.1"]);
+        let now = adv(vec!["10.0.0.1", "fd00::1"]);
+
+        let mut event = line("change", change_fields(&last, &now));
+        let ts = event.as_object_mut().unwrap().remove("ts").unwrap();
+        assert!(humantime::parse_rfc3339(ts.as_str().unwrap()).is_ok());
+
+        let golden: Value =
+            serde_json::from_str(include_str!("testdata/change_event.json")).unwrap();
+        assert_eq!(
+            event,
+            golden,
+            "{}",
+            serde_json::to_string_pretty(&event).unwrap()
+        );
+    }
+}
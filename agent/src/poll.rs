@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Context, Result};
+use rtnetlink::packet::rtnl;
+use rtnetlink::packet::rtnl::constants::{RTN_UNICAST, RT_TABLE_MAIN};
+use rtnetlink::packet::rtnl::link::nlas::{Info, InfoData, InfoKind, InfoVlan, State};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use crate::filter::AddressFamily;
+use crate::routes;
+use crate::state::AdvertisementState;
+
+// What a netlink dump would have said, pieced together from /sys/class/net,
+// /proc/net and SIOCGIFCONF, for when the agent can't open a NETLINK_ROUTE
+// socket (a seccomp profile blocking it, say). getifaddrs is no help there,
+// since glibc asks netlink for it. The messages go through the same
+// AdvertisementState as netlink's, so exclusions and the address policy
+// apply the same, but a few things only netlink knows are lost: address
+// lifetimes and, for IPv4, flags (so every IPv4 address counts as dynamic)
+// and secondary addresses without a label. A link's kind is its uevent
+// DEVTYPE, which not every driver sets.
+
+const SYS_NET: &str = "/sys/class/net";
+
+fn sys(name: &str, attr: &str) -> Option<String> {
+    std::fs::read_to_string(Path::new(SYS_NET).join(name).join(attr))
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+
+fn parse_mac(s: &str) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = s
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<_>>()?;
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(bytes)
+    }
+}
+
+fn oper_state(s: &str) -> State {
+    match s {
+        "notpresent" => State::NotPresent,
+        "down" => State::Down,
+        "lowerlayerdown" => State::LowerLayerDown,
+        "testing" => State::Testing,
+        "dormant" => State::Dormant,
+        "up" => State::Up,
+        _ => State::Unknown,
+    }
+}
+
+// name -> VLAN id, from the 8021q module's table, when it's loaded.
+fn vlan_ids() -> HashMap<String, u16> {
+    let config = match std::fs::read_to_string("/proc/net/vlan/config") {
+        Ok(config) => config,
+        Err(_) => return HashMap::new(),
+    };
+    config
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split('|').map(str::trim);
+            let name = fields.next()?;
+            let id = fields.next()?.parse().ok()?;
+            Some((name.to_owned(), id))
+        })
+        .collect()
+}
+
+fn read_link(name: &str, vlans: &HashMap<String, u16>) -> Option<rtnl::link::LinkMessage> {
+    let index: u32 = sys(name, "ifindex")?.parse().ok()?;
+    let mut nlas = vec![rtnl::link::nlas::Nla::IfName(name.to_owned())];
+    if let Some(mtu) = sys(name, "mtu").and_then(|m| m.parse().ok()) {
+        nlas.push(rtnl::link::nlas::Nla::Mtu(mtu));
+    }
+    if let Some(state) = sys(name, "operstate") {
+        nlas.push(rtnl::link::nlas::Nla::OperState(oper_state(&state)));
+    }
+    if let Some(mac) = sys(name, "address").as_deref().and_then(parse_mac) {
+        nlas.push(rtnl::link::nlas::Nla::Address(mac));
+    }
+    // A link without one below it names itself.
+    match sys(name, "iflink").and_then(|i| i.parse().ok()) {
+        Some(link) if link != index && link != 0 => {
+            nlas.push(rtnl::link::nlas::Nla::Link(link));
+        }
+        _ => {}
+    }
+    let master = std::fs::read_link(Path::new(SYS_NET).join(name).join("master"))
+        .ok()
+        .and_then(|m| m.file_name().map(|m| m.to_string_lossy().into_owned()))
+        .and_then(|m| sys(&m, "ifindex")?.parse().ok());
+    if let Some(master) = master {
+        nlas.push(rtnl::link::nlas::Nla::Master(master));
+    }
+    let mut info = vec![];
+    let kind = sys(name, "uevent").and_then(|u| {
+        u.lines()
+            .find_map(|l| l.strip_prefix("DEVTYPE=").map(str::to_owned))
+    });
+    if let Some(kind) = kind {
+        info.push(Info::Kind(InfoKind::Other(kind)));
+    }
+    if let Some(id) = vlans.get(name) {
+        info.push(Info::Data(InfoData::Vlan(vec![InfoVlan::Id(*id)])));
+    }
+    if !info.is_empty() {
+        nlas.push(rtnl::link::nlas::Nla::Info(info));
+    }
+
+    let mut l = rtnl::link::LinkMessage::default();
+    l.header.index = index;
+    l.header.link_layer_type = sys(name, "type").and_then(|t| t.parse().ok()).unwrap_or(0);
+    l.header.flags = sys(name, "flags")
+        .and_then(|f| u32::from_str_radix(f.trim_start_matches("0x"), 16).ok())
+        .unwrap_or(0);
+    l.nlas = nlas;
+    Some(l)
+}
+
+fn read_links() -> Result<Vec<rtnl::link::LinkMessage>> {
+    let vlans = vlan_ids();
+    let mut links: Vec<_> = std::fs::read_dir(SYS_NET)
+        .with_context(|| format!("error listing {}", SYS_NET))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| read_link(&e.file_name().to_string_lossy(), &vlans))
+        .collect();
+    // As a dump would list them.
+    links.sort_by_key(|l| l.header.index);
+    Ok(links)
+}
+
+fn address(
+    index: u32,
+    prefix_len: u8,
+    flags: u8,
+    addr: IpAddr,
+    label: Option<String>,
+) -> rtnl::address::AddressMessage {
+    let (family, bytes) = match addr {
+        IpAddr::V4(a) => (libc::AF_INET, a.octets().to_vec()),
+        IpAddr::V6(a) => (libc::AF_INET6, a.octets().to_vec()),
+    };
+    let mut a = rtnl::address::AddressMessage::default();
+    a.header.family = family as u8;
+    a.header.index = index;
+    a.header.prefix_len = prefix_len;
+    a.header.flags = flags;
+    a.header.scope = crate::filter::default_scope(&addr);
+    a.nlas.push(rtnl::address::nlas::Nla::Address(bytes));
+    if let Some(label) = label {
+        a.nlas.push(rtnl::address::nlas::Nla::Label(label));
+    }
+    a
+}
+
+// One line per address: the address in hex, then the interface index,
+// prefix length, scope and flags, in hex, then its name.
+fn read_v6() -> Result<Vec<rtnl::address::AddressMessage>> {
+    let table = match std::fs::read_to_string("/proc/net/if_inet6") {
+        Ok(table) => table,
+        // IPv6 is disabled.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("error reading /proc/net/if_inet6"),
+    };
+    Ok(table
+        .lines()
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            if fields.len() < 6 {
+                return None;
+            }
+            let addr = u128::from_str_radix(fields[0], 16).ok()?;
+            let index = u32::from_str_radix(fields[1], 16).ok()?;
+            let prefix_len = u8::from_str_radix(fields[2], 16).ok()?;
+            let flags = u8::from_str_radix(fields[4], 16).ok()?;
+            Some(address(
+                index,
+                prefix_len,
+                flags,
+                Ipv6Addr::from(addr).into(),
+                None,
+            ))
+        })
+        .collect())
+}
+
+fn ifreq(name: &[libc::c_char; libc::IFNAMSIZ]) -> libc::ifreq {
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    req.ifr_name = *name;
+    req
+}
+
+fn sockaddr_v4(sa: &libc::sockaddr) -> Option<[u8; 4]> {
+    if sa.sa_family as i32 != libc::AF_INET {
+        return None;
+    }
+    let sin = unsafe { &*(sa as *const libc::sockaddr as *const libc::sockaddr_in) };
+    Some(sin.sin_addr.s_addr.to_ne_bytes())
+}
+
+// SIOCGIFCONF lists an entry per IPv4 address label, named after it
+// (eth0:web for an alias), so unlabelled secondaries are missed.
+fn read_v4(links: &[rtnl::link::LinkMessage]) -> Result<Vec<rtnl::address::AddressMessage>> {
+    let indexes: HashMap<&str, u32> = links
+        .iter()
+        .filter_map(|l| {
+            l.nlas.iter().find_map(|nla| match nla {
+                rtnl::link::nlas::Nla::IfName(name) => Some((name.as_str(), l.header.index)),
+                _ => None,
+            })
+        })
+        .collect();
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("error opening an AF_INET socket");
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut reqs: Vec<libc::ifreq> = (0..1024).map(|_| unsafe { std::mem::zeroed() }).collect();
+    let mut conf = libc::ifconf {
+        ifc_len: (reqs.len() * std::mem::size_of::<libc::ifreq>()) as libc::c_int,
+        ifc_ifcu: libc::__c_anonymous_ifc_ifcu {
+            ifcu_req: reqs.as_mut_ptr(),
+        },
+    };
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::SIOCGIFCONF, &mut conf) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("SIOCGIFCONF failed");
+    }
+    let count = conf.ifc_len as usize / std::mem::size_of::<libc::ifreq>();
+
+    let mut addrs = vec![];
+    for req in reqs.iter().take(count) {
+        let label = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let addr = match sockaddr_v4(unsafe { &req.ifr_ifru.ifru_addr }) {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let name = label.split(':').next().unwrap_or_default();
+        let index = match indexes.get(name) {
+            Some(&index) => index,
+            None => continue,
+        };
+        let mut mask = ifreq(&req.ifr_name);
+        let prefix_len =
+            if unsafe { libc::ioctl(fd.as_raw_fd(), libc::SIOCGIFNETMASK, &mut mask) } == 0 {
+                sockaddr_v4(unsafe { &mask.ifr_ifru.ifru_netmask })
+                    .map(|m| u32::from_be_bytes(m).count_ones() as u8)
+                    .unwrap_or(32)
+            } else {
+                32
+            };
+        addrs.push(address(index, prefix_len, 0, addr.into(), Some(label)));
+    }
+    Ok(addrs)
+}
+
+// An all-zero gateway is a route straight out of the device.
+fn route(family: i32, gateway: Vec<u8>, index: u32, metric: u32) -> rtnl::route::RouteMessage {
+    let mut r = rtnl::route::RouteMessage::default();
+    r.header.address_family = family as u8;
+    r.header.table = RT_TABLE_MAIN;
+    r.header.kind = RTN_UNICAST;
+    r.nlas = vec![
+        rtnl::route::nlas::Nla::Oif(index),
+        rtnl::route::nlas::Nla::Priority(metric),
+    ];
+    if gateway.iter().any(|b| *b != 0) {
+        r.nlas.push(rtnl::route::nlas::Nla::Gateway(gateway));
+    }
+    r
+}
+
+// RTF_REJECT: unreachable, as the kernel's own IPv6 default out of lo is.
+const RTF_REJECT: u32 = 0x0200;
+
+// Default routes. /proc/net/route prints addresses as the hex of the
+// in-memory (network order) u32; ipv6_route prints them in plain hex.
+fn read_routes(
+    family: AddressFamily,
+    links: &[rtnl::link::LinkMessage],
+) -> Result<Vec<rtnl::route::RouteMessage>> {
+    let index = |name: &str| {
+        links.iter().find_map(|l| {
+            l.nlas.iter().find_map(|nla| match nla {
+                rtnl::link::nlas::Nla::IfName(n) if n == name => Some(l.header.index),
+                _ => None,
+            })
+        })
+    };
+    let mut ret = vec![];
+    if family.v6() {
+        if let Ok(table) = std::fs::read_to_string("/proc/net/ipv6_route") {
+            for l in table.lines() {
+                let f: Vec<&str> = l.split_whitespace().collect();
+                if f.len() < 10 || f[1] != "00" || u128::from_str_radix(f[0], 16) != Ok(0) {
+                    continue;
+                }
+                if u32::from_str_radix(f[8], 16).map_or(true, |flags| flags & RTF_REJECT != 0) {
+                    continue;
+                }
+                let gateway = match u128::from_str_radix(f[4], 16) {
+                    Ok(g) => g,
+                    _ => continue,
+                };
+                let (metric, index) = match (u32::from_str_radix(f[5], 16), index(f[9])) {
+                    (Ok(metric), Some(index)) => (metric, index),
+                    _ => continue,
+                };
+                ret.push(route(
+                    libc::AF_INET6,
+                    gateway.to_be_bytes().to_vec(),
+                    index,
+                    metric,
+                ));
+            }
+        }
+    }
+    if family.v4() {
+        let table =
+            std::fs::read_to_string("/proc/net/route").context("error reading /proc/net/route")?;
+        for l in table.lines().skip(1) {
+            let f: Vec<&str> = l.split_whitespace().collect();
+            if f.len() < 8 || f[1] != "00000000" || f[7] != "00000000" {
+                continue;
+            }
+            if u32::from_str_radix(f[3], 16).map_or(true, |flags| flags & RTF_REJECT != 0) {
+                continue;
+            }
+            let gateway = match u32::from_str_radix(f[2], 16) {
+                Ok(g) => g,
+                _ => continue,
+            };
+            let (metric, index) = match (f[6].parse(), index(f[0])) {
+                (Ok(metric), Some(index)) => (metric, index),
+                _ => continue,
+            };
+            ret.push(route(
+                libc::AF_INET,
+                gateway.to_ne_bytes().to_vec(),
+                index,
+                metric,
+            ));
+        }
+    }
+    Ok(ret)
+}
+
+// Rebuilds `state` from scratch out of what the kernel shows outside
+// netlink, as process_ifaces and list_default_routes would from a dump.
+pub fn load(state: &mut AdvertisementState) -> Result<()> {
+    let links = read_links()?;
+    if links.is_empty() {
+        return Err(anyhow!("no interfaces in {}", SYS_NET));
+    }
+    let family = state.filter().family;
+    let mut addrs = vec![];
+    if family.v6() {
+        addrs.extend(read_v6()?);
+    }
+    if family.v4() {
+        addrs.extend(read_v4(&links)?);
+    }
+    let found = read_routes(family, &links)?;
+
+    state.reset();
+    state.add_links(&links)?;
+    for addr in addrs.iter() {
+        if state.tracks(addr.header.index) {
+            state.apply_new_address(addr)?;
+        }
+    }
+    let mut default_routes = vec![];
+    for r in found.iter() {
+        routes::add_route(&mut default_routes, state.interfaces(), r);
+    }
+    state.set_default_routes(default_routes);
+    Ok(())
+}
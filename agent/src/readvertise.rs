@@ -0,0 +1,182 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::Opt;
+
+// When to send the current advertisement again even though nothing changed,
+// for --readvertise-interval-secs. Each wait is the interval, or whatever the
+// server suggested within --readvertise-min-secs and --readvertise-max-secs,
+// give or take --readvertise-jitter-percent, so agents started together
+// don't stay in step.
+pub struct Schedule<R = StdRng> {
+    interval: Duration,
+    jitter: f64,
+    min: Duration,
+    max: Duration,
+    rng: R,
+}
+
+impl Schedule {
+    pub fn new(opt: &Opt) -> Option<Schedule> {
+        let interval = Duration::from_secs(opt.readvertise_interval_secs?);
+        Some(Schedule::with_rng(
+            interval,
+            opt.readvertise_jitter_percent,
+            Duration::from_secs(opt.readvertise_min_secs),
+            Duration::from_secs(opt.readvertise_max_secs),
+            StdRng::from_entropy(),
+        ))
+    }
+}
+
+impl<R: Rng> Schedule<R> {
+    pub fn with_rng(
+        interval: Duration,
+        jitter_percent: u32,
+        min: Duration,
+        max: Duration,
+        rng: R,
+    ) -> Schedule<R> {
+        Schedule {
+            interval,
+            jitter: f64::from(jitter_percent.min(100)) / 100.0,
+            min,
+            max: max.max(min),
+            rng,
+        }
+    }
+
+    // The interval a server's suggestion comes to, 0 being none.
+    fn adopt(&self, suggested_secs: u32) -> Option<Duration> {
+        if suggested_secs == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(suggested_secs.into()).clamp(self.min, self.max))
+    }
+
+    pub fn next(&mut self, suggested_secs: u32) -> Duration {
+        let base = self.adopt(suggested_secs).unwrap_or(self.interval);
+        if self.jitter == 0.0 {
+            return base;
+        }
+        base.mul_f64(1.0 + self.rng.gen_range(-self.jitter..=self.jitter))
+    }
+}
+
+// Never, without a schedule.
+pub async fn until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    use super::Schedule;
+    use crate::Opt;
+
+    fn schedule(jitter_percent: u32, seed: u64) -> Schedule {
+        Schedule::with_rng(
+            Duration::from_secs(600),
+            jitter_percent,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            StdRng::seed_from_u64(seed),
+        )
+    }
+
+    #[test]
+    fn hints_clamped() {
+        let mut s = schedule(0, 1);
+        let secs = |s: &mut Schedule, hint| s.next(hint).as_secs();
+        // No hint: the configured interval.
+        assert_eq!(secs(&mut s, 0), 600);
+        assert_eq!(secs(&mut s, 1200), 1200);
+        assert_eq!(secs(&mut s, 1), 60);
+        assert_eq!(secs(&mut s, 59), 60);
+        assert_eq!(secs(&mut s, 3601), 3600);
+        assert_eq!(secs(&mut s, u32::MAX), 3600);
+        // A hint only lasts as long as the server keeps giving it.
+        assert_eq!(secs(&mut s, 0), 600);
+
+        // Bounds the wrong way round hold everything at the minimum.
+        let mut s = Schedule::with_rng(
+            Duration::from_secs(600),
+            0,
+            Duration::from_secs(300),
+            Duration::from_secs(100),
+            StdRng::seed_from_u64(1),
+        );
+        assert_eq!(secs(&mut s, 50), 300);
+        assert_eq!(secs(&mut s, 5000), 300);
+    }
+
+    #[test]
+    fn jitter_bounded() {
+        let mut s = schedule(20, 7);
+        let waits: Vec<Duration> = (0..1000).map(|_| s.next(0)).collect();
+        assert!(waits
+            .iter()
+            .all(|w| (480.0..=720.0).contains(&w.as_secs_f64())));
+        assert!(waits.iter().any(|w| w.as_secs() < 540));
+        assert!(waits.iter().any(|w| w.as_secs() >= 660));
+        // Around a clamped hint just the same.
+        assert!((0..1000)
+            .map(|_| s.next(1).as_secs_f64())
+            .all(|w| (48.0..=72.0).contains(&w)));
+        // Over 100% would make for negative waits.
+        let mut s = schedule(250, 7);
+        assert!((0..1000).all(|_| s.next(0) <= Duration::from_secs(1200)));
+    }
+
+    // Two agents started the same instant: without jitter they re-advertise
+    // in step for good; with it they drift apart within a few ticks.
+    #[test]
+    fn jitter_decorrelates() {
+        let fire_times = |mut s: Schedule| {
+            let mut at = Duration::default();
+            (0..50)
+                .map(|_| {
+                    at += s.next(0);
+                    at
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fire_times(schedule(0, 1)), fire_times(schedule(0, 2)));
+
+        let (a, b) = (fire_times(schedule(20, 1)), fire_times(schedule(20, 2)));
+        let apart = |i: usize| a[i].abs_diff(b[i]);
+        let together = (0..50)
+            .filter(|&i| apart(i) < Duration::from_secs(5))
+            .count();
+        assert!(together <= 5, "{} of 50 ticks within 5s", together);
+        assert!(apart(49) > Duration::from_secs(30), "{:?}", apart(49));
+    }
+
+    #[test]
+    fn from_flags() {
+        let opt = |args: &[&str]| {
+            Opt::from_iter_safe(std::iter::once("agent").chain(args.iter().copied())).unwrap()
+        };
+        assert!(Schedule::new(&opt(&[])).is_none());
+        let mut s = Schedule::new(&opt(&[
+            "--readvertise-interval-secs",
+            "900",
+            "--readvertise-jitter-percent",
+            "0",
+            "--readvertise-max-secs",
+            "1000",
+        ]))
+        .unwrap();
+        assert_eq!(s.next(0), Duration::from_secs(900));
+        assert_eq!(s.next(5000), Duration::from_secs(1000));
+    }
+}
@@ -0,0 +1,271 @@
+use rtnetlink::packet::rtnl::{
+    self,
+    constants::{RTA_GATEWAY, RTN_UNICAST, RT_TABLE_MAIN},
+};
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use proto::strapper;
+
+// The default routes `r` makes: one, or with ECMP (RTA_MULTIPATH), one per
+// nexthop. A route straight out of a device (a point-to-point link, a
+// tunnel) has no gateway, and is kept with an empty one.
+pub fn default_routes(r: &rtnl::route::RouteMessage) -> Vec<strapper::Route> {
+    // Unreachable and blackhole defaults don't lead anywhere.
+    if r.header.destination_prefix_length != 0 || r.header.kind != RTN_UNICAST {
+        return vec![];
+    }
+
+    let mut table = r.header.table as u32;
+    let mut gateway = None;
+    let mut metric = 0;
+    let mut index = None;
+    let mut hops = vec![];
+    for nla in r.nlas.iter() {
+        match nla {
+            rtnl::route::nlas::Nla::Table(t) => table = *t,
+            rtnl::route::nlas::Nla::Priority(p) => metric = *p,
+            rtnl::route::nlas::Nla::Oif(i) => index = Some(*i),
+            rtnl::route::nlas::Nla::Gateway(g) => gateway = parse_addr(g),
+            rtnl::route::nlas::Nla::MultiPath(m) => hops = nexthops(m),
+            _ => {}
+        }
+    }
+
+    if table != RT_TABLE_MAIN as u32 {
+        return vec![];
+    }
+    if hops.is_empty() {
+        hops.extend(index.map(|i| (i, gateway)));
+    }
+    hops.into_iter()
+        .map(|(index, gateway)| strapper::Route {
+            gateway: gateway.map(|g| g.to_string()).unwrap_or_default(),
+            metric,
+            index,
+        })
+        .collect()
+}
+
+// struct rtnexthop: length, flags, hops, then the interface index.
+const RTNH_LEN: usize = 8;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// The (interface, gateway) of each struct rtnexthop in an RTA_MULTIPATH,
+// each followed by its own attributes. Stops at the first that doesn't fit.
+fn nexthops(mut b: &[u8]) -> Vec<(u32, Option<IpAddr>)> {
+    let mut hops = vec![];
+    while b.len() >= RTNH_LEN {
+        let len = u16::from_ne_bytes([b[0], b[1]]) as usize;
+        if len < RTNH_LEN || len > b.len() {
+            break;
+        }
+        let index = u32::from_ne_bytes([b[4], b[5], b[6], b[7]]);
+        let mut gateway = None;
+        let mut attrs = &b[RTNH_LEN..len];
+        while attrs.len() >= 4 {
+            let attr_len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+            if attr_len < 4 || attr_len > attrs.len() {
+                break;
+            }
+            if u16::from_ne_bytes([attrs[2], attrs[3]]) == RTA_GATEWAY {
+                gateway = parse_addr(&attrs[4..attr_len]);
+            }
+            attrs = &attrs[align(attr_len).min(attrs.len())..];
+        }
+        hops.push((index, gateway));
+        b = &b[align(len).min(b.len())..];
+    }
+    hops
+}
+
+fn parse_addr(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => {
+            let a: [u8; 4] = b.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(a)))
+        }
+        16 => {
+            let a: [u8; 16] = b.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(a)))
+        }
+        _ => None,
+    }
+}
+
+fn sort_key(r: &strapper::Route) -> (u32, Option<IpAddr>, u32) {
+    (r.metric, r.gateway.parse().ok(), r.index)
+}
+
+// Routes out of interfaces we don't advertise (excluded, unknown) are ignored
+// the same way their addresses are.
+pub fn add_route(
+    routes: &mut Vec<strapper::Route>,
+    ifaces: &[strapper::Interface],
+    r: &rtnl::route::RouteMessage,
+) -> bool {
+    let mut changed = false;
+    for route in default_routes(r) {
+        if ifaces.iter().all(|i| i.index != route.index) || routes.contains(&route) {
+            continue;
+        }
+        let pos = routes.partition_point(|e| sort_key(e) < sort_key(&route));
+        routes.insert(pos, route);
+        changed = true;
+    }
+    changed
+}
+
+pub fn del_route(routes: &mut Vec<strapper::Route>, r: &rtnl::route::RouteMessage) -> bool {
+    let gone = default_routes(r);
+    let before = routes.len();
+    routes.retain(|e| !gone.contains(e));
+    routes.len() != before
+}
+
+pub fn retain_for_ifaces(
+    routes: &mut Vec<strapper::Route>,
+    ifaces: &[strapper::Interface],
+) -> bool {
+    let before = routes.len();
+    routes.retain(|r| ifaces.iter().any(|i| i.index == r.index));
+    routes.len() != before
+}
+
+#[cfg(test)]
+mod tests {
+    use rtnetlink::packet::rtnl::{
+        self,
+        constants::{RTA_GATEWAY, RTN_UNICAST, RTN_UNREACHABLE, RT_TABLE_MAIN},
+    };
+
+    use super::{add_route, default_routes, del_route};
+    use proto::strapper;
+
+    fn route(nlas: Vec<rtnl::route::nlas::Nla>) -> rtnl::route::RouteMessage {
+        let mut r = rtnl::route::RouteMessage::default();
+        r.header.table = RT_TABLE_MAIN;
+        r.header.kind = RTN_UNICAST;
+        r.nlas = nlas;
+        r
+    }
+
+    // As the kernel lays out RTA_MULTIPATH.
+    fn multipath(hops: &[(u32, Option<[u8; 4]>)]) -> rtnl::route::nlas::Nla {
+        let mut b = vec![];
+        for (index, gateway) in hops {
+            let len: u16 = if gateway.is_some() { 16 } else { 8 };
+            b.extend(&len.to_ne_bytes());
+            b.extend(&[0, 0]);
+            b.extend(&index.to_ne_bytes());
+            if let Some(g) = gateway {
+                b.extend(&8u16.to_ne_bytes());
+                b.extend(&RTA_GATEWAY.to_ne_bytes());
+                b.extend(g);
+            }
+        }
+        rtnl::route::nlas::Nla::MultiPath(b)
+    }
+
+    fn found(gateway: &str, metric: u32, index: u32) -> strapper::Route {
+        strapper::Route {
+            gateway: gateway.to_owned(),
+            metric,
+            index,
+        }
+    }
+
+    #[test]
+    fn through_a_gateway() {
+        let r = route(vec![
+            rtnl::route::nlas::Nla::Gateway(vec![10, 0, 0, 1]),
+            rtnl::route::nlas::Nla::Oif(2),
+            rtnl::route::nlas::Nla::Priority(100),
+        ]);
+        assert_eq!(default_routes(&r), [found("10.0.0.1", 100, 2)]);
+    }
+
+    #[test]
+    fn device_only() {
+        let r = route(vec![rtnl::route::nlas::Nla::Oif(5)]);
+        assert_eq!(default_routes(&r), [found("", 0, 5)]);
+    }
+
+    #[test]
+    fn ecmp() {
+        let r = route(vec![
+            rtnl::route::nlas::Nla::Priority(20),
+            multipath(&[
+                (2, Some([10, 0, 0, 1])),
+                (3, Some([10, 0, 1, 1])),
+                (4, None),
+            ]),
+        ]);
+        assert_eq!(
+            default_routes(&r),
+            [
+                found("10.0.0.1", 20, 2),
+                found("10.0.1.1", 20, 3),
+                found("", 20, 4)
+            ]
+        );
+
+        // Only the nexthops that fit are read.
+        let mut truncated = r.clone();
+        if let rtnl::route::nlas::Nla::MultiPath(b) = &mut truncated.nlas[1] {
+            b.truncate(20);
+        }
+        assert_eq!(default_routes(&truncated), [found("10.0.0.1", 20, 2)]);
+    }
+
+    #[test]
+    fn not_default_routes() {
+        let mut prefix = route(vec![rtnl::route::nlas::Nla::Oif(2)]);
+        prefix.header.destination_prefix_length = 8;
+        let mut unreachable = route(vec![rtnl::route::nlas::Nla::Oif(1)]);
+        unreachable.header.kind = RTN_UNREACHABLE;
+        let other_table = route(vec![
+            rtnl::route::nlas::Nla::Oif(2),
+            rtnl::route::nlas::Nla::Table(100),
+        ]);
+        for r in [prefix, unreachable, other_table].iter() {
+            assert!(default_routes(r).is_empty());
+        }
+    }
+
+    #[test]
+    fn add_and_del() {
+        let ifaces: Vec<strapper::Interface> = [2, 3]
+            .iter()
+            .map(|&index| strapper::Interface {
+                index,
+                ..Default::default()
+            })
+            .collect();
+        let r = route(vec![
+            rtnl::route::nlas::Nla::Priority(20),
+            // 9 isn't advertised.
+            multipath(&[
+                (3, Some([10, 0, 1, 1])),
+                (9, None),
+                (2, Some([10, 0, 0, 1])),
+            ]),
+        ]);
+        let mut routes = vec![found("", 10, 2)];
+        assert!(add_route(&mut routes, &ifaces, &r));
+        assert!(!add_route(&mut routes, &ifaces, &r));
+        assert_eq!(
+            routes,
+            [
+                found("", 10, 2),
+                found("10.0.0.1", 20, 2),
+                found("10.0.1.1", 20, 3)
+            ]
+        );
+        assert!(del_route(&mut routes, &r));
+        assert_eq!(routes, [found("", 10, 2)]);
+    }
+}
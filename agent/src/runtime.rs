@@ -0,0 +1,115 @@
+use anyhow::{anyhow, ensure, Result};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::str::FromStr;
+use tokio::runtime::{Builder, Runtime};
+
+use client::RetryPolicy;
+
+// The current-thread runtime keeps the agent to one thread; the
+// multi-threaded one keeps a slow RPC from holding up netlink events.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flavor {
+    Current,
+    Multi,
+}
+
+impl FromStr for Flavor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current" => Ok(Flavor::Current),
+            "multi" => Ok(Flavor::Multi),
+            _ => Err(anyhow!(
+                "unknown runtime '{}' (expected current or multi)",
+                s
+            )),
+        }
+    }
+}
+
+// `workers` only applies to the multi-threaded runtime; tokio picks one per
+// core without it.
+pub fn build(flavor: Flavor, workers: Option<usize>) -> Result<Runtime> {
+    let mut builder = match flavor {
+        Flavor::Current => {
+            ensure!(workers.is_none(), "--worker-threads needs --runtime multi");
+            Builder::new_current_thread()
+        }
+        Flavor::Multi => {
+            let mut builder = Builder::new_multi_thread();
+            if let Some(workers) = workers {
+                ensure!(workers > 0, "--worker-threads must be at least 1");
+                builder.worker_threads(workers);
+            }
+            builder
+        }
+    };
+    Ok(builder.enable_all().build()?)
+}
+
+// tokio's clock rather than the system's, so the time a backoff has spent is
+// the time its sleeps took: the same thing in the agent, but under a paused
+// runtime only tokio's clock moves.
+pub struct Clock;
+
+impl client::backoff::Clock for Clock {
+    fn now(&self) -> std::time::Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+pub type Backoff = client::backoff::Backoff<StdRng, Clock>;
+
+pub fn backoff(policy: RetryPolicy) -> Backoff {
+    Backoff::with_rng_and_clock(policy, StdRng::from_entropy(), Clock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, Flavor};
+
+    // Either runtime can be down to one thread polling everything, so a
+    // blocking sleep anywhere in the agent stalls netlink and RPCs alike.
+    // Waits go through tokio::time; anything that has to block goes through
+    // spawn_blocking.
+    #[test]
+    fn no_blocking_sleeps() {
+        let needle = concat!("thread", "::sleep");
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut offenders = vec![];
+        let mut dirs = vec![src];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|e| e == "rs") {
+                    let source = std::fs::read_to_string(&path).unwrap();
+                    offenders.extend(
+                        source
+                            .lines()
+                            .enumerate()
+                            .filter(|(_, l)| l.contains(needle))
+                            .map(|(n, _)| format!("{}:{}", path.display(), n + 1)),
+                    );
+                }
+            }
+        }
+        assert!(offenders.is_empty(), "{}", offenders.join(", "));
+    }
+
+    #[test]
+    fn flavors() {
+        assert_eq!("current".parse::<Flavor>().unwrap(), Flavor::Current);
+        assert_eq!("multi".parse::<Flavor>().unwrap(), Flavor::Multi);
+        assert!("threaded".parse::<Flavor>().is_err());
+
+        assert!(build(Flavor::Current, None).is_ok());
+        assert!(build(Flavor::Current, Some(2)).is_err());
+        assert!(build(Flavor::Multi, Some(0)).is_err());
+        let rt = build(Flavor::Multi, Some(2)).unwrap();
+        assert_eq!(rt.metrics().num_workers(), 2);
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::anyhow;
+use rtnetlink::packet::rtnl::constants::{IFA_F_MANAGETEMPADDR, IFA_F_PERMANENT};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use proto::strapper;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelectionPolicy {
+    All,
+    First,
+    PreferStatic,
+}
+
+impl FromStr for SelectionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(SelectionPolicy::All),
+            "first" => Ok(SelectionPolicy::First),
+            "prefer-static" => Ok(SelectionPolicy::PreferStatic),
+            _ => Err(anyhow!(
+                "unknown address policy '{}' (expected all, first or prefer-static)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub addr: IpAddr,
+    pub flags: u32,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub label: String,
+}
+
+impl Candidate {
+    fn is_static(&self) -> bool {
+        self.flags & IFA_F_PERMANENT != 0 && self.flags & IFA_F_MANAGETEMPADDR == 0
+    }
+
+    pub fn origin(&self) -> strapper::AddressOrigin {
+        if self.is_static() {
+            strapper::AddressOrigin::Static
+        } else {
+            strapper::AddressOrigin::Dynamic
+        }
+    }
+}
+
+// Every acceptable address seen per interface index, kept sorted by address,
+// whether or not the policy currently advertises it, so a deleted selection
+// can fall back to the next candidate.
+#[derive(Default)]
+pub struct Candidates(HashMap<u32, Vec<Candidate>>);
+
+impl Candidates {
+    pub fn for_index(&mut self, index: u32) -> &mut Vec<Candidate> {
+        self.0.entry(index).or_default()
+    }
+
+    pub fn take(&mut self, index: u32) -> Vec<Candidate> {
+        self.0.remove(&index).unwrap_or_default()
+    }
+}
+
+impl SelectionPolicy {
+    pub fn select<'a>(&self, candidates: &'a [Candidate]) -> Vec<&'a Candidate> {
+        match self {
+            SelectionPolicy::All => candidates.iter().collect(),
+            SelectionPolicy::First => {
+                per_family(candidates, |c| c.iter().copied().min_by_key(|c| c.addr))
+            }
+            SelectionPolicy::PreferStatic => per_family(candidates, |c| {
+                c.iter()
+                    .copied()
+                    .filter(|c| c.is_static())
+                    .min_by_key(|c| c.addr)
+                    .or_else(|| c.iter().copied().min_by_key(|c| c.addr))
+            }),
+        }
+    }
+}
+
+fn per_family<'a, F>(candidates: &'a [Candidate], pick: F) -> Vec<&'a Candidate>
+where
+    F: Fn(&[&'a Candidate]) -> Option<&'a Candidate>,
+{
+    let (v6, v4): (Vec<&Candidate>, Vec<&Candidate>) =
+        candidates.iter().partition(|c| c.addr.is_ipv6());
+    let mut picked: Vec<&Candidate> = pick(&v4).into_iter().chain(pick(&v6)).collect();
+    picked.sort_by_key(|c| c.addr);
+    picked
+}
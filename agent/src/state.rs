@@ -0,0 +1,1582 @@
+use anyhow::{anyhow, Result};
+use rtnetlink::packet::nlas::Nla;
+use rtnetlink::packet::rtnl;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use proto::strapper;
+
+use crate::filter::{AddressPolicy, LinkFilter};
+use crate::output;
+use crate::routes;
+use crate::select::{Candidate, Candidates, SelectionPolicy};
+
+// What we currently believe about the node, built up from netlink link,
+// address and route messages. Nothing here talks to the kernel; whoever owns
+// the socket feeds messages in and fetches whatever a change asks for.
+pub struct AdvertisementState {
+    links: LinkFilter,
+    filter: AddressPolicy,
+    policy: SelectionPolicy,
+    candidates: Candidates,
+    advertisement: strapper::NodeAdvertisement,
+    // Where each interface sits in advertisement.interfaces, by index, so
+    // address messages find theirs (or find they're for one we don't track)
+    // without a search. Rebuilt whenever links come, go or move.
+    positions: HashMap<u32, usize>,
+    // Whether the kernel has refreshed or deprecated an address since
+    // take_refreshed last asked.
+    refreshed: bool,
+}
+
+impl AdvertisementState {
+    // `base` carries the node-wide fields (hostname, version, start time);
+    // its interfaces and routes are filled in from messages.
+    pub fn new(
+        links: LinkFilter,
+        filter: AddressPolicy,
+        policy: SelectionPolicy,
+        base: strapper::NodeAdvertisement,
+    ) -> AdvertisementState {
+        AdvertisementState {
+            links,
+            filter,
+            policy,
+            candidates: Candidates::default(),
+            advertisement: base,
+            positions: HashMap::new(),
+            refreshed: false,
+        }
+    }
+
+    pub fn filter(&self) -> &AddressPolicy {
+        &self.filter
+    }
+
+    pub fn advertisement(&self) -> &strapper::NodeAdvertisement {
+        &self.advertisement
+    }
+
+    pub fn interfaces(&self) -> &[strapper::Interface] {
+        &self.advertisement.interfaces
+    }
+
+    pub fn address_count(&self) -> usize {
+        self.advertisement
+            .interfaces
+            .iter()
+            .map(|i| i.ipaddr.len())
+            .sum()
+    }
+
+    // Forgets every interface, address and route, keeping the node-wide
+    // fields, ahead of dumping everything again.
+    pub fn reset(&mut self) {
+        self.advertisement.interfaces.clear();
+        self.advertisement.default_routes.clear();
+        self.candidates = Candidates::default();
+        self.positions.clear();
+        self.refreshed = false;
+    }
+
+    // Marks a new state, seen now.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.advertisement.sequence = sequence;
+        self.advertisement.observed_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+    }
+
+    pub fn set_default_routes(&mut self, routes: Vec<strapper::Route>) {
+        self.advertisement.default_routes = routes;
+    }
+
+    // A link from the initial dump. Returns whether it was admitted.
+    pub fn add_link(&mut self, l: &rtnl::link::LinkMessage) -> Result<bool> {
+        let interfaces = &mut self.advertisement.interfaces;
+        let added = add_iface_if_not_exists_and_not_excluded(interfaces, &self.links, l)?;
+        if added {
+            // Dumps come in index order, so the new one is nearly always last.
+            let last = interfaces.len() - 1;
+            if interfaces[last].index == l.header.index {
+                self.positions.insert(l.header.index, last);
+            } else {
+                self.reindex();
+            }
+        }
+        Ok(added)
+    }
+
+    // The links of a dump. One that can't be made sense of is skipped with a
+    // warning rather than keeping the rest from being advertised; only a
+    // dump with nothing usable in it fails.
+    pub fn add_links(&mut self, links: &[rtnl::link::LinkMessage]) -> Result<()> {
+        let mut failed = 0;
+        for l in links {
+            if let Err(e) = self.add_link(l) {
+                failed += 1;
+                output::warning(format_args!(
+                    "skipping interface index {}: {}",
+                    l.header.index, e
+                ));
+            }
+        }
+        if failed > 0 && failed == links.len() {
+            return Err(anyhow!("all {} interfaces failed to process", failed));
+        }
+        Ok(())
+    }
+
+    fn reindex(&mut self) {
+        self.positions = self
+            .advertisement
+            .interfaces
+            .iter()
+            .enumerate()
+            .map(|(pos, i)| (i.index, pos))
+            .collect();
+    }
+
+    // Whether interface `index` is advertised, i.e. whether its addresses
+    // are worth looking at.
+    pub fn tracks(&self, index: u32) -> bool {
+        self.positions.contains_key(&index)
+    }
+
+    // A RTM_NEWLINK event. An `Added` interface has no addresses yet; the
+    // caller is expected to dump them, and the default routes, for it.
+    pub fn apply_link(&mut self, l: &rtnl::link::LinkMessage) -> Result<LinkUpdate> {
+        let advertisement = &mut self.advertisement;
+        let update = update_link(
+            &mut advertisement.interfaces,
+            &mut self.candidates,
+            &self.links,
+            l,
+        )?;
+        if let LinkUpdate::Changed = update {
+            routes::retain_for_ifaces(&mut advertisement.default_routes, &advertisement.interfaces);
+        }
+        if !matches!(update, LinkUpdate::Unchanged) {
+            self.reindex();
+        }
+        Ok(update)
+    }
+
+    // A RTM_DELLINK event. Returns whether the interface was advertised.
+    pub fn apply_del_link(&mut self, l: &rtnl::link::LinkMessage) -> bool {
+        let advertisement = &mut self.advertisement;
+        let pos = match self.positions.get(&l.header.index) {
+            Some(&pos) => pos,
+            None => return false,
+        };
+        let old = advertisement.interfaces.remove(pos);
+        self.candidates.take(old.index);
+        output::info(format_args!("interface {} removed", old.name));
+        routes::retain_for_ifaces(&mut advertisement.default_routes, &advertisement.interfaces);
+        self.reindex();
+        true
+    }
+
+    pub fn apply_new_address(&mut self, addr: &rtnl::address::AddressMessage) -> Result<bool> {
+        let before = self
+            .interface(addr.header.index)
+            .map(|i| i.address_info.clone())
+            .unwrap_or_default();
+        let changed = add_addr(
+            &mut self.advertisement.interfaces,
+            &mut self.candidates,
+            self.policy,
+            &self.filter,
+            &self.positions,
+            addr,
+        )?;
+        if changed {
+            if let Some(iface) = self.interface(addr.header.index) {
+                self.refreshed |= refreshes(&before, &iface.address_info);
+            }
+        }
+        Ok(changed)
+    }
+
+    // Whether an address's lifetimes were refreshed, or it was deprecated,
+    // since the last call. The advertisement looks the same either way, but
+    // the server's TTLs come from the lifetimes.
+    pub fn take_refreshed(&mut self) -> bool {
+        std::mem::take(&mut self.refreshed)
+    }
+
+    pub fn apply_del_address(&mut self, addr: &rtnl::address::AddressMessage) -> Result<bool> {
+        del_addr(
+            &mut self.advertisement.interfaces,
+            &mut self.candidates,
+            self.policy,
+            &self.filter,
+            &self.positions,
+            addr,
+        )
+    }
+
+    pub fn apply_new_route(&mut self, route: &rtnl::route::RouteMessage) -> bool {
+        let advertisement = &mut self.advertisement;
+        routes::add_route(
+            &mut advertisement.default_routes,
+            &advertisement.interfaces,
+            route,
+        )
+    }
+
+    pub fn apply_del_route(&mut self, route: &rtnl::route::RouteMessage) -> bool {
+        routes::del_route(&mut self.advertisement.default_routes, route)
+    }
+
+    // Forgets everything known about the addresses on interface `index`
+    // ahead of a fresh dump, returning what was advertised, or None if the
+    // interface isn't tracked.
+    fn clear_addresses(&mut self, index: u32) -> Option<Vec<String>> {
+        let pos = *self.positions.get(&index)?;
+        let iface = &mut self.advertisement.interfaces[pos];
+        let before = std::mem::take(&mut iface.ipaddr);
+        iface.address_info.clear();
+        self.candidates.take(index);
+        Some(before)
+    }
+
+    // Rebuilds an interface's addresses from `dump`, a fresh dump of them,
+    // whatever events came before; returns what was advertised, as
+    // clear_addresses does.
+    pub fn replace_addresses(
+        &mut self,
+        index: u32,
+        dump: &[rtnl::address::AddressMessage],
+    ) -> Result<Option<Vec<String>>> {
+        let before = match self.clear_addresses(index) {
+            Some(before) => before,
+            None => return Ok(None),
+        };
+        for addr in dump.iter().filter(|a| a.header.index == index) {
+            self.apply_new_address(addr)?;
+        }
+        Ok(Some(before))
+    }
+
+    pub fn interface(&self, index: u32) -> Option<&strapper::Interface> {
+        let pos = *self.positions.get(&index)?;
+        self.advertisement.interfaces.get(pos)
+    }
+
+    // Sets each wireguard interface's public key to the one in `keys`, by
+    // index, clearing those it has none for. Returns whether any changed.
+    pub fn set_wireguard_keys(&mut self, keys: &HashMap<u32, String>) -> bool {
+        let mut changed = false;
+        for iface in self.advertisement.interfaces.iter_mut() {
+            let key = match iface.kind.as_str() {
+                "wireguard" => keys.get(&iface.index).cloned(),
+                _ => None,
+            };
+            if iface.wireguard_public_key != key {
+                iface.wireguard_public_key = key;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+pub enum LinkUpdate {
+    Unchanged,
+    Changed,
+    Added(u32),
+}
+
+fn update_link(
+    v: &mut Vec<strapper::Interface>,
+    candidates: &mut Candidates,
+    links: &LinkFilter,
+    l: &rtnl::link::LinkMessage,
+) -> Result<LinkUpdate> {
+    let name = l.nlas.iter().find_map(|nla| match nla {
+        rtnl::link::nlas::Nla::IfName(n) if !n.is_empty() => Some(n),
+        _ => None,
+    });
+    let name = match name {
+        Some(n) => n,
+        None => return Ok(LinkUpdate::Unchanged),
+    };
+    let excluded = links.excludes_name(name);
+
+    if links.excludes_slave(l) {
+        return Ok(match v.iter().position(|i| i.index == l.header.index) {
+            Some(pos) => {
+                let old = v.remove(pos);
+                candidates.take(old.index);
+                output::info(format_args!(
+                    "interface {} was enslaved, dropping it",
+                    old.name
+                ));
+                LinkUpdate::Changed
+            }
+            None => LinkUpdate::Unchanged,
+        });
+    }
+
+    // The index identifies a link, and failing that its hardware MAC: some
+    // drivers re-index a NIC on rename, and the new index can show up before
+    // the RTM_DELLINK for the old one (which may never come if the socket
+    // overran). Only a physical NIC's permanent address is its own, though;
+    // VLANs, macvlans, bonds and bridges borrow theirs, so they never match.
+    let pos = v
+        .iter()
+        .position(|i| i.index == l.header.index)
+        .or_else(|| {
+            let mac = hardware_mac(l)?;
+            v.iter()
+                .position(|i| i.mac == mac && i.kind.is_empty() && i.parent_index.is_none())
+        });
+
+    let pos = match pos {
+        Some(p) => p,
+        None if excluded => return Ok(LinkUpdate::Unchanged),
+        None => {
+            return Ok(if add_iface_if_not_exists_and_not_excluded(v, links, l)? {
+                LinkUpdate::Added(l.header.index)
+            } else {
+                LinkUpdate::Unchanged
+            })
+        }
+    };
+
+    if excluded {
+        let old = v.remove(pos);
+        candidates.take(old.index);
+        output::info(format_args!(
+            "interface {} renamed to excluded {}, dropping it",
+            old.name, name
+        ));
+        return Ok(LinkUpdate::Changed);
+    }
+
+    let iface = &mut v[pos];
+    let mut changed = false;
+    if iface.index != l.header.index {
+        output::info(format_args!(
+            "interface {} moved from index {} to {}",
+            iface.name, iface.index, l.header.index
+        ));
+        let c = candidates.take(iface.index);
+        *candidates.for_index(l.header.index) = c;
+        iface.index = l.header.index;
+        changed = true;
+    }
+    if &iface.name != name {
+        output::info(format_args!("interface {} renamed to {}", iface.name, name));
+        iface.name = name.clone();
+        changed = true;
+    }
+    let attrs = link_attrs(l);
+    if (iface.mtu, iface.oper_state, &iface.kind) != (attrs.mtu, attrs.oper_state, &attrs.kind)
+        || (iface.vlan_id, iface.parent_index) != (attrs.vlan_id, attrs.parent_index)
+    {
+        iface.mtu = attrs.mtu;
+        iface.oper_state = attrs.oper_state;
+        iface.kind = attrs.kind;
+        iface.vlan_id = attrs.vlan_id;
+        iface.parent_index = attrs.parent_index;
+        changed = true;
+    }
+
+    v.sort_by_key(|i| i.index);
+
+    Ok(if changed {
+        LinkUpdate::Changed
+    } else {
+        LinkUpdate::Unchanged
+    })
+}
+
+fn add_addr(
+    v: &mut [strapper::Interface],
+    candidates: &mut Candidates,
+    policy: SelectionPolicy,
+    filter: &AddressPolicy,
+    positions: &HashMap<u32, usize>,
+    addr: &rtnl::address::AddressMessage,
+) -> Result<bool> {
+    process_addr_message(
+        v,
+        candidates,
+        policy,
+        filter,
+        positions,
+        addr,
+        |c, a| match c.binary_search_by(|ea| (ea.addr, &ea.label).cmp(&(a.addr, &a.label))) {
+            Ok(pos) => c[pos] = a,
+            Err(pos) => c.insert(pos, a),
+        },
+    )
+}
+
+fn del_addr(
+    v: &mut [strapper::Interface],
+    candidates: &mut Candidates,
+    policy: SelectionPolicy,
+    filter: &AddressPolicy,
+    positions: &HashMap<u32, usize>,
+    addr: &rtnl::address::AddressMessage,
+) -> Result<bool> {
+    // Matching on the label too keeps deleting a primary from taking an
+    // alias of the same address with it.
+    process_addr_message(v, candidates, policy, filter, positions, addr, |c, a| {
+        let before = c.len();
+        c.retain(|ea| (ea.addr, &ea.label) != (a.addr, &a.label));
+        if c.len() == before {
+            output::info(format_args!(
+                "ignoring delete of unknown address {}",
+                a.addr
+            ));
+        }
+    })
+}
+
+fn addr_flags(addr: &rtnl::address::AddressMessage) -> u32 {
+    addr.nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rtnl::address::nlas::Nla::Flags(f) => Some(*f),
+            _ => None,
+        })
+        .unwrap_or(addr.header.flags as u32)
+}
+
+// IPv4 alias labels (eth0:web). The kernel labels unaliased addresses with
+// the interface name, and those count as unlabelled.
+fn addr_label(addr: &rtnl::address::AddressMessage, iface: &str) -> String {
+    addr.nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rtnl::address::nlas::Nla::Label(l) if l != iface => Some(l.clone()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+// Lifetimes in seconds from IFA_CACHEINFO; addresses without one (IPv4 on
+// older kernels) are treated as never expiring.
+fn addr_lifetimes(addr: &rtnl::address::AddressMessage) -> (u32, u32) {
+    addr.nlas
+        .iter()
+        .find_map(|nla| match nla {
+            rtnl::address::nlas::Nla::CacheInfo(c) => Some((
+                u32::from_ne_bytes(c.get(0..4)?.try_into().ok()?),
+                u32::from_ne_bytes(c.get(4..8)?.try_into().ok()?),
+            )),
+            _ => None,
+        })
+        .unwrap_or((u32::MAX, u32::MAX))
+}
+
+fn process_addr_message<F>(
+    v: &mut [strapper::Interface],
+    candidates: &mut Candidates,
+    policy: SelectionPolicy,
+    filter: &AddressPolicy,
+    positions: &HashMap<u32, usize>,
+    addr: &rtnl::address::AddressMessage,
+    f: F,
+) -> Result<bool>
+where
+    F: Fn(&mut Vec<Candidate>, Candidate),
+{
+    // Before anything else, since most addresses on a box full of excluded
+    // container veths are for interfaces we don't track.
+    let iface = match positions
+        .get(&addr.header.index)
+        .and_then(|&pos| v.get_mut(pos))
+    {
+        Some(iface) => iface,
+        None => return Ok(false),
+    };
+
+    let flags = addr_flags(addr);
+    let (preferred_lifetime, valid_lifetime) = addr_lifetimes(addr);
+    let label = addr_label(addr, &iface.name);
+    let scope = addr.header.scope;
+    let c = candidates.for_index(iface.index);
+
+    for nla in addr.nlas.iter() {
+        if let rtnl::address::nlas::Nla::Address(addr) = nla {
+            let ip = if let Ok(a) = <[u8; 16]>::try_from(addr.as_slice()) {
+                IpAddr::V6(Ipv6Addr::from(a))
+            } else if let Ok(a) = <[u8; 4]>::try_from(addr.as_slice()) {
+                IpAddr::V4(Ipv4Addr::from(a))
+            } else {
+                output::info(format_args!(
+                    "skipping address of unrecognized length {} on interface index {}",
+                    addr.len(),
+                    iface.index
+                ));
+                continue;
+            };
+
+            if !filter.accepts(scope, &ip, &label) {
+                continue;
+            }
+
+            f(
+                c,
+                Candidate {
+                    addr: ip,
+                    flags,
+                    preferred_lifetime,
+                    valid_lifetime,
+                    label: label.clone(),
+                },
+            );
+        }
+    }
+
+    let mut selected = policy.select(c);
+    // The same address under two labels is still one address.
+    selected.dedup_by_key(|c| c.addr);
+    // Lifetimes only change here when the kernel sends them, which it does
+    // when it refreshes or deprecates an address, so a change in them counts
+    // as much as one in the selected addresses: the server's TTLs come from
+    // them. See refreshes for which get advertised.
+    let address_info: Vec<strapper::AddressInfo> = selected
+        .iter()
+        .map(|c| strapper::AddressInfo {
+            address: c.addr.to_string(),
+            preferred_lifetime: c.preferred_lifetime,
+            valid_lifetime: c.valid_lifetime,
+            label: c.label.clone(),
+            origin: c.origin() as i32,
+            vip: filter.is_vip(&c.addr),
+        })
+        .collect();
+    let selected: Vec<String> = selected.iter().map(|c| c.addr.to_string()).collect();
+    if selected == iface.ipaddr && address_info == iface.address_info {
+        return Ok(false);
+    }
+    iface.address_info = address_info;
+    iface.ipaddr = selected;
+    Ok(true)
+}
+
+// Whether `after` has lifetimes worth advertising over `before`'s: one the
+// kernel refreshed (they only go up on a refresh), or an address it
+// deprecated or brought back. Ones that merely ran down are left for the
+// next advertisement to carry.
+fn refreshes(before: &[strapper::AddressInfo], after: &[strapper::AddressInfo]) -> bool {
+    after
+        .iter()
+        .any(|a| match before.iter().find(|b| b.address == a.address) {
+            Some(b) => {
+                a.preferred_lifetime > b.preferred_lifetime
+                    || a.valid_lifetime > b.valid_lifetime
+                    || (a.preferred_lifetime == 0) != (b.preferred_lifetime == 0)
+            }
+            None => false,
+        })
+}
+
+// Only a 6-byte link address counts as a MAC, that being all an
+// advertisement can carry. Others, such as InfiniBand's 20 bytes, EUI-64s
+// and the IP endpoints tunnels report, leave the link without one.
+const MAC_LEN: usize = 6;
+
+fn link_mac(l: &rtnl::link::LinkMessage) -> Option<String> {
+    let mut addr = None;
+    let mut perm_addr = None;
+    for nla in l.nlas.iter() {
+        match nla {
+            rtnl::link::nlas::Nla::Address(a) if a.len() == MAC_LEN => addr = Some(a),
+            rtnl::link::nlas::Nla::PermAddress(a) if a.len() == MAC_LEN => perm_addr = Some(a),
+            _ => {}
+        }
+    }
+
+    // Bond/bridge members and MAC-randomizing wifi cards report a borrowed or
+    // ephemeral MAC in Address, so prefer the hardware one when we have it.
+    perm_addr
+        .or(addr)
+        .map(|a| proto::node::format_mac(a.as_slice()))
+}
+
+// The burned-in address, which only physical NICs report; virtual links
+// leave it out or zeroed.
+fn hardware_mac(l: &rtnl::link::LinkMessage) -> Option<String> {
+    let attrs = link_attrs(l);
+    if !attrs.kind.is_empty() || attrs.parent_index.is_some() {
+        return None;
+    }
+    l.nlas.iter().find_map(|nla| match nla {
+        rtnl::link::nlas::Nla::PermAddress(a)
+            if a.len() == MAC_LEN && a.iter().any(|&b| b != 0) =>
+        {
+            Some(proto::node::format_mac(a.as_slice()))
+        }
+        _ => None,
+    })
+}
+
+struct LinkAttrs {
+    mtu: u32,
+    oper_state: i32,
+    kind: String,
+    vlan_id: Option<u32>,
+    parent_index: Option<u32>,
+}
+
+fn link_attrs(l: &rtnl::link::LinkMessage) -> LinkAttrs {
+    let mut attrs = LinkAttrs {
+        mtu: 0,
+        oper_state: strapper::OperState::Unknown as i32,
+        kind: String::new(),
+        vlan_id: None,
+        parent_index: None,
+    };
+    for nla in l.nlas.iter() {
+        match nla {
+            rtnl::link::nlas::Nla::Mtu(mtu) => attrs.mtu = *mtu,
+            rtnl::link::nlas::Nla::OperState(state) => {
+                let state: u8 = (*state).into();
+                attrs.oper_state = state as i32;
+            }
+            // Tunnels not bound to a device report link 0.
+            rtnl::link::nlas::Nla::Link(index) if *index != 0 => attrs.parent_index = Some(*index),
+            rtnl::link::nlas::Nla::Info(infos) => {
+                for info in infos {
+                    match info {
+                        rtnl::link::nlas::Info::Kind(kind) => {
+                            let mut buf = vec![0; kind.value_len()];
+                            kind.emit_value(&mut buf);
+                            attrs.kind = String::from_utf8_lossy(&buf)
+                                .trim_end_matches('\0')
+                                .to_owned();
+                        }
+                        rtnl::link::nlas::Info::Data(rtnl::link::nlas::InfoData::Vlan(vlan)) => {
+                            for v in vlan {
+                                if let rtnl::link::nlas::InfoVlan::Id(id) = v {
+                                    attrs.vlan_id = Some(u32::from(*id));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    attrs
+}
+
+fn add_iface_if_not_exists_and_not_excluded(
+    v: &mut Vec<strapper::Interface>,
+    links: &LinkFilter,
+    l: &rtnl::link::LinkMessage,
+) -> Result<bool> {
+    // A link dumped again, say by a dump racing its RTM_NEWLINK, is already
+    // there under its index.
+    if links.excludes_slave(l) || v.iter().any(|i| i.index == l.header.index) {
+        return Ok(false);
+    }
+
+    let mut i_name = None;
+
+    // An empty name is as good as none.
+    for nla in l.nlas.iter() {
+        if let rtnl::link::nlas::Nla::IfName(name) = nla {
+            if name.is_empty() {
+                continue;
+            }
+            if links.excludes_name(name) {
+                return Ok(false);
+            }
+
+            for iface in v.iter() {
+                if &iface.name == name {
+                    return Ok(false);
+                }
+            }
+            i_name = Some(name);
+        }
+    }
+
+    let name = i_name.ok_or_else(|| anyhow!("name is unexpectedly missing"))?;
+    let mac = match link_mac(l) {
+        Some(mac) => mac,
+        None if links.skip_macless => return Ok(false),
+        None => String::new(),
+    };
+
+    let attrs = link_attrs(l);
+    let iface = strapper::Interface {
+        name: name.clone(),
+        mac,
+        ipaddr: Vec::new(),
+        address_info: Vec::new(),
+        index: l.header.index,
+        mtu: attrs.mtu,
+        oper_state: attrs.oper_state,
+        kind: attrs.kind,
+        vlan_id: attrs.vlan_id,
+        parent_index: attrs.parent_index,
+        wireguard_public_key: None,
+    };
+    let pos = v.partition_point(|i| i.index < iface.index);
+    v.insert(pos, iface);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use rtnetlink::packet::rtnl;
+    use rtnetlink::packet::rtnl::constants::{
+        IFA_F_MANAGETEMPADDR, IFA_F_PERMANENT, RT_SCOPE_LINK,
+    };
+    use std::net::IpAddr;
+
+    use proto::strapper::AddressOrigin;
+
+    use super::{link_mac, AdvertisementState, LinkUpdate};
+    use crate::filter::{AddressOptions, AddressScope};
+    use crate::testing::{address, link, options, state, state_with, with_lifetimes};
+
+    const ADDRESSES: &[(u32, &str)] = &[
+        (1, "fd00::10"),
+        (1, "192.168.0.10"),
+        (1, "fd00::9"),
+        (1, "10.0.0.2"),
+        (2, "fd01::1"),
+        (2, "172.16.0.1"),
+        (2, "172.16.0.100"),
+        (7, "10.7.0.1"),
+    ];
+
+    // Come and gone before the end, in any order among the rest.
+    const TRANSIENT: &[(u32, &str)] = &[(1, "192.168.0.11"), (7, "fd07::1")];
+
+    #[test]
+    fn arrival_order_is_forgotten() {
+        let links = vec![
+            link(7, "wg0", [2, 0, 0, 0, 0, 7]),
+            link(1, "eth0", [2, 0, 0, 0, 0, 1]),
+            link(2, "eth1", [2, 0, 0, 0, 0, 2]),
+        ];
+        let build = |rng: &mut StdRng| {
+            let mut s = state(&[]);
+            let mut links = links.clone();
+            links.shuffle(rng);
+            for (n, l) in links.iter().enumerate() {
+                // Dumped or announced, the result is the same.
+                if n % 2 == 0 {
+                    s.add_link(l).unwrap();
+                } else {
+                    s.apply_link(l).unwrap();
+                }
+            }
+            let mut events: Vec<(bool, u32, &str)> = ADDRESSES
+                .iter()
+                .chain(TRANSIENT)
+                .map(|&(i, a)| (true, i, a))
+                .collect();
+            events.shuffle(rng);
+            for &(i, a) in TRANSIENT {
+                let added = events.iter().position(|e| e == &(true, i, a)).unwrap();
+                let at = rng.gen_range(added + 1..=events.len());
+                events.insert(at, (false, i, a));
+            }
+            for (add, i, a) in events {
+                if add {
+                    s.apply_new_address(&address(i, a)).unwrap();
+                } else {
+                    s.apply_del_address(&address(i, a)).unwrap();
+                }
+            }
+            s.advertisement().clone()
+        };
+
+        let mut rng = StdRng::seed_from_u64(311);
+        let first = build(&mut rng);
+        let indexes: Vec<u32> = first.interfaces.iter().map(|i| i.index).collect();
+        assert_eq!(indexes, [1, 2, 7]);
+        for iface in &first.interfaces {
+            let mut sorted: Vec<IpAddr> = iface.ipaddr.iter().map(|a| a.parse().unwrap()).collect();
+            sorted.sort();
+            let sorted: Vec<String> = sorted.iter().map(|a| a.to_string()).collect();
+            assert_eq!(iface.ipaddr, sorted);
+        }
+        assert_eq!(
+            first.interfaces[0].ipaddr,
+            ["10.0.0.2", "192.168.0.10", "fd00::9", "fd00::10"]
+        );
+        for _ in 0..200 {
+            assert_eq!(build(&mut rng), first);
+        }
+    }
+
+    fn names(s: &AdvertisementState) -> Vec<&str> {
+        s.interfaces().iter().map(|i| i.name.as_str()).collect()
+    }
+
+    // udev's predictable names arrive as a rename after the link does.
+    #[test]
+    fn renamed_into_excluded() {
+        let mut s = state(&["^veth"]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        assert!(matches!(
+            s.apply_link(&link(5, "eth1", [2, 0, 0, 0, 0, 5])).unwrap(),
+            LinkUpdate::Added(5)
+        ));
+        s.apply_new_address(&address(5, "10.0.0.5")).unwrap();
+        assert_eq!(names(&s), ["eth0", "eth1"]);
+
+        assert!(matches!(
+            s.apply_link(&link(5, "veth5", [2, 0, 0, 0, 0, 5])).unwrap(),
+            LinkUpdate::Changed
+        ));
+        assert_eq!(names(&s), ["eth0"]);
+        assert_eq!(s.address_count(), 0);
+        assert!(!s.tracks(5));
+        // Its addresses went with it.
+        assert!(!s.apply_new_address(&address(5, "10.0.0.6")).unwrap());
+
+        // And back out again, as a new interface with none of its old state.
+        assert!(matches!(
+            s.apply_link(&link(5, "enp5s0", [2, 0, 0, 0, 0, 5]))
+                .unwrap(),
+            LinkUpdate::Added(5)
+        ));
+        assert_eq!(names(&s), ["eth0", "enp5s0"]);
+        assert_eq!(s.address_count(), 0);
+    }
+
+    #[test]
+    fn renamed_in_place() {
+        let mut s = state(&["^veth"]);
+        s.add_link(&link(3, "eth0", [2, 0, 0, 0, 0, 3])).unwrap();
+        s.apply_new_address(&address(3, "10.0.0.3")).unwrap();
+        assert!(matches!(
+            s.apply_link(&link(3, "enp3s0", [2, 0, 0, 0, 0, 3]))
+                .unwrap(),
+            LinkUpdate::Changed
+        ));
+        assert_eq!(names(&s), ["enp3s0"]);
+        assert_eq!(s.interface(3).unwrap().ipaddr, ["10.0.0.3"]);
+        assert!(matches!(
+            s.apply_link(&link(3, "enp3s0", [2, 0, 0, 0, 0, 3]))
+                .unwrap(),
+            LinkUpdate::Unchanged
+        ));
+    }
+
+    // A MAC says nothing about which link a message is for: a VLAN shares
+    // its parent's.
+    #[test]
+    fn same_mac_other_index() {
+        let mut s = state(&[]);
+        let mac = [2, 0, 0, 0, 0, 1];
+        s.add_link(&link(1, "eth0", mac)).unwrap();
+        s.apply_new_address(&address(1, "10.0.0.1")).unwrap();
+        assert!(matches!(
+            s.apply_link(&link(9, "eth0.9", mac)).unwrap(),
+            LinkUpdate::Added(9)
+        ));
+        assert_eq!(names(&s), ["eth0", "eth0.9"]);
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+    }
+
+    // A driver that re-indexes on rename, with the new index reported first.
+    #[test]
+    fn reindexed_by_mac() {
+        let hardware = |index, name| {
+            let mut l = link(index, name, [2, 0, 0, 0, 0, 4]);
+            l.nlas
+                .push(rtnl::link::nlas::Nla::PermAddress(vec![2, 0, 0, 0, 0, 4]));
+            l
+        };
+        let mut s = state(&[]);
+        s.add_link(&hardware(4, "eth0")).unwrap();
+        s.add_link(&link(6, "eth1", [2, 0, 0, 0, 0, 6])).unwrap();
+        s.apply_new_address(&address(4, "10.0.0.4")).unwrap();
+
+        assert!(matches!(
+            s.apply_link(&hardware(8, "enp8s0")).unwrap(),
+            LinkUpdate::Changed
+        ));
+        assert_eq!(names(&s), ["eth1", "enp8s0"]);
+        assert!(!s.tracks(4));
+        assert_eq!(s.interface(8).unwrap().ipaddr, ["10.0.0.4"]);
+        s.apply_new_address(&address(8, "10.0.0.8")).unwrap();
+        assert_eq!(s.interface(8).unwrap().ipaddr, ["10.0.0.4", "10.0.0.8"]);
+
+        // The old index's RTM_DELLINK, arriving late, is for nothing we have.
+        assert!(!s.apply_del_link(&hardware(4, "eth0")));
+        assert_eq!(names(&s), ["eth1", "enp8s0"]);
+
+        // Only a permanent address is an identity: eth1 has none, and a VLAN
+        // on the NIC borrows its MAC without its hardware.
+        assert!(matches!(
+            s.apply_link(&link(10, "eth2", [2, 0, 0, 0, 0, 6])).unwrap(),
+            LinkUpdate::Added(10)
+        ));
+        let mut vlan = hardware(11, "enp8s0.11");
+        vlan.nlas.push(rtnl::link::nlas::Nla::Link(8));
+        assert!(matches!(
+            s.apply_link(&vlan).unwrap(),
+            LinkUpdate::Added(11)
+        ));
+        assert_eq!(names(&s), ["eth1", "enp8s0", "eth2", "enp8s0.11"]);
+    }
+
+    // The same, with the old index's RTM_DELLINK first.
+    #[test]
+    fn deleted_and_readded() {
+        let mut s = state(&[]);
+        s.add_link(&link(4, "eth0", [2, 0, 0, 0, 0, 4])).unwrap();
+        s.apply_new_address(&address(4, "10.0.0.4")).unwrap();
+
+        assert!(s.apply_del_link(&link(4, "eth0", [2, 0, 0, 0, 0, 4])));
+        assert!(names(&s).is_empty());
+        assert!(!s.tracks(4));
+        assert!(!s.apply_del_link(&link(4, "eth0", [2, 0, 0, 0, 0, 4])));
+
+        assert!(matches!(
+            s.apply_link(&link(8, "enp8s0", [2, 0, 0, 0, 0, 4]))
+                .unwrap(),
+            LinkUpdate::Added(8)
+        ));
+        assert_eq!(names(&s), ["enp8s0"]);
+        assert_eq!(s.address_count(), 0);
+    }
+
+    // A dump with one of everything that goes wrong in it.
+    #[test]
+    fn bad_entries_are_skipped() {
+        let mut nameless = link(2, "", [2, 0, 0, 0, 0, 2]);
+        nameless
+            .nlas
+            .retain(|nla| !matches!(nla, rtnl::link::nlas::Nla::IfName(_)));
+        let mut infiniband = link(3, "ib0", [0; 6]);
+        infiniband.nlas[1] = rtnl::link::nlas::Nla::Address(vec![0x80; 20]);
+        let links = vec![
+            link(1, "eth0", [2, 0, 0, 0, 0, 1]),
+            nameless,
+            infiniband,
+            link(4, "eth1", [2, 0, 0, 0, 0, 4]),
+        ];
+        let mut s = state(&[]);
+        s.add_links(&links).unwrap();
+        assert_eq!(names(&s), ["eth0", "ib0", "eth1"]);
+        assert_eq!(s.interface(3).unwrap().mac, "");
+
+        let mut odd = address(1, "10.0.0.1");
+        odd.nlas = vec![
+            rtnl::address::nlas::Nla::Address(vec![10, 0, 0]),
+            rtnl::address::nlas::Nla::Address(vec![10, 0, 0, 2]),
+        ];
+        assert!(s.apply_new_address(&odd).unwrap());
+        let mut short = address(4, "10.0.0.4");
+        short.nlas = vec![rtnl::address::nlas::Nla::Address(vec![0; 5])];
+        assert!(!s.apply_new_address(&short).unwrap());
+        s.apply_new_address(&address(4, "10.0.0.4")).unwrap();
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.2"]);
+        assert_eq!(s.interface(4).unwrap().ipaddr, ["10.0.0.4"]);
+    }
+
+    #[test]
+    fn nothing_usable() {
+        let mut nameless = link(2, "", [2, 0, 0, 0, 0, 2]);
+        nameless.nlas.clear();
+        let mut s = state(&["^veth"]);
+        assert!(s.add_links(&[nameless.clone(), nameless]).is_err());
+        // Excluded isn't failed.
+        s.add_links(&[link(1, "veth0", [2, 0, 0, 0, 0, 1])])
+            .unwrap();
+        s.add_links(&[]).unwrap();
+    }
+
+    fn lifetimes(s: &AdvertisementState, index: u32) -> Vec<(u32, u32)> {
+        s.interface(index)
+            .unwrap()
+            .address_info
+            .iter()
+            .map(|a| (a.preferred_lifetime, a.valid_lifetime))
+            .collect()
+    }
+
+    #[test]
+    fn lifetimes_from_cacheinfo() {
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        assert!(s
+            .apply_new_address(&with_lifetimes(address(1, "fd00::1"), 1800, 3600))
+            .unwrap());
+        // Without one, as IPv4 on older kernels, they never run out.
+        s.apply_new_address(&address(1, "10.0.0.1")).unwrap();
+        assert_eq!(lifetimes(&s, 1), [(u32::MAX, u32::MAX), (1800, 3600)]);
+    }
+
+    // The kernel only sends lifetimes when it refreshes or deprecates an
+    // address, and either is worth advertising; one that just ran down
+    // isn't.
+    #[test]
+    fn refreshed_and_deprecated() {
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        let slaac = |preferred, valid| with_lifetimes(address(1, "fd00::1"), preferred, valid);
+        s.apply_new_address(&slaac(1800, 3600)).unwrap();
+        assert!(!s.take_refreshed());
+
+        assert!(!s.apply_new_address(&slaac(1800, 3600)).unwrap());
+        assert!(s.apply_new_address(&slaac(1200, 3000)).unwrap());
+        assert!(!s.take_refreshed());
+
+        assert!(s.apply_new_address(&slaac(1800, 3600)).unwrap());
+        assert!(s.take_refreshed());
+        assert!(!s.take_refreshed());
+        assert!(s.apply_new_address(&slaac(1200, 7200)).unwrap());
+        assert!(s.take_refreshed());
+
+        assert!(s.apply_new_address(&slaac(0, 600)).unwrap());
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["fd00::1"]);
+        assert!(s.take_refreshed());
+        assert!(s.apply_new_address(&slaac(0, 300)).unwrap());
+        assert!(!s.take_refreshed());
+        assert!(s.apply_new_address(&slaac(60, 300)).unwrap());
+        assert!(s.take_refreshed());
+
+        // New addresses are a change of their own.
+        s.apply_new_address(&address(1, "fd00::2")).unwrap();
+        assert!(!s.take_refreshed());
+        s.reset();
+        assert!(!s.take_refreshed());
+    }
+
+    // The kernel's scope decides, whatever the address looks like, for
+    // deletes as much as adds.
+    #[test]
+    fn link_scoped_come_and_go_quietly() {
+        let mut s = state_with(
+            &[],
+            AddressOptions {
+                scope: AddressScope::Universe,
+                ..options()
+            },
+        );
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        let mut anycast = address(1, "10.0.0.1");
+        anycast.header.scope = RT_SCOPE_LINK;
+        assert!(!s.apply_new_address(&anycast).unwrap());
+        assert!(!s.apply_del_address(&anycast).unwrap());
+        assert!(s.interface(1).unwrap().ipaddr.is_empty());
+
+        assert!(s.apply_new_address(&address(1, "10.0.0.1")).unwrap());
+        assert!(!s.apply_del_address(&anycast).unwrap());
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+        assert!(s.apply_del_address(&address(1, "10.0.0.1")).unwrap());
+    }
+
+    #[test]
+    fn deletes_filtered_like_adds() {
+        for include_ula in [false, true] {
+            let mut s = state_with(
+                &[],
+                AddressOptions {
+                    include_ula,
+                    ..options()
+                },
+            );
+            s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+            assert_eq!(
+                s.apply_new_address(&address(1, "fd00::1")).unwrap(),
+                include_ula
+            );
+            assert_eq!(
+                s.apply_del_address(&address(1, "fd00::1")).unwrap(),
+                include_ula
+            );
+        }
+    }
+
+    // Colon-separated lowercase whatever the bits say, with the hardware
+    // address over the one the link borrowed.
+    #[test]
+    fn mac_from_bytes() {
+        let cases: &[([u8; 6], &str)] = &[
+            ([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef], "52:54:00:ab:cd:ef"),
+            // Locally administered.
+            ([0x02, 0x42, 0xac, 0x11, 0x00, 0x02], "02:42:ac:11:00:02"),
+            // Multicast, and both bits at once.
+            ([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb], "01:00:5e:00:00:fb"),
+            ([0x33, 0x33, 0xff, 0xff, 0xff, 0xff], "33:33:ff:ff:ff:ff"),
+            ([0xff; 6], "ff:ff:ff:ff:ff:ff"),
+            ([0; 6], "00:00:00:00:00:00"),
+        ];
+        for (bytes, mac) in cases {
+            assert_eq!(link_mac(&link(1, "eth0", *bytes)).as_deref(), Some(*mac));
+        }
+
+        let mut bonded = link(1, "eth0", [0x02, 0, 0, 0, 0, 1]);
+        bonded.nlas.push(rtnl::link::nlas::Nla::PermAddress(vec![
+            0xAA, 0xBB, 0xCC, 0, 0, 1,
+        ]));
+        assert_eq!(link_mac(&bonded).as_deref(), Some("aa:bb:cc:00:00:01"));
+
+        // Not Ethernet: an InfiniBand GUID, and a tunnel's IPv4 address.
+        let mut odd = link(1, "ib0", [0; 6]);
+        odd.nlas = vec![rtnl::link::nlas::Nla::Address(vec![0x80; 20])];
+        assert_eq!(link_mac(&odd), None);
+        odd.nlas = vec![rtnl::link::nlas::Nla::Address(vec![10, 0, 0, 1])];
+        assert_eq!(link_mac(&odd), None);
+    }
+
+    // The kernel's true order of events for a burst, and its addresses
+    // (with lifetimes) afterwards, which a dump would return.
+    fn burst(
+        rng: &mut StdRng,
+    ) -> (
+        Vec<(bool, rtnl::address::AddressMessage)>,
+        Vec<rtnl::address::AddressMessage>,
+    ) {
+        const POOL: &[(u32, &str)] = &[
+            (1, "10.0.0.1"),
+            (1, "10.0.0.2"),
+            (1, "fd00::1"),
+            (1, "fd00::2"),
+            (2, "172.16.0.1"),
+            (2, "fd01::1"),
+        ];
+        let mut kernel: Vec<(u32, &str, u32)> = vec![];
+        let mut events = vec![];
+        for _ in 0..rng.gen_range(1..40) {
+            let &(index, a) = POOL.choose(rng).unwrap();
+            let present = kernel.iter().position(|&(i, k, _)| (i, k) == (index, a));
+            match present {
+                Some(p) if rng.gen_bool(0.5) => {
+                    let (_, _, preferred) = kernel.remove(p);
+                    events.push((false, with_lifetimes(address(index, a), preferred, 7200)));
+                }
+                _ => {
+                    // New, or the same again with its lifetimes renewed.
+                    let preferred = rng.gen_range(0..3600);
+                    match present {
+                        Some(p) => kernel[p].2 = preferred,
+                        None => kernel.push((index, a, preferred)),
+                    }
+                    events.push((true, with_lifetimes(address(index, a), preferred, 7200)));
+                }
+            }
+        }
+        let dump = kernel
+            .into_iter()
+            .map(|(index, a, preferred)| with_lifetimes(address(index, a), preferred, 7200))
+            .collect();
+        (events, dump)
+    }
+
+    fn two_links() -> AdvertisementState {
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        s.add_link(&link(2, "eth1", [2, 0, 0, 0, 0, 2])).unwrap();
+        s
+    }
+
+    // Whatever order the events come in, duplicated or not, the targeted
+    // dump afterwards leaves exactly what the kernel has.
+    #[test]
+    fn reordered_events_end_at_the_dump() {
+        let mut rng = StdRng::seed_from_u64(351);
+        for _ in 0..300 {
+            let (mut events, dump) = burst(&mut rng);
+            for _ in 0..rng.gen_range(0..events.len() * 2) {
+                let i = rng.gen_range(0..events.len());
+                let j = (i + 1).min(events.len() - 1);
+                if rng.gen_bool(0.2) {
+                    let again = events[i].clone();
+                    events.insert(j, again);
+                } else {
+                    events.swap(i, j);
+                }
+            }
+
+            let mut s = two_links();
+            for (new, addr) in events.iter() {
+                if *new {
+                    s.apply_new_address(addr).unwrap();
+                } else {
+                    s.apply_del_address(addr).unwrap();
+                }
+            }
+            for index in [1, 2] {
+                s.replace_addresses(index, &dump).unwrap().unwrap();
+            }
+
+            let mut truth = two_links();
+            for addr in dump.iter() {
+                truth.apply_new_address(addr).unwrap();
+            }
+            assert_eq!(s.advertisement(), truth.advertisement());
+        }
+    }
+
+    #[test]
+    fn deleting_the_unknown() {
+        let mut s = two_links();
+        assert!(!s.apply_del_address(&address(1, "10.0.0.1")).unwrap());
+        // The delete overtook the add it followed.
+        s.apply_new_address(&address(1, "10.0.0.1")).unwrap();
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+        assert_eq!(s.replace_addresses(1, &[]).unwrap().unwrap(), ["10.0.0.1"]);
+        assert!(s.interface(1).unwrap().ipaddr.is_empty());
+        assert_eq!(s.replace_addresses(9, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn excluded() {
+        let mut s = state(&["^veth", "^docker0$"]);
+        assert!(!s
+            .add_link(&link(3, "veth1a2b", [2, 0, 0, 0, 0, 3]))
+            .unwrap());
+        assert!(!s.add_link(&link(4, "docker0", [2, 0, 0, 0, 0, 4])).unwrap());
+        assert!(s
+            .add_link(&link(5, "docker01", [2, 0, 0, 0, 0, 5]))
+            .unwrap());
+        let mut member = link(6, "eth0", [2, 0, 0, 0, 0, 6]);
+        member.nlas.push(rtnl::link::nlas::Nla::Master(7));
+        assert!(!s.add_link(&member).unwrap());
+        assert_eq!(names(&s), ["docker01"]);
+
+        for index in [3, 4, 6] {
+            assert!(!s.apply_new_address(&address(index, "10.0.0.1")).unwrap());
+            assert!(!s.tracks(index));
+        }
+        assert_eq!(s.address_count(), 0);
+    }
+
+    #[test]
+    fn duplicates_suppressed() {
+        let mut s = state(&[]);
+        let eth0 = link(1, "eth0", [2, 0, 0, 0, 0, 1]);
+        assert!(s.add_link(&eth0).unwrap());
+        assert!(!s.add_link(&eth0).unwrap());
+        assert!(matches!(
+            s.apply_link(&eth0).unwrap(),
+            LinkUpdate::Unchanged
+        ));
+        assert_eq!(names(&s), ["eth0"]);
+
+        assert!(s.apply_new_address(&address(1, "10.0.0.1")).unwrap());
+        assert!(!s.apply_new_address(&address(1, "10.0.0.1")).unwrap());
+
+        // An alias of the same address is one address, and outlives the
+        // primary's delete.
+        let mut alias = address(1, "10.0.0.1");
+        alias
+            .nlas
+            .push(rtnl::address::nlas::Nla::Label("eth0:web".to_owned()));
+        s.apply_new_address(&alias).unwrap();
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+        s.apply_del_address(&address(1, "10.0.0.1")).unwrap();
+        assert_eq!(s.interface(1).unwrap().ipaddr, ["10.0.0.1"]);
+        assert!(s.apply_del_address(&alias).unwrap());
+        assert!(s.interface(1).unwrap().ipaddr.is_empty());
+    }
+
+    #[test]
+    fn unknown_interface() {
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        let before = s.advertisement().clone();
+        assert!(!s.apply_new_address(&address(9, "10.0.0.1")).unwrap());
+        assert!(!s.apply_del_address(&address(9, "10.0.0.1")).unwrap());
+        assert!(!s.apply_del_link(&link(9, "eth9", [2, 0, 0, 0, 0, 9])));
+        assert_eq!(*s.advertisement(), before);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        let before = s.advertisement().clone();
+
+        let mut short = address(1, "10.0.0.1");
+        short.nlas = vec![rtnl::address::nlas::Nla::Address(vec![10, 0, 0, 1, 0])];
+        assert!(!s.apply_new_address(&short).unwrap());
+        let mut bare = address(1, "10.0.0.1");
+        bare.nlas.clear();
+        assert!(!s.apply_new_address(&bare).unwrap());
+        assert!(!s.apply_del_address(&bare).unwrap());
+
+        let mut nameless = link(2, "eth1", [2, 0, 0, 0, 0, 2]);
+        nameless
+            .nlas
+            .retain(|n| !matches!(n, rtnl::link::nlas::Nla::IfName(_)));
+        assert!(s.add_link(&nameless).is_err());
+        // An event without one has nothing to act on.
+        assert!(matches!(
+            s.apply_link(&nameless).unwrap(),
+            LinkUpdate::Unchanged
+        ));
+        // Nor does one with an empty name.
+        let empty = link(2, "", [2, 0, 0, 0, 0, 2]);
+        assert!(s.add_link(&empty).is_err());
+        assert!(matches!(
+            s.apply_link(&empty).unwrap(),
+            LinkUpdate::Unchanged
+        ));
+        // An index dumped again under another name stays the one interface.
+        assert!(!s.add_link(&link(1, "eth9", [2, 0, 0, 0, 0, 9])).unwrap());
+        assert_eq!(*s.advertisement(), before);
+    }
+
+    // IFA_F_PERMANENT makes an address static, from IFA_FLAGS when the
+    // kernel sends it (it's the only place flags past the first 8 fit) and
+    // the header otherwise. SLAAC's mngtmpaddr templates are permanent too,
+    // but the addresses they stand for come and go.
+    #[test]
+    fn origins_from_flags() {
+        let with_header = |addr: &str, flags: u32| {
+            let mut m = address(1, addr);
+            m.header.flags = flags as u8;
+            m
+        };
+        let with_nla = |addr: &str, header: u32, flags: u32| {
+            let mut m = with_header(addr, header);
+            m.nlas.push(rtnl::address::nlas::Nla::Flags(flags));
+            m
+        };
+        let cases = [
+            ("10.0.0.1", address(1, "10.0.0.1"), AddressOrigin::Dynamic),
+            (
+                "10.0.0.2",
+                with_header("10.0.0.2", IFA_F_PERMANENT),
+                AddressOrigin::Static,
+            ),
+            (
+                "10.0.0.3",
+                with_nla("10.0.0.3", 0, IFA_F_PERMANENT),
+                AddressOrigin::Static,
+            ),
+            (
+                "10.0.0.4",
+                with_nla("10.0.0.4", IFA_F_PERMANENT, 0),
+                AddressOrigin::Dynamic,
+            ),
+            (
+                "fd00::1",
+                with_nla(
+                    "fd00::1",
+                    IFA_F_PERMANENT,
+                    IFA_F_PERMANENT | IFA_F_MANAGETEMPADDR,
+                ),
+                AddressOrigin::Dynamic,
+            ),
+            (
+                "fd00::2",
+                with_nla("fd00::2", 0, IFA_F_PERMANENT),
+                AddressOrigin::Static,
+            ),
+        ];
+
+        let mut s = state(&[]);
+        s.add_link(&link(1, "eth0", [2, 0, 0, 0, 0, 1])).unwrap();
+        for (_, m, _) in &cases {
+            assert!(s.apply_new_address(m).unwrap());
+        }
+        let info = &s.interface(1).unwrap().address_info;
+        for (addr, _, origin) in &cases {
+            let i = info
+                .iter()
+                .find(|i| i.address == *addr)
+                .expect("address info");
+            assert_eq!(AddressOrigin::from_i32(i.origin), Some(*origin), "{}", addr);
+        }
+    }
+
+    // Any mix of the attributes the kernel could send, well formed or not:
+    // lengths a MAC or an address can't have, repeats, and those missing.
+    fn any_link(rng: &mut StdRng) -> rtnl::link::LinkMessage {
+        use rtnl::link::nlas::{Info, InfoData, InfoKind, InfoVlan, Nla, State};
+        let bytes = |rng: &mut StdRng| {
+            let len = *[0, 1, 4, 6, 6, 8, 16, 20, 32].choose(rng).unwrap();
+            (0..len).map(|_| rng.gen()).collect::<Vec<u8>>()
+        };
+        let mut l = link(
+            rng.gen_range(1..=6),
+            ["eth0", "eth1", "wg0", "veth0", "ib0", ""]
+                .choose(rng)
+                .unwrap(),
+            [2, 0, 0, 0, 0, rng.gen_range(1..=3)],
+        );
+        l.nlas.retain(|_| rng.gen_bool(0.8));
+        for _ in 0..rng.gen_range(0..6) {
+            l.nlas.push(match rng.gen_range(0..8) {
+                0 => Nla::IfName(["eth2", "veth1", ""].choose(rng).unwrap().to_string()),
+                1 => Nla::Address(bytes(rng)),
+                2 => Nla::PermAddress(bytes(rng)),
+                3 => Nla::Mtu(rng.gen()),
+                4 => Nla::OperState(State::from(rng.gen::<u8>())),
+                5 => Nla::Link(rng.gen_range(0..8)),
+                6 => Nla::Master(rng.gen_range(0..8)),
+                _ => Nla::Info(vec![
+                    Info::Kind(if rng.gen() {
+                        InfoKind::Vlan
+                    } else {
+                        InfoKind::Other(["", "bond", "wireguard"].choose(rng).unwrap().to_string())
+                    }),
+                    Info::Data(InfoData::Vlan(vec![InfoVlan::Id(rng.gen())])),
+                ]),
+            });
+        }
+        l.nlas.shuffle(rng);
+        l
+    }
+
+    fn any_address(rng: &mut StdRng) -> rtnl::address::AddressMessage {
+        use rtnl::address::nlas::Nla;
+        // Mostly a few addresses that can be deleted again.
+        let bytes = |rng: &mut StdRng| match rng.gen_range(0..4) {
+            0 => {
+                let len = *[0, 3, 5, 8, 15, 17].choose(rng).unwrap();
+                (0..len).map(|_| rng.gen()).collect()
+            }
+            _ => {
+                let a: IpAddr = [
+                    "10.0.0.1",
+                    "100.64.0.1",
+                    "fd00::1",
+                    "fe80::1",
+                    "2001:db8::1",
+                ]
+                .choose(rng)
+                .unwrap()
+                .parse()
+                .unwrap();
+                match a {
+                    IpAddr::V4(a) => a.octets().to_vec(),
+                    IpAddr::V6(a) => a.octets().to_vec(),
+                }
+            }
+        };
+        let mut m = address(rng.gen_range(1..=6), "10.0.0.1");
+        m.header.family = *[libc::AF_INET as u8, libc::AF_INET6 as u8, 0]
+            .choose(rng)
+            .unwrap();
+        m.header.scope = *[0, 200, RT_SCOPE_LINK, 254].choose(rng).unwrap();
+        m.header.flags = rng.gen();
+        m.header.prefix_len = rng.gen();
+        m.nlas.clear();
+        for _ in 0..rng.gen_range(0..5) {
+            m.nlas.push(match rng.gen_range(0..6) {
+                0 | 1 => Nla::Address(bytes(rng)),
+                2 => Nla::Local(bytes(rng)),
+                3 => Nla::Label(
+                    ["", "eth0", "eth0:web", "lo"]
+                        .choose(rng)
+                        .unwrap()
+                        .to_string(),
+                ),
+                4 => Nla::Flags(rng.gen()),
+                _ => {
+                    let len = *[0, 4, 8, 12, 16].choose(rng).unwrap();
+                    Nla::CacheInfo((0..len).map(|_| rng.gen()).collect())
+                }
+            });
+        }
+        m
+    }
+
+    fn named(l: &rtnl::link::LinkMessage) -> bool {
+        l.nlas
+            .iter()
+            .any(|n| matches!(n, rtnl::link::nlas::Nla::IfName(n) if !n.is_empty()))
+    }
+
+    // Whatever comes in, in whatever order, the state neither panics nor
+    // errors on anything but a nameless link, and what it would advertise
+    // stays valid. Seeded and bounded so it runs with the rest; a failure
+    // prints the seed to replay it with.
+    #[test]
+    fn arbitrary_messages() {
+        for seed in 0..500 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut s = state(&["^veth"]);
+            for _ in 0..40 {
+                match rng.gen_range(0..8) {
+                    0 => {
+                        let l = any_link(&mut rng);
+                        if s.add_link(&l).is_err() {
+                            assert!(!named(&l), "seed {}: {:?}", seed, l);
+                        }
+                    }
+                    1 => {
+                        let l = any_link(&mut rng);
+                        if s.apply_link(&l).is_err() {
+                            assert!(!named(&l), "seed {}: {:?}", seed, l);
+                        }
+                    }
+                    2 => {
+                        s.apply_del_link(&any_link(&mut rng));
+                    }
+                    3 => {
+                        let links: Vec<_> = (0..rng.gen_range(0..4))
+                            .map(|_| any_link(&mut rng))
+                            .collect();
+                        let _ = s.add_links(&links);
+                    }
+                    4 | 5 => {
+                        let m = any_address(&mut rng);
+                        s.apply_new_address(&m)
+                            .unwrap_or_else(|e| panic!("seed {}: {:#}", seed, e));
+                    }
+                    6 => {
+                        let m = any_address(&mut rng);
+                        s.apply_del_address(&m)
+                            .unwrap_or_else(|e| panic!("seed {}: {:#}", seed, e));
+                    }
+                    _ => {
+                        let dump: Vec<_> = (0..rng.gen_range(0..4))
+                            .map(|_| any_address(&mut rng))
+                            .collect();
+                        s.replace_addresses(rng.gen_range(1..=6), &dump)
+                            .unwrap_or_else(|e| panic!("seed {}: {:#}", seed, e));
+                    }
+                }
+
+                let ad = s.advertisement();
+                assert!(
+                    ad.interfaces.windows(2).all(|w| w[0].index < w[1].index),
+                    "seed {}",
+                    seed
+                );
+                for iface in &ad.interfaces {
+                    assert!(!iface.name.is_empty(), "seed {}", seed);
+                    assert!(s.tracks(iface.index), "seed {}", seed);
+                    assert!(
+                        iface.mac.is_empty() || iface.mac.len() == 17,
+                        "seed {}",
+                        seed
+                    );
+                    let info: Vec<&String> =
+                        iface.address_info.iter().map(|a| &a.address).collect();
+                    assert_eq!(
+                        info,
+                        iface.ipaddr.iter().collect::<Vec<_>>(),
+                        "seed {}",
+                        seed
+                    );
+                }
+            }
+            // The full check parses every MAC and address; once a run is
+            // enough.
+            assert_eq!(s.advertisement().validate(), Ok(()), "seed {}", seed);
+        }
+    }
+}
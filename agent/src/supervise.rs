@@ -0,0 +1,550 @@
+use anyhow::{ensure, Context, Result};
+use futures_util::future::{self, AbortHandle, Aborted};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time::Instant;
+
+use client::{RetryPolicy, Target};
+
+use crate::hostname::HostnameSource;
+use crate::runtime::{self, Backoff};
+use crate::upstream::Clients;
+use crate::{output, retry_policy, run_advertise, Opt, StreamEnded};
+
+// --all-netns: the agent's own namespace, and every named one, each
+// advertised as a node of its own by a run of the usual event loop with its
+// netlink socket in that namespace. The runs share the agent's task and its
+// clients, so a namespace going away only means dropping its run, which
+// closes its sockets and stops everything it started.
+
+const NETNS_DIR: &str = "/var/run/netns";
+
+pub fn check_hostname_fmt(fmt: &str) -> Result<()> {
+    ensure!(
+        fmt.contains("{netns}"),
+        "--netns-hostname-fmt needs {{netns}}, or every namespace would advertise the same node"
+    );
+    Ok(())
+}
+
+fn node_name(fmt: &str, hostname: &str, netns: &str) -> String {
+    fmt.replace("{hostname}", hostname)
+        .replace("{netns}", netns)
+}
+
+// The namespace's own options: its netlink socket in it, and its name fixed.
+// The state cache only describes the agent's own namespace.
+fn netns_opt(opt: &Opt, netns: &str, hostname: String) -> Opt {
+    Opt {
+        netns: Some(netns.to_owned()),
+        hostname_source: HostnameSource::Static(hostname),
+        state_cache: None,
+        ..opt.clone()
+    }
+}
+
+// One node's loop, started over when its netlink stream ends as the agent's
+// own is.
+async fn node(opt: &Opt, started: u64, clients: &Clients) -> Result<()> {
+    let mut sequence = 0;
+    loop {
+        match run_advertise(opt, started, &mut sequence, Some(clients)).await {
+            Err(e) if !opt.exit_on_stream_end && e.is::<StreamEnded>() => {
+                output::warning(format_args!("{}, starting over", e));
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            r => return r,
+        }
+    }
+}
+
+// The namespaces under `dir`, by name, each with the inode the name leads
+// to, which tells a namespace from one made under the same name after it.
+fn list(dir: &Path) -> Result<BTreeMap<String, u64>> {
+    let mut names = BTreeMap::new();
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("error listing {}", dir.display()))?
+    {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        // Gone again since the listing.
+        if let Ok(metadata) = std::fs::metadata(entry.path()) {
+            names.insert(name, metadata.ino());
+        }
+    }
+    Ok(names)
+}
+
+struct Inotify(RawFd);
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+// Wakes on namespaces being added to or removed from the directory. What
+// changed isn't kept: the directory is listed again instead, which also
+// covers events lost to an overflow.
+struct Watch(AsyncFd<Inotify>);
+
+impl Watch {
+    fn new(dir: &Path) -> Result<Watch> {
+        // As `ip netns add` would.
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("error creating {}", dir.display()))?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("error starting inotify");
+        }
+        let inotify = Inotify(fd);
+        let path = std::ffi::CString::new(dir.as_os_str().to_string_lossy().as_bytes())
+            .context("netns directory has a NUL in it")?;
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("error watching {}", dir.display()));
+        }
+        Ok(Watch(AsyncFd::new(inotify)?))
+    }
+
+    // Waits for events and throws them away.
+    async fn changed(&self) -> Result<()> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            let read = guard.try_io(|fd| {
+                let mut buf = [0u8; 4096];
+                let n = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Ok(read) = read {
+                return read.context("error reading inotify events");
+            }
+        }
+    }
+}
+
+// What becomes of each namespace's node. Nodes does it for real;
+// supervise only sees this, so it runs as well against something standing in
+// for the event loop and the servers.
+#[tonic::async_trait(?Send)]
+trait Namespaces {
+    fn hostname(&self, netns: &str) -> String;
+
+    // Advertises the namespace's node until dropped or it fails.
+    async fn advertise(&self, netns: &str, hostname: &str) -> Result<()>;
+
+    // Tells each server the node is gone.
+    async fn withdraw(&self, hostname: &str);
+}
+
+struct Nodes<'a> {
+    opt: &'a Opt,
+    started: u64,
+    hostname: String,
+    clients: Clients,
+}
+
+#[tonic::async_trait(?Send)]
+impl Namespaces for Nodes<'_> {
+    fn hostname(&self, netns: &str) -> String {
+        node_name(&self.opt.netns_hostname_fmt, &self.hostname, netns)
+    }
+
+    async fn advertise(&self, netns: &str, hostname: &str) -> Result<()> {
+        let opt = netns_opt(self.opt, netns, hostname.to_owned());
+        node(&opt, self.started, &self.clients).await
+    }
+
+    async fn withdraw(&self, hostname: &str) {
+        let targets: Vec<Target> = std::iter::once(self.opt.endpoint.clone())
+            .chain(self.opt.also_endpoint.iter().cloned())
+            .collect();
+        for target in targets {
+            let deregistered = async {
+                self.clients
+                    .get(self.opt, &target)
+                    .await?
+                    .deregister(hostname)
+                    .await
+            };
+            match deregistered.await {
+                Ok(()) => output::info(format_args!("deregistered {} at {}", hostname, target)),
+                Err(e) => output::warning(format_args!(
+                    "unable to deregister {} at {}: {:#}",
+                    hostname, target, e
+                )),
+            }
+        }
+    }
+}
+
+struct Running {
+    hostname: String,
+    ino: u64,
+    abort: AbortHandle,
+    // Between runs that end, which start over after a wait rather than
+    // leaving the namespace unadvertised.
+    backoff: Backoff,
+    since: Instant,
+}
+
+struct Supervisor {
+    dir: PathBuf,
+    debounce: Duration,
+    restart: RetryPolicy,
+}
+
+impl Supervisor {
+    // Runs until `host`, the agent's own namespace's node, ends.
+    async fn run(
+        &self,
+        namespaces: &impl Namespaces,
+        host: impl std::future::Future<Output = Result<()>>,
+    ) -> Result<()> {
+        let watch = Watch::new(&self.dir)?;
+        tokio::pin!(host);
+        let mut running: HashMap<String, Running> = HashMap::new();
+        let mut nodes = FuturesUnordered::new();
+        let mut withdrawals = FuturesUnordered::new();
+        // A namespace's run, after `wait`.
+        let start = |netns: String, ino: u64, hostname: String, wait: Duration| {
+            let named = netns.clone();
+            let (run, abort) = future::abortable(async move {
+                tokio::time::sleep(wait).await;
+                namespaces.advertise(&named, &hostname).await
+            });
+            (async move { (netns, ino, run.await) }, abort)
+        };
+        let mut listed = true;
+        loop {
+            if listed {
+                let names = list(&self.dir)?;
+                let current = |netns: &String, r: &Running| names.get(netns) == Some(&r.ino);
+                for (netns, r) in running.iter().filter(|&(n, r)| !current(n, r)) {
+                    r.abort.abort();
+                    // A namespace made again under the name is advertised as
+                    // the same node, so there's nothing to withdraw.
+                    if names.contains_key(netns) {
+                        output::info(format_args!(
+                            "network namespace {} was replaced, starting over",
+                            netns
+                        ));
+                        continue;
+                    }
+                    output::info(format_args!(
+                        "network namespace {} is gone, withdrawing {}",
+                        netns, r.hostname
+                    ));
+                    let hostname = r.hostname.clone();
+                    withdrawals.push(async move { namespaces.withdraw(&hostname).await });
+                }
+                running.retain(|n, r| current(n, r));
+                let added: Vec<(String, u64)> = names
+                    .into_iter()
+                    .filter(|(n, _)| !running.contains_key(n))
+                    .collect();
+                for (netns, ino) in added {
+                    let hostname = namespaces.hostname(&netns);
+                    output::info(format_args!(
+                        "advertising network namespace {} as {}",
+                        netns, hostname
+                    ));
+                    let (run, abort) = start(netns.clone(), ino, hostname.clone(), Duration::ZERO);
+                    nodes.push(run);
+                    running.insert(
+                        netns,
+                        Running {
+                            hostname,
+                            ino,
+                            abort,
+                            backoff: runtime::backoff(self.restart.clone()),
+                            since: Instant::now(),
+                        },
+                    );
+                }
+                listed = false;
+            }
+
+            tokio::select! {
+                r = &mut host => return r,
+                r = watch.changed() => {
+                    r?;
+                    // Take in the rest of a burst, such as a CNI plugin's
+                    // namespace that's gone again before it's worth a node.
+                    while let Ok(r) = tokio::time::timeout(self.debounce, watch.changed()).await {
+                        r?;
+                    }
+                    listed = true;
+                }
+                Some((netns, ino, r)) = nodes.next() => {
+                    let r = match r {
+                        // Removed or replaced above.
+                        Err(Aborted) => continue,
+                        Ok(r) => r,
+                    };
+                    let entry = match running.get_mut(&netns) {
+                        Some(entry) if entry.ino == ino => entry,
+                        _ => continue,
+                    };
+                    // A run that held up for a while isn't part of a
+                    // string of failures.
+                    if entry.since.elapsed() > self.restart.max_delay {
+                        entry.backoff = runtime::backoff(self.restart.clone());
+                    }
+                    let wait = entry.backoff.next_delay().unwrap_or(self.restart.max_delay);
+                    let why = match r {
+                        Ok(()) => "every server stopped listening".to_owned(),
+                        Err(e) => format!("{:#}", e),
+                    };
+                    output::warning(format_args!(
+                        "stopped advertising network namespace {} ({}), starting over in {} seconds",
+                        netns, why, wait.as_secs()
+                    ));
+                    let (run, abort) = start(netns, ino, entry.hostname.clone(), wait);
+                    entry.abort = abort;
+                    entry.since = Instant::now() + wait;
+                    nodes.push(run);
+                }
+                Some(()) = withdrawals.next() => {}
+            }
+        }
+    }
+}
+
+pub async fn run(opt: &Opt, started: u64) -> Result<()> {
+    let clients = Clients::default();
+    let namespaces = Nodes {
+        opt,
+        started,
+        hostname: opt.hostname_source.resolve().await?,
+        clients: clients.clone(),
+    };
+    let supervisor = Supervisor {
+        dir: PathBuf::from(NETNS_DIR),
+        debounce: Duration::from_millis(opt.event_debounce_ms),
+        restart: RetryPolicy {
+            max_tries: u32::MAX,
+            max_elapsed: None,
+            ..retry_policy(opt)
+        },
+    };
+    supervisor
+        .run(&namespaces, node(opt, started, &clients))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::os::unix::fs::MetadataExt;
+
+    // Stands in for the event loop and the servers. Each run holds a
+    // socket, as a real one holds its netlink and gRPC sockets, and counts
+    // itself while it's alive.
+    #[derive(Default)]
+    struct Fake {
+        live: RefCell<HashMap<String, usize>>,
+        runs: RefCell<HashMap<String, usize>>,
+        sockets: RefCell<Vec<u64>>,
+        withdrawn: RefCell<Vec<String>>,
+        // Namespaces whose next run fails.
+        fail: RefCell<HashSet<String>>,
+    }
+
+    struct Alive<'a>(&'a Fake, String);
+
+    impl Drop for Alive<'_> {
+        fn drop(&mut self) {
+            *self.0.live.borrow_mut().get_mut(&self.1).unwrap() -= 1;
+        }
+    }
+
+    #[tonic::async_trait(?Send)]
+    impl Namespaces for Fake {
+        fn hostname(&self, netns: &str) -> String {
+            format!("host--{}", netns)
+        }
+
+        async fn advertise(&self, netns: &str, _hostname: &str) -> Result<()> {
+            *self.live.borrow_mut().entry(netns.to_owned()).or_default() += 1;
+            *self.runs.borrow_mut().entry(netns.to_owned()).or_default() += 1;
+            let _alive = Alive(self, netns.to_owned());
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+            self.sockets
+                .borrow_mut()
+                .push(std::fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd()))?.ino());
+            if self.fail.borrow_mut().remove(netns) {
+                anyhow::bail!("netlink socket closed");
+            }
+            future::pending().await
+        }
+
+        async fn withdraw(&self, hostname: &str) {
+            self.withdrawn.borrow_mut().push(hostname.to_owned());
+        }
+    }
+
+    impl Fake {
+        fn live(&self) -> BTreeMap<String, usize> {
+            self.live
+                .borrow()
+                .iter()
+                .filter(|(_, &n)| n > 0)
+                .map(|(netns, &n)| (netns.clone(), n))
+                .collect()
+        }
+
+        // Of the sockets runs opened, those still open.
+        fn open_sockets(&self) -> usize {
+            let open: HashSet<u64> = std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|e| std::fs::metadata(e.ok()?.path()).ok())
+                .map(|m| m.ino())
+                .collect();
+            self.sockets
+                .borrow()
+                .iter()
+                .filter(|ino| open.contains(ino))
+                .count()
+        }
+    }
+
+    fn supervisor(dir: &Path) -> Supervisor {
+        Supervisor {
+            dir: dir.to_owned(),
+            debounce: Duration::from_millis(5),
+            restart: RetryPolicy {
+                max_tries: u32::MAX,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(50),
+                max_elapsed: None,
+            },
+        }
+    }
+
+    // Drives `supervisor` alongside `test`, which it must not outlive.
+    async fn with_supervisor(
+        supervisor: &Supervisor,
+        fake: &Fake,
+        test: impl std::future::Future<Output = ()>,
+    ) {
+        tokio::select! {
+            r = supervisor.run(fake, future::pending()) => panic!("supervisor ended: {:?}", r.err()),
+            () = test => {}
+        }
+    }
+
+    async fn until(what: &str, cond: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if cond() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[tokio::test]
+    async fn churn_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = Fake::default();
+        let supervisor = supervisor(dir.path());
+        let path = |name: &str| dir.path().join(name);
+        with_supervisor(&supervisor, &fake, async {
+            std::fs::write(path("stay"), "").unwrap();
+            // As a CNI plugin would: namespaces made, replaced and removed
+            // every few milliseconds.
+            for round in 0..200u32 {
+                let name = format!("cni-{}", round % 7);
+                if path(&name).exists() {
+                    std::fs::remove_file(path(&name)).unwrap();
+                }
+                if round % 3 != 0 {
+                    std::fs::write(path(&name), "").unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis((round % 4) as u64)).await;
+            }
+            for round in 0..7 {
+                let _ = std::fs::remove_file(path(&format!("cni-{}", round)));
+            }
+            std::fs::write(path("late"), "").unwrap();
+
+            let expected: BTreeMap<String, usize> =
+                vec![("late".to_owned(), 1), ("stay".to_owned(), 1)]
+                    .into_iter()
+                    .collect();
+            until("only the remaining namespaces to run", || {
+                fake.live() == expected
+            })
+            .await;
+            until("every removed namespace to be withdrawn", || {
+                let withdrawn: HashSet<String> = fake.withdrawn.borrow().iter().cloned().collect();
+                fake.runs
+                    .borrow()
+                    .keys()
+                    .filter(|n| !expected.contains_key(*n))
+                    .all(|n| withdrawn.contains(&fake.hostname(n)))
+            })
+            .await;
+            let withdrawn = fake.withdrawn.borrow();
+            assert!(!withdrawn.contains(&"host--stay".to_owned()));
+            assert!(!withdrawn.contains(&"host--late".to_owned()));
+            assert_eq!(fake.open_sockets(), 2);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn failed_run_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = Fake::default();
+        fake.fail.borrow_mut().insert("flaky".to_owned());
+        let supervisor = supervisor(dir.path());
+        with_supervisor(&supervisor, &fake, async {
+            std::fs::write(dir.path().join("flaky"), "").unwrap();
+            until("a second run", || {
+                fake.runs.borrow().get("flaky") == Some(&2)
+            })
+            .await;
+            until("it to be running", || fake.live().get("flaky") == Some(&1)).await;
+            assert!(fake.withdrawn.borrow().is_empty());
+            assert_eq!(fake.open_sockets(), 1);
+        })
+        .await;
+    }
+
+    #[test]
+    fn hostname_fmt_needs_netns() {
+        assert!(check_hostname_fmt("{hostname}--{netns}").is_ok());
+        assert!(check_hostname_fmt("{hostname}").is_err());
+        assert_eq!(node_name("{netns}.{hostname}", "web1", "blue"), "blue.web1");
+    }
+}
@@ -0,0 +1,180 @@
+// Netlink messages and states for tests, shaped like the kernel's.
+
+use anyhow::Result;
+use rtnetlink::packet::rtnl;
+use rtnetlink::packet::rtnl::constants::RT_SCOPE_UNIVERSE;
+use rtnetlink::IpVersion;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use proto::strapper;
+
+use crate::event::Event;
+use crate::filter::{AddressFamily, AddressOptions, AddressPolicy, AddressScope, LinkFilter};
+use crate::kernel::Kernel;
+use crate::select::SelectionPolicy;
+use crate::state::AdvertisementState;
+
+pub fn link(index: u32, name: &str, mac: [u8; 6]) -> rtnl::link::LinkMessage {
+    let mut l = rtnl::link::LinkMessage::default();
+    l.header.index = index;
+    l.nlas = vec![
+        rtnl::link::nlas::Nla::IfName(name.to_owned()),
+        rtnl::link::nlas::Nla::Address(mac.to_vec()),
+    ];
+    l
+}
+
+pub fn address(index: u32, addr: &str) -> rtnl::address::AddressMessage {
+    let ip: IpAddr = addr.parse().expect("test address");
+    let (family, bytes) = match ip {
+        IpAddr::V4(a) => (libc::AF_INET, a.octets().to_vec()),
+        IpAddr::V6(a) => (libc::AF_INET6, a.octets().to_vec()),
+    };
+    let mut m = rtnl::address::AddressMessage::default();
+    m.header.family = family as u8;
+    m.header.index = index;
+    m.header.scope = RT_SCOPE_UNIVERSE;
+    m.nlas = vec![rtnl::address::nlas::Nla::Address(bytes)];
+    m
+}
+
+// As IFA_CACHEINFO carries them, in seconds.
+pub fn with_lifetimes(
+    mut m: rtnl::address::AddressMessage,
+    preferred: u32,
+    valid: u32,
+) -> rtnl::address::AddressMessage {
+    let mut info = vec![0; 16];
+    info[..4].copy_from_slice(&preferred.to_ne_bytes());
+    info[4..8].copy_from_slice(&valid.to_ne_bytes());
+    m.nlas.push(rtnl::address::nlas::Nla::CacheInfo(info));
+    m
+}
+
+// Everything the filters can be talked into taking.
+pub fn options() -> AddressOptions {
+    AddressOptions {
+        family: AddressFamily::Both,
+        scope: AddressScope::All,
+        include_ula: true,
+        include_link_local: true,
+        include_v4_cgnat: true,
+        only_labels: None,
+        exclude_labels: None,
+        vips: vec![],
+    }
+}
+
+pub fn state(exclude: &[&str]) -> AdvertisementState {
+    state_with(exclude, options())
+}
+
+pub fn state_with(exclude: &[&str], options: AddressOptions) -> AdvertisementState {
+    let links = LinkFilter {
+        exclude: exclude
+            .iter()
+            .map(|r| regex::Regex::new(r).expect("test pattern"))
+            .collect(),
+        skip_macless: false,
+        include_slaves: false,
+    };
+    AdvertisementState::new(
+        links,
+        AddressPolicy::from_options(options),
+        SelectionPolicy::All,
+        strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            ..Default::default()
+        },
+    )
+}
+
+#[derive(Default)]
+struct Tables {
+    links: Vec<rtnl::link::LinkMessage>,
+    addresses: Vec<(String, rtnl::address::AddressMessage)>,
+}
+
+// A kernel to dump from, changed along with the events that say so. Clones
+// share it, so a test keeps one while the tracker dumps from another.
+#[derive(Clone, Default)]
+pub struct FakeKernel(Rc<RefCell<Tables>>);
+
+impl FakeKernel {
+    pub fn add_link(&self, index: u32, name: &str, mac: [u8; 6]) -> Event {
+        let l = link(index, name, mac);
+        self.0.borrow_mut().links.push(l.clone());
+        Event::NewLink(l)
+    }
+
+    pub fn del_link(&self, index: u32) -> Event {
+        let mut tables = self.0.borrow_mut();
+        tables.addresses.retain(|(_, a)| a.header.index != index);
+        let pos = tables
+            .links
+            .iter()
+            .position(|l| l.header.index == index)
+            .expect("a link to delete");
+        Event::DelLink(tables.links.remove(pos))
+    }
+
+    pub fn add_address(&self, index: u32, addr: &str) -> Event {
+        let a = address(index, addr);
+        self.0
+            .borrow_mut()
+            .addresses
+            .push((addr.to_owned(), a.clone()));
+        Event::NewAddress(a)
+    }
+
+    pub fn del_address(&self, index: u32, addr: &str) -> Event {
+        let mut tables = self.0.borrow_mut();
+        let pos = tables
+            .addresses
+            .iter()
+            .position(|(a, m)| a == addr && m.header.index == index)
+            .expect("an address to delete");
+        Event::DelAddress(tables.addresses.remove(pos).1)
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Kernel for FakeKernel {
+    async fn links(&self) -> Result<Vec<rtnl::link::LinkMessage>> {
+        Ok(self.0.borrow().links.clone())
+    }
+
+    async fn addresses(
+        &self,
+        family: u8,
+        keep: &dyn Fn(u32) -> bool,
+    ) -> Result<Vec<rtnl::address::AddressMessage>> {
+        Ok(self
+            .0
+            .borrow()
+            .addresses
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.header.family == family && keep(a.header.index))
+            .cloned()
+            .collect())
+    }
+
+    async fn interface_addresses(&self, index: u32) -> Result<Vec<rtnl::address::AddressMessage>> {
+        Ok(self
+            .0
+            .borrow()
+            .addresses
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.header.index == index)
+            .cloned()
+            .collect())
+    }
+
+    async fn routes(&self, _: IpVersion) -> Result<Vec<rtnl::route::RouteMessage>> {
+        Ok(vec![])
+    }
+}
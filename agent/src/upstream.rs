@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use futures_util::future;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{oneshot, watch, Mutex};
+
+use client::{RetryPolicy, StrapperClient, Target};
+use proto::strapper;
+
+use crate::notices::{self, Notices};
+use crate::runtime;
+use crate::{output, Opt};
+
+// Which servers have to accept the first advertisement before we tell
+// systemd we're ready.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReadyRequires {
+    All,
+    Any,
+    Primary,
+}
+
+impl FromStr for ReadyRequires {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ReadyRequires::All),
+            "any" => Ok(ReadyRequires::Any),
+            "primary" => Ok(ReadyRequires::Primary),
+            _ => Err(anyhow!(
+                "unknown ready requirement '{}' (expected all, any or primary)",
+                s
+            )),
+        }
+    }
+}
+
+impl ReadyRequires {
+    // `accepted` fires once per server, the primary's first.
+    pub async fn wait(self, mut accepted: Vec<oneshot::Receiver<()>>) {
+        match self {
+            ReadyRequires::Primary => {
+                let _ = accepted.swap_remove(0).await;
+            }
+            ReadyRequires::All => {
+                future::join_all(accepted).await;
+            }
+            ReadyRequires::Any => {
+                let _ = future::select_ok(accepted).await;
+            }
+        }
+    }
+}
+
+// Sends advertisements to one server. Upstream does it over gRPC; the loop
+// in run only sees this, and waits on nothing but tokio::time, so it runs as
+// well under a paused clock against something standing in for the server.
+#[tonic::async_trait(?Send)]
+pub trait Advertiser {
+    // For messages.
+    fn name(&self) -> String;
+
+    fn primary(&self) -> bool;
+
+    // The sequence of what the server last accepted.
+    fn accepted(&self) -> Option<u64>;
+
+    async fn advertise(
+        &mut self,
+        opt: &Opt,
+        advertisement: &strapper::NodeAdvertisement,
+    ) -> Result<strapper::AdvertiseResult>;
+}
+
+// One server we advertise to: --endpoint (or whatever --discover found) or
+// an --also-endpoint. Each sends the latest advertisement on its own, so a
+// server that's down only holds up itself.
+pub struct Upstream {
+    pub target: Target,
+    pub primary: bool,
+    // Built on the first attempt and kept; the channel reconnects on its own
+    // after a keepalive failure tears the connection down.
+    pub client: Option<StrapperClient>,
+    // What this server last accepted, the base for its deltas.
+    pub accepted: Option<strapper::NodeAdvertisement>,
+    // Where `client` comes from under --all-netns.
+    pub clients: Option<Clients>,
+}
+
+impl Upstream {
+    pub fn new(target: Target, primary: bool) -> Upstream {
+        Upstream {
+            target,
+            primary,
+            client: None,
+            accepted: None,
+            clients: None,
+        }
+    }
+}
+
+// Clients by server, for the nodes of --all-netns to share, so that each
+// server gets one channel however many namespaces there are. The first node
+// to need one builds it.
+#[derive(Clone, Default)]
+pub struct Clients(Arc<Mutex<HashMap<String, StrapperClient>>>);
+
+impl Clients {
+    pub async fn get(&self, opt: &Opt, target: &Target) -> Result<StrapperClient> {
+        let mut clients = self.0.lock().await;
+        if let Some(client) = clients.get(&target.to_string()) {
+            return Ok(client.clone());
+        }
+        let client = crate::authenticated_client(opt, target).await?;
+        clients.insert(target.to_string(), client.clone());
+        Ok(client)
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Advertiser for Upstream {
+    fn name(&self) -> String {
+        self.target.to_string()
+    }
+
+    fn primary(&self) -> bool {
+        self.primary
+    }
+
+    fn accepted(&self) -> Option<u64> {
+        self.accepted.as_ref().map(|a| a.sequence)
+    }
+
+    async fn advertise(
+        &mut self,
+        opt: &Opt,
+        advertisement: &strapper::NodeAdvertisement,
+    ) -> Result<strapper::AdvertiseResult> {
+        let result = crate::advertise(opt, self, advertisement).await?;
+        self.accepted = Some(advertisement.clone());
+        Ok(result)
+    }
+}
+
+// Giving up on the primary ends the agent, as it always has, unless any
+// server will do; the others back off and try again for as long as it
+// takes.
+fn fatal(advertiser: &impl Advertiser, opt: &Opt) -> bool {
+    advertiser.primary() && opt.ready_requires != ReadyRequires::Any
+}
+
+// Sends every new advertisement in `latest` until it closes. `ready` fires
+// the first time the server holds the current one, and `hints`, if given,
+// gets the re-advertise interval it suggests each time.
+pub async fn run(
+    mut advertiser: impl Advertiser,
+    opt: &Opt,
+    mut latest: watch::Receiver<strapper::NodeAdvertisement>,
+    ready: oneshot::Sender<()>,
+    hints: Option<watch::Sender<u32>>,
+) -> Result<()> {
+    let mut ready = Some(ready);
+    // Between failed rounds, each of which already retried under
+    // --retry-max-tries.
+    let schedule = RetryPolicy {
+        max_tries: u32::MAX,
+        max_elapsed: None,
+        ..crate::retry_policy(opt)
+    };
+    let mut backoff = runtime::backoff(schedule.clone());
+    let mut failures = 0;
+    let mut notices = Notices::new(advertiser.primary(), notices::path(opt));
+    loop {
+        let advertisement = latest.borrow_and_update().clone();
+        if advertiser.accepted() != Some(advertisement.sequence) {
+            match advertiser.advertise(opt, &advertisement).await {
+                Ok(result) => {
+                    if let Some(hints) = &hints {
+                        let _ = hints.send(result.suggested_readvertise_secs);
+                    }
+                    notices.update(&advertiser.name(), result.notices).await;
+                    failures = 0;
+                    backoff = runtime::backoff(schedule.clone());
+                }
+                Err(e) if fatal(&advertiser, opt) => return Err(e),
+                Err(e) if !client::advertise_retryable(&e) => {
+                    if let Some(said) = notices::from_error(&e) {
+                        notices.update(&advertiser.name(), said).await;
+                    }
+                    // Sending it again would only be refused again.
+                    output::warning(format_args!(
+                        "{} refused the advertisement ({:#}), waiting for it to change",
+                        advertiser.name(),
+                        e
+                    ));
+                    if latest.changed().await.is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let wait = backoff.next_delay().unwrap_or(schedule.max_delay);
+                    output::warning(format_args!(
+                        "advertising to {} failed {} times in a row ({:#}), trying again in {} seconds",
+                        advertiser.name(),
+                        failures,
+                        e,
+                        wait.as_secs()
+                    ));
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
+            }
+        }
+        if let Some(ready) = ready.take() {
+            let _ = ready.send(());
+        }
+        if latest.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
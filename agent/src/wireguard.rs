@@ -0,0 +1,294 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::state::AdvertisementState;
+use crate::{netns, output, Opt};
+
+// From linux/genetlink.h and linux/wireguard.h; rtnetlink doesn't speak
+// generic netlink.
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WGDEVICE_A_IFINDEX: u16 = 1;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+// Flags share the attribute type field.
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// A generic netlink socket in the agent's namespace, or in --netns.
+struct Socket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+fn attr(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(align(buf.len()), 0);
+}
+
+fn attrs(mut payload: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if payload.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([payload[0], payload[1]]) as usize;
+        let kind = u16::from_ne_bytes([payload[2], payload[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > payload.len() {
+            return None;
+        }
+        let value = &payload[4..len];
+        payload = &payload[align(len).min(payload.len())..];
+        Some((kind, value))
+    })
+}
+
+impl Socket {
+    fn open() -> std::io::Result<Socket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Socket {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    // Sends one request and collects the generic netlink payloads (past the
+    // genl header) of its replies, up to the end of a dump.
+    fn request(
+        &mut self,
+        family: u16,
+        flags: u16,
+        cmd: u8,
+        version: u8,
+        body: &[u8],
+    ) -> std::io::Result<Vec<Vec<u8>>> {
+        self.seq += 1;
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + GENL_HDRLEN + body.len());
+        msg.extend_from_slice(&((NLMSG_HDRLEN + GENL_HDRLEN + body.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&family.to_ne_bytes());
+        msg.extend_from_slice(&(flags | libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&[cmd, version, 0, 0]);
+        msg.extend_from_slice(body);
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let dump = flags & libc::NLM_F_DUMP as u16 != 0;
+        let mut replies = vec![];
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut data = &buf[..n as usize];
+            while data.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes([data[4], data[5]]);
+                let seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    break;
+                }
+                let payload = &data[NLMSG_HDRLEN..len];
+                data = &data[align(len).min(data.len())..];
+                if seq != self.seq {
+                    continue;
+                }
+                match kind as i32 {
+                    libc::NLMSG_DONE => return Ok(replies),
+                    libc::NLMSG_ERROR => {
+                        let errno = payload
+                            .get(0..4)
+                            .map(|e| i32::from_ne_bytes(e.try_into().unwrap()))
+                            .unwrap_or(0);
+                        if errno == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(std::io::Error::from_raw_os_error(-errno));
+                    }
+                    _ if payload.len() >= GENL_HDRLEN => {
+                        replies.push(payload[GENL_HDRLEN..].to_vec());
+                    }
+                    _ => {}
+                }
+            }
+            if !dump && !replies.is_empty() {
+                return Ok(replies);
+            }
+        }
+    }
+
+    // None when the wireguard module isn't loaded.
+    fn wireguard_family(&mut self) -> std::io::Result<Option<u16>> {
+        let mut body = vec![];
+        attr(
+            &mut body,
+            CTRL_ATTR_FAMILY_NAME,
+            format!("{}\0", WG_GENL_NAME).as_bytes(),
+        );
+        let replies = match self.request(GENL_ID_CTRL, 0, CTRL_CMD_GETFAMILY, 1, &body) {
+            Ok(replies) => replies,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(replies.iter().find_map(|r| {
+            attrs(r)
+                .find(|(kind, value)| *kind == CTRL_ATTR_FAMILY_ID && value.len() == 2)
+                .map(|(_, value)| u16::from_ne_bytes([value[0], value[1]]))
+        }))
+    }
+
+    fn public_key(&mut self, family: u16, index: u32) -> std::io::Result<Option<[u8; 32]>> {
+        let mut body = vec![];
+        attr(&mut body, WGDEVICE_A_IFINDEX, &index.to_ne_bytes());
+        // Only the first message of the dump has the device's own
+        // attributes; the rest are its peers.
+        let replies = self.request(
+            family,
+            libc::NLM_F_DUMP as u16,
+            WG_CMD_GET_DEVICE,
+            WG_GENL_VERSION,
+            &body,
+        )?;
+        Ok(replies.iter().find_map(|r| {
+            attrs(r)
+                .find(|(kind, _)| *kind == WGDEVICE_A_PUBLIC_KEY)
+                .and_then(|(_, value)| value.try_into().ok())
+        }))
+    }
+}
+
+// The public keys of the wireguard devices at `indexes`, base64 encoded as
+// `wg` prints them. A device without a key configured, or gone by the time
+// it's asked about, is left out.
+pub fn public_keys(netns: Option<&Path>, indexes: &[u32]) -> Result<HashMap<u32, String>> {
+    let mut socket = match netns {
+        Some(path) => netns::in_netns(path, Socket::open)?,
+        None => Socket::open()?,
+    };
+    let family = match socket
+        .wireguard_family()
+        .context("error looking up the wireguard generic netlink family")?
+    {
+        Some(family) => family,
+        None => return Ok(HashMap::new()),
+    };
+    let mut keys = HashMap::new();
+    for &index in indexes {
+        match socket.public_key(family, index) {
+            Ok(Some(key)) => {
+                keys.insert(index, base64::encode(key));
+            }
+            Ok(None) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                return Err(anyhow!("reading wireguard keys requires CAP_NET_ADMIN"));
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("error reading wireguard device {}", index));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+// Keeps the public keys of the advertised wireguard interfaces current.
+// Nothing on rtnetlink says when a key changes, so besides links coming and
+// going, they're read again every --wireguard-key-interval-secs.
+pub struct Keys {
+    netns: Option<PathBuf>,
+    // Set after a failed read has been warned about, so a missing capability
+    // isn't repeated every interval.
+    failing: bool,
+}
+
+impl Keys {
+    pub fn new(opt: &Opt) -> Option<Keys> {
+        if opt.skip_wireguard_keys {
+            return None;
+        }
+        Some(Keys {
+            netns: opt.netns.as_deref().map(netns::resolve),
+            failing: false,
+        })
+    }
+
+    // Returns whether any interface's key changed. Failing to read them
+    // leaves them out rather than failing enumeration.
+    pub async fn refresh(&mut self, state: &mut AdvertisementState) -> bool {
+        let indexes: Vec<u32> = state
+            .interfaces()
+            .iter()
+            .filter(|i| i.kind == "wireguard")
+            .map(|i| i.index)
+            .collect();
+        if indexes.is_empty() {
+            return state.set_wireguard_keys(&HashMap::new());
+        }
+        let netns = self.netns.clone();
+        let read = tokio::task::spawn_blocking(move || public_keys(netns.as_deref(), &indexes))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        let keys = match read {
+            Ok(keys) => {
+                if self.failing {
+                    output::info("reading wireguard keys again");
+                    self.failing = false;
+                }
+                keys
+            }
+            Err(e) => {
+                if !self.failing {
+                    output::warning(format_args!(
+                        "{:#}; advertising wireguard interfaces without their public keys",
+                        e
+                    ));
+                    self.failing = true;
+                }
+                HashMap::new()
+            }
+        };
+        state.set_wireguard_keys(&keys)
+    }
+}
@@ -0,0 +1,187 @@
+//! Retry schedules: exponential backoff with full jitter.
+//!
+//! Without jitter every client that lost the server at the same moment comes
+//! back at the same moments too, so the recovering server sees synchronized
+//! spikes. With full jitter each wait is drawn uniformly between zero and the
+//! exponential cap.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+use crate::RetryPolicy;
+
+/// Where a [`Backoff`] gets the current time from.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The waits between attempts of one operation under a [`RetryPolicy`].
+pub struct Backoff<R = StdRng, C = SystemClock> {
+    policy: RetryPolicy,
+    rng: R,
+    clock: C,
+    started: Instant,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Backoff {
+        Backoff::with_rng_and_clock(policy, StdRng::from_entropy(), SystemClock)
+    }
+}
+
+impl<R: Rng, C: Clock> Backoff<R, C> {
+    /// A backoff drawing from `rng` and timing itself with `clock`, for
+    /// checking schedules without sleeping.
+    pub fn with_rng_and_clock(policy: RetryPolicy, rng: R, clock: C) -> Backoff<R, C> {
+        let started = clock.now();
+        Backoff {
+            policy,
+            rng,
+            clock,
+            started,
+            attempt: 0,
+        }
+    }
+
+    /// How many attempts have failed so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next
+    /// one, or `None` once the policy's tries or elapsed time are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let cap = self.policy.delay(self.attempt);
+        self.attempt += 1;
+        if self.attempt >= self.policy.max_tries {
+            return None;
+        }
+
+        let mut delay = self.rng.gen_range(Duration::ZERO..=cap);
+        if let Some(max_elapsed) = self.policy.max_elapsed {
+            let remaining = max_elapsed.checked_sub(self.clock.now() - self.started)?;
+            if remaining.is_zero() {
+                return None;
+            }
+            delay = delay.min(remaining);
+        }
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use super::{Backoff, Clock};
+    use crate::RetryPolicy;
+
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn policy(max_elapsed: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_tries: 6,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(8),
+            max_elapsed,
+        }
+    }
+
+    fn schedule(seed: u64) -> Vec<Duration> {
+        let mut b = Backoff::with_rng_and_clock(
+            policy(None),
+            StdRng::seed_from_u64(seed),
+            FakeClock::new(),
+        );
+        std::iter::from_fn(|| b.next_delay()).collect()
+    }
+
+    #[test]
+    fn under_the_caps() {
+        let caps = [1, 2, 4, 8, 8].map(Duration::from_secs);
+        for seed in 0..50 {
+            let delays = schedule(seed);
+            // The sixth try is the last, so there are five waits.
+            assert_eq!(delays.len(), caps.len());
+            for (d, cap) in delays.iter().zip(caps.iter()) {
+                assert!(d <= cap, "seed {}: {:?} over {:?}", seed, d, cap);
+            }
+        }
+    }
+
+    #[test]
+    fn jittered() {
+        assert_eq!(schedule(7), schedule(7));
+        assert_ne!(schedule(7), schedule(8));
+
+        // Draws for the same attempt spread across the whole range.
+        let mut rng = StdRng::seed_from_u64(1);
+        let firsts: Vec<_> = (0..200)
+            .map(|_| {
+                let mut b = Backoff::with_rng_and_clock(
+                    RetryPolicy {
+                        base_delay: Duration::from_secs(8),
+                        ..policy(None)
+                    },
+                    &mut rng,
+                    FakeClock::new(),
+                );
+                b.next_delay().unwrap()
+            })
+            .collect();
+        assert!(firsts.iter().any(|d| *d < Duration::from_secs(1)));
+        assert!(firsts.iter().any(|d| *d > Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn max_elapsed() {
+        let clock = FakeClock::new();
+        let mut b = Backoff::with_rng_and_clock(
+            RetryPolicy {
+                max_tries: 100,
+                ..policy(Some(Duration::from_secs(10)))
+            },
+            StdRng::seed_from_u64(3),
+            clock.clone(),
+        );
+        clock.advance(Duration::from_millis(9500));
+        let d = b.next_delay().unwrap();
+        assert!(d <= Duration::from_millis(500), "{:?}", d);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(b.next_delay(), None);
+        assert_eq!(b.attempt(), 2);
+    }
+}
@@ -0,0 +1,704 @@
+//! Client library for registering nodes with a strapper server.
+//!
+//! This is the same advertise and retry logic the netlink agent uses, for
+//! programs that know their own interfaces and addresses and just need to
+//! send them.
+//!
+//! ```no_run
+//! use client::{InterfaceBuilder, NodeAdvertisementBuilder, RetryPolicy, StrapperClient};
+//!
+//! # async fn register() -> anyhow::Result<()> {
+//! let advertisement = NodeAdvertisementBuilder::new("appliance01")
+//!     .interface(
+//!         InterfaceBuilder::new("eth0", 2)
+//!             .mac("52:54:00:12:34:56")
+//!             .address("2001:db8::10")
+//!             .address("10.0.0.10")
+//!             .build()?,
+//!     )
+//!     .build()?;
+//!
+//! let mut client = StrapperClient::connect("http://leader.infra.ibj.io:55555".parse()?).await?;
+//! client
+//!     .advertise_with_retry(&advertisement, &RetryPolicy::default(), |e, try_cnt, wait| {
+//!         eprintln!("advertise failed ({}, try {}), retrying in {:?}", e, try_cnt, wait)
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backoff;
+pub mod trace;
+
+use anyhow::{anyhow, Result};
+use prost::Message;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use proto::strapper::{self, node_state_service_client::NodeStateServiceClient};
+
+use backoff::Backoff;
+
+/// A connection to a strapper server's `NodeStateService`.
+///
+/// The underlying channel reconnects on its own, so one client can be kept
+/// for the lifetime of a program.
+#[derive(Clone)]
+pub struct StrapperClient {
+    channel: Channel,
+    inner: NodeStateServiceClient<Channel>,
+    max_message_bytes: usize,
+}
+
+/// The largest advertisement a client sends unless told otherwise, matching
+/// the server's default `--max-advertisement-bytes`.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+
+impl StrapperClient {
+    /// Connects to the server at `uri`, failing if it cannot be reached.
+    pub async fn connect(uri: Uri) -> Result<StrapperClient> {
+        let channel = Endpoint::from(uri).connect().await?;
+        Ok(StrapperClient::from_channel(channel))
+    }
+
+    /// Builds a client over `endpoint` without connecting until the first
+    /// request, so endpoint options such as keepalives apply to every
+    /// reconnect.
+    pub fn connect_lazy(endpoint: Endpoint) -> Result<StrapperClient> {
+        Ok(StrapperClient::from_channel(endpoint.connect_lazy()?))
+    }
+
+    /// Connects over a unix domain socket. `endpoint` only supplies
+    /// connection options; its URI is ignored.
+    pub async fn connect_unix(endpoint: Endpoint, path: PathBuf) -> Result<StrapperClient> {
+        let channel = endpoint
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                tokio::net::UnixStream::connect(path.clone())
+            }))
+            .await?;
+        Ok(StrapperClient::from_channel(channel))
+    }
+
+    /// Connects to `target`: lazily for URIs, as [`StrapperClient::connect_lazy`]
+    /// does, and right away for unix sockets, so a missing socket shows up
+    /// here. `endpoint` supplies connection options.
+    pub async fn connect_target(target: &Target, endpoint: Endpoint) -> Result<StrapperClient> {
+        match target {
+            Target::Uri(_) => StrapperClient::connect_lazy(endpoint),
+            Target::Unix(path) => StrapperClient::connect_unix(endpoint, path.clone()).await,
+        }
+    }
+
+    pub fn from_channel(channel: Channel) -> StrapperClient {
+        StrapperClient {
+            inner: NodeStateServiceClient::new(channel.clone()),
+            channel,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+
+    /// Refuses to send advertisements that encode to more than `bytes`,
+    /// failing with [`MessageTooLarge`] rather than leaving the server to
+    /// reject them.
+    pub fn with_max_message_size(mut self, bytes: usize) -> StrapperClient {
+        self.max_message_bytes = bytes;
+        self
+    }
+
+    /// Attaches `authorization: Bearer <token>` to every request made by
+    /// this client, for servers started with `--auth-token-file`.
+    // tonic's interceptors return a Status.
+    #[allow(clippy::result_large_err)]
+    pub fn with_auth_token(self, token: &str) -> Result<StrapperClient> {
+        let value: MetadataValue<Ascii> = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| anyhow!("auth token contains invalid characters"))?;
+        let inner = NodeStateServiceClient::with_interceptor(
+            self.channel.clone(),
+            move |mut req: tonic::Request<()>| {
+                req.metadata_mut().insert("authorization", value.clone());
+                Ok(req)
+            },
+        );
+        Ok(StrapperClient {
+            channel: self.channel,
+            inner,
+            max_message_bytes: self.max_message_bytes,
+        })
+    }
+
+    /// Asks the server what `advertisement` would change in DNS, without
+    /// writing or recording anything.
+    pub async fn plan_advertise(
+        &mut self,
+        advertisement: &strapper::NodeAdvertisement,
+    ) -> Result<strapper::AdvertisePlan> {
+        Ok(self
+            .inner
+            .plan_advertise(advertisement.clone())
+            .await?
+            .into_inner())
+    }
+
+    /// Removes a node and its records from the server.
+    pub async fn deregister(&mut self, hostname: &str) -> Result<()> {
+        let request = strapper::DeregisterRequest {
+            hostname: hostname.to_owned(),
+        };
+        self.inner.deregister(request).await?;
+        Ok(())
+    }
+
+    /// Fetches every node the server knows, and the records it has
+    /// quarantined.
+    pub async fn list_nodes(&mut self) -> Result<strapper::NodeList> {
+        Ok(self.inner.list_nodes(()).await?.into_inner())
+    }
+
+    /// Fetches what the server recently did for nodes matching `request`,
+    /// newest first.
+    pub async fn history(
+        &mut self,
+        request: strapper::HistoryRequest,
+    ) -> Result<Vec<strapper::HistoryEntry>> {
+        Ok(self.inner.get_history(request).await?.into_inner().entries)
+    }
+
+    /// Fetches the server's version, uptime and counters.
+    pub async fn status(&mut self) -> Result<strapper::ServerStatus> {
+        Ok(self.inner.get_status(()).await?.into_inner())
+    }
+
+    /// Fetches the configuration the server is running with, secrets left
+    /// out.
+    pub async fn config(&mut self) -> Result<strapper::ServerConfig> {
+        Ok(self.inner.get_config(()).await?.into_inner())
+    }
+
+    /// Pauses or resumes writes to `zone`, or to every zone if it is empty.
+    /// Resuming returns once the writes held for the zone have been applied.
+    pub async fn set_write_pause(
+        &mut self,
+        zone: &str,
+        paused: bool,
+    ) -> Result<strapper::WritePauseState> {
+        let request = strapper::WritePauseRequest {
+            zone: zone.to_owned(),
+            paused,
+        };
+        Ok(self.inner.set_write_pause(request).await?.into_inner())
+    }
+
+    /// Freezes `hostname`'s records in pdns, for `duration` or until
+    /// [`unfreeze_node`](Self::unfreeze_node). The node need not be known
+    /// to the server yet.
+    pub async fn freeze_node(
+        &mut self,
+        hostname: &str,
+        duration: Option<Duration>,
+    ) -> Result<strapper::FrozenNode> {
+        let request = strapper::FreezeRequest {
+            hostname: hostname.to_owned(),
+            duration_secs: duration.map_or(0, |d| d.as_secs().max(1)),
+        };
+        Ok(self.inner.freeze_node(request).await?.into_inner())
+    }
+
+    /// Unfreezes `hostname`, returning once what it advertised meanwhile
+    /// has been written. Unfreezing a node that isn't frozen does nothing.
+    pub async fn unfreeze_node(&mut self, hostname: &str) -> Result<()> {
+        let request = strapper::UnfreezeRequest {
+            hostname: hostname.to_owned(),
+        };
+        self.inner.unfreeze_node(request).await?;
+        Ok(())
+    }
+
+    /// Fetches where pdns differs from what the server's registry comes to:
+    /// the last report, or with `refresh` (or if there isn't one yet) a
+    /// fresh one.
+    pub async fn drift(&mut self, refresh: bool) -> Result<strapper::DriftReport> {
+        let request = strapper::DriftRequest { refresh };
+        Ok(self.inner.get_drift(request).await?.into_inner())
+    }
+
+    /// Makes the server a primary or a standby. Promoting a standby returns
+    /// once it has written its registry to pdns.
+    pub async fn set_role(&mut self, role: strapper::Role) -> Result<strapper::ServerStatus> {
+        let request = strapper::RoleRequest { role: role as i32 };
+        Ok(self.inner.set_role(request).await?.into_inner())
+    }
+
+    /// Sends one advertisement, without retrying, under a fresh request id.
+    /// Returns what the server made of it, including the id it acknowledged;
+    /// failures are an [`AdvertiseError`] carrying the id that was sent.
+    /// With the `otel` feature the caller's trace context goes along; see
+    /// [`trace::inject`].
+    pub async fn advertise(
+        &mut self,
+        advertisement: &strapper::NodeAdvertisement,
+    ) -> Result<strapper::AdvertiseResult> {
+        let size = advertisement.encoded_len();
+        if size > self.max_message_bytes {
+            return Err(MessageTooLarge {
+                size,
+                limit: self.max_message_bytes,
+            }
+            .into());
+        }
+        let request_id = new_request_id();
+        let mut request = tonic::Request::new(advertisement.clone());
+        request
+            .metadata_mut()
+            .insert(REQUEST_ID_METADATA, request_id.parse()?);
+        trace::inject(request.metadata_mut());
+        match self.inner.advertise(request).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(status) => Err(AdvertiseError { request_id, status }.into()),
+        }
+    }
+
+    /// Sends the changes since the advertisement the server last accepted
+    /// from this agent, without retrying. A server without that base fails
+    /// it with an [`AdvertiseError`] for which
+    /// [`AdvertiseError::resync_required`] is true; send a full advertisement
+    /// then.
+    pub async fn advertise_delta(
+        &mut self,
+        delta: &strapper::AdvertisementDelta,
+    ) -> Result<strapper::AdvertiseResult> {
+        let size = delta.encoded_len();
+        if size > self.max_message_bytes {
+            return Err(MessageTooLarge {
+                size,
+                limit: self.max_message_bytes,
+            }
+            .into());
+        }
+        let request_id = new_request_id();
+        let mut request = tonic::Request::new(delta.clone());
+        request
+            .metadata_mut()
+            .insert(REQUEST_ID_METADATA, request_id.parse()?);
+        trace::inject(request.metadata_mut());
+        match self.inner.advertise_delta(request).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(status) => Err(AdvertiseError { request_id, status }.into()),
+        }
+    }
+
+    /// [`StrapperClient::advertise_delta`], retried as
+    /// [`StrapperClient::advertise_with_retry`] does; a resync-required
+    /// failure is one of those not retried.
+    pub async fn advertise_delta_with_retry<F>(
+        &mut self,
+        delta: &strapper::AdvertisementDelta,
+        policy: &RetryPolicy,
+        mut on_retry: F,
+    ) -> Result<strapper::AdvertiseResult>
+    where
+        F: FnMut(&anyhow::Error, u32, Duration),
+    {
+        let mut backoff = Backoff::new(policy.clone());
+        loop {
+            let e = match self.advertise_delta(delta).await {
+                Ok(result) => return Ok(result),
+                Err(e) if !advertise_retryable(&e) => return Err(e),
+                Err(e) => e,
+            };
+            let try_cnt = backoff.attempt();
+            let wait = match backoff.next_delay() {
+                Some(wait) => wait,
+                None => return Err(e.context("advertise exceeded tries")),
+            };
+            let wait = e
+                .downcast_ref::<AdvertiseError>()
+                .and_then(|e| retry_after_hint(&e.status))
+                .unwrap_or(wait);
+            on_retry(&e, try_cnt, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Sends an advertisement, retrying with jittered exponential backoff
+    /// according to `policy`. `on_retry` is called with the error, the attempt number and
+    /// the time until the next attempt before each wait. Returns the result
+    /// of the attempt that succeeded.
+    ///
+    /// A `ResourceExhausted` or `Unavailable` response carrying a
+    /// retry-after hint, from the server's rate limiter or a server waiting
+    /// for pdns, replaces the normal backoff for that attempt.
+    /// A [`MessageTooLarge`] advertisement isn't retried, nor is a failure
+    /// [`AdvertiseError::retryable`] says can't go differently.
+    pub async fn advertise_with_retry<F>(
+        &mut self,
+        advertisement: &strapper::NodeAdvertisement,
+        policy: &RetryPolicy,
+        mut on_retry: F,
+    ) -> Result<strapper::AdvertiseResult>
+    where
+        F: FnMut(&anyhow::Error, u32, Duration),
+    {
+        let mut backoff = Backoff::new(policy.clone());
+        loop {
+            let e = match self.advertise(advertisement).await {
+                Ok(result) => return Ok(result),
+                Err(e) if !advertise_retryable(&e) => return Err(e),
+                Err(e) => e,
+            };
+            let try_cnt = backoff.attempt();
+            let wait = match backoff.next_delay() {
+                Some(wait) => wait,
+                None => return Err(e.context("advertise exceeded tries")),
+            };
+            let wait = e
+                .downcast_ref::<AdvertiseError>()
+                .and_then(|e| retry_after_hint(&e.status))
+                .unwrap_or(wait);
+            on_retry(&e, try_cnt, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Whether an error from one of the advertise calls is worth retrying:
+/// anything but a [`MessageTooLarge`] or an [`AdvertiseError`] that isn't
+/// [`AdvertiseError::retryable`].
+pub fn advertise_retryable(e: &anyhow::Error) -> bool {
+    !e.is::<MessageTooLarge>()
+        && e.downcast_ref::<AdvertiseError>()
+            .is_none_or(AdvertiseError::retryable)
+}
+
+/// Formats a MAC address the way advertisements carry it: lowercase hex
+/// octets separated by colons, independent of eui48's default notation.
+pub fn format_mac(mac: &[u8]) -> String {
+    proto::node::format_mac(mac)
+}
+
+/// The gRPC metadata key carrying an advertisement's request id.
+pub const REQUEST_ID_METADATA: &str = "x-request-id";
+
+/// A random (version 4) UUID.
+pub fn new_request_id() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        u16::from_be_bytes([b[4], b[5]]),
+        u16::from_be_bytes([b[6], b[7]]),
+        u16::from_be_bytes([b[8], b[9]]),
+        u64::from_be_bytes([0, 0, b[10], b[11], b[12], b[13], b[14], b[15]])
+    )
+}
+
+/// A rejected advertisement, along with the request id the server logged it
+/// under.
+#[derive(Debug)]
+pub struct AdvertiseError {
+    pub request_id: String,
+    pub status: tonic::Status,
+}
+
+impl AdvertiseError {
+    /// Whether the server rejected a delta for lack of the advertisement it
+    /// was based on.
+    pub fn resync_required(&self) -> bool {
+        self.status.code() == tonic::Code::FailedPrecondition
+            && self.status.details() == proto::delta::RESYNC_REQUIRED
+    }
+
+    /// Whether sending the same thing again could go differently. A
+    /// `FailedPrecondition` is the server refusing it as configured, for
+    /// instance because no remapper takes any of its addresses, so it can't.
+    pub fn retryable(&self) -> bool {
+        self.status.code() != tonic::Code::FailedPrecondition
+    }
+}
+
+impl std::fmt::Display for AdvertiseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (request {})", self.status, self.request_id)
+    }
+}
+
+impl std::error::Error for AdvertiseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.status)
+    }
+}
+
+/// An advertisement [`StrapperClient::advertise`] refused to send because
+/// it encodes to more than the client's limit.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "advertisement of {} bytes exceeds the limit of {}",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// Where a server listens: a URI, or `unix:<path>` for a unix domain socket.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Uri(Uri),
+    Unix(PathBuf),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(anyhow!("unix socket path is empty")),
+            Some(path) => Ok(Target::Unix(PathBuf::from(path))),
+            None => Ok(Target::Uri(s.parse()?)),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::Uri(uri) => write!(f, "{}", uri),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Target {
+    /// An [`Endpoint`] to hang connection options on. Unix targets get a
+    /// placeholder URI, which only shows up as the HTTP/2 authority.
+    pub fn endpoint(&self) -> Endpoint {
+        match self {
+            Target::Uri(uri) => Endpoint::from(uri.clone()),
+            Target::Unix(_) => Endpoint::from_static("http://localhost"),
+        }
+    }
+}
+
+/// How many times, and how patiently, [`StrapperClient::advertise_with_retry`]
+/// tries before giving up. See [`backoff`] for how waits are drawn.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_tries: u32,
+    pub base_delay: Duration,
+    /// Caps the exponential growth of the wait.
+    pub max_delay: Duration,
+    /// Gives up once this long has passed since the first attempt.
+    pub max_elapsed: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_tries: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_elapsed: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The longest wait after the given (zero-based) failed attempt; the
+    /// actual wait is drawn below it.
+    pub fn delay(&self, try_cnt: u32) -> Duration {
+        2_u32
+            .checked_pow(try_cnt)
+            .and_then(|m| self.base_delay.checked_mul(m))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// Reads the first token from a token file (one per line, `#` comments),
+/// returning it along with whether the file is readable by everyone.
+pub fn read_token_file(path: &Path) -> Result<(String, bool)> {
+    let world_readable = std::fs::metadata(path)
+        .map_err(|e| anyhow!("error reading {}: {}", path.display(), e))?
+        .permissions()
+        .mode()
+        & 0o004
+        != 0;
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("error reading {}: {}", path.display(), e))?
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .ok_or_else(|| anyhow!("{} contains no token", path.display()))?
+        .to_owned();
+    Ok((token, world_readable))
+}
+
+/// Extracts the wait requested by a rate-limited server, or one still
+/// waiting for pdns, if `status` is from one.
+pub fn retry_after_hint(status: &tonic::Status) -> Option<Duration> {
+    if !matches!(
+        status.code(),
+        tonic::Code::ResourceExhausted | tonic::Code::Unavailable
+    ) {
+        return None;
+    }
+    let (_, hint) = status.message().split_once("retry after ")?;
+    hint.trim_end_matches('s')
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Builds a validated [`strapper::Interface`].
+pub struct InterfaceBuilder {
+    iface: strapper::Interface,
+}
+
+impl InterfaceBuilder {
+    pub fn new(name: impl Into<String>, index: u32) -> InterfaceBuilder {
+        InterfaceBuilder {
+            iface: strapper::Interface {
+                name: name.into(),
+                index,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets the MAC address, in any notation `eui48` accepts. Interfaces
+    /// without a link-layer address can leave this unset.
+    pub fn mac(mut self, mac: impl Into<String>) -> InterfaceBuilder {
+        self.iface.mac = mac.into();
+        self
+    }
+
+    pub fn address(mut self, addr: impl Into<String>) -> InterfaceBuilder {
+        self.iface.ipaddr.push(addr.into());
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u32) -> InterfaceBuilder {
+        self.iface.mtu = mtu;
+        self
+    }
+
+    pub fn oper_state(mut self, state: strapper::OperState) -> InterfaceBuilder {
+        self.iface.oper_state = state as i32;
+        self
+    }
+
+    pub fn kind(mut self, kind: impl Into<String>) -> InterfaceBuilder {
+        self.iface.kind = kind.into();
+        self
+    }
+
+    /// Sets the 802.1Q id, for VLAN interfaces.
+    pub fn vlan_id(mut self, id: u32) -> InterfaceBuilder {
+        self.iface.vlan_id = Some(id);
+        self
+    }
+
+    /// Sets the index of the link this one sits on.
+    pub fn parent_index(mut self, index: u32) -> InterfaceBuilder {
+        self.iface.parent_index = Some(index);
+        self
+    }
+
+    /// Sets the base64 public key, for wireguard interfaces.
+    pub fn wireguard_public_key(mut self, key: impl Into<String>) -> InterfaceBuilder {
+        self.iface.wireguard_public_key = Some(key.into());
+        self
+    }
+
+    /// Validates the name, MAC, addresses and wireguard key (see
+    /// [`strapper::Interface::problems`]), normalizing the MAC to lowercase
+    /// colon-separated form and addresses to their canonical text.
+    pub fn build(mut self) -> Result<strapper::Interface> {
+        if let Some(problem) = self.iface.problems().into_iter().next() {
+            return Err(problem.into());
+        }
+        if let Some(mac) = proto::node::normalize_mac(&self.iface.mac) {
+            self.iface.mac = mac;
+        }
+        for a in self.iface.ipaddr.iter_mut() {
+            if let Ok(ip) = a.parse::<IpAddr>() {
+                *a = ip.to_string();
+            }
+        }
+        Ok(self.iface)
+    }
+}
+
+/// Builds a validated [`strapper::NodeAdvertisement`].
+pub struct NodeAdvertisementBuilder {
+    advertisement: strapper::NodeAdvertisement,
+}
+
+impl NodeAdvertisementBuilder {
+    pub fn new(hostname: impl Into<String>) -> NodeAdvertisementBuilder {
+        NodeAdvertisementBuilder {
+            advertisement: strapper::NodeAdvertisement {
+                hostname: hostname.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn interface(mut self, iface: strapper::Interface) -> NodeAdvertisementBuilder {
+        self.advertisement.interfaces.push(iface);
+        self
+    }
+
+    pub fn default_route(mut self, route: strapper::Route) -> NodeAdvertisementBuilder {
+        self.advertisement.default_routes.push(route);
+        self
+    }
+
+    /// Adds a label for remappers to select on, replacing any earlier value
+    /// for the same key.
+    pub fn label(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> NodeAdvertisementBuilder {
+        self.advertisement.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Has the server keep the node's records in the zone but disabled.
+    pub fn disabled(mut self, disabled: bool) -> NodeAdvertisementBuilder {
+        self.advertisement.disabled = disabled;
+        self
+    }
+
+    /// Caps the TTL the server publishes the node's records with.
+    pub fn ttl_override(mut self, ttl: u32) -> NodeAdvertisementBuilder {
+        self.advertisement.ttl_override = Some(ttl);
+        self
+    }
+
+    /// Validates the whole advertisement, interfaces included, failing with
+    /// the first of [`strapper::NodeAdvertisement::validate`]'s problems.
+    pub fn build(self) -> Result<strapper::NodeAdvertisement> {
+        if let Err(mut problems) = self.advertisement.validate() {
+            return Err(problems.swap_remove(0).into());
+        }
+        Ok(self.advertisement)
+    }
+}
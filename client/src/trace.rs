@@ -0,0 +1,36 @@
+//! W3C trace context for outgoing advertisements.
+
+use tonic::metadata::MetadataMap;
+
+/// Adds the caller's current OpenTelemetry context to `metadata` as a
+/// `traceparent` (and `tracestate`), through the global propagator. Does
+/// nothing without the `otel` feature.
+#[cfg(feature = "otel")]
+pub fn inject(metadata: &mut MetadataMap) {
+    use opentelemetry::propagation::Injector;
+    use opentelemetry::{global, Context};
+    use tonic::metadata::{MetadataKey, MetadataValue};
+
+    struct Metadata<'a>(&'a mut MetadataMap);
+
+    impl Injector for Metadata<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(key), Ok(value)) = (
+                MetadataKey::from_bytes(key.as_bytes()),
+                MetadataValue::from_str(&value),
+            ) {
+                self.0.insert(key, value);
+            }
+        }
+    }
+
+    global::get_text_map_propagator(|p| {
+        p.inject_context(&Context::current(), &mut Metadata(metadata))
+    });
+}
+
+/// Adds the caller's current OpenTelemetry context to `metadata` as a
+/// `traceparent` (and `tracestate`), through the global propagator. Does
+/// nothing without the `otel` feature.
+#[cfg(not(feature = "otel"))]
+pub fn inject(_metadata: &mut MetadataMap) {}
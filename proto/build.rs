@@ -2,6 +2,21 @@ fn main() {
 /*    tonic_build::configure()
         .out_dir("src/")
         .format(true)
-        .compile(&["proto/strapper.proto"], &["proto"])
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .file_descriptor_set_path("src/descriptor.bin")
+        .compile_with_config(
+            labels_config(),
+            &["proto/strapper.proto", "proto/reflection.proto"],
+            &["proto"],
+        )
         .unwrap()*/
 }
+
+// Labels go in a BTreeMap so they encode in key order, and canonical
+// hashes don't depend on the order a HashMap happens to iterate in.
+#[allow(dead_code)]
+fn labels_config() -> prost_build::Config {
+    let mut config = prost_build::Config::new();
+    config.btree_map([".strapper.NodeAdvertisement.labels"]);
+    config
+}
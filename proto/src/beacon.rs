@@ -0,0 +1,51 @@
+// The UDP beacon a server started with --announce sends and an agent started
+// with --discover listens for: a magic, then an HMAC-SHA256 tag over the
+// encoded strapper.Beacon that follows, so only holders of the shared key can
+// point agents somewhere.
+
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use prost::Message;
+
+use crate::strapper;
+
+// Older beacons (or ones from this far in the future) are refused, which
+// bounds how long a captured one can be replayed.
+pub const MAX_AGE_SECS: u64 = 300;
+
+const MAGIC: &[u8] = b"strapper-beacon1";
+const TAG_LEN: usize = 32;
+
+fn tag(key: &[u8], payload: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(payload)?;
+    signer.sign_to_vec()
+}
+
+pub fn encode(
+    beacon: &strapper::Beacon,
+    key: &[u8],
+) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let mut payload = Vec::with_capacity(beacon.encoded_len());
+    // Only fails when out of buffer space, and a Vec grows.
+    beacon.encode(&mut payload).unwrap();
+    let mut packet = MAGIC.to_vec();
+    packet.extend(tag(key, &payload)?);
+    packet.extend(payload);
+    Ok(packet)
+}
+
+// The beacon in `packet` if it's well formed and was signed with `key`. The
+// timestamp is left for the caller to check against its clock.
+pub fn decode(packet: &[u8], key: &[u8]) -> Result<strapper::Beacon, String> {
+    let rest = packet.strip_prefix(MAGIC).ok_or("not a strapper beacon")?;
+    if rest.len() < TAG_LEN {
+        return Err("truncated beacon".to_owned());
+    }
+    let (presented, payload) = rest.split_at(TAG_LEN);
+    let expected = tag(key, payload).map_err(|e| e.to_string())?;
+    if !memcmp::eq(presented, &expected) {
+        return Err("bad signature (is the key the same as the server's?)".to_owned());
+    }
+    strapper::Beacon::decode(payload).map_err(|e| format!("invalid beacon: {}", e))
+}
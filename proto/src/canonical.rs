@@ -0,0 +1,245 @@
+// One answer to "is this the same advertisement" for the agent and server:
+// a SHA-256 over a normalized copy, so the interface and address order the
+// kernel happened to report, MAC case, and fields that change without the
+// node changing (sequence, start time, observation time, lifetimes) don't
+// count.
+
+use openssl::sha::sha256;
+use prost::Message;
+use std::net::IpAddr;
+
+use crate::strapper;
+
+pub fn canonicalize(adv: &strapper::NodeAdvertisement) -> strapper::NodeAdvertisement {
+    let mut adv = adv.clone();
+    adv.sequence = 0;
+    adv.agent_start_time = 0;
+    adv.observed_ms = 0;
+    // Set by the server, not the agent.
+    adv.effective_hostname.clear();
+    adv.source_address.clear();
+    adv.self_advertised = false;
+    adv.interfaces.sort_by_key(|i| i.index);
+    for i in adv.interfaces.iter_mut() {
+        i.mac = i.mac.to_ascii_lowercase();
+        i.ipaddr
+            .sort_by_cached_key(|a| (a.parse::<IpAddr>().ok(), a.clone()));
+        i.address_info.sort_by(|a, b| a.address.cmp(&b.address));
+        for info in i.address_info.iter_mut() {
+            info.preferred_lifetime = 0;
+            info.valid_lifetime = 0;
+        }
+    }
+    adv.default_routes
+        .sort_by(|a, b| (a.index, &a.gateway, a.metric).cmp(&(b.index, &b.gateway, b.metric)));
+    adv.services
+        .sort_by(|a, b| (&a.name, &a.protocol, a.port).cmp(&(&b.name, &b.protocol, b.port)));
+    adv
+}
+
+pub fn canonical_hash(adv: &strapper::NodeAdvertisement) -> [u8; 32] {
+    let adv = canonicalize(adv);
+    let mut buf = Vec::with_capacity(adv.encoded_len());
+    // Only fails when out of buffer space, and a Vec grows.
+    adv.encode(&mut buf).unwrap();
+    sha256(&buf)
+}
+
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Enough of the hash to match an agent's log line to the server's.
+pub fn short_hash(adv: &strapper::NodeAdvertisement) -> String {
+    hex(&canonical_hash(adv)[..6])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A named change to make to node().
+    type Change = (&'static str, fn(&mut strapper::NodeAdvertisement));
+
+    fn info(address: &str, preferred_lifetime: u32) -> strapper::AddressInfo {
+        strapper::AddressInfo {
+            address: address.to_owned(),
+            preferred_lifetime,
+            valid_lifetime: preferred_lifetime * 2,
+            ..Default::default()
+        }
+    }
+
+    fn node() -> strapper::NodeAdvertisement {
+        strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![
+                strapper::Interface {
+                    name: "eth0".to_owned(),
+                    mac: "02:00:00:00:00:01".to_owned(),
+                    index: 2,
+                    ipaddr: vec![
+                        "10.0.0.9".to_owned(),
+                        "10.0.0.10".to_owned(),
+                        "fd00::1".to_owned(),
+                    ],
+                    address_info: vec![info("10.0.0.9", 600), info("fd00::1", 1800)],
+                    ..Default::default()
+                },
+                strapper::Interface {
+                    name: "eth1".to_owned(),
+                    mac: "02:00:00:00:00:02".to_owned(),
+                    index: 3,
+                    ipaddr: vec!["192.168.1.1".to_owned()],
+                    ..Default::default()
+                },
+            ],
+            default_routes: vec![
+                strapper::Route {
+                    gateway: "10.0.0.1".to_owned(),
+                    metric: 100,
+                    index: 2,
+                },
+                strapper::Route {
+                    gateway: "192.168.1.254".to_owned(),
+                    metric: 200,
+                    index: 3,
+                },
+            ],
+            services: vec![
+                strapper::Service {
+                    name: "ssh".to_owned(),
+                    protocol: "tcp".to_owned(),
+                    port: 22,
+                },
+                strapper::Service {
+                    name: "node-exporter".to_owned(),
+                    protocol: "tcp".to_owned(),
+                    port: 9100,
+                },
+            ],
+            labels: vec![("role", "ingress"), ("rack", "b4")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            sequence: 7,
+            agent_start_time: 1_600_000_000_000_000_000,
+            observed_ms: 1_600_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    // The same node as the kernel or a restarted agent might report it.
+    #[test]
+    fn order_insensitive() {
+        let base = canonical_hash(&node());
+        let same: Vec<Change> = vec![
+            ("interfaces reversed", |a| a.interfaces.reverse()),
+            ("addresses reordered", |a| {
+                a.interfaces[0].ipaddr.rotate_left(1);
+                a.interfaces[0].address_info.reverse();
+            }),
+            ("routes reordered", |a| a.default_routes.reverse()),
+            ("services reordered", |a| a.services.reverse()),
+            ("MAC case", |a| {
+                a.interfaces[1].mac = a.interfaces[1].mac.to_ascii_uppercase()
+            }),
+            ("sequence", |a| a.sequence += 1),
+            ("restart", |a| a.agent_start_time += 1),
+            ("observed", |a| a.observed_ms += 1),
+            ("lifetimes", |a| {
+                a.interfaces[0].address_info[0].preferred_lifetime = 0;
+                a.interfaces[0].address_info[1].valid_lifetime = u32::MAX;
+            }),
+            ("server-side fields", |a| {
+                a.effective_hostname = "alias".to_owned();
+                a.source_address = "10.0.0.9".to_owned();
+                a.self_advertised = true;
+            }),
+        ];
+        for (what, change) in same {
+            let mut adv = node();
+            change(&mut adv);
+            assert_eq!(canonical_hash(&adv), base, "{}", what);
+        }
+
+        let mut shuffled = node();
+        shuffled.interfaces.reverse();
+        for i in shuffled.interfaces.iter_mut() {
+            i.ipaddr.reverse();
+        }
+        shuffled.default_routes.reverse();
+        shuffled.services.reverse();
+        shuffled.sequence = 1;
+        assert_eq!(canonical_hash(&shuffled), base);
+        assert_eq!(short_hash(&shuffled), short_hash(&node()));
+    }
+
+    // Anything the server publishes or acts on.
+    #[test]
+    fn content_sensitive() {
+        let different: Vec<Change> = vec![
+            ("hostname", |a| a.hostname = "other".to_owned()),
+            ("address added", |a| {
+                a.interfaces[1].ipaddr.push("192.168.1.2".to_owned())
+            }),
+            ("address removed", |a| {
+                a.interfaces[0].ipaddr.pop();
+            }),
+            ("address changed", |a| {
+                a.interfaces[0].ipaddr[0] = "10.0.0.8".to_owned()
+            }),
+            ("address moved", |a| {
+                let addr = a.interfaces[1].ipaddr.remove(0);
+                a.interfaces[0].ipaddr.push(addr);
+            }),
+            ("MAC", |a| {
+                a.interfaces[0].mac = "02:00:00:00:00:03".to_owned()
+            }),
+            ("interface renamed", |a| {
+                a.interfaces[1].name = "eth2".to_owned()
+            }),
+            ("interface index", |a| a.interfaces[1].index = 4),
+            ("interface removed", |a| {
+                a.interfaces.pop();
+            }),
+            ("mtu", |a| a.interfaces[0].mtu = 9000),
+            ("origin", |a| {
+                a.interfaces[0].address_info[0].origin = strapper::AddressOrigin::Static as i32
+            }),
+            ("route metric", |a| a.default_routes[0].metric = 50),
+            ("route removed", |a| {
+                a.default_routes.pop();
+            }),
+            ("service port", |a| a.services[0].port = 2222),
+            ("label value", |a| {
+                a.labels.insert("role".to_owned(), "egress".to_owned());
+            }),
+            ("label removed", |a| {
+                a.labels.remove("rack");
+            }),
+            ("disabled", |a| a.disabled = true),
+            ("ttl override", |a| a.ttl_override = Some(60)),
+            ("agent version", |a| a.agent_version = "0.2.0".to_owned()),
+        ];
+        let mut seen = vec![(canonical_hash(&node()), "unchanged")];
+        for (what, change) in different {
+            let mut adv = node();
+            change(&mut adv);
+            let hash = canonical_hash(&adv);
+            if let Some((_, other)) = seen.iter().find(|(h, _)| *h == hash) {
+                panic!("{} hashes the same as {}", what, other);
+            }
+            seen.push((hash, what));
+        }
+    }
+
+    #[test]
+    fn hex_forms() {
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+        let full = hex(&canonical_hash(&node()));
+        assert_eq!(full.len(), 64);
+        assert!(full.starts_with(&short_hash(&node())));
+        assert_eq!(short_hash(&node()).len(), 12);
+    }
+}
@@ -0,0 +1,325 @@
+// What changed between two advertisements from the same node, for the
+// agent's and server's logs, where the whole advertisement of a node with
+// twenty interfaces buries the one address that moved. Both sides are
+// canonicalized first, so this reports exactly what canonical_hash notices.
+
+use std::fmt;
+
+use crate::canonical::canonicalize;
+use crate::strapper;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+    InterfaceAdded(String),
+    InterfaceRemoved(String),
+    InterfaceRenamed {
+        from: String,
+        to: String,
+    },
+    // Something about the interface other than its addresses, named.
+    InterfaceChanged {
+        interface: String,
+        what: &'static str,
+    },
+    AddressAdded {
+        interface: String,
+        address: String,
+    },
+    AddressRemoved {
+        interface: String,
+        address: String,
+    },
+    // Something about the node as a whole, named.
+    Node(&'static str),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::InterfaceAdded(name) => write!(f, "iface {} added", name),
+            Change::InterfaceRemoved(name) => write!(f, "iface {} removed", name),
+            Change::InterfaceRenamed { from, to } => write!(f, "iface {} renamed {}", from, to),
+            Change::InterfaceChanged { interface, what } => {
+                write!(f, "{} changed on {}", what, interface)
+            }
+            Change::AddressAdded { interface, address } => {
+                write!(f, "+{} on {}", address, interface)
+            }
+            Change::AddressRemoved { interface, address } => {
+                write!(f, "-{} on {}", address, interface)
+            }
+            Change::Node(what) => write!(f, "{} changed", what),
+        }
+    }
+}
+
+// The changes taking `old` to `new`, node-wide ones first, then by
+// interface index. Interfaces are matched by index, as the kernel keeps
+// it across renames.
+pub fn changes(
+    old: &strapper::NodeAdvertisement,
+    new: &strapper::NodeAdvertisement,
+) -> Vec<Change> {
+    let (old, new) = (canonicalize(old), canonicalize(new));
+    let mut changes = vec![];
+    let node: [(&'static str, bool); 8] = [
+        ("hostname", old.hostname != new.hostname),
+        ("fqdn", old.fqdn != new.fqdn),
+        ("agent version", old.agent_version != new.agent_version),
+        ("labels", old.labels != new.labels),
+        ("services", old.services != new.services),
+        ("default routes", old.default_routes != new.default_routes),
+        ("maintenance", old.disabled != new.disabled),
+        ("ttl override", old.ttl_override != new.ttl_override),
+    ];
+    changes.extend(
+        node.iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(what, _)| Change::Node(what)),
+    );
+
+    let mut old_ifaces = old.interfaces.iter().peekable();
+    let mut new_ifaces = new.interfaces.iter().peekable();
+    loop {
+        match (old_ifaces.peek(), new_ifaces.peek()) {
+            (None, None) => break,
+            (Some(o), Some(n)) if o.index == n.index => {
+                interface_changes(o, n, &mut changes);
+                old_ifaces.next();
+                new_ifaces.next();
+            }
+            (Some(o), Some(n)) if o.index < n.index => {
+                changes.push(Change::InterfaceRemoved(o.name.clone()));
+                old_ifaces.next();
+            }
+            (Some(o), None) => {
+                changes.push(Change::InterfaceRemoved(o.name.clone()));
+                old_ifaces.next();
+            }
+            (_, Some(n)) => {
+                changes.push(Change::InterfaceAdded(n.name.clone()));
+                new_ifaces.next();
+            }
+        }
+    }
+    changes
+}
+
+fn interface_changes(
+    old: &strapper::Interface,
+    new: &strapper::Interface,
+    changes: &mut Vec<Change>,
+) {
+    if old.name != new.name {
+        changes.push(Change::InterfaceRenamed {
+            from: old.name.clone(),
+            to: new.name.clone(),
+        });
+    }
+    let fields: [(&'static str, bool); 8] = [
+        ("mac", old.mac != new.mac),
+        ("mtu", old.mtu != new.mtu),
+        ("oper state", old.oper_state != new.oper_state),
+        ("kind", old.kind != new.kind),
+        ("vlan id", old.vlan_id != new.vlan_id),
+        ("parent", old.parent_index != new.parent_index),
+        (
+            "wireguard key",
+            old.wireguard_public_key != new.wireguard_public_key,
+        ),
+        // Flags such as vip; lifetimes are already zeroed.
+        ("address info", old.address_info != new.address_info),
+    ];
+    changes.extend(
+        fields
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(what, _)| Change::InterfaceChanged {
+                interface: new.name.clone(),
+                what,
+            }),
+    );
+    changes.extend(
+        new.ipaddr
+            .iter()
+            .filter(|a| !old.ipaddr.contains(a))
+            .map(|a| Change::AddressAdded {
+                interface: new.name.clone(),
+                address: a.clone(),
+            }),
+    );
+    changes.extend(
+        old.ipaddr
+            .iter()
+            .filter(|a| !new.ipaddr.contains(a))
+            .map(|a| Change::AddressRemoved {
+                interface: new.name.clone(),
+                address: a.clone(),
+            }),
+    );
+}
+
+// `changes` as one line, or past `max` of them, counted instead.
+pub fn describe(changes: &[Change], max: usize) -> String {
+    if changes.is_empty() {
+        return "nothing".to_owned();
+    }
+    if changes.len() <= max {
+        return changes
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+    let count = |f: fn(&Change) -> bool| changes.iter().filter(|c| f(c)).count();
+    let counts = [
+        (
+            count(|c| matches!(c, Change::AddressAdded { .. })),
+            "addresses added",
+        ),
+        (
+            count(|c| matches!(c, Change::AddressRemoved { .. })),
+            "addresses removed",
+        ),
+        (
+            count(|c| matches!(c, Change::InterfaceAdded(_))),
+            "interfaces added",
+        ),
+        (
+            count(|c| matches!(c, Change::InterfaceRemoved(_))),
+            "interfaces removed",
+        ),
+        (
+            count(|c| {
+                matches!(
+                    c,
+                    Change::InterfaceRenamed { .. }
+                        | Change::InterfaceChanged { .. }
+                        | Change::Node(_)
+                )
+            }),
+            "other changes",
+        ),
+    ];
+    format!(
+        "{} changes: {}",
+        changes.len(),
+        counts
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, what)| format!("{} {}", n, what))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{iface, node};
+
+    const MAC: &str = "02:00:00:00:00:01";
+
+    #[test]
+    fn same_node_is_no_change() {
+        let old = node(vec![iface("eth0", 2, MAC, &["10.0.0.1", "fd00::1"])]);
+        let mut new = old.clone();
+        new.sequence = 7;
+        new.agent_start_time = 100;
+        new.interfaces[0].ipaddr.reverse();
+        new.interfaces[0].mac = new.interfaces[0].mac.to_ascii_uppercase();
+        assert_eq!(changes(&old, &new), vec![]);
+        assert_eq!(describe(&changes(&old, &new), 5), "nothing");
+    }
+
+    #[test]
+    fn addresses() {
+        let old = node(vec![iface("eth0", 2, MAC, &["10.0.0.1", "10.0.0.2"])]);
+        let new = node(vec![iface("eth0", 2, MAC, &["10.0.0.2", "10.0.0.3"])]);
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                Change::AddressAdded {
+                    interface: "eth0".to_owned(),
+                    address: "10.0.0.3".to_owned(),
+                },
+                Change::AddressRemoved {
+                    interface: "eth0".to_owned(),
+                    address: "10.0.0.1".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            describe(&changes(&old, &new), 5),
+            "+10.0.0.3 on eth0, -10.0.0.1 on eth0"
+        );
+    }
+
+    #[test]
+    fn interfaces_by_index() {
+        let old = node(vec![iface("eth0", 2, MAC, &[]), iface("eth1", 3, MAC, &[])]);
+        let new = node(vec![iface("eth2", 4, MAC, &[]), iface("lan0", 2, MAC, &[])]);
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                Change::InterfaceRenamed {
+                    from: "eth0".to_owned(),
+                    to: "lan0".to_owned(),
+                },
+                Change::InterfaceRemoved("eth1".to_owned()),
+                Change::InterfaceAdded("eth2".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn interface_and_node_fields() {
+        let old = node(vec![iface("eth0", 2, MAC, &[])]);
+        let mut new = old.clone();
+        new.interfaces[0].mtu = 9000;
+        new.interfaces[0].mac = "02:00:00:00:00:02".to_owned();
+        new.disabled = true;
+        assert_eq!(
+            changes(&old, &new),
+            vec![
+                Change::Node("maintenance"),
+                Change::InterfaceChanged {
+                    interface: "eth0".to_owned(),
+                    what: "mac",
+                },
+                Change::InterfaceChanged {
+                    interface: "eth0".to_owned(),
+                    what: "mtu",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lifetimes_alone_are_no_change() {
+        let info = |preferred| strapper::AddressInfo {
+            address: "fd00::1".to_owned(),
+            preferred_lifetime: preferred,
+            valid_lifetime: preferred,
+            ..Default::default()
+        };
+        let mut old = node(vec![iface("eth0", 2, MAC, &["fd00::1"])]);
+        old.interfaces[0].address_info = vec![info(3600)];
+        let mut new = old.clone();
+        new.interfaces[0].address_info = vec![info(1800)];
+        assert_eq!(changes(&old, &new), vec![]);
+    }
+
+    #[test]
+    fn counted_past_max() {
+        let old = node(vec![iface("eth0", 2, MAC, &[])]);
+        let new = node(vec![
+            iface("eth0", 2, MAC, &["10.0.0.1", "10.0.0.2", "10.0.0.3"]),
+            iface("eth1", 3, MAC, &[]),
+        ]);
+        assert_eq!(
+            describe(&changes(&old, &new), 2),
+            "4 changes: 3 addresses added, 1 interfaces added"
+        );
+    }
+}
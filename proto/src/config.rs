@@ -0,0 +1,69 @@
+// GetConfig's answer as JSON, the same from /v1/config and strapperctl.
+
+use serde_json::{json, Value};
+
+use crate::strapper;
+
+impl strapper::RemapperConfig {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "spec": self.spec,
+            "net": self.net,
+            "zone": self.zone,
+            "entry_formats": self.entry_formats,
+            "merge": self.merge,
+            "synthesize_a": self.synthesize_a,
+            "labels": self.labels,
+            "prefer": self.prefer,
+            "iface": self.iface,
+            "mac_prefix": self.mac_prefix,
+            "vlan": self.vlan,
+            "transform": self.transform,
+            "vip": self.vip,
+        })
+    }
+}
+
+impl strapper::ServerConfig {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.source,
+            "loaded_at_ms": self.loaded_at_ms,
+            "remappers": self.remappers.iter().map(strapper::RemapperConfig::to_json).collect::<Vec<_>>(),
+            "pdns_targets": self.pdns_targets.iter().map(|t| json!({
+                "endpoint": t.endpoint,
+                "server": t.server,
+                "api_key_fingerprint": t.api_key_fingerprint,
+            })).collect::<Vec<_>>(),
+            "pdns_quorum": self.pdns_quorum,
+            "pdns_proxy": self.pdns_proxy,
+            "max_advertise_per_minute": self.max_advertise_per_minute,
+            "max_advertise_global_per_minute": self.max_advertise_global_per_minute,
+            "pdns_zone_rate": self.pdns_zone_rate,
+            "pdns_zone_burst": self.pdns_zone_burst,
+            "ttl_policy": self.ttl_policy,
+            "record_ttl": self.record_ttl,
+            "min_ttl": self.min_ttl,
+            "vip_ttl": self.vip_ttl,
+            "quarantine_after": self.quarantine_after,
+            "quarantine_retry_secs": self.quarantine_retry_secs,
+            "missing_zone_ttl_secs": self.missing_zone_ttl_secs,
+            "ownership_conflict": self.ownership_conflict,
+            "on_no_match": self.on_no_match,
+            "name_source": self.name_source,
+            "preserve_hostname_case": self.preserve_hostname_case,
+            "strict": self.strict,
+            "nat64_prefix": self.nat64_prefix,
+            "otlp_endpoint": self.otlp_endpoint,
+            "trace_sample_ratio": self.trace_sample_ratio,
+            "mode": self.mode,
+            "drift_interval_secs": self.drift_interval_secs,
+            "zone_fetch_max_names": self.zone_fetch_max_names,
+            "reconcile_max_zone_rrsets": self.reconcile_max_zone_rrsets,
+            "listeners": self.listeners,
+            "self_advertise": self.self_advertise,
+            "pdns_rectify": self.pdns_rectify,
+            "pdns_rectify_debounce_ms": self.pdns_rectify_debounce_ms,
+        })
+    }
+}
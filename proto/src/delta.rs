@@ -0,0 +1,179 @@
+// AdvertisementDelta on both ends: the agent builds one with `diff`, and the
+// server rebuilds the full advertisement with `apply`. `diff` only returns
+// operations that `apply` turns back into exactly the new advertisement, so
+// either way the server ends up holding the same state.
+
+use crate::strapper::{self, delta_op::Op};
+
+// The status details of an AdvertiseDelta the server has no base for.
+pub const RESYNC_REQUIRED: &[u8] = b"resync required";
+
+fn iface_mut(
+    adv: &mut strapper::NodeAdvertisement,
+    index: u32,
+) -> Result<&mut strapper::Interface, String> {
+    adv.interfaces
+        .iter_mut()
+        .find(|i| i.index == index)
+        .ok_or_else(|| format!("no interface with index {}", index))
+}
+
+fn apply_op(adv: &mut strapper::NodeAdvertisement, op: &Op) -> Result<(), String> {
+    match op {
+        Op::AddAddress(a) => {
+            let iface = iface_mut(adv, a.iface_index)?;
+            if !iface.ipaddr.contains(&a.address) {
+                iface.ipaddr.push(a.address.clone());
+            }
+            if let Some(info) = &a.info {
+                match iface
+                    .address_info
+                    .iter_mut()
+                    .find(|i| i.address == a.address)
+                {
+                    Some(existing) => *existing = info.clone(),
+                    None => iface.address_info.push(info.clone()),
+                }
+            }
+        }
+        Op::DelAddress(a) => {
+            let iface = iface_mut(adv, a.iface_index)?;
+            let before = iface.ipaddr.len();
+            iface.ipaddr.retain(|addr| *addr != a.address);
+            if iface.ipaddr.len() == before {
+                return Err(format!("{} isn't on {}", a.address, iface.name));
+            }
+            iface.address_info.retain(|i| i.address != a.address);
+        }
+        Op::AddInterface(new) => {
+            let interfaces = &mut adv.interfaces;
+            match interfaces.binary_search_by_key(&new.index, |i| i.index) {
+                Ok(pos) => interfaces[pos] = new.clone(),
+                Err(pos) => interfaces.insert(pos, new.clone()),
+            }
+        }
+        Op::DelInterface(index) => {
+            let before = adv.interfaces.len();
+            adv.interfaces.retain(|i| i.index != *index);
+            if adv.interfaces.len() == before {
+                return Err(format!("no interface with index {}", index));
+            }
+        }
+        Op::SetHostname(hostname) => adv.hostname = hostname.clone(),
+        Op::SetDefaultRoutes(r) => adv.default_routes = r.routes.clone(),
+    }
+    Ok(())
+}
+
+// Applies `ops` to `adv` in order. An error means the base isn't what the
+// sender diffed against.
+pub fn apply(
+    adv: &mut strapper::NodeAdvertisement,
+    ops: &[strapper::DeltaOp],
+) -> Result<(), String> {
+    for op in ops {
+        let op = op.op.as_ref().ok_or("empty delta operation")?;
+        apply_op(adv, op)?;
+    }
+    Ok(())
+}
+
+fn op(op: Op) -> strapper::DeltaOp {
+    strapper::DeltaOp { op: Some(op) }
+}
+
+// Per-address operations when only addresses changed, the whole interface
+// otherwise.
+fn diff_iface(old: &strapper::Interface, new: &strapper::Interface) -> Vec<strapper::DeltaOp> {
+    let strip = |i: &strapper::Interface| strapper::Interface {
+        ipaddr: vec![],
+        address_info: vec![],
+        ..i.clone()
+    };
+    if strip(old) == strip(new) {
+        let info = |addr: &str| new.address_info.iter().find(|i| i.address == addr).cloned();
+        let mut ops = vec![];
+        for addr in old.ipaddr.iter().filter(|a| !new.ipaddr.contains(a)) {
+            ops.push(op(Op::DelAddress(strapper::AddressOp {
+                iface_index: new.index,
+                address: addr.clone(),
+                info: None,
+            })));
+        }
+        for addr in new.ipaddr.iter() {
+            let old_info = old.address_info.iter().find(|i| i.address == *addr);
+            if old.ipaddr.contains(addr) && old_info == info(addr).as_ref() {
+                continue;
+            }
+            ops.push(op(Op::AddAddress(strapper::AddressOp {
+                iface_index: new.index,
+                address: addr.clone(),
+                info: info(addr),
+            })));
+        }
+
+        let mut check = strapper::NodeAdvertisement {
+            interfaces: vec![old.clone()],
+            ..Default::default()
+        };
+        if apply(&mut check, &ops).is_ok() && check.interfaces == [new.clone()] {
+            return ops;
+        }
+    }
+    vec![op(Op::AddInterface(new.clone()))]
+}
+
+// The operations taking `old` to `new`, or None if they'd differ in
+// something a delta can't carry (the fqdn, labels, maintenance flags, agent
+// version or start time).
+// Sequences and observation times are the caller's business.
+pub fn diff(
+    old: &strapper::NodeAdvertisement,
+    new: &strapper::NodeAdvertisement,
+) -> Option<Vec<strapper::DeltaOp>> {
+    let fixed = |a: &strapper::NodeAdvertisement| {
+        (
+            a.fqdn.clone(),
+            a.labels.clone(),
+            a.disabled,
+            a.ttl_override,
+            a.agent_version.clone(),
+            a.agent_start_time,
+            a.effective_hostname.clone(),
+        )
+    };
+    if fixed(old) != fixed(new) {
+        return None;
+    }
+
+    let mut ops = vec![];
+    if old.hostname != new.hostname {
+        ops.push(op(Op::SetHostname(new.hostname.clone())));
+    }
+    for o in old.interfaces.iter() {
+        if !new.interfaces.iter().any(|n| n.index == o.index) {
+            ops.push(op(Op::DelInterface(o.index)));
+        }
+    }
+    for n in new.interfaces.iter() {
+        match old.interfaces.iter().find(|o| o.index == n.index) {
+            Some(o) if o == n => {}
+            Some(o) => ops.extend(diff_iface(o, n)),
+            None => ops.push(op(Op::AddInterface(n.clone()))),
+        }
+    }
+    if old.default_routes != new.default_routes {
+        ops.push(op(Op::SetDefaultRoutes(strapper::RouteList {
+            routes: new.default_routes.clone(),
+        })));
+    }
+
+    let mut check = old.clone();
+    apply(&mut check, &ops).ok()?;
+    check.sequence = new.sequence;
+    check.observed_ms = new.observed_ms;
+    if check != *new {
+        return None;
+    }
+    Some(ops)
+}
@@ -0,0 +1,41 @@
+// GetDrift's answer as JSON, the same from /v1/drift and strapperctl.
+
+use serde_json::{json, Value};
+
+use crate::strapper;
+
+impl strapper::DriftEntry {
+    pub fn kind_name(&self) -> &'static str {
+        match strapper::DriftKind::from_i32(self.kind) {
+            Some(strapper::DriftKind::DriftUnexpected) => "unexpected",
+            Some(strapper::DriftKind::DriftContent) => "content",
+            Some(strapper::DriftKind::DriftTtl) => "ttl",
+            _ => "missing",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "endpoint": self.endpoint,
+            "zone": self.zone,
+            "name": self.name,
+            "type": self.r#type,
+            "kind": self.kind_name(),
+            "expected": self.expected,
+            "actual": self.actual,
+            "expected_ttl": self.expected_ttl,
+            "actual_ttl": self.actual_ttl,
+        })
+    }
+}
+
+impl strapper::DriftReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "checked_at_ms": self.checked_at_ms,
+            "rrsets_checked": self.rrsets_checked,
+            "entries": self.entries.iter().map(strapper::DriftEntry::to_json).collect::<Vec<_>>(),
+            "errors": self.errors,
+        })
+    }
+}
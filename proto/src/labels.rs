@@ -0,0 +1,46 @@
+// Node labels (role=ingress, site=ams1), as the agent sends them and the
+// server checks them. Keys are short and plain enough to name in a remapper;
+// values are anything printable, though only those that make a DNS label can
+// fill in a {label:key}.
+
+pub const MAX_LABELS: usize = 64;
+const MAX_KEY_LEN: usize = 63;
+const MAX_VALUE_LEN: usize = 255;
+
+pub fn check(key: &str, value: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "label key '{}' must be 1 to {} characters",
+            key, MAX_KEY_LEN
+        ));
+    }
+    if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+    {
+        return Err(format!(
+            "label key '{}' can only hold letters, digits, -, _, . and /",
+            key
+        ));
+    }
+    if value.len() > MAX_VALUE_LEN {
+        return Err(format!(
+            "label {}'s value is over {} characters",
+            key, MAX_VALUE_LEN
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!("label {}'s value holds control characters", key));
+    }
+    Ok(())
+}
+
+// key=value, with the value possibly empty.
+pub fn parse(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("label '{}' must be key=value", s))?;
+    let (key, value) = (key.trim(), value.trim());
+    check(key, value)?;
+    Ok((key.to_owned(), value.to_owned()))
+}
@@ -1 +1,20 @@
+pub mod beacon;
+pub mod canonical;
+pub mod changes;
+pub mod config;
+pub mod delta;
+pub mod drift;
+pub mod labels;
+pub mod node;
+pub mod notice;
 pub mod strapper;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wireguard;
+
+#[path = "grpc.reflection.v1alpha.rs"]
+pub mod reflection;
+
+// Every message and service above, as an encoded FileDescriptorSet, for
+// serving reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!("descriptor.bin");
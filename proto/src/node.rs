@@ -0,0 +1,527 @@
+// Checks, normalization and rendering for the two messages everything
+// passes around, so the agent, server, client library and strapperctl agree
+// on what a valid advertisement is and how one reads.
+
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::{canonical, labels, strapper, wireguard};
+
+// Something wrong with an advertisement. The fatal ones fail it outright;
+// the rest only concern part of it, which the server skips (or, when
+// strict, fails it for).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Problem {
+    EmptyHostname,
+    TooManyLabels(usize),
+    // labels::check's explanation.
+    BadLabel(String),
+    ZeroTtlOverride,
+    // By index, having no name to go by.
+    EmptyInterfaceName(u32),
+    DuplicateIndex(u32),
+    BadMac { interface: String, mac: String },
+    BadAddress { interface: String, address: String },
+    BadWireguardKey { interface: String, key: String },
+}
+
+impl Problem {
+    pub fn is_fatal(&self) -> bool {
+        // Remappers select on labels, so unlike a bad address a bad label
+        // can't just be left out.
+        matches!(
+            self,
+            Problem::EmptyHostname
+                | Problem::TooManyLabels(_)
+                | Problem::BadLabel(_)
+                | Problem::ZeroTtlOverride
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::EmptyHostname => write!(f, "hostname is empty"),
+            Problem::TooManyLabels(n) => {
+                write!(f, "{} labels, over the {} allowed", n, labels::MAX_LABELS)
+            }
+            Problem::BadLabel(e) => write!(f, "{}", e),
+            Problem::ZeroTtlOverride => write!(f, "ttl_override must be at least 1"),
+            Problem::EmptyInterfaceName(index) => {
+                write!(f, "interface with index {} has no name", index)
+            }
+            Problem::DuplicateIndex(index) => {
+                write!(f, "more than one interface with index {}", index)
+            }
+            Problem::BadMac { interface, mac } => {
+                write!(f, "invalid MAC '{}' on {}", mac, interface)
+            }
+            Problem::BadAddress { interface, address } => {
+                write!(f, "unparseable address '{}' on {}", address, interface)
+            }
+            Problem::BadWireguardKey { interface, key } => {
+                write!(f, "invalid wireguard public key '{}' on {}", key, interface)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Problem {}
+
+// Lowercase hex octets separated by colons, as advertisements carry MACs.
+pub fn format_mac(mac: &[u8]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// A MAC in any notation eui48 reads, as format_mac writes it.
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    eui48::MacAddress::parse_str(mac)
+        .ok()
+        .map(|m| format_mac(m.as_bytes()))
+}
+
+fn oper_state(state: i32) -> String {
+    strapper::OperState::from_i32(state)
+        .map(|s| format!("{:?}", s))
+        .unwrap_or_else(|| state.to_string())
+}
+
+fn origin(origin: i32) -> &'static str {
+    match strapper::AddressOrigin::from_i32(origin) {
+        Some(strapper::AddressOrigin::Static) => "static",
+        Some(strapper::AddressOrigin::Dynamic) => "dynamic",
+        _ => "unknown",
+    }
+}
+
+impl strapper::Interface {
+    // An interface with no addresses yet. `mac` can be empty for links
+    // without one, and otherwise is normalized.
+    pub fn try_new(
+        name: impl Into<String>,
+        mac: &str,
+        index: u32,
+    ) -> Result<strapper::Interface, Problem> {
+        let name = name.into();
+        if name.is_empty() {
+            return Err(Problem::EmptyInterfaceName(index));
+        }
+        let mac = if mac.is_empty() {
+            String::new()
+        } else {
+            normalize_mac(mac).ok_or_else(|| Problem::BadMac {
+                interface: name.clone(),
+                mac: mac.to_owned(),
+            })?
+        };
+        Ok(strapper::Interface {
+            name,
+            mac,
+            index,
+            ..Default::default()
+        })
+    }
+
+    // Everything wrong with the interface on its own, none of it fatal.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        if self.name.is_empty() {
+            problems.push(Problem::EmptyInterfaceName(self.index));
+        }
+        if !self.mac.is_empty() && normalize_mac(&self.mac).is_none() {
+            problems.push(Problem::BadMac {
+                interface: self.name.clone(),
+                mac: self.mac.clone(),
+            });
+        }
+        for a in self.ipaddr.iter().filter(|a| a.parse::<IpAddr>().is_err()) {
+            problems.push(Problem::BadAddress {
+                interface: self.name.clone(),
+                address: a.clone(),
+            });
+        }
+        if let Some(key) = &self.wireguard_public_key {
+            if !wireguard::is_public_key(key) {
+                problems.push(Problem::BadWireguardKey {
+                    interface: self.name.clone(),
+                    key: key.clone(),
+                });
+            }
+        }
+        problems
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "index": self.index,
+            "mac": self.mac,
+            "addresses": self.ipaddr,
+            "address_info": self.address_info.iter().map(|a| json!({
+                "address": a.address,
+                "preferred_lifetime": a.preferred_lifetime,
+                "valid_lifetime": a.valid_lifetime,
+                "label": a.label,
+                "origin": origin(a.origin),
+                "vip": a.vip,
+            })).collect::<Vec<_>>(),
+            "mtu": self.mtu,
+            "oper_state": oper_state(self.oper_state),
+            "kind": self.kind,
+            "vlan_id": self.vlan_id,
+            "wireguard_public_key": self.wireguard_public_key,
+            "parent_index": self.parent_index,
+        })
+    }
+}
+
+// eth0 (index 4, vlan, 52:54:00:12:34:56, Up): 2001:db8::1, 192.0.2.1
+impl fmt::Display for strapper::Interface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (index {}", self.name, self.index)?;
+        if !self.kind.is_empty() {
+            write!(f, ", {}", self.kind)?;
+        }
+        if !self.mac.is_empty() {
+            write!(f, ", {}", self.mac)?;
+        }
+        write!(f, ", {})", oper_state(self.oper_state))?;
+        if self.ipaddr.is_empty() {
+            write!(f, ": no addresses")
+        } else {
+            write!(f, ": {}", self.ipaddr.join(", "))
+        }
+    }
+}
+
+impl strapper::NodeAdvertisement {
+    // Every problem with the advertisement, fatal ones first.
+    pub fn validate(&self) -> Result<(), Vec<Problem>> {
+        let mut problems = vec![];
+        if self.hostname.is_empty() {
+            problems.push(Problem::EmptyHostname);
+        }
+        if self.labels.len() > labels::MAX_LABELS {
+            problems.push(Problem::TooManyLabels(self.labels.len()));
+        }
+        for (key, value) in self.labels.iter() {
+            if let Err(e) = labels::check(key, value) {
+                problems.push(Problem::BadLabel(e));
+            }
+        }
+        if self.ttl_override == Some(0) {
+            problems.push(Problem::ZeroTtlOverride);
+        }
+        let mut seen = HashSet::new();
+        for iface in self.interfaces.iter() {
+            if !seen.insert(iface.index) {
+                problems.push(Problem::DuplicateIndex(iface.index));
+            }
+            problems.extend(iface.problems());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // See canonical::canonicalize.
+    pub fn canonicalize(&self) -> strapper::NodeAdvertisement {
+        canonical::canonicalize(self)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "hostname": self.hostname,
+            "fqdn": self.fqdn,
+            "effective_hostname": self.effective_hostname,
+            "source_address": self.source_address,
+            "self_advertised": self.self_advertised,
+            "sequence": self.sequence,
+            "observed_ms": self.observed_ms,
+            "agent_version": self.agent_version,
+            "agent_start_time": self.agent_start_time,
+            "labels": self.labels,
+            "interfaces": self.interfaces.iter().map(strapper::Interface::to_json).collect::<Vec<_>>(),
+            "default_routes": self.default_routes.iter().map(|r| json!({
+                "gateway": r.gateway,
+                "metric": r.metric,
+                "index": r.index,
+            })).collect::<Vec<_>>(),
+            "services": self.services.iter().map(|s| json!({
+                "name": s.name,
+                "protocol": s.protocol,
+                "port": s.port,
+            })).collect::<Vec<_>>(),
+            "disabled": self.disabled,
+            "ttl_override": self.ttl_override,
+        })
+    }
+}
+
+// One line for the node, then one per interface and default route.
+impl fmt::Display for strapper::NodeAdvertisement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.hostname)?;
+        if !self.fqdn.is_empty() && self.fqdn != self.hostname {
+            write!(f, " ({})", self.fqdn)?;
+        }
+        if self.disabled {
+            write!(f, ", records disabled")?;
+        }
+        if let Some(ttl) = self.ttl_override {
+            write!(f, ", TTLs capped at {}s", ttl)?;
+        }
+        for iface in self.interfaces.iter() {
+            write!(f, "\n  {}", iface)?;
+        }
+        for r in self.default_routes.iter() {
+            write!(
+                f,
+                "\n  default via {} on index {}, metric {}",
+                r.gateway, r.index, r.metric
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_mac, normalize_mac, Problem};
+    use crate::testing::{iface, node};
+    use crate::{labels, strapper};
+
+    const WG_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    #[test]
+    fn macs() {
+        assert_eq!(
+            format_mac(&[0x52, 0x54, 0, 0x12, 0x34, 0xab]),
+            "52:54:00:12:34:ab"
+        );
+        assert_eq!(format_mac(&[]), "");
+        for mac in [
+            "52:54:00:12:34:ab",
+            "52:54:00:12:34:AB",
+            "52-54-00-12-34-ab",
+            "5254.0012.34ab",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                normalize_mac(mac).as_deref(),
+                Some("52:54:00:12:34:ab"),
+                "{}",
+                mac
+            );
+        }
+        for mac in ["", "52:54:00:12:34", "52:54:00:12:34:zz", "eth0"].iter() {
+            assert_eq!(normalize_mac(mac), None, "{}", mac);
+        }
+    }
+
+    #[test]
+    fn try_new() {
+        let i = strapper::Interface::try_new("eth0", "52-54-00-12-34-AB", 2).unwrap();
+        assert_eq!(
+            (i.name.as_str(), i.mac.as_str(), i.index),
+            ("eth0", "52:54:00:12:34:ab", 2)
+        );
+        assert!(i.ipaddr.is_empty());
+        assert_eq!(strapper::Interface::try_new("lo", "", 1).unwrap().mac, "");
+        assert_eq!(
+            strapper::Interface::try_new("", "", 7),
+            Err(Problem::EmptyInterfaceName(7))
+        );
+        assert_eq!(
+            strapper::Interface::try_new("eth0", "garbage", 2),
+            Err(Problem::BadMac {
+                interface: "eth0".to_owned(),
+                mac: "garbage".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn interface_problems() {
+        let mut i = iface(
+            "eth0",
+            2,
+            "52:54:00:12:34:ab",
+            &["2001:db8::1", "192.0.2.1"],
+        );
+        i.wireguard_public_key = Some(WG_KEY.to_owned());
+        assert_eq!(i.problems(), []);
+
+        let mut i = iface(
+            "eth0",
+            2,
+            "nope",
+            &["2001:db8::1", "2001:db8::1/64", "host"],
+        );
+        i.wireguard_public_key = Some("short=".to_owned());
+        let problems = i.problems();
+        assert_eq!(
+            problems,
+            [
+                Problem::BadMac {
+                    interface: "eth0".to_owned(),
+                    mac: "nope".to_owned()
+                },
+                Problem::BadAddress {
+                    interface: "eth0".to_owned(),
+                    address: "2001:db8::1/64".to_owned()
+                },
+                Problem::BadAddress {
+                    interface: "eth0".to_owned(),
+                    address: "host".to_owned()
+                },
+                Problem::BadWireguardKey {
+                    interface: "eth0".to_owned(),
+                    key: "short=".to_owned()
+                },
+            ]
+        );
+        assert!(problems.iter().all(|p| !p.is_fatal()));
+        assert_eq!(
+            problems.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "invalid MAC 'nope' on eth0",
+                "unparseable address '2001:db8::1/64' on eth0",
+                "unparseable address 'host' on eth0",
+                "invalid wireguard public key 'short=' on eth0",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(
+            node(vec![
+                iface("lo", 1, "", &["::1"]),
+                iface("eth0", 2, "52:54:00:12:34:ab", &["2001:db8::1"]),
+            ])
+            .validate(),
+            Ok(())
+        );
+
+        // Fatal or not, every problem is reported, the node's own first.
+        let mut adv = node(vec![
+            iface("eth0", 2, "", &["bad"]),
+            iface("eth1", 2, "", &[]),
+            iface("", 3, "", &[]),
+        ]);
+        adv.hostname.clear();
+        adv.ttl_override = Some(0);
+        adv.labels.insert("role".to_owned(), "ingress".to_owned());
+        adv.labels.insert("bad key".to_owned(), "x".to_owned());
+        let problems = adv.validate().unwrap_err();
+        assert_eq!(
+            problems,
+            [
+                Problem::EmptyHostname,
+                Problem::BadLabel(labels::check("bad key", "x").unwrap_err()),
+                Problem::ZeroTtlOverride,
+                Problem::BadAddress {
+                    interface: "eth0".to_owned(),
+                    address: "bad".to_owned()
+                },
+                Problem::DuplicateIndex(2),
+                Problem::EmptyInterfaceName(3),
+            ]
+        );
+        assert_eq!(
+            problems.iter().map(Problem::is_fatal).collect::<Vec<_>>(),
+            [true, true, true, false, false, false]
+        );
+
+        let mut adv = node(vec![]);
+        for n in 0..=labels::MAX_LABELS {
+            adv.labels.insert(format!("k{}", n), String::new());
+        }
+        assert_eq!(
+            adv.validate(),
+            Err(vec![Problem::TooManyLabels(labels::MAX_LABELS + 1)])
+        );
+        assert_eq!(
+            Problem::TooManyLabels(65).to_string(),
+            "65 labels, over the 64 allowed"
+        );
+        adv.labels.remove("k0");
+        adv.ttl_override = Some(1);
+        assert_eq!(adv.validate(), Ok(()));
+    }
+
+    #[test]
+    fn display() {
+        let mut eth0 = iface(
+            "eth0.100",
+            4,
+            "52:54:00:12:34:56",
+            &["2001:db8::1", "192.0.2.1"],
+        );
+        eth0.kind = "vlan".to_owned();
+        eth0.oper_state = strapper::OperState::Up as i32;
+        let mut lo = iface("lo", 1, "", &[]);
+        lo.oper_state = 42;
+        assert_eq!(
+            eth0.to_string(),
+            "eth0.100 (index 4, vlan, 52:54:00:12:34:56, Up): 2001:db8::1, 192.0.2.1"
+        );
+        assert_eq!(lo.to_string(), "lo (index 1, 42): no addresses");
+
+        let mut adv = node(vec![lo, eth0]);
+        adv.fqdn = "node.example.com".to_owned();
+        adv.disabled = true;
+        adv.ttl_override = Some(60);
+        adv.default_routes.push(strapper::Route {
+            gateway: "fe80::1".to_owned(),
+            index: 4,
+            metric: 1024,
+        });
+        assert_eq!(
+            adv.to_string(),
+            "node (node.example.com), records disabled, TTLs capped at 60s\n  \
+             lo (index 1, 42): no addresses\n  \
+             eth0.100 (index 4, vlan, 52:54:00:12:34:56, Up): 2001:db8::1, 192.0.2.1\n  \
+             default via fe80::1 on index 4, metric 1024"
+        );
+        adv.fqdn = "node".to_owned();
+        adv.disabled = false;
+        adv.ttl_override = None;
+        adv.interfaces.clear();
+        adv.default_routes.clear();
+        assert_eq!(adv.to_string(), "node");
+    }
+
+    #[test]
+    fn json() {
+        let mut i = iface("eth0", 2, "52:54:00:12:34:ab", &["2001:db8::1"]);
+        i.oper_state = strapper::OperState::Down as i32;
+        i.address_info.push(strapper::AddressInfo {
+            address: "2001:db8::1".to_owned(),
+            preferred_lifetime: 1800,
+            valid_lifetime: 3600,
+            origin: strapper::AddressOrigin::Dynamic as i32,
+            ..Default::default()
+        });
+        let adv = node(vec![i]);
+        let json = adv.to_json();
+        assert_eq!(json["hostname"], "node");
+        let eth0 = &json["interfaces"][0];
+        assert_eq!(eth0["name"], "eth0");
+        assert_eq!(eth0["oper_state"], "Down");
+        assert_eq!(eth0["addresses"][0], "2001:db8::1");
+        assert_eq!(eth0["address_info"][0]["origin"], "dynamic");
+        assert_eq!(eth0["address_info"][0]["preferred_lifetime"], 1800);
+        assert_eq!(json["ttl_override"], serde_json::Value::Null);
+    }
+}
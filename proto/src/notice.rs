@@ -0,0 +1,70 @@
+// Notices: what the server has to say about an advertisement that the
+// node's owner can act on, each under a code that stays the same from
+// release to release. An advertisement accepted with something to say
+// carries them in its AdvertiseResult; one refused outright carries the code
+// as its status details, and the status message as the notice's.
+
+use crate::strapper::{Notice, NoticeSeverity};
+
+// The agent is older than the server's --min-agent-version.
+pub const AGENT_OUTDATED: &str = "agent-outdated";
+// The hostname can't go into a record name.
+pub const HOSTNAME_INVALID: &str = "hostname-invalid";
+// Something in the advertisement didn't check out; see validate on the
+// server.
+pub const INVALID_ADVERTISEMENT: &str = "invalid-advertisement";
+// The advertisement came from an address the node doesn't have.
+pub const SOURCE_MISMATCH: &str = "source-mismatch";
+pub const NO_ADDRESSES: &str = "no-addresses";
+// Addresses no remapper takes, some or all of them.
+pub const NO_REMAPPER_MATCH: &str = "no-remapper-match";
+
+const CODES: &[&str] = &[
+    AGENT_OUTDATED,
+    HOSTNAME_INVALID,
+    INVALID_ADVERTISEMENT,
+    SOURCE_MISMATCH,
+    NO_ADDRESSES,
+    NO_REMAPPER_MATCH,
+];
+
+pub fn warning(code: &str, message: String) -> Notice {
+    Notice {
+        severity: NoticeSeverity::Warning as i32,
+        code: code.to_owned(),
+        message,
+    }
+}
+
+// A refusal that reads back as an error notice under `code`.
+pub fn refusal(status: tonic::Code, code: &'static str, message: String) -> tonic::Status {
+    tonic::Status::with_details(
+        status,
+        message,
+        prost::bytes::Bytes::from_static(code.as_bytes()),
+    )
+}
+
+pub fn from_status(status: &tonic::Status) -> Option<Notice> {
+    let code = std::str::from_utf8(status.details()).ok()?;
+    if !CODES.contains(&code) {
+        return None;
+    }
+    Some(Notice {
+        severity: NoticeSeverity::Error as i32,
+        code: code.to_owned(),
+        message: status.message().to_owned(),
+    })
+}
+
+pub fn severity(notice: &Notice) -> NoticeSeverity {
+    NoticeSeverity::from_i32(notice.severity).unwrap_or(NoticeSeverity::Warning)
+}
+
+pub fn severity_name(notice: &Notice) -> &'static str {
+    match severity(notice) {
+        NoticeSeverity::Info => "info",
+        NoticeSeverity::Warning => "warning",
+        NoticeSeverity::Error => "error",
+    }
+}
@@ -0,0 +1,22 @@
+// Advertisements for tests, built the same way in every crate that checks
+// them.
+
+use crate::strapper;
+
+pub fn iface(name: &str, index: u32, mac: &str, addrs: &[&str]) -> strapper::Interface {
+    strapper::Interface {
+        name: name.to_owned(),
+        index,
+        mac: mac.to_owned(),
+        ipaddr: addrs.iter().map(|a| a.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+pub fn node(interfaces: Vec<strapper::Interface>) -> strapper::NodeAdvertisement {
+    strapper::NodeAdvertisement {
+        hostname: "node".to_owned(),
+        interfaces,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,12 @@
+// A wireguard public key is 32 bytes, which is 43 base64 digits and a pad.
+// The last digit only carries 4 bits, so its low 2 have to be zero.
+pub fn is_public_key(key: &str) -> bool {
+    let digits = match key.strip_suffix('=') {
+        Some(digits) if digits.len() == 43 => digits,
+        _ => return false,
+    };
+    digits
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+        && digits.ends_with(|c| "AEIMQUYcgkosw048".contains(c))
+}
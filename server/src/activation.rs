@@ -0,0 +1,118 @@
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+// sd_listen_fds(3): passed sockets start at fd 3.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Inherited {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+fn sockopt(fd: RawFd, opt: libc::c_int) -> Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut val as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("fd {} passed by systemd is not a socket", fd));
+    }
+    Ok(val)
+}
+
+fn family(fd: RawFd) -> Result<libc::sa_family_t> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("error reading address of fd {}", fd));
+    }
+    Ok(addr.ss_family)
+}
+
+// Picks up a listener handed over by systemd socket activation, if there is
+// one. Anything other than a single listening stream socket meant for this
+// process is a unit misconfiguration, so it's an error rather than a silent
+// fallback to --bind.
+pub fn listen_fds() -> Result<Option<Inherited>> {
+    let pid = match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(None),
+    };
+    let fds = std::env::var("LISTEN_FDS").unwrap_or_default();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let pid: u32 = pid
+        .parse()
+        .with_context(|| format!("invalid LISTEN_PID '{}'", pid))?;
+    if pid != std::process::id() {
+        bail!(
+            "LISTEN_PID {} does not match our pid {}; sockets were meant for another process",
+            pid,
+            std::process::id()
+        );
+    }
+    let fds: u32 = fds
+        .parse()
+        .with_context(|| format!("invalid LISTEN_FDS '{}'", fds))?;
+    if fds != 1 {
+        bail!("expected exactly one socket from systemd, got {}", fds);
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    if sockopt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        bail!("fd {} passed by systemd is not a stream socket", fd);
+    }
+    if sockopt(fd, libc::SO_ACCEPTCONN)? == 0 {
+        bail!("fd {} passed by systemd is not listening", fd);
+    }
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    let family = family(fd)?;
+    debug!("inherited socket fd {} (family {})", fd, family);
+    match family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => Ok(Some(Inherited::Tcp(unsafe {
+            std::net::TcpListener::from_raw_fd(fd)
+        }))),
+        libc::AF_UNIX => Ok(Some(Inherited::Unix(unsafe {
+            std::os::unix::net::UnixListener::from_raw_fd(fd)
+        }))),
+        f => bail!("fd {} passed by systemd has unsupported family {}", fd, f),
+    }
+}
+
+// sd_notify(3) READY=1, for Type=notify units. A no-op outside systemd.
+pub fn notify_ready() -> Result<()> {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    UnixDatagram::unbound()?
+        .send_to_addr(b"READY=1", &addr)
+        .with_context(|| format!("error notifying systemd via {}", path))?;
+    Ok(())
+}
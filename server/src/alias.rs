@@ -0,0 +1,89 @@
+use anyhow::{anyhow, ensure};
+use regex::Regex;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// --hostname-alias old=new
+#[derive(Clone)]
+pub struct HostnameAlias {
+    from: String,
+    to: String,
+}
+
+impl FromStr for HostnameAlias {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid hostname alias '{}' (expected old=new)", s))?;
+        ensure!(
+            !from.is_empty() && !to.is_empty(),
+            "invalid hostname alias '{}' (expected old=new)",
+            s
+        );
+        Ok(HostnameAlias {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+}
+
+// --hostname-rewrite s/pattern/replacement/, sed style. The replacement uses
+// regex's $1 / ${name} syntax for captures.
+#[derive(Clone)]
+pub struct HostnameRewrite {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl FromStr for HostnameRewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "invalid hostname rewrite '{}' (expected s/pattern/replacement/)",
+                s
+            )
+        };
+        let body = s.strip_prefix("s/").ok_or_else(invalid)?;
+        let body = body.strip_suffix('/').ok_or_else(invalid)?;
+        let (pattern, replacement) = body.split_once('/').ok_or_else(invalid)?;
+        if replacement.contains('/') {
+            return Err(invalid());
+        }
+        Ok(HostnameRewrite {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_owned(),
+        })
+    }
+}
+
+// Maps the hostname a node advertises to the name its records are published
+// and tracked under. Exact aliases win; otherwise every rewrite is applied in
+// order.
+pub struct Aliases {
+    exact: HashMap<String, String>,
+    rewrites: Vec<HostnameRewrite>,
+}
+
+impl Aliases {
+    pub fn new(aliases: Vec<HostnameAlias>, rewrites: Vec<HostnameRewrite>) -> Aliases {
+        Aliases {
+            exact: aliases.into_iter().map(|a| (a.from, a.to)).collect(),
+            rewrites,
+        }
+    }
+
+    pub fn resolve(&self, hostname: &str) -> String {
+        if let Some(to) = self.exact.get(hostname) {
+            return to.clone();
+        }
+        self.rewrites.iter().fold(hostname.to_owned(), |name, r| {
+            r.pattern
+                .replace(&name, r.replacement.as_str())
+                .into_owned()
+        })
+    }
+}
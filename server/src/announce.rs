@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use proto::{beacon, strapper};
+
+// Sends a beacon to `dest` every `interval` so agents started with
+// --discover can find this server before there's DNS for it.
+pub struct Announcer {
+    socket: tokio::net::UdpSocket,
+    endpoint: String,
+    key: Vec<u8>,
+    interval: Duration,
+}
+
+impl Announcer {
+    // Without an `endpoint` the beacon names the address it's sent from and
+    // `grpc_port`.
+    pub fn new(
+        dest: SocketAddr,
+        interface: Option<&str>,
+        endpoint: Option<&str>,
+        grpc_port: Option<u16>,
+        key: &[u8],
+        interval: Duration,
+    ) -> Result<Announcer> {
+        let any: SocketAddr = match dest {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(any).context("error binding the announce socket")?;
+        socket.set_broadcast(true)?;
+        if let Some(interface) = interface {
+            bind_to_device(&socket, interface)?;
+        }
+        // Connecting has the kernel pick the source address now, so it can
+        // go in the beacon.
+        socket
+            .connect(dest)
+            .with_context(|| format!("no route to announce to {}", dest))?;
+
+        let endpoint = match (endpoint, grpc_port) {
+            (Some(e), _) => e.to_owned(),
+            (None, Some(port)) => {
+                format!(
+                    "http://{}",
+                    SocketAddr::new(socket.local_addr()?.ip(), port)
+                )
+            }
+            (None, None) => {
+                return Err(anyhow!(
+                    "--announce needs --announce-endpoint when not listening on TCP"
+                ))
+            }
+        };
+        info!("announcing {} to {} every {:?}", endpoint, dest, interval);
+
+        socket.set_nonblocking(true)?;
+        Ok(Announcer {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            endpoint,
+            key: key.to_vec(),
+            interval,
+        })
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let beacon = strapper::Beacon {
+                endpoint: self.endpoint.clone(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            };
+            let sent = match beacon::encode(&beacon, &self.key) {
+                Ok(packet) => self.socket.send(&packet).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match sent {
+                Ok(_) => debug!("sent beacon for {}", self.endpoint),
+                Err(e) => warn!("error sending beacon: {}", e),
+            }
+        }
+    }
+}
+
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> Result<()> {
+    let name = interface.as_bytes();
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("error binding the announce socket to {}", interface));
+    }
+    Ok(())
+}
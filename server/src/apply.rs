@@ -0,0 +1,326 @@
+use log::{debug, error, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::audit::{AuditLog, Origin};
+use crate::history::History;
+use crate::metrics::{self, Metrics};
+use crate::otel;
+use crate::quarantine::Quarantine;
+use crate::verify::Verifier;
+use crate::{Applied, ApplyError, PdnsApi, PdnsRrsetUpdate, PdnsTarget};
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RrsetKey {
+    pub zone: String,
+    pub name: String,
+    pub type_: &'static str,
+    // Merge-mode rrsets are shared, so each owner's updates are kept apart.
+    pub owner: Option<String>,
+}
+
+impl RrsetKey {
+    pub fn new(zone: &str, update: &PdnsRrsetUpdate) -> RrsetKey {
+        RrsetKey {
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_,
+            owner: update.merge_owner.clone(),
+        }
+    }
+}
+
+// Where what became of each update goes, whichever way it was applied.
+#[derive(Clone)]
+pub struct Outcomes {
+    pub audit: Option<AuditLog>,
+    pub history: History,
+    pub quarantine: Arc<Quarantine>,
+    pub verifier: Option<Arc<Verifier>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Outcomes {
+    pub fn record(&self, origin: &Origin, zone: &str, update: &PdnsRrsetUpdate, applied: &Applied) {
+        if let Some(audit) = &self.audit {
+            audit.record(origin, zone, update, applied);
+        }
+        self.history.applied(origin, zone, update, applied);
+        self.quarantine.record(zone, update, applied.result());
+    }
+
+    // Whether --verify-dns has anything to look up after `applied`.
+    pub fn verifying(&self, update: &PdnsRrsetUpdate, applied: &Applied) -> bool {
+        self.verifier.is_some() && applied.result().is_ok() && Verifier::checkable(update)
+    }
+
+    // Gives up at `deadline`, when there is one, as having not seen it.
+    pub async fn verify(
+        &self,
+        origin: &Origin,
+        zone: &str,
+        update: &PdnsRrsetUpdate,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<(), String> {
+        let verifier = match &self.verifier {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let result = match deadline {
+            Some(d) => tokio::time::timeout_at(d, verifier.verify(update))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!(
+                        "{} {} not seen at {} before the request's deadline",
+                        update.type_,
+                        update.name,
+                        verifier.resolver()
+                    ))
+                }),
+            None => verifier.verify(update).await,
+        };
+        match &result {
+            Ok(()) => {
+                metrics::inc(&self.metrics.dns_verified);
+                debug!(
+                    "[{}] {} {} checks out at {}",
+                    origin.request_id,
+                    update.type_,
+                    update.name,
+                    verifier.resolver()
+                );
+            }
+            Err(e) => {
+                metrics::inc(&self.metrics.dns_verify_failures);
+                warn!("[{}] {}", origin.request_id, e);
+            }
+        }
+        if let Some(audit) = &self.audit {
+            audit.verified(origin, zone, update, &result);
+        }
+        result
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    updates: HashMap<RrsetKey, (PdnsRrsetUpdate, Arc<Origin>)>,
+    in_flight: HashSet<RrsetKey>,
+}
+
+#[derive(Clone)]
+pub struct ApplyQueue {
+    tx: mpsc::Sender<RrsetKey>,
+    pending: Arc<Mutex<Pending>>,
+    queue_size: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl ApplyQueue {
+    pub fn start(
+        pdns: Arc<PdnsApi>,
+        metrics: Arc<Metrics>,
+        outcomes: Outcomes,
+        workers: usize,
+        queue_size: usize,
+        retries: u32,
+    ) -> ApplyQueue {
+        let (tx, rx) = mpsc::channel(queue_size);
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        for id in 0..workers {
+            let worker = Worker {
+                id,
+                pdns: pdns.clone(),
+                outcomes: outcomes.clone(),
+                retries,
+            };
+            tokio::spawn(worker.run(rx.clone(), pending.clone()));
+        }
+
+        ApplyQueue {
+            tx,
+            pending,
+            queue_size,
+            metrics,
+        }
+    }
+
+    // More than half the queue is waiting on pdns.
+    pub fn busy(&self) -> bool {
+        self.pending.lock().unwrap().updates.len() > self.queue_size / 2
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn enqueue(
+        &self,
+        updates: Vec<(String, PdnsRrsetUpdate)>,
+        origin: Origin,
+    ) -> Result<(), tonic::Status> {
+        let origin = Arc::new(origin);
+        let mut permits = Vec::with_capacity(updates.len());
+        for _ in 0..updates.len() {
+            match self.tx.try_reserve() {
+                Ok(p) => permits.push(p),
+                Err(_) => {
+                    metrics::inc(&self.metrics.apply_rejected);
+                    warn!("apply queue full, rejecting {} updates", updates.len());
+                    return Err(tonic::Status::resource_exhausted("apply queue is full"));
+                }
+            }
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for ((zone, update), permit) in updates.into_iter().zip(permits) {
+            let key = RrsetKey::new(&zone, &update);
+            let queued = pending.in_flight.contains(&key);
+            if pending
+                .updates
+                .insert(key.clone(), (update, origin.clone()))
+                .is_some()
+            {
+                debug!("collapsed queued update for {:?}", key);
+                metrics::inc(&self.metrics.apply_collapsed);
+            } else if !queued {
+                permit.send(key);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Worker {
+    id: usize,
+    pdns: Arc<PdnsApi>,
+    outcomes: Outcomes,
+    retries: u32,
+}
+
+impl Worker {
+    async fn run(
+        self,
+        rx: Arc<tokio::sync::Mutex<mpsc::Receiver<RrsetKey>>>,
+        pending: Arc<Mutex<Pending>>,
+    ) {
+        loop {
+            let key = match rx.lock().await.recv().await {
+                Some(k) => k,
+                None => return,
+            };
+
+            {
+                let mut p = pending.lock().unwrap();
+                if p.in_flight.contains(&key) || !p.updates.contains_key(&key) {
+                    continue;
+                }
+                p.in_flight.insert(key.clone());
+            }
+
+            // Anything enqueued for this key while we were applying lands in
+            // `pending` without being re-queued, so drain it here to keep updates
+            // for one rrset ordered. Waiting on the zone's rate limit before
+            // taking an update means one enqueued meanwhile replaces it instead
+            // of following it.
+            loop {
+                {
+                    let mut p = pending.lock().unwrap();
+                    if !p.updates.contains_key(&key) {
+                        p.in_flight.remove(&key);
+                        break;
+                    }
+                }
+                let admitted = self.pdns.throttle(&key.zone).await;
+                let (update, origin) = match pending.lock().unwrap().updates.remove(&key) {
+                    Some(next) => next,
+                    None => continue,
+                };
+                debug!("worker {}: applying {:?}", self.id, key);
+                let applied = if admitted {
+                    self.pdns
+                        .apply_throttled(
+                            &key.zone,
+                            update.clone(),
+                            self.retries,
+                            &origin.request_id,
+                        )
+                        .await
+                } else {
+                    self.pdns.throttled(&key.zone)
+                };
+                self.outcomes.record(&origin, &key.zone, &update, &applied);
+                // Off the worker, so the wait doesn't hold up the queue.
+                if self.outcomes.verifying(&update, &applied) {
+                    let outcomes = self.outcomes.clone();
+                    let zone = key.zone.clone();
+                    let origin = origin.clone();
+                    tokio::spawn(async move {
+                        let _ = outcomes.verify(&origin, &zone, &update, None).await;
+                    });
+                }
+            }
+        }
+    }
+}
+
+pub async fn apply_with_retries(
+    target: &PdnsTarget,
+    metrics: &Metrics,
+    zone: &str,
+    update: PdnsRrsetUpdate,
+    retries: u32,
+    request_id: &str,
+) -> Result<(), ApplyError> {
+    let mut try_cnt = 0;
+    loop {
+        let attempt = otel::traced(
+            None,
+            "pdns PATCH",
+            vec![
+                ("pdns.endpoint", target.endpoint.clone()),
+                ("dns.zone", zone.to_owned()),
+                ("dns.name", update.name.clone()),
+                ("dns.type", update.type_.to_owned()),
+                ("attempt", (try_cnt + 1).to_string()),
+            ],
+            target.apply_update(zone, update.clone()),
+        );
+        match attempt.await {
+            Ok(()) => {
+                metrics::inc(&metrics.pdns_applied);
+                metrics::inc(&target.applied);
+                debug!(
+                    "{}: applied {} {} in {} (applied: {}, failures: {})",
+                    target.endpoint,
+                    update.type_,
+                    update.name,
+                    zone,
+                    metrics::get(&target.applied),
+                    metrics::get(&target.failures)
+                );
+                return Ok(());
+            }
+            Err(e) if try_cnt < retries && e.is_retryable() => {
+                metrics::inc(&metrics.pdns_retries);
+                let next_try = 2_u64.pow(try_cnt);
+                warn!(
+                    "[{}] {}: applying {} {} failed ({}), retrying in {} seconds",
+                    request_id, target.endpoint, update.type_, update.name, e, next_try
+                );
+                tokio::time::sleep(tokio::time::Duration::from_secs(next_try)).await;
+                try_cnt += 1;
+            }
+            Err(e) => {
+                metrics::inc(&metrics.pdns_failures);
+                metrics::inc(&target.failures);
+                error!(
+                    "[{}] {}: giving up on {} {} in {}: {}",
+                    request_id, target.endpoint, update.type_, update.name, zone, e
+                );
+                return Err(e);
+            }
+        }
+    }
+}
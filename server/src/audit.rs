@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::metrics::{self, Metrics};
+use crate::{Applied, ApplyError, PdnsRrsetUpdate};
+
+const FSYNC_INTERVAL_SECS: u64 = 5;
+
+// Who caused a change: the connection it came in on, the hostname it was
+// advertised (or deregistered) under, the labels and maintenance flags it
+// carried and the request id it was logged with.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    pub peer: Option<SocketAddr>,
+    pub hostname: String,
+    pub labels: BTreeMap<String, String>,
+    pub disabled: bool,
+    pub ttl_override: Option<u32>,
+    pub request_id: String,
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    timestamp: String,
+    peer: Option<String>,
+    hostname: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    disabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_override: Option<u32>,
+    request_id: String,
+    zone: String,
+    name: String,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    old_content: Option<Vec<String>>,
+    new_content: Vec<String>,
+    changetype: &'static str,
+    pdns_status: Option<u16>,
+    error: Option<String>,
+    // Per target, when there's more than one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    targets: Vec<AuditTarget>,
+}
+
+#[derive(Serialize)]
+struct AuditTarget {
+    endpoint: String,
+    pdns_status: Option<u16>,
+    error: Option<String>,
+}
+
+fn outcome(result: Result<(), &ApplyError>) -> (Option<u16>, Option<String>) {
+    match result {
+        Ok(()) => (Some(reqwest::StatusCode::NO_CONTENT.as_u16()), None),
+        Err(e @ (ApplyError::Response(status, _) | ApplyError::ZoneMissing(status, _))) => {
+            (Some(status.as_u16()), Some(e.to_string()))
+        }
+        Err(e @ (ApplyError::Request(_) | ApplyError::Throttled(_) | ApplyError::ReadOnly)) => {
+            (None, Some(e.to_string()))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    tx: mpsc::Sender<AuditEntry>,
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    pub async fn start(path: PathBuf, metrics: Arc<Metrics>) -> Result<AuditLog> {
+        let file = open(&path).await?;
+        let reopen = signal(SignalKind::user_defined1())?;
+        let (tx, rx) = mpsc::channel(4096);
+        tokio::spawn(writer(path, file, rx, reopen, metrics.clone()));
+        Ok(AuditLog { tx, metrics })
+    }
+
+    // A --verify-dns lookup of an rrset written earlier, as changetype
+    // VERIFY with error set if the resolver never gave what was written.
+    pub fn verified(
+        &self,
+        origin: &Origin,
+        zone: &str,
+        update: &PdnsRrsetUpdate,
+        result: &Result<(), String>,
+    ) {
+        self.send(AuditEntry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            peer: origin.peer.map(|p| p.to_string()),
+            hostname: origin.hostname.clone(),
+            labels: origin.labels.clone(),
+            disabled: origin.disabled,
+            ttl_override: origin.ttl_override,
+            request_id: origin.request_id.clone(),
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_,
+            old_content: None,
+            new_content: update.records.iter().map(|r| r.content.clone()).collect(),
+            changetype: "VERIFY",
+            pdns_status: None,
+            error: result.as_ref().err().cloned(),
+            targets: vec![],
+        });
+    }
+
+    pub fn record(&self, origin: &Origin, zone: &str, update: &PdnsRrsetUpdate, applied: &Applied) {
+        let (pdns_status, error) = outcome(applied.result());
+        let targets = if applied.results.len() > 1 {
+            applied
+                .results
+                .iter()
+                .map(|(endpoint, result)| {
+                    let (pdns_status, error) = outcome(result.as_ref().map(|_| ()));
+                    AuditTarget {
+                        endpoint: endpoint.clone(),
+                        pdns_status,
+                        error,
+                    }
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let entry = AuditEntry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            peer: origin.peer.map(|p| p.to_string()),
+            hostname: origin.hostname.clone(),
+            labels: origin.labels.clone(),
+            disabled: origin.disabled,
+            ttl_override: origin.ttl_override,
+            request_id: origin.request_id.clone(),
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_,
+            old_content: None,
+            new_content: update.records.iter().map(|r| r.content.clone()).collect(),
+            changetype: update.changetype,
+            pdns_status,
+            error,
+            targets,
+        };
+        self.send(entry);
+    }
+
+    fn send(&self, entry: AuditEntry) {
+        if let Err(e) = self.tx.try_send(entry) {
+            let entry = e.into_inner();
+            metrics::inc(&self.metrics.audit_failures);
+            error!(
+                "audit log backlogged, dropped entry for {} {}",
+                entry.type_, entry.name
+            );
+        }
+    }
+}
+
+async fn open(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("error opening audit log {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+async fn flush(out: &mut BufWriter<File>) -> std::io::Result<()> {
+    out.flush().await?;
+    out.get_ref().sync_data().await
+}
+
+async fn writer(
+    path: PathBuf,
+    mut out: BufWriter<File>,
+    mut rx: mpsc::Receiver<AuditEntry>,
+    mut reopen: tokio::signal::unix::Signal,
+    metrics: Arc<Metrics>,
+) {
+    // The last content we successfully published per rrset, so entries can
+    // say what a change replaced. Only covers changes made since startup.
+    let mut published: HashMap<(String, String, &'static str), Vec<String>> = HashMap::new();
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(FSYNC_INTERVAL_SECS));
+    let mut dirty = false;
+
+    loop {
+        tokio::select! {
+            entry = rx.recv() => {
+                let mut entry = match entry {
+                    Some(e) => e,
+                    None => break,
+                };
+                let key = (entry.zone.clone(), entry.name.clone(), entry.type_);
+                match entry.changetype {
+                    // Lookups change nothing.
+                    "VERIFY" => {}
+                    _ if entry.error.is_some() => {
+                        entry.old_content = published.get(&key).cloned();
+                    }
+                    "DELETE" => entry.old_content = published.remove(&key),
+                    _ => {
+                        entry.old_content = published.insert(key, entry.new_content.clone());
+                    }
+                }
+
+                let mut line = serde_json::to_vec(&entry).unwrap();
+                line.push(b'\n');
+                if let Err(e) = out.write_all(&line).await {
+                    metrics::inc(&metrics.audit_failures);
+                    error!("error writing audit log {}: {}", path.display(), e);
+                }
+                dirty = true;
+            }
+            _ = interval.tick(), if dirty => {
+                if let Err(e) = flush(&mut out).await {
+                    metrics::inc(&metrics.audit_failures);
+                    error!("error syncing audit log {}: {}", path.display(), e);
+                }
+                dirty = false;
+            }
+            _ = reopen.recv() => {
+                if let Err(e) = flush(&mut out).await {
+                    metrics::inc(&metrics.audit_failures);
+                    error!("error syncing audit log {}: {}", path.display(), e);
+                }
+                match open(&path).await {
+                    Ok(o) => {
+                        info!("reopened audit log {}", path.display());
+                        out = o;
+                    }
+                    Err(e) => {
+                        metrics::inc(&metrics.audit_failures);
+                        error!("{:?}", e);
+                    }
+                }
+                dirty = false;
+            }
+        }
+    }
+
+    if let Err(e) = flush(&mut out).await {
+        error!("error syncing audit log {}: {}", path.display(), e);
+    }
+}
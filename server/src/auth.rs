@@ -0,0 +1,176 @@
+use anyhow::{ensure, Context, Result};
+use log::warn;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub struct TokenSet {
+    tokens: Vec<Vec<u8>>,
+}
+
+impl TokenSet {
+    // One token per line; blank lines and lines starting with '#' are ignored
+    // so old and new tokens can be listed side by side during a rotation.
+    pub fn load(path: &Path) -> Result<TokenSet> {
+        let mode = std::fs::metadata(path)
+            .with_context(|| format!("error reading {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o004 != 0 {
+            warn!(
+                "auth token file {} is world-readable (mode {:o})",
+                path.display(),
+                mode & 0o777
+            );
+        }
+
+        let tokens: Vec<Vec<u8>> = std::fs::read_to_string(path)
+            .with_context(|| format!("error reading {}", path.display()))?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.as_bytes().to_vec())
+            .collect();
+        ensure!(!tokens.is_empty(), "{} contains no tokens", path.display());
+
+        Ok(TokenSet { tokens })
+    }
+
+    // The token to present when this server is the client, e.g. to its peer.
+    pub fn first(&self) -> &str {
+        std::str::from_utf8(&self.tokens[0]).unwrap_or_default()
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self, authorization: Option<&str>) -> Result<(), tonic::Status> {
+        let presented = match authorization.and_then(|v| v.strip_prefix("Bearer ")) {
+            Some(t) => t.as_bytes(),
+            None => return Err(tonic::Status::unauthenticated("missing bearer token")),
+        };
+
+        // Check every token so timing doesn't reveal which (if any) matched.
+        let matched = self
+            .tokens
+            .iter()
+            .fold(false, |m, t| constant_time_eq(t, presented) | m);
+        if matched {
+            Ok(())
+        } else {
+            Err(tonic::Status::unauthenticated("invalid bearer token"))
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::strapper::node_state_service_client::NodeStateServiceClient;
+    use std::convert::Infallible;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use structopt::StructOpt;
+    use tonic::transport::Channel;
+
+    use super::TokenSet;
+
+    fn token_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        f
+    }
+
+    // The server's own gRPC service, interceptor and all, on a loopback port.
+    async fn serve(tokens: &tempfile::NamedTempFile) -> SocketAddr {
+        let path = tokens.path().to_str().unwrap();
+        let opt = crate::Opt::from_iter_safe(&["server", "--auth-token-file", path]).unwrap();
+        let service = crate::grpc_service(crate::build_server(&opt).await.unwrap());
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(hyper::service::make_service_fn(move |_| {
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(service) }
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn get_status(
+        addr: SocketAddr,
+        authorization: Option<&str>,
+    ) -> Result<(), tonic::Status> {
+        let mut client: NodeStateServiceClient<Channel> =
+            NodeStateServiceClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+        let mut request = tonic::Request::new(());
+        if let Some(a) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", a.parse().unwrap());
+        }
+        client.get_status(request).await.map(|_| ())
+    }
+
+    fn refused(r: Result<(), tonic::Status>) -> String {
+        let status = r.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        status.message().to_owned()
+    }
+
+    #[tokio::test]
+    async fn missing_and_wrong() {
+        let tokens = token_file("s3cret\n");
+        let addr = serve(&tokens).await;
+
+        get_status(addr, Some("Bearer s3cret")).await.unwrap();
+        assert_eq!(
+            refused(get_status(addr, None).await),
+            "missing bearer token"
+        );
+        assert_eq!(
+            refused(get_status(addr, Some("s3cret")).await),
+            "missing bearer token"
+        );
+        assert_eq!(
+            refused(get_status(addr, Some("Basic s3cret")).await),
+            "missing bearer token"
+        );
+        for wrong in &["Bearer ", "Bearer s3cre", "Bearer s3cret2", "Bearer S3CRET"] {
+            assert_eq!(
+                refused(get_status(addr, Some(wrong)).await),
+                "invalid bearer token"
+            );
+        }
+    }
+
+    // Both tokens work while both are listed; the old one stops once it's
+    // taken out.
+    #[tokio::test]
+    async fn rotated() {
+        let during = token_file("# rotating, 2021-03\nold\n\n  new  \n");
+        let addr = serve(&during).await;
+        get_status(addr, Some("Bearer old")).await.unwrap();
+        get_status(addr, Some("Bearer new")).await.unwrap();
+
+        let after = token_file("new\n");
+        let addr = serve(&after).await;
+        get_status(addr, Some("Bearer new")).await.unwrap();
+        assert_eq!(
+            refused(get_status(addr, Some("Bearer old")).await),
+            "invalid bearer token"
+        );
+    }
+
+    #[test]
+    fn no_tokens() {
+        assert!(TokenSet::load(token_file("# none yet\n\n").path()).is_err());
+        let tokens = TokenSet::load(token_file("\n# peer\nfirst\nsecond\n").path()).unwrap();
+        assert_eq!(tokens.first(), "first");
+    }
+}
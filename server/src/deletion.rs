@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use proto::strapper;
+
+use crate::apply::RrsetKey;
+use crate::audit::Origin;
+use crate::PdnsRrsetUpdate;
+
+struct State {
+    lifted: bool,
+    // The registry came from somewhere that knew every node, so nothing
+    // is missing from it.
+    loaded: bool,
+    seen: HashSet<String>,
+    held: HashMap<RrsetKey, (PdnsRrsetUpdate, Arc<Origin>)>,
+}
+
+// Keeps a freshly started server from deleting records it only doesn't know
+// about yet: until --deletion-grace-secs have passed and
+// --deletion-min-nodes nodes have advertised, or a primary has pushed its
+// registry to us, DELETEs are held instead of written. A held delete is
+// dropped if the rrset is written again meanwhile, and the rest are checked
+// against the registry once the guard lifts; it never comes back.
+pub struct DeletionGuard {
+    started: Instant,
+    grace: Duration,
+    min_nodes: usize,
+    state: Mutex<State>,
+}
+
+impl DeletionGuard {
+    pub fn new(grace: Duration, min_nodes: usize) -> DeletionGuard {
+        DeletionGuard {
+            started: Instant::now(),
+            grace,
+            min_nodes,
+            state: Mutex::new(State {
+                lifted: grace.is_zero() && min_nodes == 0,
+                loaded: false,
+                seen: HashSet::new(),
+                held: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn lifted(&self) -> bool {
+        self.state.lock().unwrap().lifted
+    }
+
+    pub fn seen(&self, hostname: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state.lifted {
+            state.seen.insert(hostname.to_owned());
+        }
+    }
+
+    pub fn loaded(&self) {
+        self.state.lock().unwrap().loaded = true;
+    }
+
+    // The updates that may be written now; the deletes among them are held
+    // while the guard is up.
+    pub fn hold(
+        &self,
+        updates: Vec<(String, PdnsRrsetUpdate)>,
+        origin: &Origin,
+    ) -> Vec<(String, PdnsRrsetUpdate)> {
+        let mut state = self.state.lock().unwrap();
+        if state.lifted {
+            return updates;
+        }
+        let origin = Arc::new(origin.clone());
+        let mut allowed = vec![];
+        for (zone, update) in updates {
+            let key = RrsetKey::new(&zone, &update);
+            if update.changetype == "DELETE" {
+                state.held.insert(key, (update, origin.clone()));
+            } else {
+                state.held.remove(&key);
+                allowed.push((zone, update));
+            }
+        }
+        allowed
+    }
+
+    // Lifts the guard if it's time, returning the deletes it held; None if
+    // it's not time, or already lifted.
+    pub fn lift(&self) -> Option<Vec<(String, PdnsRrsetUpdate, Arc<Origin>)>> {
+        let mut state = self.state.lock().unwrap();
+        if state.lifted || !(state.loaded || self.due(&state)) {
+            return None;
+        }
+        state.lifted = true;
+        state.seen.clear();
+        Some(
+            state
+                .held
+                .drain()
+                .map(|(key, (update, origin))| (key.zone, update, origin))
+                .collect(),
+        )
+    }
+
+    fn due(&self, state: &State) -> bool {
+        self.started.elapsed() >= self.grace && state.seen.len() >= self.min_nodes
+    }
+
+    pub fn to_proto(&self) -> strapper::DeletionGuard {
+        let state = self.state.lock().unwrap();
+        strapper::DeletionGuard {
+            active: !state.lifted,
+            remaining_secs: if state.lifted {
+                0
+            } else {
+                self.grace.saturating_sub(self.started.elapsed()).as_secs()
+            },
+            nodes_seen: state.seen.len() as u32,
+            min_nodes: self.min_nodes as u32,
+            held_deletes: state.held.len() as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DeletionGuard;
+    use crate::audit::Origin;
+    use crate::{PdnsRecord, PdnsRrsetUpdate};
+
+    fn origin(hostname: &str) -> Origin {
+        Origin {
+            peer: None,
+            hostname: hostname.to_owned(),
+            labels: Default::default(),
+            disabled: false,
+            ttl_override: None,
+            request_id: "test".to_owned(),
+        }
+    }
+
+    fn name(node: usize) -> String {
+        format!("node{}.example.com.", node)
+    }
+
+    fn replace(node: usize) -> (String, PdnsRrsetUpdate) {
+        (
+            "example.com.".to_owned(),
+            PdnsRrsetUpdate {
+                name: name(node),
+                type_: "AAAA",
+                ttl: 3600,
+                changetype: "REPLACE",
+                records: vec![PdnsRecord {
+                    content: format!("2001:db8::{:x}", node),
+                    disabled: false,
+                }],
+                comments: vec![],
+                merge_owner: None,
+                vip: false,
+            },
+        )
+    }
+
+    fn delete(node: usize) -> (String, PdnsRrsetUpdate) {
+        let (zone, mut update) = replace(node);
+        update.changetype = "DELETE";
+        update.records.clear();
+        (zone, update)
+    }
+
+    // A cold start knows 1 of the 100 nodes with records in pdns. Whatever
+    // would clean up after the other 99 deletes nothing until enough of
+    // them have been heard from, and then only the records of those that
+    // haven't come back.
+    #[test]
+    fn cold_start() {
+        let guard = DeletionGuard::new(Duration::ZERO, 50);
+        assert!(!guard.lifted());
+
+        guard.seen("node0");
+        let mut updates = vec![replace(0)];
+        updates.extend((1..100).map(delete));
+        let written = guard.hold(updates, &origin("node0"));
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].1.name, name(0));
+        assert_eq!(written[0].1.changetype, "REPLACE");
+        assert!(guard.lift().is_none());
+        let status = guard.to_proto();
+        assert!(status.active);
+        assert_eq!((status.nodes_seen, status.min_nodes), (1, 50));
+        assert_eq!(status.held_deletes, 99);
+
+        // Each node that comes back writes its rrset again, which drops the
+        // delete held for it. The same node twice counts once.
+        for node in 1..49 {
+            guard.seen(&format!("node{}", node));
+            let written = guard.hold(vec![replace(node)], &origin("node"));
+            assert_eq!(written.len(), 1);
+        }
+        guard.seen("node1");
+        assert!(guard.lift().is_none());
+        assert_eq!(guard.to_proto().nodes_seen, 49);
+        assert_eq!(guard.to_proto().held_deletes, 51);
+
+        guard.seen("node49");
+        guard.hold(vec![replace(49)], &origin("node49"));
+        let mut held: Vec<_> = guard
+            .lift()
+            .expect("lifted at 50 nodes")
+            .into_iter()
+            .map(|(zone, update, _)| (zone, update.name))
+            .collect();
+        held.sort();
+        let mut expected: Vec<_> = (50..100)
+            .map(|n| ("example.com.".to_owned(), name(n)))
+            .collect();
+        expected.sort();
+        assert_eq!(held, expected);
+
+        // Lifted for good.
+        assert!(guard.lifted());
+        assert!(guard.lift().is_none());
+        assert_eq!(guard.hold(vec![delete(1)], &origin("node1")).len(), 1);
+        let status = guard.to_proto();
+        assert!(!status.active);
+        assert_eq!((status.remaining_secs, status.held_deletes), (0, 0));
+    }
+
+    #[test]
+    fn grace_and_state_file() {
+        // Time alone isn't enough while nodes are missing.
+        let guard = DeletionGuard::new(Duration::from_secs(3600), 0);
+        guard.hold(vec![delete(1)], &origin("node1"));
+        assert!(guard.lift().is_none());
+        assert!(guard.to_proto().remaining_secs > 3590);
+        // A loaded state file knew every node, grace or not.
+        guard.loaded();
+        assert_eq!(guard.lift().map(|held| held.len()), Some(1));
+
+        // Nothing to wait for, nothing held.
+        let guard = DeletionGuard::new(Duration::ZERO, 0);
+        assert!(guard.lifted());
+        assert_eq!(guard.hold(vec![delete(1)], &origin("node1")).len(), 1);
+    }
+}
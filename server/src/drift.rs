@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use proto::strapper;
+
+use crate::{merge, PdnsRecord, PdnsRrset, PdnsRrsetUpdate};
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+struct Key {
+    zone: String,
+    // Lowercased and without the trailing dot, as pdns may spell it either
+    // way.
+    name: String,
+    type_: String,
+}
+
+impl Key {
+    fn new(zone: &str, name: &str, type_: &str) -> Key {
+        Key {
+            zone: zone.to_owned(),
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            type_: type_.to_owned(),
+        }
+    }
+}
+
+// One rrset as the registry says it should be.
+struct Desired {
+    name: String,
+    ttl: u32,
+    records: Vec<PdnsRecord>,
+    // The nodes whose records a shared (merge-mode) rrset holds; None for
+    // one that belongs to a single node.
+    owners: Option<HashSet<String>>,
+    // For VIP rrsets, when the node it's taken from saw the address.
+    observed_ms: u64,
+}
+
+impl Desired {
+    fn deleted(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+// What the registry comes to: the updates planning makes for each node,
+// given as (when the node saw its addresses, its updates), folded into one
+// rrset each the way writing them would leave pdns. Shared rrsets hold
+// every owner's records, a VIP goes to whichever node saw it last, and of
+// nodes contending for any other rrset the first is taken, as with
+// --ownership-conflict first-writer.
+pub struct Expected {
+    rrsets: BTreeMap<Key, Desired>,
+    // The names and types strapper writes, outside of which anything in pdns
+    // is someone else's business.
+    names: HashSet<(String, String)>,
+    types: HashSet<&'static str>,
+}
+
+impl Expected {
+    pub fn new(nodes: impl IntoIterator<Item = (u64, Vec<(String, PdnsRrsetUpdate)>)>) -> Expected {
+        let mut expected = Expected {
+            rrsets: BTreeMap::new(),
+            names: HashSet::new(),
+            types: HashSet::new(),
+        };
+        for (observed_ms, updates) in nodes {
+            for (zone, update) in updates {
+                expected.add(&zone, update, observed_ms);
+            }
+        }
+        expected
+    }
+
+    fn add(&mut self, zone: &str, update: PdnsRrsetUpdate, observed_ms: u64) {
+        let key = Key::new(zone, &update.name, update.type_);
+        self.names.insert((zone.to_owned(), key.name.clone()));
+        self.types.insert(update.type_);
+        let records = if update.changetype == "DELETE" {
+            vec![]
+        } else {
+            update.records
+        };
+        let (name, ttl) = (update.name, update.ttl);
+        if let Some(owner) = update.merge_owner {
+            let desired = self.rrsets.entry(key).or_insert_with(|| Desired {
+                name,
+                ttl,
+                records: vec![],
+                owners: Some(HashSet::new()),
+                observed_ms,
+            });
+            if !records.is_empty() {
+                desired
+                    .owners
+                    .get_or_insert_with(HashSet::new)
+                    .insert(owner);
+            }
+            for r in records {
+                if !desired.records.iter().any(|d| d.content == r.content) {
+                    desired.records.push(r);
+                }
+            }
+            return;
+        }
+        let desired = Desired {
+            name,
+            ttl,
+            records,
+            owners: None,
+            observed_ms,
+        };
+        let keep = match self.rrsets.get(&key) {
+            None => false,
+            Some(d) if d.deleted() => false,
+            // A node withdrawing what another publishes doesn't count.
+            Some(_) if desired.deleted() => true,
+            Some(d) => !(update.vip && observed_ms > d.observed_ms),
+        };
+        if !keep {
+            self.rrsets.insert(key, desired);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rrsets.len()
+    }
+
+    pub fn zones(&self) -> Vec<String> {
+        let mut zones: Vec<_> = self.rrsets.keys().map(|k| k.zone.clone()).collect();
+        zones.dedup();
+        zones
+    }
+
+    // The names the registry uses in the zone, as pdns spells them, for
+    // asking for just those.
+    pub fn names_in(&self, zone: &str) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .rrsets
+            .range(Key::new(zone, "", "")..)
+            .take_while(|(key, _)| key.zone == zone)
+            .map(|(key, _)| format!("{}.", key.name))
+            .collect();
+        names.dedup();
+        names
+    }
+
+    // How the zone's rrsets in pdns, given to the comparison one at a time,
+    // differ from what's expected of it.
+    pub fn compare(&self, endpoint: &str, zone: &str) -> Comparison<'_> {
+        Comparison {
+            expected: self,
+            endpoint: endpoint.to_owned(),
+            zone: zone.to_owned(),
+            seen: HashSet::new(),
+            entries: vec![],
+        }
+    }
+}
+
+// One zone on one target, compared as its rrsets are read, so a large zone
+// needn't be held whole.
+pub struct Comparison<'a> {
+    expected: &'a Expected,
+    endpoint: String,
+    zone: String,
+    seen: HashSet<Key>,
+    entries: Vec<strapper::DriftEntry>,
+}
+
+impl Comparison<'_> {
+    fn entry(&self, name: &str, type_: &str, kind: strapper::DriftKind) -> strapper::DriftEntry {
+        strapper::DriftEntry {
+            endpoint: self.endpoint.clone(),
+            zone: self.zone.clone(),
+            name: name.to_owned(),
+            r#type: type_.to_owned(),
+            kind: kind as i32,
+            ..Default::default()
+        }
+    }
+
+    pub fn rrset(&mut self, rrset: &PdnsRrset) {
+        let key = Key::new(&self.zone, &rrset.name, &rrset.type_);
+        let desired = match self.expected.rrsets.get(&key) {
+            Some(d) => d,
+            None => {
+                if let Some(e) = self.unaccounted(&key, rrset) {
+                    let entry = strapper::DriftEntry {
+                        actual: contents(&rrset.records),
+                        actual_ttl: rrset.ttl,
+                        ..self.entry(&rrset.name, &rrset.type_, e)
+                    };
+                    self.entries.push(entry);
+                }
+                return;
+            }
+        };
+        self.seen.insert(key);
+        if desired.deleted() {
+            // A shared rrset nobody publishes into any more is only ours to
+            // delete if strapper still marks records in it.
+            let ours = desired.owners.is_none() || merge::owned(&rrset.comments).next().is_some();
+            if ours && !rrset.records.is_empty() {
+                let entry = strapper::DriftEntry {
+                    actual: contents(&rrset.records),
+                    actual_ttl: rrset.ttl,
+                    ..self.entry(
+                        &rrset.name,
+                        &rrset.type_,
+                        strapper::DriftKind::DriftUnexpected,
+                    )
+                };
+                self.entries.push(entry);
+            }
+            return;
+        }
+        let base = || strapper::DriftEntry {
+            expected_ttl: desired.ttl,
+            actual_ttl: rrset.ttl,
+            ..self.entry(&rrset.name, &rrset.type_, strapper::DriftKind::DriftContent)
+        };
+        let mut entries = vec![];
+        match &desired.owners {
+            None => {
+                if !same_records(&desired.records, &rrset.records) {
+                    entries.push(strapper::DriftEntry {
+                        expected: contents(&desired.records),
+                        actual: contents(&rrset.records),
+                        ..base()
+                    });
+                }
+            }
+            // Shared rrsets may hold records put there by hand, so only what
+            // nodes are missing, and what strapper marked for nodes that are
+            // gone, counts.
+            Some(owners) => {
+                let missing: Vec<_> = desired
+                    .records
+                    .iter()
+                    .filter(|d| {
+                        !rrset
+                            .records
+                            .iter()
+                            .any(|r| same_content(&r.content, &d.content))
+                    })
+                    .map(|d| d.content.clone())
+                    .collect();
+                if !missing.is_empty() {
+                    entries.push(strapper::DriftEntry {
+                        kind: strapper::DriftKind::DriftMissing as i32,
+                        expected: missing,
+                        ..base()
+                    });
+                }
+                let stale: Vec<_> = merge::owned(&rrset.comments)
+                    .filter(|(owner, _)| !owners.contains(*owner))
+                    .map(|(_, content)| content.to_owned())
+                    .collect();
+                if !stale.is_empty() {
+                    entries.push(strapper::DriftEntry {
+                        kind: strapper::DriftKind::DriftUnexpected as i32,
+                        actual: stale,
+                        ..base()
+                    });
+                }
+            }
+        }
+        if rrset.ttl != desired.ttl {
+            entries.push(strapper::DriftEntry {
+                kind: strapper::DriftKind::DriftTtl as i32,
+                ..base()
+            });
+        }
+        self.entries.extend(entries);
+    }
+
+    // An rrset no node accounts for is unexpected if strapper marked records
+    // in it, or it's of a type strapper writes at a name it writes to.
+    fn unaccounted(&self, key: &Key, rrset: &PdnsRrset) -> Option<strapper::DriftKind> {
+        let marked = merge::owned(&rrset.comments).next().is_some();
+        let managed = self.expected.types.contains(rrset.type_.as_str())
+            && self
+                .expected
+                .names
+                .contains(&(self.zone.clone(), key.name.clone()));
+        if marked || managed {
+            Some(strapper::DriftKind::DriftUnexpected)
+        } else {
+            None
+        }
+    }
+
+    // The entries, once every rrset has been given; anything expected that
+    // wasn't is missing.
+    pub fn finish(mut self) -> Vec<strapper::DriftEntry> {
+        let expected = self.expected;
+        for (key, desired) in expected.rrsets.range(Key::new(&self.zone, "", "")..) {
+            if key.zone != self.zone {
+                break;
+            }
+            if self.seen.contains(key) || desired.deleted() {
+                continue;
+            }
+            let entry = strapper::DriftEntry {
+                expected: contents(&desired.records),
+                expected_ttl: desired.ttl,
+                ..self.entry(&desired.name, &key.type_, strapper::DriftKind::DriftMissing)
+            };
+            self.entries.push(entry);
+        }
+        self.entries
+    }
+}
+
+fn contents(records: &[PdnsRecord]) -> Vec<String> {
+    let mut contents: Vec<_> = records
+        .iter()
+        .map(|r| {
+            if r.disabled {
+                format!("{} (disabled)", r.content)
+            } else {
+                r.content.clone()
+            }
+        })
+        .collect();
+    contents.sort();
+    contents
+}
+
+// pdns gives names back in its own case.
+fn same_content(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn same_records(a: &[PdnsRecord], b: &[PdnsRecord]) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|x| {
+            b.iter()
+                .any(|y| same_content(&x.content, &y.content) && x.disabled == y.disabled)
+        })
+}
+
+// The last report, and a turn so two checks don't run at once.
+#[derive(Default)]
+pub struct Drift {
+    last: Mutex<Option<strapper::DriftReport>>,
+    pub running: tokio::sync::Mutex<()>,
+}
+
+impl Drift {
+    pub fn last(&self) -> Option<strapper::DriftReport> {
+        self.last.lock().unwrap().clone()
+    }
+
+    pub fn record(
+        &self,
+        rrsets_checked: usize,
+        entries: Vec<strapper::DriftEntry>,
+        errors: Vec<String>,
+    ) -> strapper::DriftReport {
+        let report = strapper::DriftReport {
+            checked_at_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            rrsets_checked: rrsets_checked as u32,
+            entries,
+            errors,
+        };
+        *self.last.lock().unwrap() = Some(report.clone());
+        report
+    }
+
+    // Entries of each kind in the last report, for /v1/metrics; None
+    // before the first.
+    pub fn counts(&self) -> Option<[(&'static str, usize); 4]> {
+        let last = self.last.lock().unwrap();
+        let report = last.as_ref()?;
+        let count = |kind: strapper::DriftKind| {
+            report
+                .entries
+                .iter()
+                .filter(|e| e.kind == kind as i32)
+                .count()
+        };
+        Some([
+            ("missing", count(strapper::DriftKind::DriftMissing)),
+            ("unexpected", count(strapper::DriftKind::DriftUnexpected)),
+            ("content", count(strapper::DriftKind::DriftContent)),
+            ("ttl", count(strapper::DriftKind::DriftTtl)),
+        ])
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::io;
+
+// What kind of failure ended the server, for scripts to tell apart by exit
+// code. The codes are the agent's, where the two have the same kind:
+//
+//   1  internal: anything not below
+//   2  config: bad arguments, files they name, or settings that conflict
+//   3  network: a listener couldn't be bound or taken over
+//   5  pdns: pdns couldn't be reached at startup
+//
+// (4 is the agent's netlink.) Errors are classified by what's in their
+// chain; where that can't tell, the failing call attaches a category as
+// context.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Category {
+    Internal,
+    Config,
+    Network,
+    Pdns,
+}
+
+impl Category {
+    pub fn code(self) -> i32 {
+        match self {
+            Category::Internal => 1,
+            Category::Config => 2,
+            Category::Network => 3,
+            Category::Pdns => 5,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Internal => "internal",
+            Category::Config => "config",
+            Category::Network => "network",
+            Category::Pdns => "pdns",
+        }
+    }
+
+    // What to look at first, if there's anything to say.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Category::Internal => None,
+            Category::Config => Some("see --help for the options involved"),
+            Category::Network => Some(
+                "is something else listening there, or does the port need CAP_NET_BIND_SERVICE?",
+            ),
+            Category::Pdns => Some("is --pdns-endpoint reachable, and --pdns-api-key right?"),
+        }
+    }
+}
+
+// As context, so the category reads as part of the message.
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Category::Internal => "internal error",
+            Category::Config => "invalid configuration",
+            Category::Network => "unable to listen",
+            Category::Pdns => "pdns unavailable",
+        })
+    }
+}
+
+pub fn classify(error: &anyhow::Error) -> Category {
+    if let Some(category) = error.downcast_ref::<Category>() {
+        return *category;
+    }
+    for cause in error.chain() {
+        if cause.is::<reqwest::Error>() {
+            return Category::Pdns;
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            if let io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable = e.kind() {
+                return Category::Network;
+            }
+        }
+    }
+    Category::Internal
+}
+
+// For map_err: tags the error with `category` unless it already classifies
+// as something other than internal.
+pub fn or(category: Category) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+    move |e| match classify(&e) {
+        Category::Internal => e.context(category),
+        _ => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use std::io;
+
+    use super::{classify, or, Category};
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        anyhow::Error::new(io::Error::from(kind))
+    }
+
+    #[tokio::test]
+    async fn by_chain() {
+        // Any failure talking to pdns, here one to even build the request.
+        let pdns = reqwest::Client::new()
+            .get("not a url")
+            .send()
+            .await
+            .unwrap_err();
+        let e = anyhow::Error::new(pdns).context("error listing zones");
+        assert_eq!(classify(&e), Category::Pdns);
+
+        for kind in &[io::ErrorKind::AddrInUse, io::ErrorKind::AddrNotAvailable] {
+            let e = io_error(*kind)
+                .context("error binding")
+                .context("error starting");
+            assert_eq!(classify(&e), Category::Network, "{:?}", kind);
+        }
+        // Unlike the agent's, refused connections aren't an upstream's.
+        assert_eq!(
+            classify(&io_error(io::ErrorKind::ConnectionRefused)),
+            Category::Internal
+        );
+        assert_eq!(classify(&anyhow!("something else")), Category::Internal);
+    }
+
+    #[test]
+    fn by_context() {
+        let e = io_error(io::ErrorKind::AddrInUse)
+            .context(Category::Config)
+            .context("error reading --config");
+        assert_eq!(classify(&e), Category::Config);
+        assert_eq!(
+            format!("{:#}", e),
+            "error reading --config: invalid configuration: address in use"
+        );
+
+        // or tags only what doesn't classify already.
+        let e = or(Category::Config)(anyhow!("conflicting options"));
+        assert_eq!(classify(&e), Category::Config);
+        let e = or(Category::Config)(io_error(io::ErrorKind::AddrInUse));
+        assert_eq!(classify(&e), Category::Network);
+        assert_eq!(format!("{:#}", e), "address in use");
+    }
+
+    #[test]
+    fn codes() {
+        let all = [
+            Category::Internal,
+            Category::Config,
+            Category::Network,
+            Category::Pdns,
+        ];
+        let codes: Vec<i32> = all.iter().map(|c| c.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 5]);
+        let names: Vec<&str> = all.iter().map(|c| c.as_str()).collect();
+        assert_eq!(names, vec!["internal", "config", "network", "pdns"]);
+        assert!(all
+            .iter()
+            .all(|c| c.hint().is_some() == (*c != Category::Internal)));
+    }
+}
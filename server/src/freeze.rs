@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use proto::strapper;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Freeze {
+    // Milliseconds since the epoch, so it means the same after a restart;
+    // None until unfrozen.
+    until_ms: Option<u64>,
+    // What the node had advertised when it was frozen, which is what's in
+    // pdns for it; not kept across restarts, like the registry.
+    #[serde(skip)]
+    pub snapshot: Option<strapper::NodeAdvertisement>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Nodes an operator has frozen, by effective hostname. The freezes are kept
+// in `path` across restarts, as WritePause keeps its flags.
+pub struct Freezes {
+    path: Option<PathBuf>,
+    frozen: Mutex<BTreeMap<String, Freeze>>,
+}
+
+impl Freezes {
+    pub fn load(path: Option<PathBuf>) -> Result<Freezes> {
+        let frozen = match &path {
+            Some(p) if p.exists() => serde_json::from_slice(
+                &std::fs::read(p).with_context(|| format!("error reading {}", p.display()))?,
+            )
+            .with_context(|| format!("error parsing {}", p.display()))?,
+            _ => BTreeMap::new(),
+        };
+        Ok(Freezes {
+            path,
+            frozen: Mutex::new(frozen),
+        })
+    }
+
+    // Lapsed freezes count until they're thawed, so nothing is written
+    // for the node ahead of its thaw.
+    pub fn frozen(&self, hostname: &str) -> bool {
+        self.frozen.lock().unwrap().contains_key(hostname)
+    }
+
+    // Freezing a frozen node only changes how long it stays frozen; its
+    // snapshot is still the one pdns has.
+    pub fn freeze(
+        &self,
+        hostname: &str,
+        duration: Option<Duration>,
+        snapshot: Option<strapper::NodeAdvertisement>,
+    ) -> Result<strapper::FrozenNode> {
+        let mut frozen = self.frozen.lock().unwrap();
+        let mut next = frozen.clone();
+        let until_ms = duration.map(|d| now_ms() + d.as_millis() as u64);
+        let snapshot = match frozen.get(hostname) {
+            Some(f) => f.snapshot.clone(),
+            None => snapshot,
+        };
+        next.insert(hostname.to_owned(), Freeze { until_ms, snapshot });
+        self.save(&next)?;
+        *frozen = next;
+        Ok(strapper::FrozenNode {
+            hostname: hostname.to_owned(),
+            until_ms: until_ms.unwrap_or(0),
+        })
+    }
+
+    // None if the node wasn't frozen.
+    pub fn unfreeze(&self, hostname: &str) -> Result<Option<Freeze>> {
+        let mut frozen = self.frozen.lock().unwrap();
+        if !frozen.contains_key(hostname) {
+            return Ok(None);
+        }
+        let mut next = frozen.clone();
+        let freeze = next.remove(hostname);
+        self.save(&next)?;
+        *frozen = next;
+        Ok(freeze)
+    }
+
+    // The nodes whose freeze has run out; they stay frozen until thawed.
+    pub fn lapsed(&self) -> Vec<String> {
+        let now = now_ms();
+        self.frozen
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, f)| f.until_ms.is_some_and(|until| until <= now))
+            .map(|(hostname, _)| hostname.clone())
+            .collect()
+    }
+
+    pub fn list(&self) -> Vec<strapper::FrozenNode> {
+        self.frozen
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hostname, f)| strapper::FrozenNode {
+                hostname: hostname.clone(),
+                until_ms: f.until_ms.unwrap_or(0),
+            })
+            .collect()
+    }
+
+    // Written before the change is made, so a failed write leaves the
+    // freezes as they were.
+    fn save(&self, frozen: &BTreeMap<String, Freeze>) -> Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec(frozen)?)
+                .with_context(|| format!("error writing {}", tmp.display()))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("error renaming to {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use proto::strapper;
+
+// How often pdns is probed until it answers, and how long a probe may take.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The wait a degraded server tells agents to come back after, well short
+// of the backoff they'd otherwise settle into.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GateState {
+    // pdns has answered, or there's no gate.
+    Open,
+    // --queue-until-pdns: advertisements are taken and their writes held.
+    Queueing,
+    // --wait-for-pdns-secs ran out: advertisements are turned away with a
+    // retry hint.
+    Degraded,
+}
+
+impl GateState {
+    pub fn to_proto(self) -> strapper::PdnsGate {
+        match self {
+            GateState::Open => strapper::PdnsGate::Open,
+            GateState::Queueing => strapper::PdnsGate::Queueing,
+            GateState::Degraded => strapper::PdnsGate::Degraded,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GateState::Open => "open",
+            GateState::Queueing => "queueing",
+            GateState::Degraded => "degraded",
+        }
+    }
+}
+
+// Whether pdns has answered since startup. A server started with
+// --wait-for-pdns-secs doesn't listen until it does or the wait runs out;
+// either way, or under --queue-until-pdns, pdns is probed in the background
+// until it answers, and only then is the gate open. It never closes again:
+// pdns going away later is what retries and the quarantine are for.
+pub struct PdnsGate {
+    state: Mutex<GateState>,
+}
+
+impl PdnsGate {
+    pub fn new(state: GateState) -> PdnsGate {
+        PdnsGate {
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn state(&self) -> GateState {
+        *self.state.lock().unwrap()
+    }
+
+    // Returns what the gate was.
+    pub fn open(&self) -> GateState {
+        std::mem::replace(&mut *self.state.lock().unwrap(), GateState::Open)
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use proto::strapper;
+
+use crate::audit::Origin;
+use crate::metrics::{self, Metrics};
+use crate::{Applied, PdnsRrsetUpdate};
+
+// What GetHistory returns without a limit.
+pub const DEFAULT_LIMIT: usize = 50;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Advertised,
+    Rejected,
+    Applied,
+    Failed,
+    Deregistered,
+}
+
+impl Kind {
+    fn to_proto(self) -> strapper::HistoryKind {
+        match self {
+            Kind::Advertised => strapper::HistoryKind::HistoryAdvertised,
+            Kind::Rejected => strapper::HistoryKind::HistoryRejected,
+            Kind::Applied => strapper::HistoryKind::HistoryApplied,
+            Kind::Failed => strapper::HistoryKind::HistoryFailed,
+            Kind::Deregistered => strapper::HistoryKind::HistoryDeregistered,
+        }
+    }
+}
+
+// zone, name and type are empty for entries about a whole node.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    timestamp_ms: u64,
+    kind: Kind,
+    hostname: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    zone: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name: String,
+    #[serde(default, rename = "type", skip_serializing_if = "String::is_empty")]
+    type_: String,
+    description: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    request_id: String,
+}
+
+impl Entry {
+    fn to_proto(&self) -> strapper::HistoryEntry {
+        strapper::HistoryEntry {
+            timestamp_ms: self.timestamp_ms,
+            kind: self.kind.to_proto() as i32,
+            hostname: self.hostname.clone(),
+            zone: self.zone.clone(),
+            name: self.name.clone(),
+            r#type: self.type_.clone(),
+            description: self.description.clone(),
+            request_id: self.request_id.clone(),
+        }
+    }
+}
+
+// Names match with or without the trailing dot, in any case.
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+// One GetHistory request; empty fields match anything.
+pub struct Filter {
+    pub hostname: String,
+    pub zone: String,
+    pub name: String,
+    pub limit: usize,
+}
+
+impl Filter {
+    fn matches(&self, e: &Entry) -> bool {
+        (self.hostname.is_empty() || same_name(&self.hostname, &e.hostname))
+            && (self.zone.is_empty() || same_name(&self.zone, &e.zone))
+            && (self.name.is_empty() || same_name(&self.name, &e.name))
+    }
+}
+
+// The last --history-size things that happened to nodes and their records:
+// accepted and rejected advertisements, rrset writes and deregistrations.
+// Recording only queues the entry for the one task that files it (and, with
+// --persist-history, appends it to the file), so the request path never
+// waits on the journal.
+#[derive(Clone)]
+pub struct History {
+    tx: mpsc::Sender<Entry>,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl History {
+    pub async fn start(
+        size: usize,
+        persist: Option<PathBuf>,
+        metrics: Arc<Metrics>,
+    ) -> Result<History> {
+        let mut entries = VecDeque::with_capacity(size);
+        let file = match &persist {
+            Some(path) => {
+                let loaded = load(path, size).await?;
+                info!(
+                    "loaded {} history entries from {}",
+                    loaded.len(),
+                    path.display()
+                );
+                entries = loaded;
+                // Rewritten so the file starts out no longer than the
+                // journal.
+                let file = compact(path, &entries).await?;
+                Some((path.clone(), file))
+            }
+            None => None,
+        };
+        let entries = Arc::new(Mutex::new(entries));
+        let (tx, rx) = mpsc::channel(4096);
+        tokio::spawn(writer(size, entries.clone(), rx, file, metrics.clone()));
+        Ok(History {
+            tx,
+            entries,
+            metrics,
+        })
+    }
+
+    fn record(&self, entry: Entry) {
+        if self.tx.try_send(entry).is_err() {
+            metrics::inc(&self.metrics.history_dropped);
+        }
+    }
+
+    pub fn node(&self, kind: Kind, hostname: &str, request_id: &str, description: String) {
+        self.record(Entry {
+            timestamp_ms: now_ms(),
+            kind,
+            hostname: hostname.to_owned(),
+            zone: String::new(),
+            name: String::new(),
+            type_: String::new(),
+            description,
+            request_id: request_id.to_owned(),
+        });
+    }
+
+    pub fn applied(
+        &self,
+        origin: &Origin,
+        zone: &str,
+        update: &PdnsRrsetUpdate,
+        applied: &Applied,
+    ) {
+        let (kind, description) = match applied.result() {
+            Ok(()) if update.changetype == "DELETE" => (Kind::Applied, "deleted".to_owned()),
+            Ok(()) => {
+                let contents: Vec<&str> =
+                    update.records.iter().map(|r| r.content.as_str()).collect();
+                (Kind::Applied, format!("set to {}", contents.join(", ")))
+            }
+            Err(e) => (
+                Kind::Failed,
+                format!("{} failed: {}", update.changetype.to_lowercase(), e),
+            ),
+        };
+        self.record(Entry {
+            timestamp_ms: now_ms(),
+            kind,
+            hostname: origin.hostname.clone(),
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_.to_owned(),
+            description,
+            request_id: origin.request_id.clone(),
+        });
+    }
+
+    // Newest first.
+    pub fn query(&self, filter: &Filter) -> Vec<strapper::HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .take(filter.limit)
+            .map(Entry::to_proto)
+            .collect()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// The newest `size` entries of a --persist-history file, one JSON object a
+// line. Lines that don't parse are skipped.
+async fn load(path: &Path, size: usize) -> Result<VecDeque<Entry>> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("error reading history {}", path.display()))
+        }
+    };
+    let mut entries = VecDeque::with_capacity(size);
+    let mut invalid = 0;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(e) => {
+                if entries.len() == size {
+                    entries.pop_front();
+                }
+                entries.push_back(e);
+            }
+            Err(_) => invalid += 1,
+        }
+    }
+    if invalid > 0 {
+        warn!(
+            "skipped {} unreadable lines of history {}",
+            invalid,
+            path.display()
+        );
+    }
+    Ok(entries)
+}
+
+fn line(entry: &Entry) -> Vec<u8> {
+    let mut line = serde_json::to_vec(entry).unwrap();
+    line.push(b'\n');
+    line
+}
+
+// Replaces the file with `entries`, returning it open for appending.
+async fn compact(path: &Path, entries: &VecDeque<Entry>) -> Result<tokio::fs::File> {
+    let tmp = path.with_extension("tmp");
+    let mut contents = vec![];
+    for e in entries {
+        contents.extend(line(e));
+    }
+    tokio::fs::write(&tmp, contents)
+        .await
+        .with_context(|| format!("error writing {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("error replacing {}", path.display()))?;
+    tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("error opening history {}", path.display()))
+}
+
+async fn writer(
+    size: usize,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+    mut rx: mpsc::Receiver<Entry>,
+    mut file: Option<(PathBuf, tokio::fs::File)>,
+    metrics: Arc<Metrics>,
+) {
+    // Lines appended since the file last held just the journal. Once there
+    // are as many again as the journal holds, it's rewritten.
+    let mut appended = 0;
+    while let Some(entry) = rx.recv().await {
+        let line = file.as_ref().map(|_| line(&entry));
+        {
+            let mut entries = entries.lock().unwrap();
+            if entries.len() >= size {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+        let (path, out) = match (&mut file, line) {
+            (Some((path, out)), Some(line)) => {
+                // tokio's File only hands the write to the OS on flush.
+                let written = match out.write_all(&line).await {
+                    Ok(()) => out.flush().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    metrics::inc(&metrics.history_dropped);
+                    error!("error writing history {}: {}", path.display(), e);
+                }
+                (path, out)
+            }
+            _ => continue,
+        };
+        appended += 1;
+        if appended > size {
+            let snapshot = entries.lock().unwrap().clone();
+            match compact(path, &snapshot).await {
+                Ok(f) => {
+                    *out = f;
+                    appended = 0;
+                }
+                Err(e) => error!("{:#}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Hostnames as they go into record names. pdns takes names in A-label form
+// only, and stores them in whatever case they're sent, so a node's name is
+// lowercased (short of --preserve-hostname-case) and each label that isn't
+// ASCII IDNA-encoded. Labels already in A-label form pass through as they
+// are. The advertisement keeps the name the node sent.
+pub fn record_name(hostname: &str, preserve_case: bool) -> Result<String, String> {
+    hostname
+        .split('.')
+        .map(|label| {
+            if !label.is_ascii() {
+                idna::domain_to_ascii(label)
+                    .map_err(|_| format!("label '{}' is not a valid IDN", label))
+            } else if preserve_case {
+                Ok(label.to_owned())
+            } else {
+                Ok(label.to_ascii_lowercase())
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|labels| labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::record_name;
+
+    #[test]
+    fn mixed_case() {
+        assert_eq!(
+            record_name("Web-01.Example.COM", false).unwrap(),
+            "web-01.example.com"
+        );
+        assert_eq!(
+            record_name("Web-01.Example.COM", true).unwrap(),
+            "Web-01.Example.COM"
+        );
+        assert_eq!(record_name("node", false).unwrap(), "node");
+    }
+
+    #[test]
+    fn unicode() {
+        // Mapped and encoded whatever the case option, IDNA lowercasing as
+        // it goes; only the labels that need it.
+        assert_eq!(
+            record_name("Bücher.Example", false).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            record_name("Bücher.Example", true).unwrap(),
+            "xn--bcher-kva.Example"
+        );
+        assert_eq!(
+            record_name("ñode.東京", false).unwrap(),
+            "xn--ode-6ma.xn--1lqs71d"
+        );
+
+        // Back from A-labels, the name is the node's, lowercased.
+        for name in &["Bücher.example", "ñode.東京", "straße.de"] {
+            let encoded = record_name(name, false).unwrap();
+            assert!(encoded.is_ascii());
+            let (decoded, result) = idna::domain_to_unicode(&encoded);
+            assert!(result.is_ok(), "{}", encoded);
+            assert_eq!(decoded, name.to_lowercase(), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn already_punycode() {
+        assert_eq!(
+            record_name("xn--bcher-kva.example", false).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            record_name("XN--BCHER-KVA.Example", false).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        // Encoding is idempotent.
+        let once = record_name("Bücher.example", false).unwrap();
+        assert_eq!(record_name(&once, false).unwrap(), once);
+        assert_eq!(record_name(&once, true).unwrap(), once);
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+#[derive(Debug, Clone)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => bail!("unix socket path is empty"),
+            Some(path) => Ok(BindAddr::Unix(PathBuf::from(path))),
+            None => Ok(BindAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl std::fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// `uid[:gid]`, numeric so it doesn't depend on NSS being up during bootstrap.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOwner {
+    uid: u32,
+    gid: Option<u32>,
+}
+
+impl FromStr for SocketOwner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (uid, gid) = match s.split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (s, None),
+        };
+        Ok(SocketOwner {
+            uid: uid
+                .parse()
+                .map_err(|_| anyhow!("invalid uid '{}' (expected uid[:gid])", uid))?,
+            gid: gid
+                .map(|g| {
+                    g.parse()
+                        .map_err(|_| anyhow!("invalid gid '{}' (expected uid[:gid])", g))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+pub fn parse_mode(s: &str) -> Result<u32> {
+    u32::from_str_radix(s, 8).map_err(|_| anyhow!("invalid socket mode '{}' (expected octal)", s))
+}
+
+// A connection accepted on either kind of listener.
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    // None over a unix socket, whose peers have no address worth the name.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Conn::Tcp(s) => s.peer_addr().ok(),
+            Conn::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Conn::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Conn::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Conn::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Conn::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub fn incoming(listener: Listener) -> impl futures::Stream<Item = io::Result<Conn>> {
+    futures::stream::unfold(listener, |listener| async move {
+        let conn = match &listener {
+            Listener::Tcp(l) => l.accept().await.map(|(s, _)| Conn::Tcp(s)),
+            Listener::Unix(l) => l.accept().await.map(|(s, _)| Conn::Unix(s)),
+        };
+        Some((conn, listener))
+    })
+}
+
+// A socket file left behind by a crashed server would make bind fail, but one
+// that still accepts connections belongs to a running server.
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("error checking {}", path.display())),
+    };
+    if !meta.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("{} is in use by another server", path.display());
+    }
+    warn!("removing stale socket {}", path.display());
+    std::fs::remove_file(path).with_context(|| format!("error removing {}", path.display()))
+}
+
+pub async fn bind_unix(path: &Path, mode: u32, owner: Option<SocketOwner>) -> Result<UnixListener> {
+    remove_stale_socket(path).await?;
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("error binding {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("error setting mode on {}", path.display()))?;
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(path, Some(owner.uid), owner.gid)
+            .with_context(|| format!("error changing owner of {}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+pub async fn shutdown_signal() {
+    let mut term = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    info!("shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    use super::{bind_unix, BindAddr};
+    use crate::mux;
+
+    #[tokio::test]
+    async fn unix_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("strapper.sock");
+        let opt = crate::Opt::from_iter_safe(&["server"]).unwrap();
+        let server = crate::build_server(&opt).await.unwrap();
+        let listener = mux::Listener::bind(
+            "default",
+            &BindAddr::Unix(path.clone()),
+            std::iter::once(mux::Service::NodeState).collect(),
+            0o600,
+            None,
+        )
+        .await
+        .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let services = mux::Services {
+            node_state: crate::grpc_service(server.clone()),
+            reflection: None,
+            server,
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(mux::serve(
+            vec![listener],
+            services,
+            (Duration::from_secs(60), Duration::from_secs(20)),
+            async move {
+                let _ = stopped.await;
+            },
+        ));
+
+        let endpoint = client::Target::Unix(path.clone()).endpoint();
+        let mut c = client::StrapperClient::connect_unix(endpoint, path.clone())
+            .await
+            .unwrap();
+        c.status().await.unwrap();
+        drop(c);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("strapper.sock");
+
+        // A crashed server's socket: still there, no one accepting.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let live = bind_unix(&path, 0o660, None).await.unwrap();
+
+        let err = bind_unix(&path, 0o660, None).await.unwrap_err();
+        assert!(err.to_string().contains("in use"), "{:#}", err);
+        drop(live);
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let err = bind_unix(&file, 0o660, None).await.unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{:#}", err);
+    }
+
+    #[test]
+    fn bind_addr() {
+        assert!(matches!("unix:/run/s.sock".parse(), Ok(BindAddr::Unix(_))));
+        assert!(matches!("127.0.0.1:50051".parse(), Ok(BindAddr::Tcp(_))));
+        assert!("unix:".parse::<BindAddr>().is_err());
+        assert_eq!(
+            "unix:/run/s.sock".parse::<BindAddr>().unwrap().to_string(),
+            "unix:/run/s.sock"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use proto::strapper;
+
+// How soon a removed or replaced lock file is noticed when nothing is being
+// written, and how often a lock held elsewhere is tried again.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockState {
+    Held,
+    // Another instance had it when we started.
+    Waiting,
+    // The file we held it on was removed or replaced, so another instance
+    // can have locked the new one.
+    Lost,
+}
+
+impl LockState {
+    pub fn to_proto(self) -> strapper::WriterLock {
+        match self {
+            LockState::Held => strapper::WriterLock::Held,
+            LockState::Waiting => strapper::WriterLock::Waiting,
+            LockState::Lost => strapper::WriterLock::Lost,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockState::Held => "held",
+            LockState::Waiting => "waiting",
+            LockState::Lost => "lost",
+        }
+    }
+}
+
+struct Inner {
+    file: Option<File>,
+    state: LockState,
+}
+
+// --lock-file: an advisory flock only one server instance at a time can
+// hold, and only that one writes to pdns. flock goes with the open file, so
+// it's released when the process exits however it exits, and a lock file
+// someone deletes or replaces no longer excludes anybody; that's noticed by
+// comparing the file we hold with what's at the path.
+pub struct WriterLock {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+// None if someone else holds it.
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // The holder's pid stays put until we have the lock.
+        .truncate(false)
+        .mode(0o644)
+        .open(path)
+        .with_context(|| format!("error opening {}", path.display()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(e).with_context(|| format!("error locking {}", path.display()));
+    }
+    // Replaced between the open and the flock; the next try gets the new
+    // one.
+    if !same_file(&file, path) {
+        return Ok(None);
+    }
+    // For whoever finds it locked.
+    file.set_len(0)
+        .and_then(|()| writeln!(file, "{}", std::process::id()))
+        .with_context(|| format!("error writing {}", path.display()))?;
+    Ok(Some(file))
+}
+
+fn same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+impl WriterLock {
+    pub fn acquire(path: PathBuf) -> Result<WriterLock> {
+        let file = try_lock(&path)?;
+        let state = if file.is_some() {
+            LockState::Held
+        } else {
+            LockState::Waiting
+        };
+        Ok(WriterLock {
+            path,
+            inner: Mutex::new(Inner { file, state }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn state(&self) -> LockState {
+        self.inner.lock().unwrap().state
+    }
+
+    // The pid the holder wrote into the file, as far as it can be read.
+    pub fn holder(&self) -> String {
+        match std::fs::read_to_string(&self.path) {
+            Ok(pid) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+            _ => "an unknown process".to_owned(),
+        }
+    }
+
+    // Whether the lock was held until now but isn't any more.
+    pub fn check_lost(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let held = match &inner.file {
+            Some(file) if inner.state == LockState::Held => file,
+            _ => return false,
+        };
+        if same_file(held, &self.path) {
+            return false;
+        }
+        inner.file = None;
+        inner.state = LockState::Lost;
+        true
+    }
+
+    // Tries again for a lock we don't hold, returning whether we now do.
+    pub fn retry(&self) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == LockState::Held {
+            return Ok(true);
+        }
+        match try_lock(&self.path)? {
+            Some(file) => {
+                inner.file = Some(file);
+                inner.state = LockState::Held;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
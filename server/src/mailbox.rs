@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+#[derive(Default)]
+struct Slot {
+    // Set while an advertisement for the node is being applied.
+    busy: bool,
+    // The newest advertisement waiting for that to finish.
+    pending: Option<oneshot::Sender<()>>,
+}
+
+// One advertisement in flight per node, and at most one waiting behind it:
+// a newer arrival takes the waiting one's place, and the one it replaced is
+// superseded without being applied. An agent catching up after a partition
+// then costs pdns its first and last states rather than every one between,
+// and two advertisements for a node never interleave their writes.
+#[derive(Default)]
+pub struct Mailboxes {
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+// The node's turn to be applied, for as long as it's held.
+pub struct Turn {
+    mailboxes: Arc<Mailboxes>,
+    hostname: String,
+}
+
+pub struct Superseded;
+
+impl Mailboxes {
+    pub async fn enter(self: &Arc<Self>, hostname: &str) -> Result<Turn, Superseded> {
+        let waiting = {
+            let mut slots = self.slots.lock().unwrap();
+            let slot = slots.entry(hostname.to_owned()).or_default();
+            if slot.busy {
+                // Dropping the sender tells whoever held it they're superseded.
+                let (tx, rx) = oneshot::channel();
+                slot.pending = Some(tx);
+                Some(rx)
+            } else {
+                slot.busy = true;
+                None
+            }
+        };
+        if let Some(rx) = waiting {
+            rx.await.map_err(|_| Superseded)?;
+        }
+        Ok(Turn {
+            mailboxes: self.clone(),
+            hostname: hostname.to_owned(),
+        })
+    }
+}
+
+impl Drop for Turn {
+    // Hands the node on to the advertisement waiting for it, if that's
+    // still there to take it.
+    fn drop(&mut self) {
+        let mut slots = self.mailboxes.slots.lock().unwrap();
+        let slot = match slots.get_mut(&self.hostname) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if let Some(tx) = slot.pending.take() {
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        slots.remove(&self.hostname);
+    }
+}
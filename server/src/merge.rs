@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::{PdnsComment, PdnsRecord, PdnsRrsetUpdate};
+
+// Strapper marks the records it owns in merge mode with one rrset comment
+// each, so a later update knows which records it may drop and which were put
+// there by hand (or by another node sharing the name).
+const ACCOUNT: &str = "strapper";
+
+fn ownership(owner: &str, content: &str) -> PdnsComment {
+    PdnsComment {
+        content: format!("owner={} content={}", owner, content),
+        account: ACCOUNT.to_owned(),
+    }
+}
+
+// An A record synthesize_a: made up from one of the node's IPv6 addresses
+// says which, so it can be told apart from one the node advertised.
+pub fn synthesized(owner: &str, content: &str, from: &Ipv6Addr) -> PdnsComment {
+    PdnsComment {
+        content: format!(
+            "owner={} content={} synthesized-from={}",
+            owner, content, from
+        ),
+        account: ACCOUNT.to_owned(),
+    }
+}
+
+fn parse_ownership(c: &PdnsComment) -> Option<(&str, &str)> {
+    if c.account != ACCOUNT {
+        return None;
+    }
+    let rest = c.content.strip_prefix("owner=")?;
+    let (owner, content) = rest.split_once(" content=")?;
+    let content = content
+        .split_once(" synthesized-from=")
+        .map_or(content, |(content, _)| content);
+    Some((owner, content))
+}
+
+// The owner and content of each record strapper marked as owned.
+pub fn owned(comments: &[PdnsComment]) -> impl Iterator<Item = (&str, &str)> {
+    comments.iter().filter_map(parse_ownership)
+}
+
+pub fn is_synthesized(update: &PdnsRrsetUpdate) -> bool {
+    update
+        .comments
+        .iter()
+        .any(|c| c.account == ACCOUNT && c.content.contains(" synthesized-from="))
+}
+
+// The rrset as it should be after `owner` publishes `update.records`: records
+// the owner no longer advertises are dropped unless another owner also claims
+// them, and everything strapper doesn't own is kept as is.
+pub fn merge(
+    records: Vec<PdnsRecord>,
+    comments: Vec<PdnsComment>,
+    mut update: PdnsRrsetUpdate,
+    owner: &str,
+) -> PdnsRrsetUpdate {
+    let mut mine = HashSet::new();
+    let mut others = HashSet::new();
+    for (o, content) in comments.iter().filter_map(parse_ownership) {
+        if o == owner {
+            mine.insert(content.to_owned());
+        } else {
+            others.insert(content.to_owned());
+        }
+    }
+
+    let new: HashSet<String> = update.records.iter().map(|r| r.content.clone()).collect();
+    let mut merged: Vec<PdnsRecord> = records
+        .into_iter()
+        .filter(|r| !new.contains(&r.content))
+        .filter(|r| !mine.contains(&r.content) || others.contains(&r.content))
+        .collect();
+    merged.extend(update.records.iter().cloned());
+    // Keep the order stable no matter which owner wrote last.
+    merged.sort_by_key(|r| (r.content.parse::<IpAddr>().ok(), r.content.clone()));
+
+    // Synthesized records come with their comment already.
+    let mut synthesized: HashMap<String, PdnsComment> = update
+        .comments
+        .drain(..)
+        .filter_map(|c| Some((parse_ownership(&c)?.1.to_owned(), c)))
+        .collect();
+    let mut merged_comments: Vec<PdnsComment> = comments
+        .into_iter()
+        .filter(|c| !matches!(parse_ownership(c), Some((o, _)) if o == owner))
+        .collect();
+    merged_comments.extend(update.records.iter().map(|r| {
+        synthesized
+            .remove(&r.content)
+            .unwrap_or_else(|| ownership(owner, &r.content))
+    }));
+
+    update.changetype = if merged.is_empty() {
+        "DELETE"
+    } else {
+        "REPLACE"
+    };
+    update.records = merged;
+    update.comments = merged_comments;
+    update
+}
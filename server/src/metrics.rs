@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Metrics {
+    pub pdns_applied: AtomicU64,
+    pub pdns_failures: AtomicU64,
+    pub pdns_retries: AtomicU64,
+    pub pdns_partial: AtomicU64,
+    pub apply_collapsed: AtomicU64,
+    pub apply_rejected: AtomicU64,
+    pub audit_failures: AtomicU64,
+    pub registry_rejected: AtomicU64,
+    pub advertise_succeeded: AtomicU64,
+    pub advertise_failed: AtomicU64,
+    pub advertise_superseded: AtomicU64,
+    pub deadline_exceeded: AtomicU64,
+    pub ownership_conflicts: AtomicU64,
+    pub source_mismatch: AtomicU64,
+    pub pdns_zone_throttled: AtomicU64,
+    pub pdns_zone_missing: AtomicU64,
+    pub missing_zone_skipped: AtomicU64,
+    pub history_dropped: AtomicU64,
+    pub advertise_no_addresses: AtomicU64,
+    pub advertise_unmatched: AtomicU64,
+    pub dns_verified: AtomicU64,
+    pub dns_verify_failures: AtomicU64,
+    pub writer_lock_lost: AtomicU64,
+    // Time PATCHes spent waiting on --pdns-zone-rate.
+    pub pdns_zone_wait: Histogram,
+    pub pdns_connections_opened: AtomicU64,
+    pub pdns_connections_reused: AtomicU64,
+    // Every pdns request up to its response headers, failed ones included,
+    // and just those that opened their connection; set against each other
+    // they give what connecting costs.
+    pub pdns_request_time: Histogram,
+    pub pdns_new_connection_request_time: Histogram,
+    // Zones read whole, how long each took to the last rrset, and reads of
+    // one name in a zone in their place.
+    pub pdns_zone_reads: AtomicU64,
+    pub pdns_zone_read_time: Histogram,
+    pub pdns_name_reads: AtomicU64,
+    // Whole-zone reads given up under --reconcile-max-zone-rrsets.
+    pub pdns_zone_too_large: AtomicU64,
+    // Zones rectified through the API under --pdns-rectify, and attempts
+    // that failed.
+    pub pdns_rectified: AtomicU64,
+    pub pdns_rectify_failures: AtomicU64,
+    // The rrsets each zone had when last read whole.
+    pub zone_rrsets: Mutex<BTreeMap<String, u64>>,
+}
+
+// Upper bounds of the buckets, in seconds.
+const WAIT_BOUNDS: [f64; 9] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+// Counts of observations at or under each bound, cumulative as Prometheus
+// has them, plus the overall count and sum.
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            bounds: &WAIT_BOUNDS,
+            buckets: WAIT_BOUNDS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                inc(bucket);
+            }
+        }
+        inc(&self.count);
+        self.sum_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "buckets": self.bounds.iter().zip(self.buckets.iter()).map(|(bound, bucket)| {
+                serde_json::json!({"le": bound, "count": get(bucket)})
+            }).collect::<Vec<_>>(),
+            "count": get(&self.count),
+            "sum_secs": get(&self.sum_micros) as f64 / 1e6,
+        })
+    }
+}
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
@@ -0,0 +1,89 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    until: Instant,
+    skipped: u64,
+}
+
+// Zones pdns said don't exist. Their updates are skipped for
+// --missing-zone-ttl-secs instead of being sent and failing, with one
+// warning per period; after that the next update goes through as the
+// recheck, and either finds the zone or starts another period.
+pub struct MissingZones {
+    ttl: Duration,
+    zones: Mutex<HashMap<String, Entry>>,
+}
+
+impl MissingZones {
+    pub fn new(ttl: Duration) -> MissingZones {
+        MissingZones {
+            ttl,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Concurrent updates to the zone all finding it gone count once.
+    pub fn mark(&self, zone: &str) {
+        let now = Instant::now();
+        let mut zones = self.zones.lock().unwrap();
+        let previous = match zones.get(zone) {
+            Some(e) if now < e.until => return,
+            Some(e) => Some(e.skipped),
+            None => None,
+        };
+        zones.insert(
+            zone.to_owned(),
+            Entry {
+                until: now + self.ttl,
+                skipped: 0,
+            },
+        );
+        match previous {
+            None => warn!(
+                "zone {} does not exist in pdns, skipping its updates for {}s",
+                zone,
+                self.ttl.as_secs()
+            ),
+            Some(skipped) => warn!(
+                "zone {} still does not exist in pdns ({} updates skipped), skipping its updates for another {}s",
+                zone,
+                skipped,
+                self.ttl.as_secs()
+            ),
+        }
+    }
+
+    pub fn skipping(&self, zone: &str) -> bool {
+        match self.zones.lock().unwrap().get(zone) {
+            Some(e) => Instant::now() < e.until,
+            None => false,
+        }
+    }
+
+    // For the warnings; skipping() doesn't count, so planning stays
+    // side-effect free.
+    pub fn skipped(&self, zone: &str) {
+        if let Some(e) = self.zones.lock().unwrap().get_mut(zone) {
+            e.skipped += 1;
+        }
+    }
+
+    pub fn found(&self, zone: &str) {
+        if let Some(e) = self.zones.lock().unwrap().remove(zone) {
+            info!(
+                "zone {} exists in pdns again ({} updates skipped), resuming its updates",
+                zone, e.skipped
+            );
+        }
+    }
+
+    // Including those waiting on a recheck.
+    pub fn zones(&self) -> Vec<String> {
+        let mut zones: Vec<String> = self.zones.lock().unwrap().keys().cloned().collect();
+        zones.sort();
+        zones
+    }
+}
@@ -0,0 +1,688 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::codegen::Service as _;
+use tonic::transport::NamedService;
+
+use proto::strapper::node_state_service_server::NodeStateServiceServer;
+
+use crate::activation::Inherited;
+use crate::listen::{self, BindAddr, SocketOwner};
+use crate::reflection::ReflectionServer;
+use crate::{rest, NSServer};
+
+// The listener --bind names, which a socket passed by systemd stands in for.
+pub const DEFAULT_LISTENER: &str = "grpc";
+// The listener --rest-bind names.
+pub const REST_LISTENER: &str = "rest";
+
+// Set on every gRPC request to the address it came from, any the client
+// sent being dropped first; see peer().
+const PEER_HEADER: &str = "x-strapper-peer";
+
+// What a listener can carry. The gRPC ones are told from HTTP by
+// content-type, so one listener can carry any mix of them.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Service {
+    NodeState,
+    Reflection,
+    Health,
+    Admin,
+    Metrics,
+    Rest,
+}
+
+impl Service {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Service::NodeState => "node-state",
+            Service::Reflection => "reflection",
+            Service::Health => "health",
+            Service::Admin => "admin",
+            Service::Metrics => "metrics",
+            Service::Rest => "rest",
+        }
+    }
+}
+
+impl FromStr for Service {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "node-state" => Ok(Service::NodeState),
+            "reflection" => Ok(Service::Reflection),
+            "health" => Ok(Service::Health),
+            "admin" => Ok(Service::Admin),
+            "metrics" => Ok(Service::Metrics),
+            "rest" => Ok(Service::Rest),
+            _ => Err(anyhow!(
+                "unknown service '{}' (expected node-state, reflection, health, admin, metrics or rest)",
+                s
+            )),
+        }
+    }
+}
+
+// --listener name=addr
+#[derive(Clone, Debug)]
+pub struct ListenerSpec {
+    pub name: String,
+    pub addr: BindAddr,
+}
+
+impl FromStr for ListenerSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addr) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid listener '{}' (expected name=addr)", s))?;
+        ensure!(!name.is_empty(), "listener '{}' has no name", s);
+        Ok(ListenerSpec {
+            name: name.to_owned(),
+            addr: addr
+                .parse()
+                .with_context(|| format!("invalid address for listener {}", name))?,
+        })
+    }
+}
+
+// --attach service=listener
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub service: Service,
+    pub listener: String,
+}
+
+impl FromStr for Attachment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (service, listener) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid attachment '{}' (expected service=listener)", s))?;
+        Ok(Attachment {
+            service: service.parse()?,
+            listener: listener.to_owned(),
+        })
+    }
+}
+
+// Which listeners there are and what each carries.
+pub struct Layout {
+    pub listeners: BTreeMap<String, (BindAddr, BTreeSet<Service>)>,
+}
+
+impl Layout {
+    // --bind and --rest-bind name the "grpc" and "rest" listeners, which a
+    // --listener of the same name replaces. Out of the box node-state and
+    // reflection are on grpc and the HTTP services on rest, if there is one;
+    // attaching a service anywhere takes it off where it'd otherwise be.
+    pub fn new(
+        bind: &BindAddr,
+        rest_bind: Option<SocketAddr>,
+        reflection: bool,
+        listeners: &[ListenerSpec],
+        attach: &[Attachment],
+    ) -> Result<Layout> {
+        let mut addrs = BTreeMap::new();
+        addrs.insert(DEFAULT_LISTENER.to_owned(), bind.clone());
+        if let Some(addr) = rest_bind {
+            addrs.insert(REST_LISTENER.to_owned(), BindAddr::Tcp(addr));
+        }
+        let mut named = BTreeSet::new();
+        for l in listeners {
+            ensure!(
+                named.insert(l.name.as_str()),
+                "listener {} is given twice",
+                l.name
+            );
+            addrs.insert(l.name.clone(), l.addr.clone());
+        }
+
+        let mut services: BTreeMap<String, BTreeSet<Service>> = BTreeMap::new();
+        let attached: BTreeSet<Service> = attach.iter().map(|a| a.service).collect();
+        let defaults = [
+            (Service::NodeState, DEFAULT_LISTENER),
+            (Service::Reflection, DEFAULT_LISTENER),
+            (Service::Health, REST_LISTENER),
+            (Service::Admin, REST_LISTENER),
+            (Service::Metrics, REST_LISTENER),
+            (Service::Rest, REST_LISTENER),
+        ];
+        for (service, listener) in defaults.iter() {
+            if !attached.contains(service) && addrs.contains_key(*listener) {
+                services
+                    .entry(listener.to_string())
+                    .or_default()
+                    .insert(*service);
+            }
+        }
+        for a in attach {
+            ensure!(
+                addrs.contains_key(&a.listener),
+                "{} is attached to {}, which isn't a listener (see --listener)",
+                a.service.as_str(),
+                a.listener
+            );
+            services
+                .entry(a.listener.clone())
+                .or_default()
+                .insert(a.service);
+        }
+        if !reflection {
+            ensure!(
+                !attached.contains(&Service::Reflection),
+                "reflection is attached but --enable-reflection is false"
+            );
+            for s in services.values_mut() {
+                s.remove(&Service::Reflection);
+            }
+        }
+
+        let mut layout = BTreeMap::new();
+        for (name, addr) in addrs {
+            match services.remove(&name) {
+                Some(s) if !s.is_empty() => {
+                    layout.insert(name, (addr, s));
+                }
+                _ if named.contains(name.as_str()) => {
+                    bail!("nothing is attached to listener {}", name)
+                }
+                // --bind's listener when everything's been moved off it.
+                _ => {}
+            }
+        }
+        let mut seen = BTreeMap::new();
+        for (name, (addr, _)) in &layout {
+            if let Some(other) = seen.insert(addr.to_string(), name) {
+                bail!("listeners {} and {} are both on {}", other, name, addr);
+            }
+        }
+        Ok(Layout { listeners: layout })
+    }
+
+    // "name addr: service, ...", one per listener, for ServerConfig.
+    pub fn describe(&self) -> Vec<String> {
+        self.listeners
+            .iter()
+            .map(|(name, (addr, services))| {
+                format!(
+                    "{} {}: {}",
+                    name,
+                    addr,
+                    services
+                        .iter()
+                        .map(|s| s.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+            .collect()
+    }
+}
+
+// Everything a listener might hand a request to.
+#[derive(Clone)]
+pub struct Services {
+    pub node_state: NodeStateServiceServer<NSServer>,
+    pub reflection: Option<ReflectionServer>,
+    pub server: NSServer,
+}
+
+// One listener's services, dispatching each request to the one it's for.
+// Anything for a service the listener doesn't carry is answered as though
+// the service didn't exist.
+#[derive(Clone)]
+struct Router {
+    services: Services,
+    attached: Arc<BTreeSet<Service>>,
+}
+
+impl Router {
+    async fn route(self, peer: Option<SocketAddr>, mut req: Request<Body>) -> Response<BoxBody> {
+        let grpc = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/grpc"));
+        if !grpc {
+            let path = req.uri().path().to_owned();
+            return match rest::service(&path) {
+                Some(s) if self.attached.contains(&s) => {
+                    rest::handle(self.services.server, peer, req).await
+                }
+                _ => rest::not_found(&path),
+            }
+            .map(BoxBody::map_from);
+        }
+
+        req.headers_mut().remove(PEER_HEADER);
+        if let Some(value) = peer.and_then(|p| p.to_string().parse().ok()) {
+            req.headers_mut().insert(PEER_HEADER, value);
+        }
+        let service = req.uri().path().split('/').nth(1).unwrap_or_default();
+        let result = if service == <NodeStateServiceServer<NSServer> as NamedService>::NAME
+            && self.attached.contains(&Service::NodeState)
+        {
+            self.services.node_state.clone().call(req).await
+        } else if service == <ReflectionServer as NamedService>::NAME
+            && self.attached.contains(&Service::Reflection)
+        {
+            match self.services.reflection.clone() {
+                Some(mut reflection) => reflection.call(req).await,
+                None => return unimplemented(),
+            }
+        } else {
+            return unimplemented();
+        };
+        match result {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+}
+
+fn unimplemented() -> Response<BoxBody> {
+    let mut response = Response::new(BoxBody::empty());
+    let headers = response.headers_mut();
+    headers.insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert(
+        "grpc-status",
+        (tonic::Code::Unimplemented as i32)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    response
+}
+
+// Where a gRPC request came from, as the listener saw it; None over a unix
+// socket.
+pub fn peer<T>(request: &tonic::Request<T>) -> Option<SocketAddr> {
+    request
+        .metadata()
+        .get(PEER_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+// A bound listener, not yet accepting.
+pub struct Listener {
+    pub name: String,
+    pub addr: BindAddr,
+    socket: listen::Listener,
+    attached: BTreeSet<Service>,
+    // A socket file to remove on the way out; systemd's is left alone.
+    remove: Option<PathBuf>,
+}
+
+impl Listener {
+    pub async fn bind(
+        name: &str,
+        addr: &BindAddr,
+        attached: BTreeSet<Service>,
+        socket_mode: u32,
+        socket_owner: Option<SocketOwner>,
+    ) -> Result<Listener> {
+        let (socket, addr, remove) = match addr {
+            BindAddr::Tcp(a) => {
+                let l = tokio::net::TcpListener::bind(a)
+                    .await
+                    .with_context(|| format!("error binding {} for listener {}", a, name))?;
+                // Port 0 picks a free port; this is the one picked.
+                let local = l.local_addr()?;
+                (listen::Listener::Tcp(l), BindAddr::Tcp(local), None)
+            }
+            BindAddr::Unix(path) => (
+                listen::Listener::Unix(listen::bind_unix(path, socket_mode, socket_owner).await?),
+                addr.clone(),
+                Some(path.clone()),
+            ),
+        };
+        Ok(Listener {
+            name: name.to_owned(),
+            addr,
+            socket,
+            attached,
+            remove,
+        })
+    }
+
+    pub fn inherit(
+        name: &str,
+        inherited: Inherited,
+        attached: BTreeSet<Service>,
+    ) -> Result<Listener> {
+        let (socket, addr) = match inherited {
+            Inherited::Tcp(l) => {
+                l.set_nonblocking(true)?;
+                let l = tokio::net::TcpListener::from_std(l)?;
+                let local = l.local_addr()?;
+                (listen::Listener::Tcp(l), BindAddr::Tcp(local))
+            }
+            Inherited::Unix(l) => {
+                l.set_nonblocking(true)?;
+                let l = tokio::net::UnixListener::from_std(l)?;
+                let path = l
+                    .local_addr()?
+                    .as_pathname()
+                    .map(PathBuf::from)
+                    .unwrap_or_default();
+                (listen::Listener::Unix(l), BindAddr::Unix(path))
+            }
+        };
+        Ok(Listener {
+            name: name.to_owned(),
+            addr,
+            socket,
+            attached,
+            remove: None,
+        })
+    }
+
+    pub fn carries(&self, service: Service) -> bool {
+        self.attached.contains(&service)
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.attached.iter().map(|s| s.as_str())
+    }
+
+    async fn run(
+        self,
+        services: Services,
+        keepalive: (Duration, Duration),
+        mut stop: tokio::sync::watch::Receiver<bool>,
+    ) -> Result<()> {
+        let router = Router {
+            services,
+            attached: Arc::new(self.attached),
+        };
+        let make_service = make_service_fn(move |conn: &listen::Conn| {
+            let router = router.clone();
+            let peer = conn.peer_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let router = router.clone();
+                    async move { Ok::<_, Infallible>(router.route(peer, req).await) }
+                }))
+            }
+        });
+        let (name, addr) = (self.name, self.addr);
+        let result = hyper::Server::builder(hyper::server::accept::from_stream(listen::incoming(
+            self.socket,
+        )))
+        .http2_keep_alive_interval(keepalive.0)
+        .http2_keep_alive_timeout(keepalive.1)
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            let _ = stop.changed().await;
+        })
+        .await
+        .with_context(|| format!("listener {} on {} failed", name, addr));
+        if let Some(path) = self.remove {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("error removing {}: {}", path.display(), e);
+            }
+        }
+        result
+    }
+}
+
+// Serves every listener until `shutdown`, or until one of them fails, then
+// stops them all together, waiting for their requests in flight to finish.
+pub async fn serve(
+    listeners: Vec<Listener>,
+    services: Services,
+    keepalive: (Duration, Duration),
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let mut running: FuturesUnordered<_> = listeners
+        .into_iter()
+        .map(|l| tokio::spawn(l.run(services.clone(), keepalive, stopped.clone())))
+        .collect();
+    let mut result = Ok(());
+    tokio::select! {
+        _ = shutdown => {}
+        Some(r) = running.next() => {
+            result = r.map_err(anyhow::Error::from).and_then(|r| r);
+            if result.is_ok() {
+                result = Err(anyhow!("a listener stopped unexpectedly"));
+            }
+        }
+    }
+    let _ = stop.send(true);
+    while let Some(r) = running.next().await {
+        if let Err(e) = r.map_err(anyhow::Error::from).and_then(|r| r) {
+            if result.is_ok() {
+                result = Err(e);
+            } else {
+                warn!("{:#}", e);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{serve, Attachment, BindAddr, Layout, Listener, ListenerSpec, Service, Services};
+
+    type Serving = (
+        Vec<SocketAddr>,
+        tokio::sync::oneshot::Sender<()>,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    );
+
+    // Serves each set of services on a listener of its own.
+    async fn start(attached: &[&[Service]]) -> Serving {
+        let opt = crate::Opt::from_iter_safe(&["server"]).unwrap();
+        let server = crate::build_server(&opt).await.unwrap();
+        let mut listeners = Vec::new();
+        for (n, services) in attached.iter().enumerate() {
+            let l = Listener::bind(
+                &format!("l{}", n),
+                &BindAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+                services.iter().copied().collect(),
+                0o600,
+                None,
+            )
+            .await
+            .unwrap();
+            listeners.push(l);
+        }
+        let addrs = listeners
+            .iter()
+            .map(|l| match l.addr {
+                BindAddr::Tcp(a) => a,
+                BindAddr::Unix(_) => unreachable!(),
+            })
+            .collect();
+        let services = Services {
+            node_state: crate::grpc_service(server.clone()),
+            reflection: None,
+            server,
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(serve(
+            listeners,
+            services,
+            (Duration::from_secs(60), Duration::from_secs(20)),
+            async move {
+                let _ = stopped.await;
+            },
+        ));
+        (addrs, stop, serving)
+    }
+
+    fn layout(listeners: &[&str], attach: &[&str]) -> anyhow::Result<Vec<String>> {
+        let listeners: Vec<ListenerSpec> = listeners.iter().map(|l| l.parse().unwrap()).collect();
+        let attach: Vec<Attachment> = attach.iter().map(|a| a.parse().unwrap()).collect();
+        Layout::new(
+            &"127.0.0.1:50051".parse().unwrap(),
+            Some("127.0.0.1:8080".parse().unwrap()),
+            false,
+            &listeners,
+            &attach,
+        )
+        .map(|l| l.describe())
+    }
+
+    #[test]
+    fn attached_services_move() {
+        assert_eq!(
+            layout(&[], &[]).unwrap(),
+            [
+                "grpc 127.0.0.1:50051: node-state",
+                "rest 127.0.0.1:8080: health, admin, metrics, rest",
+            ]
+        );
+        assert_eq!(
+            layout(&["ops=127.0.0.1:9000"], &["admin=ops", "metrics=ops"]).unwrap(),
+            [
+                "grpc 127.0.0.1:50051: node-state",
+                "ops 127.0.0.1:9000: admin, metrics",
+                "rest 127.0.0.1:8080: health, rest",
+            ]
+        );
+        // A --listener with nothing on it is a mistake; --bind's, emptied by
+        // attaching its services elsewhere, just isn't served.
+        assert_eq!(
+            layout(&["ops=unix:/run/ops.sock"], &["node-state=rest"])
+                .unwrap_err()
+                .to_string(),
+            "nothing is attached to listener ops"
+        );
+        assert_eq!(
+            layout(&[], &["node-state=rest"]).unwrap(),
+            ["rest 127.0.0.1:8080: node-state, health, admin, metrics, rest"]
+        );
+
+        for (listeners, attach, err) in [
+            (
+                &[][..],
+                &["admin=ops"][..],
+                "admin is attached to ops, which isn't a listener",
+            ),
+            (
+                &["ops=127.0.0.1:8080"],
+                &["admin=ops"],
+                "listeners ops and rest are both on",
+            ),
+            (
+                &["ops=127.0.0.1:9000", "ops=127.0.0.1:9001"],
+                &["admin=ops"],
+                "listener ops is given twice",
+            ),
+            (&[], &["reflection=grpc"], "reflection is attached but"),
+        ] {
+            let e = layout(listeners, attach).unwrap_err().to_string();
+            assert!(e.starts_with(err), "{}", e);
+        }
+        assert!("health".parse::<Attachment>().is_err());
+        assert!("bogus=rest".parse::<Attachment>().is_err());
+        assert!("=127.0.0.1:1".parse::<ListenerSpec>().is_err());
+    }
+
+    // The status line of a plain HTTP/1.1 GET.
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        s.write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_owned()
+    }
+
+    #[tokio::test]
+    async fn listeners_are_isolated() {
+        let (addrs, stop, serving) = start(&[&[Service::NodeState], &[Service::Rest]]).await;
+
+        let grpc = |addr: SocketAddr| async move {
+            let uri = format!("http://{}", addr).parse().unwrap();
+            client::StrapperClient::connect(uri)
+                .await
+                .unwrap()
+                .status()
+                .await
+        };
+        grpc(addrs[0]).await.unwrap();
+        let err = grpc(addrs[1]).await.unwrap_err();
+        let status = err.downcast_ref::<tonic::Status>().unwrap();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+        assert_eq!(get(addrs[1], "/v1/status").await, "HTTP/1.1 200 OK");
+        assert_eq!(get(addrs[0], "/v1/status").await, "HTTP/1.1 404 Not Found");
+        // Neither carries health, though it is on rest by default.
+        assert_eq!(get(addrs[1], "/v1/health").await, "HTTP/1.1 404 Not Found");
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_every_listener() {
+        let (addrs, stop, serving) = start(&[&[Service::Rest], &[Service::Rest]]).await;
+
+        // A request on each, in flight until the rest of its body comes.
+        let mut streams = Vec::new();
+        for &addr in &addrs {
+            let mut s = TcpStream::connect(addr).await.unwrap();
+            s.write_all(b"POST /v1/plan HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\n[")
+                .await
+                .unwrap();
+            streams.push(s);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        stop.send(()).unwrap();
+        let mut serving = serving;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), &mut serving)
+                .await
+                .is_err(),
+            "stopped with requests in flight"
+        );
+
+        for s in &mut streams {
+            s.write_all(b"]").await.unwrap();
+            let mut response = String::new();
+            s.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        }
+        serving.await.unwrap().unwrap();
+        for &addr in &addrs {
+            assert!(TcpStream::connect(addr).await.is_err());
+        }
+    }
+}
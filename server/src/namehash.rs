@@ -0,0 +1,107 @@
+use anyhow::{anyhow, ensure, Result};
+use log::warn;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use proto::canonical;
+
+use crate::auth;
+
+// What a remapper's transform= does to the node's name before it fills in
+// {}: nothing, a digest of the whole name, or the first label with a digest
+// after it (web1-3fa9c20b17de4c55), for zones other people can read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transform {
+    None,
+    Hash,
+    PrefixHash,
+}
+
+impl FromStr for Transform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Transform::None),
+            "hash" => Ok(Transform::Hash),
+            "prefix-hash" => Ok(Transform::PrefixHash),
+            _ => Err(anyhow!(
+                "unknown name transform '{}' (expected none, hash or prefix-hash)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transform::None => "none",
+            Transform::Hash => "hash",
+            Transform::PrefixHash => "prefix-hash",
+        })
+    }
+}
+
+// Digests are the first --name-hash-length hex digits of an HMAC-SHA256
+// keyed with the first key in --name-hash-key-file, so a node keeps its name
+// across restarts, and nobody without the key can hash a list of likely
+// hostnames to see which are there.
+pub struct NameHasher {
+    key: PKey<openssl::pkey::Private>,
+    len: usize,
+    // Digest to the names that came to it, to point out collisions once
+    // each; the records collide as ownership conflicts either way.
+    seen: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl NameHasher {
+    pub fn load(path: &Path, len: usize) -> Result<NameHasher> {
+        ensure!(
+            (4..=64).contains(&len),
+            "--name-hash-length must be between 4 and 64"
+        );
+        let keys = auth::TokenSet::load(path)?;
+        Ok(NameHasher {
+            key: PKey::hmac(keys.first().as_bytes())?,
+            len,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn digest(&self, name: &str) -> String {
+        // HMAC over a key PKey accepted only fails to allocate.
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+        signer.update(name.to_ascii_lowercase().as_bytes()).unwrap();
+        let mut digest = canonical::hex(&signer.sign_to_vec().unwrap());
+        digest.truncate(self.len);
+
+        let mut seen = self.seen.lock().unwrap();
+        let names = seen.entry(digest.clone()).or_default();
+        if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            if let Some(first) = names.first() {
+                warn!(
+                    "{} and {} both hash to {}; their records will conflict (a longer --name-hash-length makes this less likely)",
+                    first, name, digest
+                );
+            }
+            names.push(name.to_owned());
+        }
+        digest
+    }
+
+    pub fn apply(&self, transform: Transform, name: &str) -> String {
+        match transform {
+            Transform::None => name.to_owned(),
+            Transform::Hash => self.digest(name),
+            Transform::PrefixHash => {
+                let first = name.split('.').next().unwrap_or(name);
+                format!("{}-{}", first, self.digest(name))
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{anyhow, ensure};
+use ipnet::Ipv6Net;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// --nat64-prefix: the /96 the edge maps IPv4 addresses into, as in RFC 6052.
+// A node address under it carries the IPv4 address v4-only clients reach it
+// by in its low 32 bits, which synthesize_a: remappers publish as an A
+// record next to the AAAA.
+#[derive(Clone, Copy, Debug)]
+pub struct Prefix(Ipv6Net);
+
+impl Prefix {
+    // The IPv4 address embedded in `addr`, or None when `addr` isn't under
+    // the prefix or embeds 0.0.0.0, which nothing can be reached by.
+    pub fn synthesize(&self, addr: &Ipv6Addr) -> Option<Ipv4Addr> {
+        if !self.0.contains(addr) {
+            return None;
+        }
+        let v4 = Ipv4Addr::from(u128::from(*addr) as u32);
+        if v4.is_unspecified() {
+            return None;
+        }
+        Some(v4)
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let net: Ipv6Net = s
+            .parse()
+            .map_err(|e| anyhow!("invalid NAT64 prefix '{}': {}", s, e))?;
+        // Only /96 keeps the IPv4 address in one piece, without RFC 6052's
+        // reserved octet in the middle of it.
+        ensure!(net.prefix_len() == 96, "NAT64 prefix {} must be a /96", net);
+        ensure!(
+            net == net.trunc(),
+            "NAT64 prefix {} has host bits set (did you mean {}?)",
+            net,
+            net.trunc()
+        );
+        Ok(Prefix(net))
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::Prefix;
+
+    #[test]
+    fn synthesize() {
+        let prefix: Prefix = "64:ff9b::/96".parse().unwrap();
+        let v4 = |addr: &str| prefix.synthesize(&addr.parse().unwrap());
+        assert_eq!(v4("64:ff9b::c000:205"), Some(Ipv4Addr::new(192, 0, 2, 5)));
+        assert_eq!(
+            v4("64:ff9b::192.0.2.33"),
+            Some(Ipv4Addr::new(192, 0, 2, 33))
+        );
+        assert_eq!(
+            v4("64:ff9b::ffff:ffff"),
+            Some(Ipv4Addr::new(255, 255, 255, 255))
+        );
+        assert_eq!(v4("64:ff9b::1"), Some(Ipv4Addr::new(0, 0, 0, 1)));
+        assert_eq!(v4("64:ff9b::"), None);
+        assert_eq!(v4("64:ff9b:1::c000:205"), None);
+        assert_eq!(v4("2001:db8::c000:205"), None);
+
+        let local: Prefix = "2001:db8:64::/96".parse().unwrap();
+        assert_eq!(
+            local.synthesize(&"2001:db8:64::a00:1".parse().unwrap()),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(local.to_string(), "2001:db8:64::/96");
+    }
+
+    #[test]
+    fn parse() {
+        for (s, e) in [
+            ("64:ff9b::/64", "NAT64 prefix 64:ff9b::/64 must be a /96"),
+            (
+                "64:ff9b::1/96",
+                "NAT64 prefix 64:ff9b::1/96 has host bits set (did you mean 64:ff9b::/96?)",
+            ),
+            ("192.0.2.0/24", "invalid NAT64 prefix '192.0.2.0/24'"),
+            ("64:ff9b::", "invalid NAT64 prefix '64:ff9b::'"),
+        ]
+        .iter()
+        {
+            let err = s.parse::<Prefix>().unwrap_err().to_string();
+            assert!(err.starts_with(e), "{}: {}", s, err);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::remapper;
+
+// --zone-net-map: what {net:N} in a remapper zone stands for, by prefix.
+// One "<prefix> <label>" per line, e.g. "2001:db8:12::/48 london"; blank
+// lines and anything after a # are ignored. A label can be several, as in
+// rack1.london.
+pub struct NetMap {
+    labels: HashMap<IpNet, String>,
+}
+
+impl NetMap {
+    pub fn load(path: &Path) -> Result<NetMap> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("error reading {}", path.display()))?;
+        NetMap::parse(&text).with_context(|| format!("invalid zone net map {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<NetMap> {
+        let mut labels = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (net, label) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(net), Some(label), None) => (net, label),
+                _ => return Err(anyhow!("line {}: expected <prefix> <label>", n + 1)),
+            };
+            let net: IpNet = net
+                .parse()
+                .map_err(|e| anyhow!("line {}: invalid prefix '{}': {}", n + 1, net, e))?;
+            ensure!(
+                net == net.trunc(),
+                "line {}: {} has host bits set (did you mean {}?)",
+                n + 1,
+                net,
+                net.trunc()
+            );
+            ensure!(
+                label.split('.').all(remapper::is_label),
+                "line {}: invalid label '{}'",
+                n + 1,
+                label
+            );
+            if let Some(old) = labels.insert(net, label.to_ascii_lowercase()) {
+                return Err(anyhow!(
+                    "line {}: {} is already mapped to {}",
+                    n + 1,
+                    net,
+                    old
+                ));
+            }
+        }
+        Ok(NetMap { labels })
+    }
+
+    pub fn lookup(&self, addr: &IpAddr, bits: u8) -> Option<&str> {
+        let net = IpNet::new(*addr, bits).ok()?.trunc();
+        self.labels.get(&net).map(String::as_str)
+    }
+
+    pub fn entries(&self, bits: u8) -> impl Iterator<Item = (&IpNet, &str)> {
+        self.labels
+            .iter()
+            .filter(move |(net, _)| net.prefix_len() == bits)
+            .map(|(net, label)| (net, label.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::NetMap;
+
+    #[test]
+    fn parse() {
+        let map = NetMap::parse(
+            "# site prefixes\n\
+             2001:db8:12::/48 london\n\
+             \n\
+             2001:db8:13::/48   Paris   # upper case is fine\n\
+             2001:db8:12:1::/64 rack1.london\n\
+             10.5.0.0/16 lab\n",
+        )
+        .unwrap();
+        assert_eq!(map.len(), 4);
+        let lookup = |addr: &str, bits| map.lookup(&addr.parse::<IpAddr>().unwrap(), bits);
+        assert_eq!(lookup("2001:db8:12:ffff::1", 48), Some("london"));
+        assert_eq!(lookup("2001:db8:13::1", 48), Some("paris"));
+        assert_eq!(lookup("2001:db8:12:1::1", 64), Some("rack1.london"));
+        assert_eq!(lookup("2001:db8:12:1::1", 48), Some("london"));
+        assert_eq!(lookup("2001:db8:14::1", 48), None);
+        assert_eq!(lookup("10.5.200.1", 16), Some("lab"));
+        assert_eq!(lookup("10.6.0.1", 16), None);
+        // Past the family's width.
+        assert_eq!(lookup("10.5.0.1", 48), None);
+
+        let mut at_48: Vec<_> = map
+            .entries(48)
+            .map(|(net, label)| (net.to_string(), label))
+            .collect();
+        at_48.sort();
+        assert_eq!(
+            at_48,
+            [
+                ("2001:db8:12::/48".to_owned(), "london"),
+                ("2001:db8:13::/48".to_owned(), "paris")
+            ]
+        );
+        assert_eq!(NetMap::parse("").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn invalid() {
+        let cases = [
+            ("2001:db8:12::/48", "line 1: expected <prefix> <label>"),
+            (
+                "\n2001:db8:12::/48 london extra",
+                "line 2: expected <prefix> <label>",
+            ),
+            ("2001:db8:12::/129 london", "line 1: invalid prefix"),
+            ("london 2001:db8:12::/48", "line 1: invalid prefix 'london'"),
+            (
+                "2001:db8:12::1/48 london",
+                "has host bits set (did you mean 2001:db8:12::/48?)",
+            ),
+            (
+                "2001:db8:12::/48 lon_don!",
+                "line 1: invalid label 'lon_don!'",
+            ),
+            ("2001:db8:12::/48 london..uk", "invalid label"),
+            (
+                "2001:db8:12::/48 london\n2001:db8:12::/48 paris",
+                "line 2: 2001:db8:12::/48 is already mapped to london",
+            ),
+        ];
+        for (text, reason) in cases.iter() {
+            let e = match NetMap::parse(text) {
+                Ok(_) => panic!("{:?} parsed", text),
+                Err(e) => e.to_string(),
+            };
+            assert!(e.contains(reason), "{:?}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zones");
+        std::fs::write(&path, "2001:db8:12::/48 london\n").unwrap();
+        assert_eq!(NetMap::load(&path).unwrap().len(), 1);
+
+        std::fs::write(&path, "2001:db8:12::/48\n").unwrap();
+        let e = format!("{:#}", NetMap::load(&path).err().unwrap());
+        assert!(
+            e.contains(&format!("invalid zone net map {}", path.display())),
+            "{}",
+            e
+        );
+        assert!(e.contains("line 1"), "{}", e);
+
+        let missing = dir.path().join("missing");
+        let e = format!("{:#}", NetMap::load(&missing).err().unwrap());
+        assert!(
+            e.contains(&format!("error reading {}", missing.display())),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use proto::strapper::{self, AddressOrigin};
+
+// Which of a node's addresses a remapper publishes, by the origin the agent
+// reported for them. Agents too old to report one count as neither.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Preference {
+    Any,
+    Static,
+    Dynamic,
+}
+
+impl FromStr for Preference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Preference::Any),
+            "static" => Ok(Preference::Static),
+            "dynamic" => Ok(Preference::Dynamic),
+            _ => Err(anyhow!(
+                "unknown address preference '{}' (expected static, dynamic or any)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Preference::Any => "any",
+            Preference::Static => "static",
+            Preference::Dynamic => "dynamic",
+        })
+    }
+}
+
+pub fn origins(adv: &strapper::NodeAdvertisement) -> HashMap<IpAddr, AddressOrigin> {
+    adv.interfaces
+        .iter()
+        .flat_map(|i| i.address_info.iter())
+        .filter_map(|a| {
+            Some((
+                a.address.parse().ok()?,
+                AddressOrigin::from_i32(a.origin).unwrap_or(AddressOrigin::Unknown),
+            ))
+        })
+        .collect()
+}
+
+impl Preference {
+    // The addresses to publish out of `addrs`: per address family, those of
+    // the preferred origin, or all of them when none are.
+    pub fn select(
+        self,
+        addrs: impl Iterator<Item = IpAddr>,
+        origins: &HashMap<IpAddr, AddressOrigin>,
+    ) -> HashSet<IpAddr> {
+        let addrs: HashSet<IpAddr> = addrs.collect();
+        let want = match self {
+            Preference::Any => return addrs,
+            Preference::Static => AddressOrigin::Static,
+            Preference::Dynamic => AddressOrigin::Dynamic,
+        };
+        let preferred = |a: &IpAddr| origins.get(a) == Some(&want);
+        let has_preferred = |v4: bool| addrs.iter().any(|a| a.is_ipv4() == v4 && preferred(a));
+        let (v4, v6) = (has_preferred(true), has_preferred(false));
+        addrs
+            .iter()
+            .filter(|a| {
+                let family_has_preferred = if a.is_ipv4() { v4 } else { v6 };
+                !family_has_preferred || preferred(a)
+            })
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    use proto::strapper::{self, AddressOrigin};
+
+    use super::{origins, Preference};
+    use crate::remapper::Remapper;
+
+    #[test]
+    fn parse() {
+        for p in &[Preference::Any, Preference::Static, Preference::Dynamic] {
+            assert_eq!(p.to_string().parse::<Preference>().unwrap(), *p);
+        }
+        assert!("Static".parse::<Preference>().is_err());
+        assert!("".parse::<Preference>().is_err());
+
+        let r: Remapper = "prefer=static:10.0.0.0/8@example.com.@{}".parse().unwrap();
+        assert_eq!(r.prefer, Preference::Static);
+        assert_eq!(r.to_string(), "prefer=static:10.0.0.0/8@example.com.@{}");
+        let r: Remapper = "10.0.0.0/8@example.com.@{}".parse().unwrap();
+        assert_eq!(r.prefer, Preference::Any);
+        assert!("prefer=dhcp:10.0.0.0/8@example.com.@{}"
+            .parse::<Remapper>()
+            .is_err());
+    }
+
+    // As the agent reports them, with an old agent's address that has no
+    // origin at all.
+    fn node() -> strapper::NodeAdvertisement {
+        let info = |address: &str, origin: AddressOrigin| strapper::AddressInfo {
+            address: address.to_owned(),
+            origin: origin as i32,
+            ..Default::default()
+        };
+        strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![strapper::Interface {
+                name: "eth0".to_owned(),
+                ipaddr: vec![
+                    "10.0.0.1".to_owned(),
+                    "10.0.0.2".to_owned(),
+                    "fd00::1".to_owned(),
+                    "fd00::2".to_owned(),
+                    "fd00::3".to_owned(),
+                ],
+                address_info: vec![
+                    info("10.0.0.1", AddressOrigin::Static),
+                    info("10.0.0.2", AddressOrigin::Dynamic),
+                    info("fd00::1", AddressOrigin::Dynamic),
+                    info("fd00::2", AddressOrigin::Dynamic),
+                    info("fd00::3", AddressOrigin::Unknown),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn preference_and_fallback() {
+        let origins = origins(&node());
+        assert_eq!(origins.len(), 5);
+        let select = |p: Preference, addrs: &[&str]| {
+            let mut selected: Vec<String> = p
+                .select(addrs.iter().map(|a| a.parse().unwrap()), &origins)
+                .iter()
+                .map(IpAddr::to_string)
+                .collect();
+            selected.sort();
+            selected
+        };
+        let all = ["10.0.0.1", "10.0.0.2", "fd00::1", "fd00::2", "fd00::3"];
+
+        assert_eq!(select(Preference::Any, &all), all);
+        // IPv4 has a static address to prefer; IPv6 doesn't, so all of its
+        // addresses stay.
+        assert_eq!(
+            select(Preference::Static, &all),
+            ["10.0.0.1", "fd00::1", "fd00::2", "fd00::3"]
+        );
+        assert_eq!(
+            select(Preference::Dynamic, &all),
+            ["10.0.0.2", "fd00::1", "fd00::2"]
+        );
+        // Only what the remapper matched counts: without the static address
+        // there's nothing to prefer.
+        assert_eq!(
+            select(Preference::Static, &["10.0.0.2", "fd00::3"]),
+            ["10.0.0.2", "fd00::3"]
+        );
+        // Nor does an origin the agent didn't report.
+        assert_eq!(select(Preference::Dynamic, &["fd00::3"]), ["fd00::3"]);
+        assert_eq!(select(Preference::Static, &[]), Vec::<String>::new());
+
+        let unreported: HashMap<IpAddr, AddressOrigin> = HashMap::new();
+        let addrs = || all.iter().map(|a| a.parse().unwrap());
+        assert_eq!(Preference::Static.select(addrs(), &unreported).len(), 5);
+    }
+}
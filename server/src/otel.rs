@@ -0,0 +1,186 @@
+use std::fmt::Display;
+use std::future::Future;
+use tonic::metadata::MetadataMap;
+
+// Traces are only exported from builds with the otel feature, which is off
+// by default for the dependencies it pulls in. Without it everything here
+// does nothing, and --otlp-endpoint is refused rather than ignored.
+#[cfg(feature = "otel")]
+mod imp {
+    use anyhow::Result;
+    use opentelemetry::global;
+    use opentelemetry::propagation::Extractor;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::{self as sdktrace, Sampler};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::trace::{FutureExt, Span, StatusCode, TraceContextExt, Tracer};
+    use opentelemetry::{Context, KeyValue};
+    use std::future::Future;
+    use tonic::metadata::{KeyRef, MetadataMap};
+
+    const TRACER: &str = "strapper-server";
+
+    pub fn init(endpoint: Option<&str>, sample_ratio: f64) -> Result<()> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        // Agents decide whether their advertisements are sampled; the
+        // ratio is for requests that arrive without a trace.
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_ratio)));
+        opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(sdktrace::config().with_sampler(sampler).with_resource(
+                Resource::new(vec![KeyValue::new("service.name", "strapper-server")]),
+            ))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(())
+    }
+
+    // The exporter's shutdown blocks until what's been recorded is sent.
+    pub async fn shutdown() {
+        let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+    }
+
+    pub struct Parent(Context);
+
+    pub struct Active(Context);
+
+    struct Metadata<'a>(&'a MetadataMap);
+
+    impl Extractor for Metadata<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|v| v.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0
+                .keys()
+                .filter_map(|k| match k {
+                    KeyRef::Ascii(k) => Some(k.as_str()),
+                    KeyRef::Binary(_) => None,
+                })
+                .collect()
+        }
+    }
+
+    pub fn extract(metadata: &MetadataMap) -> Parent {
+        Parent(global::get_text_map_propagator(|p| {
+            p.extract(&Metadata(metadata))
+        }))
+    }
+
+    pub fn start(
+        parent: Option<&Parent>,
+        name: &'static str,
+        attributes: Vec<(&'static str, String)>,
+    ) -> Active {
+        let tracer = global::tracer(TRACER);
+        let span = match parent {
+            Some(Parent(cx)) => tracer.start_with_context(name, cx.clone()),
+            None => tracer.start(name),
+        };
+        for (key, value) in attributes {
+            span.set_attribute(KeyValue::new(key, value));
+        }
+        Active(Context::current_with_span(span))
+    }
+
+    pub async fn run<T>(active: &Active, fut: impl Future<Output = T>) -> T {
+        fut.with_context(active.0.clone()).await
+    }
+
+    pub fn finish(active: Active, error: Option<String>) {
+        if let Some(e) = error {
+            active.0.span().set_status(StatusCode::Error, e);
+        }
+        active.0.span().end();
+    }
+
+    pub fn attribute(key: &'static str, value: String) {
+        Context::current()
+            .span()
+            .set_attribute(KeyValue::new(key, value));
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod imp {
+    use anyhow::{ensure, Result};
+    use std::future::Future;
+    use tonic::metadata::MetadataMap;
+
+    pub fn init(endpoint: Option<&str>, _sample_ratio: f64) -> Result<()> {
+        ensure!(
+            endpoint.is_none(),
+            "--otlp-endpoint needs a server built with the otel feature"
+        );
+        Ok(())
+    }
+
+    pub async fn shutdown() {}
+
+    pub struct Parent;
+
+    pub struct Active;
+
+    pub fn extract(_metadata: &MetadataMap) -> Parent {
+        Parent
+    }
+
+    pub fn start(
+        _parent: Option<&Parent>,
+        _name: &'static str,
+        _attributes: Vec<(&'static str, String)>,
+    ) -> Active {
+        Active
+    }
+
+    pub async fn run<T>(_active: &Active, fut: impl Future<Output = T>) -> T {
+        fut.await
+    }
+
+    pub fn finish(_active: Active, _error: Option<String>) {}
+
+    pub fn attribute(_key: &'static str, _value: String) {}
+}
+
+pub use imp::{init, shutdown, Parent};
+
+// The trace context an agent sent along with a request.
+pub fn extract(metadata: &MetadataMap) -> Parent {
+    imp::extract(metadata)
+}
+
+// Runs `fut` in a span of its own, under `parent` or else whatever span
+// it's called from.
+pub async fn spanned<T>(
+    parent: Option<&Parent>,
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    fut: impl Future<Output = T>,
+) -> T {
+    let span = imp::start(parent, name, attributes);
+    let result = imp::run(&span, fut).await;
+    imp::finish(span, None);
+    result
+}
+
+// spanned, with the span marked failed if `fut` fails.
+pub async fn traced<T, E: Display>(
+    parent: Option<&Parent>,
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let span = imp::start(parent, name, attributes);
+    let result = imp::run(&span, fut).await;
+    imp::finish(span, result.as_ref().err().map(|e| e.to_string()));
+    result
+}
+
+// Adds to the span the caller is running in, if any.
+pub fn attribute(key: &'static str, value: impl ToString) {
+    imp::attribute(key, value.to_string())
+}
@@ -0,0 +1,179 @@
+use anyhow::anyhow;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::PdnsRrsetUpdate;
+
+// What to do when a node advertises a record another node already owns.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy {
+    Replace,
+    Reject,
+    // Lets updates from merge: remappers add to the rrset; everything else
+    // is rejected.
+    Merge,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(ConflictPolicy::Replace),
+            "reject" => Ok(ConflictPolicy::Reject),
+            "merge" => Ok(ConflictPolicy::Merge),
+            _ => Err(anyhow!(
+                "unknown ownership conflict policy '{}' (expected replace, reject or merge)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Key {
+    zone: String,
+    name: String,
+    type_: &'static str,
+}
+
+impl Key {
+    fn new(zone: &str, update: &PdnsRrsetUpdate) -> Key {
+        Key {
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Owner {
+    // Whoever last replaced the whole rrset.
+    exclusive: Option<String>,
+    // Nodes that merged their records in since.
+    shared: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct State {
+    records: HashMap<Key, Owner>,
+    by_host: HashMap<String, Vec<Key>>,
+}
+
+pub struct Conflict {
+    pub zone: String,
+    pub update: PdnsRrsetUpdate,
+    pub owner: String,
+    // Whether the policy let the update through anyway.
+    pub written: bool,
+}
+
+// Which node published each rrset, as far as this server has seen since it
+// started: after a restart the first node to advertise a name owns it.
+#[derive(Default)]
+pub struct Owners {
+    state: Mutex<State>,
+}
+
+impl Owners {
+    // The other node holding the rrset `update` would write to, if any.
+    // Merged updates leave other nodes' records alone, so they only clash
+    // with an rrset someone owns outright; that owner may always replace it.
+    // VIP rrsets change hands as the address moves, which is no conflict;
+    // vip::Holders keeps track of them instead.
+    pub fn owner(&self, hostname: &str, zone: &str, update: &PdnsRrsetUpdate) -> Option<String> {
+        if update.vip {
+            return None;
+        }
+        let state = self.state.lock().unwrap();
+        let owner = state.records.get(&Key::new(zone, update))?;
+        match &owner.exclusive {
+            Some(o) if o == hostname => None,
+            Some(o) => Some(o.clone()),
+            None if update.merge_owner.is_some() => None,
+            None => owner.shared.iter().find(|o| *o != hostname).cloned(),
+        }
+    }
+
+    // Splits `updates` into those `hostname` may write under `policy` and
+    // the conflicts it may not.
+    pub fn check(
+        &self,
+        hostname: &str,
+        updates: Vec<(String, PdnsRrsetUpdate)>,
+        policy: ConflictPolicy,
+    ) -> (Vec<(String, PdnsRrsetUpdate)>, Vec<Conflict>) {
+        let mut allowed = vec![];
+        let mut conflicts = vec![];
+        for (zone, update) in updates {
+            let owner = match self.owner(hostname, &zone, &update) {
+                Some(owner) => owner,
+                None => {
+                    allowed.push((zone, update));
+                    continue;
+                }
+            };
+            let written = match policy {
+                ConflictPolicy::Replace => true,
+                ConflictPolicy::Reject => false,
+                ConflictPolicy::Merge => update.merge_owner.is_some(),
+            };
+            if written {
+                allowed.push((zone.clone(), update.clone()));
+            }
+            conflicts.push(Conflict {
+                zone,
+                update,
+                owner,
+                written,
+            });
+        }
+        (allowed, conflicts)
+    }
+
+    // Records `hostname` as the owner of what it just wrote, dropping any
+    // claim on records it no longer publishes.
+    pub fn claim(&self, hostname: &str, updates: &[(String, PdnsRrsetUpdate)]) {
+        let mut state = self.state.lock().unwrap();
+        release(&mut state, hostname);
+        let mut keys = vec![];
+        for (zone, update) in updates
+            .iter()
+            .filter(|(_, u)| u.changetype != "DELETE" && !u.vip)
+        {
+            let key = Key::new(zone, update);
+            let owner = state.records.entry(key.clone()).or_default();
+            if update.merge_owner.is_some() {
+                owner.shared.insert(hostname.to_owned());
+            } else {
+                owner.exclusive = Some(hostname.to_owned());
+                owner.shared.clear();
+            }
+            keys.push(key);
+        }
+        if !keys.is_empty() {
+            state.by_host.insert(hostname.to_owned(), keys);
+        }
+    }
+
+    pub fn release(&self, hostname: &str) {
+        release(&mut self.state.lock().unwrap(), hostname);
+    }
+}
+
+fn release(state: &mut State, hostname: &str) {
+    let State { records, by_host } = state;
+    for key in by_host.remove(hostname).unwrap_or_default() {
+        if let Some(owner) = records.get_mut(&key) {
+            if owner.exclusive.as_deref() == Some(hostname) {
+                owner.exclusive = None;
+            }
+            owner.shared.remove(hostname);
+            if owner.exclusive.is_none() && owner.shared.is_empty() {
+                records.remove(&key);
+            }
+        }
+    }
+}
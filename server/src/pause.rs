@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use proto::strapper;
+
+use crate::apply::RrsetKey;
+use crate::audit::Origin;
+use crate::PdnsRrsetUpdate;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Flags {
+    all: bool,
+    zones: BTreeSet<String>,
+}
+
+impl Flags {
+    fn paused(&self, zone: &str) -> bool {
+        self.all || self.zones.contains(zone)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    flags: Flags,
+    // Every zone is held, whatever the flags say, while --lock-file is
+    // someone else's.
+    locked_out: bool,
+    // And while --queue-until-pdns waits for pdns to answer.
+    waiting_for_pdns: bool,
+    held: HashMap<RrsetKey, (PdnsRrsetUpdate, Arc<Origin>)>,
+}
+
+impl State {
+    fn paused(&self, zone: &str) -> bool {
+        self.locked_out || self.waiting_for_pdns || self.flags.paused(zone)
+    }
+
+    fn release(&mut self) -> Vec<(String, PdnsRrsetUpdate, Arc<Origin>)> {
+        let released: Vec<RrsetKey> = self
+            .held
+            .keys()
+            .filter(|k| !self.paused(&k.zone))
+            .cloned()
+            .collect();
+        released
+            .into_iter()
+            .filter_map(|k| {
+                let (update, origin) = self.held.remove(&k)?;
+                Some((k.zone, update, origin))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct HoldFull(pub usize);
+
+// Keeps writes away from pdns during maintenance. Updates for a paused zone
+// are held, the newest per record replacing whatever was held before, so
+// resuming applies only the final desired contents. The flags are kept in
+// `path` across restarts; held writes aren't, since the registry they came
+// from doesn't survive one either.
+pub struct WritePause {
+    path: Option<PathBuf>,
+    max_held: usize,
+    state: Mutex<State>,
+}
+
+impl WritePause {
+    pub fn load(path: Option<PathBuf>, max_held: usize) -> Result<WritePause> {
+        let flags = match &path {
+            Some(p) if p.exists() => serde_json::from_slice(
+                &std::fs::read(p).with_context(|| format!("error reading {}", p.display()))?,
+            )
+            .with_context(|| format!("error parsing {}", p.display()))?,
+            _ => Flags::default(),
+        };
+        Ok(WritePause {
+            path,
+            max_held,
+            state: Mutex::new(State {
+                flags,
+                locked_out: false,
+                waiting_for_pdns: false,
+                held: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn paused(&self, zone: &str) -> bool {
+        self.state.lock().unwrap().paused(zone)
+    }
+
+    // Holds the updates for paused zones and returns the rest. Either all of
+    // them fit or none are held, so an advertisement isn't half deferred.
+    pub fn hold(
+        &self,
+        updates: Vec<(String, PdnsRrsetUpdate)>,
+        origin: &Origin,
+    ) -> Result<Vec<(String, PdnsRrsetUpdate)>, HoldFull> {
+        let mut state = self.state.lock().unwrap();
+        if !state.locked_out
+            && !state.waiting_for_pdns
+            && !state.flags.all
+            && state.flags.zones.is_empty()
+        {
+            return Ok(updates);
+        }
+
+        let (held, rest): (Vec<_>, Vec<_>) = updates
+            .into_iter()
+            .partition(|(zone, _)| state.paused(zone));
+        let new = held
+            .iter()
+            .filter(|(zone, u)| !state.held.contains_key(&RrsetKey::new(zone, u)))
+            .count();
+        if state.held.len() + new > self.max_held {
+            return Err(HoldFull(state.held.len()));
+        }
+
+        let origin = Arc::new(origin.clone());
+        for (zone, update) in held {
+            let key = RrsetKey::new(&zone, &update);
+            state.held.insert(key, (update, origin.clone()));
+        }
+        Ok(rest)
+    }
+
+    // Pauses or resumes `zone`, or every zone for None, returning the held
+    // writes that are no longer paused. Resuming everything also clears the
+    // per-zone pauses. A zone resumed while everything is paused stays
+    // paused.
+    pub fn set(
+        &self,
+        zone: Option<&str>,
+        paused: bool,
+    ) -> Result<Vec<(String, PdnsRrsetUpdate, Arc<Origin>)>> {
+        let mut state = self.state.lock().unwrap();
+        let mut flags = Flags {
+            all: state.flags.all,
+            zones: state.flags.zones.clone(),
+        };
+        match (zone, paused) {
+            (None, true) => flags.all = true,
+            (None, false) => flags = Flags::default(),
+            (Some(z), true) => {
+                flags.zones.insert(z.to_owned());
+            }
+            (Some(z), false) => {
+                flags.zones.remove(z);
+            }
+        }
+
+        // Written first, so a failed write leaves the pause as it was.
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec(&flags)?)
+                .with_context(|| format!("error writing {}", tmp.display()))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("error renaming to {}", path.display()))?;
+        }
+        state.flags = flags;
+        Ok(state.release())
+    }
+
+    // Holds every write while the writer lock is elsewhere, returning the
+    // held writes no pause is left on once it's back.
+    pub fn lock_out(&self, locked_out: bool) -> Vec<(String, PdnsRrsetUpdate, Arc<Origin>)> {
+        let mut state = self.state.lock().unwrap();
+        state.locked_out = locked_out;
+        state.release()
+    }
+
+    // As lock_out, until pdns first answers.
+    pub fn wait_for_pdns(&self, waiting: bool) -> Vec<(String, PdnsRrsetUpdate, Arc<Origin>)> {
+        let mut state = self.state.lock().unwrap();
+        state.waiting_for_pdns = waiting;
+        state.release()
+    }
+
+    pub fn state(&self) -> strapper::WritePauseState {
+        let state = self.state.lock().unwrap();
+        strapper::WritePauseState {
+            all: state.flags.all,
+            zones: state.flags.zones.iter().cloned().collect(),
+            held_writes: state.held.len() as u64,
+        }
+    }
+}
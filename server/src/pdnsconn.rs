@@ -0,0 +1,62 @@
+use hyper::client::connect::HttpInfo;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::{self, Metrics};
+
+// Which of the pooled connections to pdns each request went over. reqwest
+// doesn't show us the connect itself, but hyper tags every response with
+// the local address of its connection: one we haven't seen within the pool's
+// idle timeout is a connection the request had to open, and its request
+// time includes the TCP and TLS handshakes the reused ones skip.
+pub struct Connections {
+    idle_timeout: Duration,
+    last_used: Mutex<HashMap<SocketAddr, Instant>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Connections {
+    pub fn new(idle_timeout: Duration, metrics: Arc<Metrics>) -> Connections {
+        Connections {
+            idle_timeout,
+            last_used: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let start = Instant::now();
+        let r = request.send().await;
+        let elapsed = start.elapsed();
+        self.metrics.pdns_request_time.observe(elapsed);
+        let local = r
+            .as_ref()
+            .ok()
+            .and_then(|r| r.extensions().get::<HttpInfo>())
+            .map(HttpInfo::local_addr);
+        if let Some(local) = local {
+            if self.opened(local) {
+                metrics::inc(&self.metrics.pdns_connections_opened);
+                self.metrics
+                    .pdns_new_connection_request_time
+                    .observe(elapsed);
+            } else {
+                metrics::inc(&self.metrics.pdns_connections_reused);
+            }
+        }
+        r
+    }
+
+    fn opened(&self, local: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock().unwrap();
+        // The pool has closed these, and the OS may hand the port out again.
+        last_used.retain(|_, t| now.duration_since(*t) < self.idle_timeout);
+        last_used.insert(local, now).is_none()
+    }
+}
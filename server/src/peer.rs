@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::metadata::{Ascii, MetadataMap, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+
+use proto::strapper::{self, node_state_service_client::NodeStateServiceClient};
+
+use crate::request_id;
+
+// Set on everything a server sends its peer, so a standby pointed at another
+// standby fails loudly instead of bouncing advertisements between them.
+const FORWARDED_KEY: &str = "x-strapper-forwarded";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Primary,
+    Standby,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Role::Primary),
+            "standby" => Ok(Role::Standby),
+            _ => Err(anyhow!(
+                "unknown role '{}' (expected primary or standby)",
+                s
+            )),
+        }
+    }
+}
+
+impl Role {
+    pub fn from_proto(role: i32) -> Option<Role> {
+        match strapper::Role::from_i32(role)? {
+            strapper::Role::Primary => Some(Role::Primary),
+            strapper::Role::Standby => Some(Role::Standby),
+        }
+    }
+
+    pub fn to_proto(self) -> strapper::Role {
+        match self {
+            Role::Primary => strapper::Role::Primary,
+            Role::Standby => strapper::Role::Standby,
+        }
+    }
+}
+
+pub fn is_forwarded(metadata: &MetadataMap) -> bool {
+    metadata.contains_key(FORWARDED_KEY)
+}
+
+// The other server of a pair: the primary, for a standby forwarding
+// advertisements, or the standby, for a primary pushing its registry.
+pub struct Peer {
+    uri: String,
+    client: NodeStateServiceClient<Channel>,
+    token: Option<MetadataValue<Ascii>>,
+}
+
+impl Peer {
+    // Connects lazily, so a peer that's down doesn't stop us starting.
+    pub fn connect(uri: &str, token: Option<&str>) -> Result<Peer> {
+        let channel = Endpoint::from_shared(uri.to_owned())?.connect_lazy()?;
+        let token = match token {
+            Some(t) => Some(
+                format!("Bearer {}", t)
+                    .parse()
+                    .map_err(|_| anyhow!("peer auth token contains invalid characters"))?,
+            ),
+            None => None,
+        };
+        Ok(Peer {
+            uri: uri.to_owned(),
+            client: NodeStateServiceClient::new(channel),
+            token,
+        })
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    fn request<T>(
+        &self,
+        message: T,
+        request_id: &str,
+        timeout: Option<Duration>,
+    ) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(timeout) = timeout {
+            request.set_timeout(timeout);
+        }
+        let metadata = request.metadata_mut();
+        metadata.insert(FORWARDED_KEY, MetadataValue::from_static("1"));
+        if let Ok(id) = request_id.parse() {
+            metadata.insert(request_id::METADATA_KEY, id);
+        }
+        if let Some(token) = &self.token {
+            metadata.insert("authorization", token.clone());
+        }
+        request
+    }
+
+    pub async fn advertise(
+        &self,
+        advertisement: strapper::NodeAdvertisement,
+        request_id: &str,
+        timeout: Option<Duration>,
+    ) -> Result<strapper::AdvertiseResult, tonic::Status> {
+        let request = self.request(advertisement, request_id, timeout);
+        Ok(self.client.clone().advertise(request).await?.into_inner())
+    }
+
+    pub async fn deregister(
+        &self,
+        hostname: &str,
+        request_id: &str,
+        timeout: Option<Duration>,
+    ) -> Result<(), tonic::Status> {
+        let message = strapper::DeregisterRequest {
+            hostname: hostname.to_owned(),
+        };
+        let request = self.request(message, request_id, timeout);
+        self.client.clone().deregister(request).await?;
+        Ok(())
+    }
+
+    pub async fn sync(&self, nodes: Vec<strapper::NodeAdvertisement>) -> Result<(), tonic::Status> {
+        let request = self.request(
+            strapper::RegistrySnapshot { nodes },
+            &request_id::mint(),
+            None,
+        );
+        self.client.clone().sync_registry(request).await?;
+        Ok(())
+    }
+}
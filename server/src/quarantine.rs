@@ -0,0 +1,321 @@
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::apply::RrsetKey;
+use crate::pause::WritePause;
+use crate::{ApplyError, PdnsApi, PdnsRrsetUpdate};
+
+struct Entry {
+    failures: u32,
+    quarantined: bool,
+    last_error: String,
+    update: PdnsRrsetUpdate,
+    // Bumped by every update for the record, so a revalidation that raced
+    // one knows its result is stale.
+    generation: u64,
+    // When an advertisement last had anything to say about the record.
+    seen: Instant,
+}
+
+// However many records pdns rejects, at most this many are tracked; the
+// longest unmentioned go first.
+const MAX_ENTRIES: usize = 10_000;
+
+// Records pdns keeps rejecting outright. After `threshold` consecutive
+// non-retryable failures a record is skipped on later advertisements until a
+// periodic revalidation gets it through. Records no advertisement has
+// mentioned for `forget_after`, quarantined or not, are forgotten: their
+// node has moved on, and what it last sent shouldn't be written back.
+pub struct Quarantine {
+    threshold: u32,
+    forget_after: Duration,
+    entries: Mutex<HashMap<RrsetKey, Entry>>,
+}
+
+impl Quarantine {
+    pub fn new(threshold: u32, forget_after: Duration) -> Quarantine {
+        Quarantine {
+            threshold,
+            forget_after,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Whether to skip this update; a held update replaces the stored one so
+    // revalidation tries what the node currently advertises. A delete is
+    // never held: once the node stops advertising the record there's nothing
+    // left to retry.
+    pub fn hold(&self, zone: &str, update: &PdnsRrsetUpdate) -> bool {
+        let key = RrsetKey::new(zone, update);
+        let mut entries = self.entries.lock().unwrap();
+        if update.changetype == "DELETE" {
+            if let Some(e) = entries.remove(&key) {
+                if e.quarantined {
+                    info!("released {:?} from quarantine, no longer advertised", key);
+                }
+            }
+            return false;
+        }
+        match entries.get_mut(&key) {
+            Some(e) if e.quarantined => {
+                debug!("skipping quarantined {:?}", key);
+                e.update = update.clone();
+                e.generation += 1;
+                e.seen = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn quarantined(&self, zone: &str, update: &PdnsRrsetUpdate) -> bool {
+        let key = RrsetKey::new(zone, update);
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&key)
+            .is_some_and(|e| e.quarantined)
+    }
+
+    pub fn record(&self, zone: &str, update: &PdnsRrsetUpdate, result: Result<(), &ApplyError>) {
+        let key = RrsetKey::new(zone, update);
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(e) = entries.get_mut(&key) {
+            e.generation += 1;
+            e.seen = Instant::now();
+        }
+        self.settle(&mut entries, key, update, result);
+    }
+
+    fn settle(
+        &self,
+        entries: &mut HashMap<RrsetKey, Entry>,
+        key: RrsetKey,
+        update: &PdnsRrsetUpdate,
+        result: Result<(), &ApplyError>,
+    ) {
+        match result {
+            Ok(()) => {
+                if let Some(e) = entries.remove(&key) {
+                    if e.quarantined {
+                        info!("released {:?} from quarantine", key);
+                    }
+                }
+            }
+            // The whole zone is skipped meanwhile, and its records
+            // shouldn't stay held once it turns up. Nor is a write refused
+            // in --mode readonly the record's fault.
+            Err(e)
+                if e.is_retryable()
+                    || matches!(e, ApplyError::ZoneMissing(..) | ApplyError::ReadOnly) => {}
+            Err(e) => {
+                if !entries.contains_key(&key) && entries.len() >= MAX_ENTRIES {
+                    let oldest = entries
+                        .iter()
+                        .min_by_key(|(_, e)| e.seen)
+                        .map(|(k, _)| k.clone());
+                    if let Some(oldest) = oldest {
+                        warn!(
+                            "tracking too many rejected records, forgetting {:?}",
+                            oldest
+                        );
+                        entries.remove(&oldest);
+                    }
+                }
+                let entry = entries.entry(key.clone()).or_insert_with(|| Entry {
+                    failures: 0,
+                    quarantined: false,
+                    last_error: String::new(),
+                    update: update.clone(),
+                    generation: 0,
+                    seen: Instant::now(),
+                });
+                entry.failures += 1;
+                entry.last_error = e.to_string();
+                entry.update = update.clone();
+                if !entry.quarantined && entry.failures >= self.threshold {
+                    entry.quarantined = true;
+                    warn!(
+                        "quarantining {:?} after {} consecutive failures, last: {}",
+                        key, entry.failures, entry.last_error
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<(RrsetKey, u32, String)> {
+        let mut list: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| e.quarantined)
+            .map(|(k, e)| (k.clone(), e.failures, e.last_error.clone()))
+            .collect();
+        list.sort_by(|(a, ..), (b, ..)| {
+            (&a.zone, &a.name, a.type_).cmp(&(&b.zone, &b.name, b.type_))
+        });
+        list
+    }
+
+    // Forgets what's gone unmentioned for too long, then retries the last
+    // update for every quarantined record, releasing the ones pdns now
+    // accepts. Paused zones wait for their writes to resume.
+    pub async fn revalidate(&self, pdns: &PdnsApi, pause: &WritePause) {
+        for (key, update, generation) in self.pending(pause) {
+            let applied = pdns
+                .apply_update(&key.zone, update.clone(), 0, "revalidate")
+                .await;
+            self.revalidated(key, &update, generation, applied.result());
+        }
+    }
+
+    fn pending(&self, pause: &WritePause) -> Vec<(RrsetKey, PdnsRrsetUpdate, u64)> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|k, e| {
+            let keep = e.seen.elapsed() < self.forget_after;
+            if !keep && e.quarantined {
+                info!("released {:?} from quarantine, no longer advertised", k);
+            }
+            keep
+        });
+        entries
+            .iter()
+            .filter(|(k, e)| e.quarantined && !pause.paused(&k.zone))
+            .map(|(k, e)| (k.clone(), e.update.clone(), e.generation))
+            .collect()
+    }
+
+    // Whatever pdns made of an update some advertisement has since replaced
+    // says nothing about the record now; the next round tries the new one.
+    fn revalidated(
+        &self,
+        key: RrsetKey,
+        update: &PdnsRrsetUpdate,
+        generation: u64,
+        result: Result<(), &ApplyError>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some(e) if e.generation == generation => self.settle(&mut entries, key, update, result),
+            _ => debug!("{:?} changed while revalidating", key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quarantine, MAX_ENTRIES};
+    use crate::apply::RrsetKey;
+    use crate::pause::WritePause;
+    use crate::{ApplyError, PdnsRecord, PdnsRrsetUpdate};
+    use std::time::Duration;
+
+    fn update(name: &str, content: &str) -> PdnsRrsetUpdate {
+        PdnsRrsetUpdate {
+            name: name.to_owned(),
+            type_: "A",
+            ttl: 60,
+            changetype: "REPLACE",
+            records: vec![PdnsRecord {
+                content: content.to_owned(),
+                disabled: false,
+            }],
+            comments: vec![],
+            merge_owner: None,
+            vip: false,
+        }
+    }
+
+    fn rejected() -> ApplyError {
+        ApplyError::Response(reqwest::StatusCode::UNPROCESSABLE_ENTITY, None)
+    }
+
+    fn pause() -> WritePause {
+        WritePause::load(None, 10).unwrap()
+    }
+
+    #[test]
+    fn quarantined_after_threshold() {
+        let q = Quarantine::new(2, Duration::from_secs(3600));
+        let u = update("node.example.com.", "10.0.0.1");
+        q.record("example.com.", &u, Err(&rejected()));
+        assert!(!q.hold("example.com.", &u));
+        q.record("example.com.", &u, Err(&rejected()));
+        assert!(q.hold("example.com.", &u));
+        assert_eq!(q.list().len(), 1);
+
+        q.record("example.com.", &u, Ok(()));
+        assert!(q.list().is_empty());
+    }
+
+    #[test]
+    fn newer_update_wins_over_revalidation() {
+        let q = Quarantine::new(1, Duration::from_secs(3600));
+        let old = update("node.example.com.", "10.0.0.1");
+        q.record("example.com.", &old, Err(&rejected()));
+        let pending = q.pending(&pause());
+        assert_eq!(pending.len(), 1);
+        let (key, stale, generation) = pending.into_iter().next().unwrap();
+
+        // The node re-advertises while the old contents are with pdns.
+        let new = update("node.example.com.", "10.0.0.2");
+        assert!(q.hold("example.com.", &new));
+        q.revalidated(key, &stale, generation, Ok(()));
+
+        assert_eq!(q.list().len(), 1);
+        let pending = q.pending(&pause());
+        assert_eq!(pending[0].1.records[0].content, "10.0.0.2");
+    }
+
+    #[test]
+    fn delete_releases() {
+        let q = Quarantine::new(1, Duration::from_secs(3600));
+        let u = update("node.example.com.", "10.0.0.1");
+        q.record("example.com.", &u, Err(&rejected()));
+        assert_eq!(q.list().len(), 1);
+
+        let mut delete = u.clone();
+        delete.changetype = "DELETE";
+        delete.records.clear();
+        assert!(!q.hold("example.com.", &delete));
+        assert!(q.list().is_empty());
+        assert!(q.pending(&pause()).is_empty());
+    }
+
+    #[test]
+    fn unmentioned_are_forgotten() {
+        let q = Quarantine::new(2, Duration::from_secs(0));
+        q.record(
+            "example.com.",
+            &update("a.example.com.", "10.0.0.1"),
+            Err(&rejected()),
+        );
+        let quarantined = update("b.example.com.", "10.0.0.2");
+        q.record("example.com.", &quarantined, Err(&rejected()));
+        q.record("example.com.", &quarantined, Err(&rejected()));
+        assert_eq!(q.list().len(), 1);
+
+        assert!(q.pending(&pause()).is_empty());
+        assert!(q.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let q = Quarantine::new(3, Duration::from_secs(3600));
+        for i in 0..=MAX_ENTRIES {
+            let u = update(&format!("n{}.example.com.", i), "10.0.0.1");
+            q.record("example.com.", &u, Err(&rejected()));
+        }
+        let entries = q.entries.lock().unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        let last = RrsetKey::new(
+            "example.com.",
+            &update(&format!("n{}.example.com.", MAX_ENTRIES), ""),
+        );
+        assert!(entries.contains_key(&last));
+    }
+}
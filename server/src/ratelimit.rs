@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: f64, per_sec: f64, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.last = now;
+    }
+
+    // Whether a token could be taken, without taking it.
+    fn available(&mut self, capacity: f64, per_sec: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(capacity, per_sec, now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+
+    fn take(&mut self, capacity: f64, per_sec: f64, now: Instant) -> Result<(), Duration> {
+        self.available(capacity, per_sec, now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+struct Limit {
+    capacity: f64,
+    per_sec: f64,
+}
+
+impl Limit {
+    fn per_minute(n: u32) -> Limit {
+        Limit {
+            capacity: n as f64,
+            per_sec: n as f64 / 60.0,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    host_limit: Limit,
+    hosts: Mutex<HashMap<String, TokenBucket>>,
+    global_limit: Option<Limit>,
+    global: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_host_per_minute: u32, global_per_minute: Option<u32>) -> RateLimiter {
+        let global_limit = global_per_minute.map(Limit::per_minute);
+        let global_capacity = global_limit.as_ref().map(|l| l.capacity).unwrap_or(0.0);
+        RateLimiter {
+            host_limit: Limit::per_minute(per_host_per_minute),
+            hosts: Mutex::new(HashMap::new()),
+            global_limit,
+            global: Mutex::new(TokenBucket::new(global_capacity, Instant::now())),
+        }
+    }
+
+    // On rejection, returns how long the caller should wait before trying
+    // again. Tokens are only taken once both limits allow the request, so a
+    // request the global limit turns away costs its host nothing.
+    pub fn check(&self, hostname: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let l = &self.host_limit;
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts
+            .entry(hostname.to_owned())
+            .or_insert_with(|| TokenBucket::new(l.capacity, now));
+        host.available(l.capacity, l.per_sec, now)?;
+
+        if let Some(l) = &self.global_limit {
+            self.global
+                .lock()
+                .unwrap()
+                .take(l.capacity, l.per_sec, now)?;
+        }
+        host.tokens -= 1.0;
+        Ok(())
+    }
+
+    pub fn prune(&self) -> usize {
+        prune(&mut self.hosts.lock().unwrap(), &self.host_limit)
+    }
+}
+
+// A bucket that has refilled completely behaves exactly like a fresh one, so
+// it can be dropped without changing any limiting decisions.
+fn prune(buckets: &mut HashMap<String, TokenBucket>, l: &Limit) -> usize {
+    let now = Instant::now();
+    let before = buckets.len();
+    buckets.retain(|_, b| {
+        b.refill(l.capacity, l.per_sec, now);
+        b.tokens < l.capacity
+    });
+    before - buckets.len()
+}
+
+// PATCHes per second to each pdns zone, for backends that fall over when a
+// whole fleet re-advertises at once.
+pub struct ZoneLimiter {
+    limit: Limit,
+    zones: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl ZoneLimiter {
+    pub fn new(per_sec: f64, burst: u32) -> ZoneLimiter {
+        ZoneLimiter {
+            limit: Limit {
+                capacity: burst.max(1) as f64,
+                per_sec,
+            },
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes nothing on rejection, so a caller that gives up while waiting
+    // leaves the bucket as it was.
+    pub fn check(&self, zone: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let l = &self.limit;
+        self.zones
+            .lock()
+            .unwrap()
+            .entry(zone.to_owned())
+            .or_insert_with(|| TokenBucket::new(l.capacity, now))
+            .take(l.capacity, l.per_sec, now)
+    }
+
+    pub fn prune(&self) -> usize {
+        prune(&mut self.zones.lock().unwrap(), &self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_tokens(limiter: &RateLimiter, hostname: &str) -> f64 {
+        limiter.hosts.lock().unwrap()[hostname].tokens
+    }
+
+    #[test]
+    fn per_host() {
+        let limiter = RateLimiter::new(2, None);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let wait = limiter.check("a").unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn global_rejection_leaves_host_tokens() {
+        let limiter = RateLimiter::new(3, Some(1));
+        assert!(limiter.check("a").is_ok());
+        for _ in 0..5 {
+            assert!(limiter.check("a").is_err());
+            assert!(limiter.check("b").is_err());
+        }
+        assert!((2.0..2.01).contains(&host_tokens(&limiter, "a")));
+        assert_eq!(host_tokens(&limiter, "b"), 3.0);
+    }
+
+    #[test]
+    fn host_rejection_leaves_global_tokens() {
+        let limiter = RateLimiter::new(1, Some(2));
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn prune_drops_only_full_buckets() {
+        let limiter = RateLimiter::new(2, None);
+        assert!(limiter.check("a").is_ok());
+        limiter
+            .hosts
+            .lock()
+            .unwrap()
+            .insert("b".to_owned(), TokenBucket::new(2.0, Instant::now()));
+        assert_eq!(limiter.prune(), 1);
+        assert!(limiter.hosts.lock().unwrap().contains_key("a"));
+    }
+}
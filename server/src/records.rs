@@ -0,0 +1,681 @@
+use itertools::Itertools;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+use proto::strapper;
+
+use crate::netmap::NetMap;
+use crate::remapper::{self, Remapper};
+use crate::{merge, nat64, origin, PdnsRecord, PdnsRrsetUpdate, TtlSettings};
+
+// One address record an advertisement comes to under the remappers, before
+// records sharing a name are grouped into rrset updates.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DesiredRecord {
+    pub zone: String,
+    pub name: String,
+    pub addr: IpAddr,
+    pub ttl: u32,
+    // Merged into the rrset with other nodes' records rather than replacing
+    // it.
+    pub merge: bool,
+    // For an A record a synthesize_a: remapper made up, the node address it
+    // stands for.
+    pub synthesized_from: Option<Ipv6Addr>,
+    // From a vip: remapper: the whole rrset goes to the node holding the
+    // address, however many nodes have advertised it.
+    pub vip: bool,
+}
+
+// The addresses a remapper publishes `addr` as: itself, and when the
+// remapper synthesizes A records for the node, the IPv4 address `nat64`
+// maps it to, if any.
+fn published_as(
+    addr: IpAddr,
+    synthesize: Option<&nat64::Prefix>,
+) -> Vec<(IpAddr, Option<Ipv6Addr>)> {
+    let mut published = vec![(addr, None)];
+    if let (Some(prefix), IpAddr::V6(v6)) = (synthesize, addr) {
+        match prefix.synthesize(&v6) {
+            Some(v4) => published.push((IpAddr::V4(v4), Some(v6))),
+            None => debug!(
+                "not synthesizing an A record for {}: not under {}",
+                v6, prefix
+            ),
+        }
+    }
+    published
+}
+
+// Which of the advertisement's addresses each remapper publishes, and under
+// what names. Depends on nothing but its arguments; `node_name` is the
+// remapper's name for the node (the effective hostname, maybe hashed).
+// synthesize_a: remappers only synthesize A records for nodes with no IPv4
+// address they'd publish, and only with `nat64`. TTLs are as
+// TtlSettings::for_address gives them, and addresses it withdraws aren't
+// published at all.
+pub fn match_records(
+    adv: &strapper::NodeAdvertisement,
+    remappers: &[Remapper],
+    ttl: &TtlSettings,
+    zone_net_map: Option<&NetMap>,
+    nat64: Option<&nat64::Prefix>,
+    node_name: &dyn Fn(&Remapper) -> String,
+) -> Vec<DesiredRecord> {
+    let origins = origin::origins(adv);
+    let addrs: Vec<(&strapper::Interface, IpAddr)> = adv
+        .interfaces
+        .iter()
+        .flat_map(|i| i.ipaddr.iter().map(move |a| (i, a)))
+        .filter(|(i, a)| !ttl.withdraws(i, a))
+        .filter_map(|(i, a)| Some((i, a.parse().ok()?)))
+        .collect();
+    let published: Vec<HashSet<IpAddr>> = remappers
+        .iter()
+        .map(|r| {
+            let matching = addrs
+                .iter()
+                .filter(|(i, a)| r.matches(adv, i, a))
+                .map(|(_, a)| *a);
+            r.prefer.select(matching, &origins)
+        })
+        .collect();
+
+    adv.interfaces
+        .iter()
+        .flat_map(|iface| iface.ipaddr.iter().map(move |a| (a, iface)))
+        .filter_map(|(a, iface)| Some((IpAddr::from_str(a).ok()?, a, iface)))
+        .cartesian_product(remappers.iter().zip(published.iter()))
+        .filter(|((a, _, iface), (remapper, published))| {
+            remapper.ifaces.matches(iface) && published.contains(a)
+        })
+        .flat_map(|((a, addr, iface), (remapper, published))| {
+            let synthesize =
+                nat64.filter(|_| remapper.synthesize_a && !published.iter().any(IpAddr::is_ipv4));
+            let zone = match remapper.zone_for(&a, zone_net_map) {
+                Ok(zone) => Some(zone),
+                Err(e) => {
+                    warn!(
+                        "skipping {} of {} for remapper {}: {:#}",
+                        a, adv.hostname, remapper, e
+                    );
+                    None
+                }
+            };
+            let hostname = node_name(remapper);
+            let ttl = ttl.for_address(remapper, iface, addr);
+            remapper
+                .entry_fmts
+                .iter()
+                .cartesian_product(published_as(a, synthesize))
+                .filter_map(move |(fmt, (a, synthesized_from))| {
+                    // A format naming neither the node nor anything of its
+                    // interface is a name every matching node shares, so it's
+                    // always merged. A VIP's name is shared by whichever
+                    // nodes hold it in turn, but only ever one at a time.
+                    let merge = !remapper.vip && (remapper.merge || remapper::is_shared(fmt));
+                    let zone = zone.clone()?;
+                    let name = remapper::expand(fmt, &hostname, &adv.labels, iface, addr)?;
+                    Some(DesiredRecord {
+                        name: remapper::qualify(name, &zone),
+                        zone,
+                        addr: a,
+                        ttl,
+                        merge,
+                        synthesized_from,
+                        vip: remapper.vip,
+                    })
+                })
+        })
+        // Two formats (or two remappers) can land on the same name.
+        .unique_by(|r| (r.addr, r.zone.clone(), r.name.clone()))
+        .collect()
+}
+
+// An advertisement's maintenance flags, applied to each of its updates, so
+// clearing them on a later one restores the records as they were.
+pub fn apply_maintenance(
+    adv: &strapper::NodeAdvertisement,
+    updates: &mut [(String, PdnsRrsetUpdate)],
+) {
+    for (_, update) in updates.iter_mut() {
+        if let Some(ttl) = adv.ttl_override {
+            update.ttl = update.ttl.min(ttl);
+        }
+        for record in update.records.iter_mut() {
+            record.disabled = adv.disabled;
+        }
+    }
+}
+
+// How the node's records differ from normal, for logs and history; None if
+// they don't.
+pub fn maintenance_mode(adv: &strapper::NodeAdvertisement) -> Option<String> {
+    match (adv.disabled, adv.ttl_override) {
+        (false, None) => None,
+        (true, None) => Some("disabled".to_owned()),
+        (false, Some(ttl)) => Some(format!("TTL capped at {}s", ttl)),
+        (true, Some(ttl)) => Some(format!("disabled, TTL capped at {}s", ttl)),
+    }
+}
+
+// The A and AAAA updates for a node's records, one per record except that a
+// merged rrset is shared, so all of the node's addresses for it have to go in
+// one update, as do a VIP's. Synthesized records carry a comment saying so, which merge
+// keeps as their ownership comment.
+pub fn address_updates(owner: &str, records: Vec<DesiredRecord>) -> Vec<(String, PdnsRrsetUpdate)> {
+    let mut updates: Vec<(String, PdnsRrsetUpdate)> = Vec::new();
+    for record in records {
+        let zone = record.zone;
+        let content = record.addr.to_string();
+        let (merge, vip) = (record.merge, record.vip);
+        let update = PdnsRrsetUpdate {
+            name: record.name,
+            type_: if record.addr.is_ipv4() { "A" } else { "AAAA" },
+            ttl: record.ttl,
+            changetype: "REPLACE",
+            records: vec![PdnsRecord {
+                content: content.clone(),
+                disabled: false,
+            }],
+            comments: record
+                .synthesized_from
+                .iter()
+                .map(|from| merge::synthesized(owner, &content, from))
+                .collect(),
+            merge_owner: if merge { Some(owner.to_owned()) } else { None },
+            vip,
+        };
+        if merge || vip {
+            let existing = updates.iter_mut().find(|(z, u)| {
+                u.merge_owner.is_some() == merge
+                    && u.vip == vip
+                    && *z == zone
+                    && u.name == update.name
+                    && u.type_ == update.type_
+            });
+            if let Some((_, u)) = existing {
+                u.ttl = u.ttl.min(update.ttl);
+                u.records.extend(update.records);
+                u.comments.extend(update.comments);
+                continue;
+            }
+        }
+        updates.push((zone, update));
+    }
+    updates
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::strapper;
+
+    use serde_json::json;
+    use std::net::IpAddr;
+
+    use super::{address_updates, match_records, DesiredRecord};
+    use crate::remapper::Remapper;
+    use crate::{nat64, PdnsPartialZoneRrsetPatch, TtlPolicy, TtlSettings};
+
+    // A node with a fresh address and one on its way out.
+    fn renumbering() -> strapper::NodeAdvertisement {
+        let info = |address: &str, preferred_lifetime| strapper::AddressInfo {
+            address: address.to_owned(),
+            preferred_lifetime,
+            valid_lifetime: 7200,
+            ..Default::default()
+        };
+        strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![strapper::Interface {
+                name: "eth0".to_owned(),
+                ipaddr: vec!["2001:db8:1::1".to_owned(), "2001:db8:2::1".to_owned()],
+                address_info: vec![info("2001:db8:1::1", 0), info("2001:db8:2::1", 1800)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lifetimes() {
+        let remappers: Vec<Remapper> =
+            vec!["ttl=600:2001:db8::/32@example.com.@{}".parse().unwrap()];
+        let records = |policy| {
+            let ttl = TtlSettings {
+                policy,
+                ttl: 3600,
+                min: 60,
+                vip: 30,
+            };
+            match_records(&renumbering(), &remappers, &ttl, None, None, &|_| {
+                "node".to_owned()
+            })
+            .into_iter()
+            .map(|r| (r.addr.to_string(), r.ttl))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            records(TtlPolicy::Fixed),
+            [
+                ("2001:db8:1::1".to_owned(), 600),
+                ("2001:db8:2::1".to_owned(), 600)
+            ]
+        );
+        assert_eq!(
+            records(TtlPolicy::MinOfLifetime),
+            [("2001:db8:2::1".to_owned(), 600)]
+        );
+    }
+
+    // A delegated prefix renumbered under a suffix: remapper: the record
+    // keeps its name and follows the node to the new prefix, while a net
+    // remapper for the old one stops matching.
+    #[test]
+    fn prefix_change() {
+        let remappers: Vec<Remapper> = vec![
+            "suffix:::a:b:c:d/64@suffix.example.com.@{}"
+                .parse()
+                .unwrap(),
+            "2001:db8:aa00::/56@net.example.com.@{}".parse().unwrap(),
+        ];
+        let ttl = TtlSettings {
+            policy: TtlPolicy::Fixed,
+            ttl: 3600,
+            min: 60,
+            vip: 30,
+        };
+        let records = |addrs: &[&str]| {
+            let adv = strapper::NodeAdvertisement {
+                hostname: "node".to_owned(),
+                interfaces: vec![strapper::Interface {
+                    name: "eth0".to_owned(),
+                    ipaddr: addrs.iter().map(|a| a.to_string()).collect(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            match_records(&adv, &remappers, &ttl, None, None, &|_| "node".to_owned())
+                .into_iter()
+                .map(|r| (r.name, r.addr.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let named = |name: &str, addr: &str| (name.to_owned(), addr.to_owned());
+
+        assert_eq!(
+            records(&["2001:db8:aa00:1:a:b:c:d", "2001:db8:aa00:1::99"]),
+            [
+                named("node.suffix.example.com.", "2001:db8:aa00:1:a:b:c:d"),
+                named("node.net.example.com.", "2001:db8:aa00:1:a:b:c:d"),
+                named("node.net.example.com.", "2001:db8:aa00:1::99"),
+            ]
+        );
+        assert_eq!(
+            records(&["2001:db8:bb00:1:a:b:c:d", "2001:db8:bb00:1::99"]),
+            [named("node.suffix.example.com.", "2001:db8:bb00:1:a:b:c:d")]
+        );
+        // Mid-renumbering, both prefixes at once.
+        assert_eq!(
+            records(&["2001:db8:aa00:1:a:b:c:d", "2001:db8:bb00:1:a:b:c:d"]),
+            [
+                named("node.suffix.example.com.", "2001:db8:aa00:1:a:b:c:d"),
+                named("node.net.example.com.", "2001:db8:aa00:1:a:b:c:d"),
+                named("node.suffix.example.com.", "2001:db8:bb00:1:a:b:c:d"),
+            ]
+        );
+    }
+
+    // Each address keeps the interface it came from, so a remapper
+    // constrained to one takes nothing from the others, even where their
+    // addresses share a net.
+    #[test]
+    fn interface_constrained() {
+        let remappers: Vec<Remapper> = vec![
+            "iface=^mgmt:2606:4700::/32@mgmt.example.com.@{}"
+                .parse()
+                .unwrap(),
+            "mac=02-cc-dd:2606:4700::/32@data.example.com.@{}"
+                .parse()
+                .unwrap(),
+        ];
+        let iface = |name: &str, mac: &str, addr: &str| strapper::Interface {
+            name: name.to_owned(),
+            mac: mac.to_owned(),
+            ipaddr: vec![addr.to_owned()],
+            ..Default::default()
+        };
+        let adv = strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![
+                iface("mgmt0", "02:aa:bb:00:00:01", "2606:4700::1"),
+                iface("eth0", "02:cc:dd:00:00:01", "2606:4700::2"),
+                iface("eth1", "02:ee:ff:00:00:01", "2606:4700::3"),
+            ],
+            ..Default::default()
+        };
+        let ttl = TtlSettings {
+            policy: TtlPolicy::Fixed,
+            ttl: 3600,
+            min: 60,
+            vip: 30,
+        };
+        let records: Vec<_> =
+            match_records(&adv, &remappers, &ttl, None, None, &|_| "node".to_owned())
+                .into_iter()
+                .map(|r| (r.name, r.addr.to_string()))
+                .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    "node.mgmt.example.com.".to_owned(),
+                    "2606:4700::1".to_owned()
+                ),
+                (
+                    "node.data.example.com.".to_owned(),
+                    "2606:4700::2".to_owned()
+                ),
+            ]
+        );
+    }
+
+    fn desired(zone: &str, name: &str, addr: &str, ttl: u32) -> DesiredRecord {
+        DesiredRecord {
+            zone: zone.to_owned(),
+            name: name.to_owned(),
+            addr: addr.parse().unwrap(),
+            ttl,
+            merge: false,
+            synthesized_from: None,
+            vip: false,
+        }
+    }
+
+    // Plain records go one to an update; merged ones and a VIP's share one
+    // per zone, name and type, at the lowest of their TTLs.
+    #[test]
+    fn grouping() {
+        let merged = |zone: &str, addr: &str, ttl| DesiredRecord {
+            merge: true,
+            ..desired(zone, "web.example.com.", addr, ttl)
+        };
+        let vip = |addr: &str| DesiredRecord {
+            vip: true,
+            ..desired("example.com.", "vip.example.com.", addr, 30)
+        };
+        let records = vec![
+            desired("example.com.", "node.example.com.", "2001:db8::1", 600),
+            desired("example.com.", "node.example.com.", "2001:db8::2", 600),
+            merged("example.com.", "2001:db8::1", 600),
+            merged("example.com.", "10.0.0.1", 600),
+            merged("example.com.", "2001:db8::2", 300),
+            merged("example.net.", "2001:db8::3", 600),
+            vip("2001:db8::10"),
+            vip("2001:db8::11"),
+            // The same name, but not merged: not folded into the merged
+            // rrset, which would take other nodes' records with it.
+            desired("example.com.", "web.example.com.", "2001:db8::4", 600),
+        ];
+        let updates: Vec<_> = address_updates("node", records)
+            .into_iter()
+            .map(|(zone, u)| {
+                let records: Vec<_> = u.records.iter().map(|r| r.content.as_str()).collect();
+                (
+                    zone,
+                    u.name.clone(),
+                    u.type_,
+                    u.ttl,
+                    records.join(" "),
+                    u.merge_owner.clone(),
+                    u.vip,
+                )
+            })
+            .collect();
+        let update = |zone: &str, name: &str, type_, ttl, records: &str, owner: bool, vip| {
+            (
+                zone.to_owned(),
+                name.to_owned(),
+                type_,
+                ttl,
+                records.to_owned(),
+                if owner { Some("node".to_owned()) } else { None },
+                vip,
+            )
+        };
+        assert_eq!(
+            updates,
+            [
+                update(
+                    "example.com.",
+                    "node.example.com.",
+                    "AAAA",
+                    600,
+                    "2001:db8::1",
+                    false,
+                    false
+                ),
+                update(
+                    "example.com.",
+                    "node.example.com.",
+                    "AAAA",
+                    600,
+                    "2001:db8::2",
+                    false,
+                    false
+                ),
+                update(
+                    "example.com.",
+                    "web.example.com.",
+                    "AAAA",
+                    300,
+                    "2001:db8::1 2001:db8::2",
+                    true,
+                    false
+                ),
+                update(
+                    "example.com.",
+                    "web.example.com.",
+                    "A",
+                    600,
+                    "10.0.0.1",
+                    true,
+                    false
+                ),
+                update(
+                    "example.net.",
+                    "web.example.com.",
+                    "AAAA",
+                    600,
+                    "2001:db8::3",
+                    true,
+                    false
+                ),
+                update(
+                    "example.com.",
+                    "vip.example.com.",
+                    "AAAA",
+                    30,
+                    "2001:db8::10 2001:db8::11",
+                    false,
+                    true
+                ),
+                update(
+                    "example.com.",
+                    "web.example.com.",
+                    "AAAA",
+                    600,
+                    "2001:db8::4",
+                    false,
+                    false
+                ),
+            ]
+        );
+        assert!(address_updates("node", vec![]).is_empty());
+    }
+
+    // What pdns is sent for a node, byte for byte as far as the JSON goes:
+    // its own name, a shared one merged with other nodes', and the A record
+    // synthesized from its NAT64 address, with the comment that says so.
+    #[test]
+    fn patch_body() {
+        let remappers: Vec<Remapper> = vec![
+            "2001:db8::/32@example.com.@{}".parse().unwrap(),
+            "2001:db8::/32@example.com.@web".parse().unwrap(),
+            "synthesize_a:64:ff9b::/96@nat.example.com.@{}"
+                .parse()
+                .unwrap(),
+        ];
+        let nat64: nat64::Prefix = "64:ff9b::/96".parse().unwrap();
+        let adv = strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            interfaces: vec![strapper::Interface {
+                name: "eth0".to_owned(),
+                ipaddr: vec!["2001:db8::1".to_owned(), "64:ff9b::c000:205".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let ttl = TtlSettings {
+            policy: TtlPolicy::Fixed,
+            ttl: 3600,
+            min: 60,
+            vip: 30,
+        };
+        let records = match_records(&adv, &remappers, &ttl, None, Some(&nat64), &|_| {
+            "node".to_owned()
+        });
+        assert_eq!(
+            records.iter().map(|r| r.addr).collect::<Vec<IpAddr>>(),
+            [
+                "2001:db8::1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+                "64:ff9b::c000:205".parse().unwrap(),
+                "192.0.2.5".parse().unwrap(),
+            ]
+        );
+        let bodies: Vec<_> = address_updates("node", records)
+            .into_iter()
+            .map(|(zone, update)| {
+                let body = PdnsPartialZoneRrsetPatch {
+                    rrsets: vec![update],
+                };
+                (zone, serde_json::to_value(&body).unwrap())
+            })
+            .collect();
+        let body = |name: &str, type_: &str, content: &str, comments| {
+            json!({"rrsets": [{
+                "name": name,
+                "type": type_,
+                "ttl": 3600,
+                "changetype": "REPLACE",
+                "records": [{"content": content, "disabled": false}],
+                "comments": comments,
+            }]})
+        };
+        assert_eq!(
+            bodies,
+            [
+                (
+                    "example.com.".to_owned(),
+                    body("node.example.com.", "AAAA", "2001:db8::1", json!([]))
+                ),
+                (
+                    "example.com.".to_owned(),
+                    body("web.example.com.", "AAAA", "2001:db8::1", json!([]))
+                ),
+                (
+                    "nat.example.com.".to_owned(),
+                    body(
+                        "node.nat.example.com.",
+                        "AAAA",
+                        "64:ff9b::c000:205",
+                        json!([])
+                    )
+                ),
+                (
+                    "nat.example.com.".to_owned(),
+                    body(
+                        "node.nat.example.com.",
+                        "A",
+                        "192.0.2.5",
+                        json!([{
+                            "content": "owner=node content=192.0.2.5 synthesized-from=64:ff9b::c000:205",
+                            "account": "strapper",
+                        }])
+                    )
+                ),
+            ]
+        );
+    }
+
+    // synthesize_a: adds an A record for each NAT64 address, marked as made
+    // up, and nothing for addresses outside the prefix.
+    #[test]
+    fn synthesized_a() {
+        let remappers: Vec<Remapper> = vec![
+            "synthesize_a:::/0@example.com.@{}".parse().unwrap(),
+            "64:ff9b::/96@plain.example.com.@{}".parse().unwrap(),
+        ];
+        let nat64: nat64::Prefix = "64:ff9b::/96".parse().unwrap();
+        let ttl = TtlSettings {
+            policy: TtlPolicy::Fixed,
+            ttl: 3600,
+            min: 60,
+            vip: 30,
+        };
+        let records = |addrs: &[&str], nat64: Option<&nat64::Prefix>| {
+            let adv = strapper::NodeAdvertisement {
+                hostname: "node".to_owned(),
+                interfaces: vec![strapper::Interface {
+                    name: "eth0".to_owned(),
+                    ipaddr: addrs.iter().map(|a| a.to_string()).collect(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            match_records(&adv, &remappers, &ttl, None, nat64, &|_| "node".to_owned())
+                .into_iter()
+                .map(|r| {
+                    (
+                        r.name,
+                        r.addr.to_string(),
+                        r.synthesized_from.map(|a| a.to_string()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let record = |name: &str, addr: &str, from: Option<&str>| {
+            (name.to_owned(), addr.to_owned(), from.map(str::to_owned))
+        };
+
+        assert_eq!(
+            records(&["64:ff9b::c000:205", "2001:db8::1"], Some(&nat64)),
+            [
+                record("node.example.com.", "64:ff9b::c000:205", None),
+                record("node.example.com.", "192.0.2.5", Some("64:ff9b::c000:205")),
+                record("node.plain.example.com.", "64:ff9b::c000:205", None),
+                record("node.example.com.", "2001:db8::1", None),
+            ]
+        );
+        // Only the remapper asking for it, and only with a prefix.
+        assert_eq!(
+            records(&["64:ff9b::c000:205"], None),
+            [
+                record("node.example.com.", "64:ff9b::c000:205", None),
+                record("node.plain.example.com.", "64:ff9b::c000:205", None),
+            ]
+        );
+        // The remapper's net is IPv6, so an IPv4 address of the node's
+        // own goes to other remappers and doesn't stand in for this one's.
+        assert_eq!(
+            records(&["64:ff9b::c000:205", "192.0.2.99"], Some(&nat64)),
+            [
+                record("node.example.com.", "64:ff9b::c000:205", None),
+                record("node.example.com.", "192.0.2.5", Some("64:ff9b::c000:205")),
+                record("node.plain.example.com.", "64:ff9b::c000:205", None),
+            ]
+        );
+    }
+}
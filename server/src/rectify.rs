@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::metrics::{self, Metrics};
+use crate::pdnsconn::Connections;
+
+// Whether zones are rectified through the API after strapper changes them,
+// for DNSSEC-signed zones whose own API-RECTIFY is off.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    AfterChange,
+    Never,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "after-change" => Ok(Policy::AfterChange),
+            "never" => Ok(Policy::Never),
+            _ => Err(anyhow!(
+                "unknown rectify policy '{}' (expected after-change or never)",
+                s
+            )),
+        }
+    }
+}
+
+// Rectifies of one pdns target. A change to a zone schedules one for the
+// end of the window, which every other change to the zone until then
+// shares; a change after it starts schedules the next. A failed rectify is
+// left for the next change to retry.
+pub struct Rectifier {
+    window: Duration,
+    connections: Arc<Connections>,
+    metrics: Arc<Metrics>,
+    pending: Mutex<HashSet<String>>,
+    // Zones the zone check found pdns rectifies itself, through their
+    // API-RECTIFY metadata.
+    by_pdns: Mutex<HashSet<String>>,
+}
+
+impl Rectifier {
+    pub fn new(
+        window: Duration,
+        connections: Arc<Connections>,
+        metrics: Arc<Metrics>,
+    ) -> Rectifier {
+        Rectifier {
+            window,
+            connections,
+            metrics,
+            pending: Mutex::new(HashSet::new()),
+            by_pdns: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // From the zone check, which reads each zone's api_rectify.
+    pub fn checked(&self, zone: &str, api_rectify: bool) {
+        let mut by_pdns = self.by_pdns.lock().unwrap();
+        if api_rectify {
+            if by_pdns.insert(zone.to_owned()) {
+                info!(
+                    "zone {} has API-RECTIFY set, leaving rectifying it to pdns",
+                    zone
+                );
+            }
+        } else {
+            by_pdns.remove(zone);
+        }
+    }
+
+    // After a PATCH to `zone` succeeds; `request` is the rectify's PUT,
+    // built only if one's scheduled.
+    pub fn changed(
+        self: &Arc<Self>,
+        zone: &str,
+        request: impl FnOnce() -> reqwest::RequestBuilder,
+    ) {
+        if self.by_pdns.lock().unwrap().contains(zone) {
+            return;
+        }
+        if !self.pending.lock().unwrap().insert(zone.to_owned()) {
+            return;
+        }
+        let request = request();
+        let rectifier = self.clone();
+        let zone = zone.to_owned();
+        tokio::spawn(async move {
+            tokio::time::sleep(rectifier.window).await;
+            rectifier.pending.lock().unwrap().remove(&zone);
+            rectifier.rectify(&zone, request).await;
+        });
+    }
+
+    async fn rectify(&self, zone: &str, request: reqwest::RequestBuilder) {
+        let failed = match self.connections.send(request).await {
+            Ok(r) if r.status().is_success() => None,
+            Ok(r) => Some(format!(
+                "{} - {}",
+                r.status(),
+                r.text().await.unwrap_or_default()
+            )),
+            Err(e) => Some(e.to_string()),
+        };
+        match failed {
+            None => {
+                metrics::inc(&self.metrics.pdns_rectified);
+                debug!("rectified zone {}", zone);
+            }
+            Some(e) => {
+                metrics::inc(&self.metrics.pdns_rectify_failures);
+                warn!(
+                    "unable to rectify zone {} ({}), trying again after its next change",
+                    zone, e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::Result;
+use futures::StreamExt;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use proto::reflection::server_reflection_request::MessageRequest;
+use proto::reflection::server_reflection_response::MessageResponse;
+use proto::reflection::server_reflection_server::{ServerReflection, ServerReflectionServer};
+use proto::reflection::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+    ServerReflectionResponse, ServiceResponse,
+};
+
+struct Schema {
+    // Encoded FileDescriptorProtos and what each imports, by file name.
+    files: HashMap<String, (Vec<u8>, Vec<String>)>,
+    // Fully qualified messages, enums, services and methods, to the file
+    // defining them.
+    symbols: HashMap<String, String>,
+    services: Vec<String>,
+}
+
+fn qualify(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", scope, name)
+    }
+}
+
+fn add_message(
+    symbols: &mut HashMap<String, String>,
+    file: &str,
+    scope: &str,
+    m: &DescriptorProto,
+) {
+    let name = qualify(scope, m.name());
+    for nested in m.nested_type.iter() {
+        add_message(symbols, file, &name, nested);
+    }
+    for e in m.enum_type.iter() {
+        symbols.insert(qualify(&name, e.name()), file.to_owned());
+    }
+    symbols.insert(name, file.to_owned());
+}
+
+impl Schema {
+    fn load() -> Result<Schema> {
+        let set = FileDescriptorSet::decode(proto::FILE_DESCRIPTOR_SET)?;
+        let mut schema = Schema {
+            files: HashMap::new(),
+            symbols: HashMap::new(),
+            services: vec![],
+        };
+        for file in set.file {
+            let name = file.name().to_owned();
+            let package = file.package();
+            for m in file.message_type.iter() {
+                add_message(&mut schema.symbols, &name, package, m);
+            }
+            for e in file.enum_type.iter() {
+                schema
+                    .symbols
+                    .insert(qualify(package, e.name()), name.clone());
+            }
+            for s in file.service.iter() {
+                let service = qualify(package, s.name());
+                for m in s.method.iter() {
+                    schema
+                        .symbols
+                        .insert(qualify(&service, m.name()), name.clone());
+                }
+                schema.symbols.insert(service.clone(), name.clone());
+                schema.services.push(service);
+            }
+            schema
+                .files
+                .insert(name, (encode(&file), file.dependency.clone()));
+        }
+        schema.services.sort();
+        Ok(schema)
+    }
+
+    // `name` and everything it imports, directly or not, `name` first.
+    fn file_with_imports(&self, name: &str) -> Option<Vec<Vec<u8>>> {
+        self.files.get(name)?;
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from(vec![name.to_owned()]);
+        let mut files = vec![];
+        while let Some(name) = queue.pop_front() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some((encoded, imports)) = self.files.get(&name) {
+                files.push(encoded.clone());
+                queue.extend(imports.iter().cloned());
+            }
+        }
+        Some(files)
+    }
+
+    fn respond(&self, request: ServerReflectionRequest) -> ServerReflectionResponse {
+        let files = |files: Option<Vec<Vec<u8>>>, what: &str| match files {
+            Some(file_descriptor_proto) => {
+                MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                    file_descriptor_proto,
+                })
+            }
+            None => error(tonic::Code::NotFound, format!("{} not found", what)),
+        };
+        let response = match &request.message_request {
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                })
+            }
+            Some(MessageRequest::FileByFilename(name)) => {
+                files(self.file_with_imports(name), &format!("file {}", name))
+            }
+            Some(MessageRequest::FileContainingSymbol(symbol)) => {
+                let file = self
+                    .symbols
+                    .get(symbol.trim_start_matches('.'))
+                    .and_then(|f| self.file_with_imports(f));
+                files(file, &format!("symbol {}", symbol))
+            }
+            // We define no extensions.
+            Some(MessageRequest::FileContainingExtension(_))
+            | Some(MessageRequest::AllExtensionNumbersOfType(_)) => {
+                error(tonic::Code::NotFound, "no extensions".to_owned())
+            }
+            None => error(tonic::Code::InvalidArgument, "empty request".to_owned()),
+        };
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request),
+            message_response: Some(response),
+        }
+    }
+}
+
+fn encode(file: &FileDescriptorProto) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(file.encoded_len());
+    // Only fails when out of buffer space, and a Vec grows.
+    file.encode(&mut buf).unwrap();
+    buf
+}
+
+fn error(code: tonic::Code, error_message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: code as i32,
+        error_message,
+    })
+}
+
+// gRPC server reflection over the schema compiled into the proto crate, so
+// grpcurl and the like work without the .proto files at hand.
+#[derive(Clone)]
+pub struct Reflection {
+    schema: Arc<Schema>,
+}
+
+type ResponseStream = Pin<
+    Box<
+        dyn futures::Stream<Item = Result<ServerReflectionResponse, tonic::Status>>
+            + Send
+            + Sync
+            + 'static,
+    >,
+>;
+
+#[tonic::async_trait]
+impl ServerReflection for Reflection {
+    type ServerReflectionInfoStream = ResponseStream;
+
+    async fn server_reflection_info(
+        &self,
+        request: tonic::Request<tonic::Streaming<ServerReflectionRequest>>,
+    ) -> Result<tonic::Response<Self::ServerReflectionInfoStream>, tonic::Status> {
+        let mut requests = request.into_inner();
+        let schema = self.schema.clone();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let response = request.map(|r| schema.respond(r));
+                if tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+pub type ReflectionServer = ServerReflectionServer<Reflection>;
+
+pub fn service() -> Result<ReflectionServer> {
+    Ok(ServerReflectionServer::new(Reflection {
+        schema: Arc::new(Schema::load()?),
+    }))
+}
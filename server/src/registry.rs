@@ -0,0 +1,350 @@
+use prost::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of;
+use std::sync::Mutex;
+
+use proto::strapper;
+
+use crate::sequence::Sequences;
+
+// Caps on what one node (and the fleet) may make us hold in memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_nodes: usize,
+    pub max_interfaces_per_node: usize,
+    pub max_addresses_per_interface: usize,
+    pub max_addresses_per_node: usize,
+    pub max_advertisement_bytes: usize,
+}
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    // The advertisement itself is too big, whatever else is registered.
+    Oversized(String),
+    // There's no room for another node.
+    Full(String),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::Oversized(s) | LimitExceeded::Full(s) => f.write_str(s),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub nodes: usize,
+    pub interfaces: usize,
+    pub addresses: usize,
+    pub approx_bytes: usize,
+}
+
+// Everything the server remembers about nodes, keyed by effective hostname.
+// The handlers only see this trait so the map can be swapped for something
+// persistent without touching them.
+pub trait Registry: Send + Sync {
+    // Whether `advertisement` would fit, counting it as a new node unless
+    // its hostname is already registered.
+    fn check(&self, advertisement: &strapper::NodeAdvertisement) -> Result<(), LimitExceeded>;
+
+    // See sequence::Sequences::admit.
+    fn admit(&self, hostname: &str, start_time: u64, sequence: u64) -> bool;
+
+    fn get(&self, hostname: &str) -> Option<strapper::NodeAdvertisement>;
+
+    fn insert(&self, advertisement: strapper::NodeAdvertisement);
+
+    // Also forgets the node's sequence, so a re-registration starts fresh.
+    fn remove(&self, hostname: &str) -> Option<strapper::NodeAdvertisement>;
+
+    fn list(&self) -> Vec<strapper::NodeAdvertisement>;
+
+    fn stats(&self) -> Stats;
+}
+
+struct Entry {
+    advertisement: strapper::NodeAdvertisement,
+    interfaces: usize,
+    addresses: usize,
+    approx_bytes: usize,
+}
+
+impl Entry {
+    fn new(advertisement: strapper::NodeAdvertisement) -> Entry {
+        let interfaces = advertisement.interfaces.len();
+        let addresses = advertisement
+            .interfaces
+            .iter()
+            .map(|i| i.ipaddr.len())
+            .sum();
+        // The encoded size tracks the heap the strings and vectors use
+        // closely enough; add the fixed parts of the structs on top.
+        let approx_bytes = advertisement.encoded_len()
+            + size_of::<Entry>()
+            + interfaces * size_of::<strapper::Interface>()
+            + addresses * size_of::<String>();
+        Entry {
+            advertisement,
+            interfaces,
+            addresses,
+            approx_bytes,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    nodes: HashMap<String, Entry>,
+    stats: Stats,
+}
+
+pub struct MemoryRegistry {
+    limits: Limits,
+    state: Mutex<State>,
+    sequences: Sequences,
+}
+
+impl MemoryRegistry {
+    pub fn new(limits: Limits) -> MemoryRegistry {
+        MemoryRegistry {
+            limits,
+            state: Mutex::new(State::default()),
+            sequences: Sequences::default(),
+        }
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn check(&self, advertisement: &strapper::NodeAdvertisement) -> Result<(), LimitExceeded> {
+        let bytes = advertisement.encoded_len();
+        if bytes > self.limits.max_advertisement_bytes {
+            return Err(LimitExceeded::Oversized(format!(
+                "advertisement of {} bytes exceeds the limit of {} (--max-advertisement-bytes)",
+                bytes, self.limits.max_advertisement_bytes
+            )));
+        }
+        if advertisement.interfaces.len() > self.limits.max_interfaces_per_node {
+            return Err(LimitExceeded::Oversized(format!(
+                "{} interfaces exceeds the limit of {} (--max-interfaces-per-node)",
+                advertisement.interfaces.len(),
+                self.limits.max_interfaces_per_node
+            )));
+        }
+        if let Some(i) = advertisement
+            .interfaces
+            .iter()
+            .find(|i| i.ipaddr.len() > self.limits.max_addresses_per_interface)
+        {
+            return Err(LimitExceeded::Oversized(format!(
+                "{} addresses on {} exceeds the limit of {} (--max-addresses-per-interface)",
+                i.ipaddr.len(),
+                i.name,
+                self.limits.max_addresses_per_interface
+            )));
+        }
+        let addresses: usize = advertisement
+            .interfaces
+            .iter()
+            .map(|i| i.ipaddr.len())
+            .sum();
+        if addresses > self.limits.max_addresses_per_node {
+            return Err(LimitExceeded::Oversized(format!(
+                "{} addresses exceeds the limit of {} (--max-addresses-per-node)",
+                addresses, self.limits.max_addresses_per_node
+            )));
+        }
+
+        let state = self.state.lock().unwrap();
+        if state.nodes.len() >= self.limits.max_nodes
+            && !state.nodes.contains_key(&advertisement.effective_hostname)
+        {
+            return Err(LimitExceeded::Full(format!(
+                "node limit of {} reached",
+                self.limits.max_nodes
+            )));
+        }
+        Ok(())
+    }
+
+    // Sequences are forgotten with their nodes, but one admitted for a node
+    // that then never got registered would stay. There can't be more
+    // registered nodes than --max-nodes, so past that some are strays.
+    fn admit(&self, hostname: &str, start_time: u64, sequence: u64) -> bool {
+        let admitted = self.sequences.admit(hostname, start_time, sequence);
+        if self.sequences.len() > self.limits.max_nodes {
+            let state = self.state.lock().unwrap();
+            self.sequences
+                .retain(|h| h == hostname || state.nodes.contains_key(h));
+        }
+        admitted
+    }
+
+    fn get(&self, hostname: &str) -> Option<strapper::NodeAdvertisement> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .get(hostname)
+            .map(|e| e.advertisement.clone())
+    }
+
+    fn insert(&self, advertisement: strapper::NodeAdvertisement) {
+        let hostname = advertisement.effective_hostname.clone();
+        let entry = Entry::new(advertisement);
+        let mut state = self.state.lock().unwrap();
+        let State { nodes, stats } = &mut *state;
+        stats.nodes += 1;
+        stats.interfaces += entry.interfaces;
+        stats.addresses += entry.addresses;
+        stats.approx_bytes += entry.approx_bytes + hostname.len();
+        if let Some(old) = nodes.insert(hostname.clone(), entry) {
+            stats.nodes -= 1;
+            stats.interfaces -= old.interfaces;
+            stats.addresses -= old.addresses;
+            stats.approx_bytes -= old.approx_bytes + hostname.len();
+        }
+    }
+
+    fn remove(&self, hostname: &str) -> Option<strapper::NodeAdvertisement> {
+        self.sequences.forget(hostname);
+        let mut state = self.state.lock().unwrap();
+        let State { nodes, stats } = &mut *state;
+        let old = nodes.remove(hostname)?;
+        stats.nodes -= 1;
+        stats.interfaces -= old.interfaces;
+        stats.addresses -= old.addresses;
+        stats.approx_bytes -= old.approx_bytes + hostname.len();
+        Some(old.advertisement)
+    }
+
+    fn list(&self) -> Vec<strapper::NodeAdvertisement> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .values()
+            .map(|e| e.advertisement.clone())
+            .collect()
+    }
+
+    fn stats(&self) -> Stats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::strapper;
+    use std::time::{Duration, Instant};
+
+    use super::{LimitExceeded, Limits, MemoryRegistry, Registry};
+
+    fn limits(max_nodes: usize) -> Limits {
+        Limits {
+            max_nodes,
+            max_interfaces_per_node: 64,
+            max_addresses_per_interface: 64,
+            max_addresses_per_node: 256,
+            max_advertisement_bytes: 1 << 20,
+        }
+    }
+
+    #[test]
+    fn stray_sequences_pruned() {
+        let r = MemoryRegistry::new(limits(2));
+        r.insert(strapper::NodeAdvertisement {
+            hostname: "kept".to_owned(),
+            effective_hostname: "kept".to_owned(),
+            ..Default::default()
+        });
+        assert!(r.admit("kept", 1, 5));
+        // Admitted, then refused further on, so never registered.
+        assert!(r.admit("stray-1", 1, 1));
+        assert!(r.admit("stray-2", 1, 1));
+        assert_eq!(r.sequences.len(), 2);
+
+        assert!(!r.admit("kept", 1, 4));
+        assert!(r.admit("stray-1", 1, 0));
+    }
+
+    const NODES: usize = 5000;
+
+    // Shaped like the fleet it's meant for: ten interfaces a node, an IPv4
+    // and an IPv6 address on each.
+    fn synthetic(n: usize) -> strapper::NodeAdvertisement {
+        let hostname = format!("node-{}", n);
+        strapper::NodeAdvertisement {
+            hostname: hostname.clone(),
+            effective_hostname: hostname,
+            interfaces: (0..10)
+                .map(|i| strapper::Interface {
+                    name: format!("eth{}", i),
+                    index: i + 2,
+                    mac: format!("52:54:00:{:02x}:{:02x}:{:02x}", n >> 8 & 0xff, n & 0xff, i),
+                    ipaddr: vec![
+                        format!("10.{}.{}.{}", i, n >> 8 & 0xff, n & 0xff),
+                        format!("fd00:{:x}::{:x}", i, n),
+                    ],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Not a benchmark: the budgets are loose enough for a debug build on a
+    // busy machine, and only catch something going quadratic.
+    #[test]
+    fn thousands_of_nodes() {
+        let r = MemoryRegistry::new(limits(NODES));
+        let started = Instant::now();
+        for n in 0..NODES {
+            let adv = synthetic(n);
+            r.check(&adv).unwrap();
+            assert!(r.admit(&adv.effective_hostname, 1, 1));
+            r.insert(adv);
+        }
+        let stats = r.stats();
+        assert_eq!(stats.nodes, NODES);
+        assert_eq!(stats.interfaces, NODES * 10);
+        assert_eq!(stats.addresses, NODES * 20);
+        assert!(stats.approx_bytes > NODES * 1000, "{:?}", stats);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let started = Instant::now();
+        for n in (0..NODES).rev() {
+            let adv = r.get(&format!("node-{}", n)).unwrap();
+            assert_eq!(adv.interfaces.len(), 10);
+        }
+        for _ in 0..10 {
+            assert_eq!(r.list().len(), NODES);
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // Full: a new node is refused, a registered one can still change.
+        assert!(matches!(
+            r.check(&synthetic(NODES)),
+            Err(LimitExceeded::Full(_))
+        ));
+        let mut changed = synthetic(0);
+        changed.interfaces.truncate(1);
+        r.check(&changed).unwrap();
+        r.insert(changed);
+        assert_eq!(r.stats().interfaces, NODES * 10 - 9);
+
+        let before = r.stats().approx_bytes;
+        for n in 0..NODES / 2 {
+            assert!(r.remove(&format!("node-{}", n)).is_some());
+        }
+        let stats = r.stats();
+        assert_eq!(stats.nodes, NODES / 2);
+        assert_eq!(stats.addresses, NODES / 2 * 20);
+        assert!(stats.approx_bytes < before);
+        r.check(&synthetic(NODES)).unwrap();
+    }
+}
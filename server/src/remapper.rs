@@ -0,0 +1,1003 @@
+use anyhow::{anyhow, ensure, Result};
+use log::debug;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+use proto::strapper;
+
+use crate::namehash::Transform;
+use crate::netmap::NetMap;
+use crate::origin;
+
+const PLACEHOLDERS: [&str; 3] = ["{}", "{mac}", "{label}"];
+
+// Fills in an entry format: {} is the hostname, {mac} the interface's MAC
+// as bare hex digits, since DNS labels can't hold colons, {label} the
+// address's alias label minus the interface name (web for eth0:web), and
+// {label:key} the value of the node's label key. Formats using {mac} or
+// {label} produce nothing for addresses without one, and those using
+// {label:key} nothing for nodes without the label, or whose value wouldn't
+// make a DNS label.
+pub fn expand(
+    fmt: &str,
+    hostname: &str,
+    labels: &BTreeMap<String, String>,
+    iface: &strapper::Interface,
+    addr: &str,
+) -> Option<String> {
+    if fmt.contains("{mac}") && iface.mac.is_empty() {
+        return None;
+    }
+    let mut name = fmt.replace("{mac}", &mac_digits(&iface.mac));
+    for key in node_label_keys(fmt) {
+        let value = match labels.get(key) {
+            Some(value) if is_label(value) => value,
+            Some(value) => {
+                debug!(
+                    "not filling in {} for {}: its {} label '{}' isn't a DNS label",
+                    fmt, hostname, key, value
+                );
+                return None;
+            }
+            None => {
+                debug!("not filling in {} for {}: no {} label", fmt, hostname, key);
+                return None;
+            }
+        };
+        name = name.replace(&format!("{{label:{}}}", key), value);
+    }
+    if fmt.contains("{label}") {
+        let label = iface
+            .address_info
+            .iter()
+            .find(|i| i.address == addr && !i.label.is_empty())?
+            .label
+            .as_str();
+        let label = label
+            .strip_prefix(iface.name.as_str())
+            .and_then(|l| l.strip_prefix(':'))
+            .unwrap_or(label);
+        name = name.replace("{label}", label);
+    }
+    Some(name.replace("{}", hostname))
+}
+
+// The keys of the {label:key} placeholders in a format.
+fn node_label_keys(fmt: &str) -> impl Iterator<Item = &str> {
+    fmt.split("{label:")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(key, _)| key))
+}
+
+// Names without a trailing dot are relative to the zone, which is how a
+// remapper with a {net:N} zone names things in whichever zone it comes to.
+pub fn qualify(name: String, zone: &str) -> String {
+    if name.ends_with('.') {
+        name
+    } else {
+        format!("{}.{}", name, zone)
+    }
+}
+
+pub fn is_shared(fmt: &str) -> bool {
+    !fmt.contains("{}") && !fmt.contains("{mac}") && !fmt.contains("{label}")
+}
+
+// A format giving the node one name whatever the interface or address.
+pub fn is_node_name(fmt: &str) -> bool {
+    fmt.contains("{}") && !fmt.contains("{mac}") && !fmt.contains("{label}")
+}
+
+// What part of an address a remapper matches on.
+#[derive(Clone, Debug)]
+pub enum AddrMatch {
+    Net(ipnet::IpNet),
+    // The low `bits` of an IPv6 address, i.e. the interface identifier,
+    // which survives a delegated prefix being renumbered.
+    Suffix { suffix: u128, bits: u8 },
+}
+
+fn low_bits(bits: u8) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+impl AddrMatch {
+    pub fn contains(&self, a: &IpAddr) -> bool {
+        match (self, a) {
+            (AddrMatch::Net(net), a) => net.contains(a),
+            (AddrMatch::Suffix { suffix, bits }, IpAddr::V6(a)) => {
+                u128::from(*a) & low_bits(*bits) == *suffix
+            }
+            (AddrMatch::Suffix { .. }, IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl FromStr for AddrMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = match s.strip_prefix("suffix:") {
+            Some(s) => s,
+            None => {
+                let net =
+                    ipnet::IpNet::from_str(s).map_err(|e| anyhow!("invalid net '{}': {}", s, e));
+                return Ok(AddrMatch::Net(net?));
+            }
+        };
+        let (addr, bits) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("suffix match needs a length, e.g. suffix:::1/64"))?;
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|e| anyhow!("invalid suffix '{}': {}", addr, e))?;
+        let bits: u8 = bits
+            .parse()
+            .map_err(|_| anyhow!("invalid suffix length '{}'", bits))?;
+        ensure!(
+            (1..=128).contains(&bits),
+            "suffix length must be between 1 and 128"
+        );
+        let suffix = u128::from(addr);
+        ensure!(
+            suffix & !low_bits(bits) == 0,
+            "suffix {} has bits set above the low {}",
+            addr,
+            bits
+        );
+        Ok(AddrMatch::Suffix { suffix, bits })
+    }
+}
+
+impl fmt::Display for AddrMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddrMatch::Net(net) => write!(f, "{}", net),
+            AddrMatch::Suffix { suffix, bits } => {
+                write!(f, "suffix:{}/{}", Ipv6Addr::from(*suffix), bits)
+            }
+        }
+    }
+}
+
+// Which of a node's interfaces a remapper takes addresses from: all of them
+// unless it names a pattern for the interface name, a MAC prefix, a VLAN id,
+// or some of those.
+#[derive(Clone, Debug, Default)]
+pub struct IfaceMatch {
+    name: Option<regex::Regex>,
+    // Lowercase hex digits, without separators.
+    mac_prefix: Option<String>,
+    vlan: Option<u32>,
+}
+
+fn mac_digits(mac: &str) -> String {
+    mac.chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Whether the agent marked `addr` on `iface` as a VIP.
+pub fn is_vip(iface: &strapper::Interface, addr: &IpAddr) -> bool {
+    iface
+        .address_info
+        .iter()
+        .any(|i| i.vip && i.address.parse::<IpAddr>().ok().as_ref() == Some(addr))
+}
+
+impl IfaceMatch {
+    fn parse_mac_prefix(s: &str) -> Result<String> {
+        let digits = mac_digits(s);
+        ensure!(
+            !digits.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_hexdigit() || c == '-' || c == '.'),
+            "invalid MAC prefix '{}' (hex digits, optionally split by - or .)",
+            s
+        );
+        Ok(digits)
+    }
+
+    fn parse_vlan(s: &str) -> Result<u32> {
+        let id: u32 = s.parse().map_err(|_| anyhow!("invalid VLAN id '{}'", s))?;
+        ensure!(id <= 4094, "VLAN id {} is over 4094", id);
+        Ok(id)
+    }
+
+    pub fn matches(&self, iface: &strapper::Interface) -> bool {
+        self.name.as_ref().is_none_or(|r| r.is_match(&iface.name))
+            && self
+                .mac_prefix
+                .as_ref()
+                .is_none_or(|p| mac_digits(&iface.mac).starts_with(p.as_str()))
+            && self.vlan.is_none_or(|v| iface.vlan_id == Some(v))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Remapper {
+    // Labels a node must carry, with these values, to have any of its
+    // addresses taken.
+    pub labels: Vec<(String, String)>,
+    pub addrs: AddrMatch,
+    // With zone_net set, a template holding {net:<zone_net>}.
+    pub zone: String,
+    pub zone_net: Option<u8>,
+    pub entry_fmts: Vec<String>,
+    pub merge: bool,
+    // Also publish an A record for each AAAA, through --nat64-prefix, when
+    // the node has no IPv4 address of its own for the remapper.
+    pub synthesize_a: bool,
+    // Takes only the addresses the agent marked as VIPs, which no other
+    // remapper takes.
+    pub vip: bool,
+    pub prefer: origin::Preference,
+    pub ifaces: IfaceMatch,
+    // Applied to the node's name before it goes in for {}.
+    pub transform: Transform,
+    // The TTL of its records, in place of --record-ttl (or --vip-ttl).
+    pub ttl: Option<u32>,
+}
+
+impl Remapper {
+    pub fn matches(
+        &self,
+        node: &strapper::NodeAdvertisement,
+        iface: &strapper::Interface,
+        addr: &IpAddr,
+    ) -> bool {
+        self.addrs.contains(addr)
+            && self.ifaces.matches(iface)
+            && self.selects(node)
+            && is_vip(iface, addr) == self.vip
+    }
+
+    pub fn selects(&self, node: &strapper::NodeAdvertisement) -> bool {
+        self.labels
+            .iter()
+            .all(|(key, value)| node.labels.get(key) == Some(value))
+    }
+
+    // Leading bits every matching address shares, which {net:N} leaves out.
+    fn fixed_bits(&self) -> u8 {
+        match &self.addrs {
+            AddrMatch::Net(net) => net.prefix_len(),
+            AddrMatch::Suffix { .. } => 0,
+        }
+    }
+
+    // The zone an address this remapper matched goes in. {net:N} is the
+    // address's /N prefix through `map`, or without one the hex nibbles of
+    // that prefix past the remapper's own network: 2001:db8:12::/48 is 0012
+    // under 2001:db8::/32.
+    pub fn zone_for(&self, addr: &IpAddr, map: Option<&NetMap>) -> Result<String> {
+        let bits = match self.zone_net {
+            Some(bits) => bits,
+            None => return Ok(self.zone.clone()),
+        };
+        let label = match map {
+            Some(map) => map
+                .lookup(addr, bits)
+                .ok_or_else(|| anyhow!("no --zone-net-map entry for the /{} of {}", bits, addr))?
+                .to_owned(),
+            None => nibbles(addr, self.fixed_bits(), bits),
+        };
+        let zone = self.zone.replace(&net_placeholder(bits), &label);
+        check_zone(&zone)?;
+        Ok(zone)
+    }
+
+    // Every zone the template can come to under `map`, for checking up
+    // front; None when it can't be listed (no map, or no template).
+    pub fn map_zones(&self, map: Option<&NetMap>) -> Option<Vec<String>> {
+        let bits = self.zone_net?;
+        let zones = map?
+            .entries(bits)
+            .filter(|(net, _)| match &self.addrs {
+                AddrMatch::Net(n) => n.contains(&net.network()),
+                AddrMatch::Suffix { .. } => net.network().is_ipv6(),
+            })
+            .map(|(_, label)| self.zone.replace(&net_placeholder(bits), label))
+            .collect();
+        Some(zones)
+    }
+
+    // For GetConfig.
+    pub fn to_config(&self) -> strapper::RemapperConfig {
+        strapper::RemapperConfig {
+            spec: self.to_string(),
+            net: self.addrs.to_string(),
+            zone: self.zone.clone(),
+            entry_formats: self.entry_fmts.clone(),
+            merge: self.merge,
+            synthesize_a: self.synthesize_a,
+            vip: self.vip,
+            labels: self
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+            prefer: self.prefer.to_string(),
+            iface: self
+                .ifaces
+                .name
+                .as_ref()
+                .map(|r| r.as_str().to_owned())
+                .unwrap_or_default(),
+            mac_prefix: self.ifaces.mac_prefix.clone().unwrap_or_default(),
+            vlan: self.ifaces.vlan,
+            transform: self.transform.to_string(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+fn net_placeholder(bits: u8) -> String {
+    format!("{{net:{}}}", bits)
+}
+
+// Hex nibbles `from` (rounded down to a nibble) up to `to` bits into `addr`.
+fn nibbles(addr: &IpAddr, from: u8, to: u8) -> String {
+    let (value, width) = match addr {
+        IpAddr::V4(a) => (u128::from(u32::from(*a)), 32),
+        IpAddr::V6(a) => (u128::from(*a), 128),
+    };
+    (from / 4..to / 4)
+        .map(|i| format!("{:x}", (value >> (width - 4 * (u32::from(i) + 1))) & 0xf))
+        .collect()
+}
+
+// Splits on @, taking \@ for a literal @ and \\ for a backslash, and trims
+// each part.
+fn split_parts(s: &str) -> Result<Vec<String>> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '@' => parts.push(std::mem::take(&mut part)),
+            '\\' => match chars.next() {
+                Some(c @ ('@' | '\\')) => part.push(c),
+                Some(c) => return Err(anyhow!("unknown escape '\\{}' (only \\@ and \\\\)", c)),
+                None => return Err(anyhow!("trailing backslash")),
+            },
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+    Ok(parts.into_iter().map(|p| p.trim().to_owned()).collect())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('@', "\\@")
+}
+
+pub fn check_zone(zone: &str) -> Result<()> {
+    let name = zone
+        .strip_suffix('.')
+        .ok_or_else(|| anyhow!("zone '{}' must end with a dot", zone))?;
+    ensure!(!name.is_empty(), "zone can't be the root");
+    ensure!(
+        zone.len() <= 254,
+        "zone '{}' is longer than 253 characters",
+        zone
+    );
+    for label in name.split('.') {
+        ensure!(
+            is_label(label),
+            "zone '{}' has an invalid label '{}'",
+            zone,
+            label
+        );
+    }
+    Ok(())
+}
+
+pub fn is_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// The N of a {net:N} in a zone, which may hold one; checks the zone with
+// the placeholder standing in for a label.
+fn parse_zone(zone: &str, addrs: &AddrMatch) -> Result<Option<u8>> {
+    let start = match zone.find("{net:") {
+        Some(start) => start,
+        None => {
+            check_zone(zone)?;
+            return Ok(None);
+        }
+    };
+    let len = zone[start..]
+        .find('}')
+        .ok_or_else(|| anyhow!("unclosed '{{' in zone '{}'", zone))?;
+    let digits = &zone[start + "{net:".len()..start + len];
+    let bits: u8 = digits
+        .parse()
+        .map_err(|_| anyhow!("invalid prefix length '{}' in zone '{}'", digits, zone))?;
+    let (max, fixed) = match addrs {
+        AddrMatch::Net(net) => (net.max_prefix_len(), net.prefix_len()),
+        AddrMatch::Suffix { .. } => (128, 0),
+    };
+    ensure!(
+        bits > fixed && bits <= max,
+        "{{net:{}}} in zone '{}' must be longer than the remapper's /{} and at most /{}",
+        bits,
+        zone,
+        fixed,
+        max
+    );
+    let rest = zone.replacen(&net_placeholder(bits), "net", 1);
+    ensure!(
+        !rest.contains(['{', '}']),
+        "zone '{}' can only hold one {{net:N}} and nothing else in braces",
+        zone
+    );
+    check_zone(&rest)?;
+    Ok(Some(bits))
+}
+
+fn check_format(fmt: &str) -> Result<()> {
+    ensure!(!fmt.is_empty(), "empty entry format");
+    let mut rest = fmt;
+    while let Some(at) = rest.find(['{', '}']) {
+        ensure!(
+            rest[at..].starts_with('{'),
+            "unmatched '}}' in entry format '{}'",
+            fmt
+        );
+        let len = rest[at..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unclosed '{{' in entry format '{}'", fmt))?;
+        let placeholder = &rest[at..=at + len];
+        let node_label = placeholder
+            .strip_prefix("{label:")
+            .and_then(|p| p.strip_suffix('}'));
+        if let Some(key) = node_label {
+            proto::labels::check(key, "")
+                .map_err(|e| anyhow!("{} in entry format '{}'", e, fmt))?;
+        } else {
+            ensure!(
+                PLACEHOLDERS.contains(&placeholder),
+                "unknown placeholder '{}' in entry format '{}' (expected {{}}, {{mac}}, {{label}} or {{label:key}})",
+                placeholder,
+                fmt
+            );
+        }
+        rest = &rest[at + len + 1..];
+    }
+    Ok(())
+}
+
+fn parse(s: &str) -> Result<Remapper> {
+    // [merge:][synthesize_a:][vip:][label:<key>=<value>:...][prefer=<origin>:][iface=<regex>:]
+    // [mac=<prefix>:][vlan=<id>:][transform=<transform>:][ttl=<secs>:]net@zone@fmt[@fmt...];
+    // each extra format is another name for the same
+    // node, e.g. a short alias next to the fully qualified one. merge: keeps
+    // records strapper doesn't own in the rrsets. synthesize_a: adds the
+    // A records nat64::Prefix maps AAAA ones to. vip: takes the addresses
+    // agents mark as VIPs (--vip), which move between nodes; see vip::Holders.
+    // label: only takes nodes
+    // carrying the label with that value, and can be given more than once
+    // for nodes carrying all of them. prefer= publishes only
+    // static or only dynamic addresses where a node has them. iface= and mac=
+    // only take addresses from interfaces whose name matches or whose MAC
+    // starts with the prefix (written with - rather than :). vlan= only takes
+    // them from VLAN interfaces with that id, as the agent reports it, so
+    // never from interfaces it reported no VLAN for. transform= hashes the
+    // name {} stands for; see namehash::Transform. ttl= gives its records
+    // that TTL rather than --record-ttl (or --vip-ttl); --ttl-policy
+    // min-of-lifetime still shortens it. net can also be
+    // suffix:<addr>/<bits> to match the low bits of IPv6 addresses whatever
+    // prefix they're under. zone can hold {net:N}, for a zone per /N; see
+    // Remapper::zone_for, and formats without a trailing dot are relative to
+    // the zone. Write \@ for an @ inside a part.
+    let mut merge = false;
+    let mut synthesize_a = false;
+    let mut vip = false;
+    let mut labels = vec![];
+    let mut prefer = origin::Preference::Any;
+    let mut ifaces = IfaceMatch::default();
+    let mut transform = Transform::None;
+    let mut ttl = None;
+    let mut s = s.trim();
+    loop {
+        if let Some(rest) = s.strip_prefix("merge:") {
+            merge = true;
+            s = rest;
+            continue;
+        }
+        if let Some(rest) = s.strip_prefix("synthesize_a:") {
+            synthesize_a = true;
+            s = rest;
+            continue;
+        }
+        if let Some(rest) = s.strip_prefix("vip:") {
+            vip = true;
+            s = rest;
+            continue;
+        }
+        if let Some(rest) = s.strip_prefix("label:") {
+            let (label, rest) = rest
+                .split_once(':')
+                .ok_or_else(|| anyhow!("label: needs a ':' after its key=value"))?;
+            labels.push(proto::labels::parse(label).map_err(|e| anyhow!(e))?);
+            s = rest;
+            continue;
+        }
+        let (key, rest) = match s.split_once('=') {
+            Some((key, rest))
+                if ["prefer", "iface", "mac", "vlan", "transform", "ttl"].contains(&key) =>
+            {
+                (key, rest)
+            }
+            _ => break,
+        };
+        let (value, rest) = rest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("{}= needs a ':' after it", key))?;
+        match key {
+            "prefer" => prefer = value.parse()?,
+            "iface" => {
+                ifaces.name = Some(
+                    regex::Regex::new(value)
+                        .map_err(|e| anyhow!("invalid iface= pattern: {}", e))?,
+                )
+            }
+            "mac" => ifaces.mac_prefix = Some(IfaceMatch::parse_mac_prefix(value)?),
+            "transform" => transform = value.parse()?,
+            "ttl" => {
+                let secs: u32 = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid ttl '{}'", value))?;
+                ensure!(secs > 0, "ttl must be above 0");
+                ttl = Some(secs);
+            }
+            _ => ifaces.vlan = Some(IfaceMatch::parse_vlan(value)?),
+        }
+        s = rest;
+    }
+
+    let mut parts = split_parts(s)?.into_iter();
+    let (net, zone, entry_fmts) = match (parts.next(), parts.next()) {
+        (Some(net), Some(zone)) => (net, zone, parts.collect::<Vec<_>>()),
+        _ => (String::new(), String::new(), vec![]),
+    };
+    ensure!(
+        !entry_fmts.is_empty(),
+        "expected [options:]net@zone@fmt[@fmt...]"
+    );
+    let addrs: AddrMatch = net.parse()?;
+    ensure!(
+        !synthesize_a || !matches!(&addrs, AddrMatch::Net(ipnet::IpNet::V4(_))),
+        "synthesize_a: needs an IPv6 net to take AAAA records from"
+    );
+    ensure!(
+        !(merge && vip),
+        "merge: and vip: don't go together; a VIP's rrset is only ever one node's"
+    );
+    let zone_net = parse_zone(&zone, &addrs)?;
+    for fmt in entry_fmts.iter() {
+        check_format(fmt)?;
+    }
+
+    Ok(Remapper {
+        labels,
+        addrs,
+        zone,
+        zone_net,
+        entry_fmts,
+        merge,
+        synthesize_a,
+        vip,
+        prefer,
+        ifaces,
+        transform,
+        ttl,
+    })
+}
+
+impl FromStr for Remapper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s).map_err(|e| anyhow!("invalid remapper '{}': {:#}", s, e))
+    }
+}
+
+// The remapper as it would be written on the command line, options in a
+// fixed order.
+impl fmt::Display for Remapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.merge {
+            write!(f, "merge:")?;
+        }
+        if self.synthesize_a {
+            write!(f, "synthesize_a:")?;
+        }
+        if self.vip {
+            write!(f, "vip:")?;
+        }
+        for (key, value) in self.labels.iter() {
+            write!(f, "label:{}={}:", key, value)?;
+        }
+        if self.prefer != origin::Preference::Any {
+            write!(f, "prefer={}:", self.prefer)?;
+        }
+        if let Some(name) = &self.ifaces.name {
+            write!(f, "iface={}:", name.as_str())?;
+        }
+        if let Some(prefix) = &self.ifaces.mac_prefix {
+            write!(f, "mac={}:", prefix)?;
+        }
+        if let Some(vlan) = self.ifaces.vlan {
+            write!(f, "vlan={}:", vlan)?;
+        }
+        if self.transform != Transform::None {
+            write!(f, "transform={}:", self.transform)?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, "ttl={}:", ttl)?;
+        }
+        write!(
+            f,
+            "{}@{}",
+            escape(&self.addrs.to_string()),
+            escape(&self.zone)
+        )?;
+        for fmt in self.entry_fmts.iter() {
+            write!(f, "@{}", escape(fmt))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use proto::strapper;
+
+    use super::{AddrMatch, Remapper};
+    use crate::netmap::NetMap;
+
+    #[test]
+    fn ttl() {
+        let r: Remapper = "merge:ttl=300:10.0.0.0/8@example.com.@{}".parse().unwrap();
+        assert_eq!(r.ttl, Some(300));
+        assert!(r.merge);
+        assert_eq!(r.to_string(), "merge:ttl=300:10.0.0.0/8@example.com.@{}");
+        assert_eq!(r.to_config().ttl, Some(300));
+
+        let r: Remapper = "10.0.0.0/8@example.com.@{}".parse().unwrap();
+        assert_eq!(r.ttl, None);
+        assert_eq!(r.to_config().ttl, None);
+
+        for bad in &["ttl=0:", "ttl=-1:", "ttl=1h:", "ttl=300"] {
+            let spec = format!("{}10.0.0.0/8@example.com.@{{}}", bad);
+            assert!(spec.parse::<Remapper>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn suffix() {
+        let r: Remapper = "ttl=300:suffix:::1:0:0:5/64@example.com.@{}"
+            .parse()
+            .unwrap();
+        match r.addrs {
+            AddrMatch::Suffix { suffix, bits } => {
+                assert_eq!(suffix, 0x0001_0000_0000_0005);
+                assert_eq!(bits, 64);
+            }
+            AddrMatch::Net(net) => panic!("parsed as net {}", net),
+        }
+        assert_eq!(r.ttl, Some(300));
+        assert_eq!(r.to_string(), "ttl=300:suffix:::1:0:0:5/64@example.com.@{}");
+        let again: Remapper = r.to_string().parse().unwrap();
+        assert_eq!(again.to_string(), r.to_string());
+
+        let contains = |a: &str| r.addrs.contains(&a.parse::<IpAddr>().unwrap());
+        assert!(contains("2001:db8:1:2:1::5"));
+        assert!(contains("2001:db8:ffff:ff00:1::5"));
+        assert!(contains("fd00::1:0:0:5"));
+        assert!(!contains("2001:db8:1:2:1::6"));
+        assert!(!contains("2001:db8:1:2:2::5"));
+        assert!(!contains("10.0.0.5"));
+
+        let whole: Remapper = "suffix:::1/128@example.com.@{}".parse().unwrap();
+        assert!(whole.addrs.contains(&"::1".parse().unwrap()));
+        assert!(!whole.addrs.contains(&"2001:db8::1".parse().unwrap()));
+
+        for bad in &[
+            "suffix:::1",
+            "suffix:::1/0",
+            "suffix:::1/129",
+            "suffix:::1/x",
+            "suffix:2001:db8::1/64",
+            "suffix:10.0.0.1/32",
+            "suffix:/64",
+        ] {
+            let spec = format!("{}@example.com.@{{}}", bad);
+            assert!(spec.parse::<Remapper>().is_err(), "{}", spec);
+        }
+    }
+
+    // A management network and a data network, both global: the net alone
+    // can't tell them apart, and the interface alone takes too much.
+    #[test]
+    fn interface_constraints() {
+        let node = strapper::NodeAdvertisement {
+            hostname: "node".to_owned(),
+            ..Default::default()
+        };
+        let iface = |name: &str, mac: &str| strapper::Interface {
+            name: name.to_owned(),
+            mac: mac.to_owned(),
+            ..Default::default()
+        };
+        let mgmt = iface("mgmt0", "02:aa:bb:00:00:01");
+        let data = iface("eth0", "02:cc:dd:00:00:01");
+        let matches = |spec: &str, iface: &strapper::Interface, addr: &str| {
+            let r: Remapper = spec.parse().unwrap();
+            r.matches(&node, iface, &addr.parse().unwrap())
+        };
+
+        let by_name = "iface=^mgmt:2606:4700::/32@mgmt.example.com.@{}";
+        assert!(matches(by_name, &mgmt, "2606:4700::1"));
+        // The net, but not the interface.
+        assert!(!matches(by_name, &data, "2606:4700::2"));
+        // The interface, but not the net.
+        assert!(!matches(by_name, &mgmt, "2a00:1450::1"));
+
+        let by_mac = "mac=02-AA.bb:10.0.0.0/8@mgmt.example.com.@{}";
+        assert!(matches(by_mac, &mgmt, "10.0.0.1"));
+        assert!(!matches(by_mac, &data, "10.0.0.2"));
+        assert!(!matches(by_mac, &mgmt, "192.168.0.1"));
+        assert!(!matches(by_mac, &iface("mgmt0", ""), "10.0.0.1"));
+
+        let both = "iface=^mgmt:mac=02aabb:10.0.0.0/8@mgmt.example.com.@{}";
+        assert!(matches(both, &mgmt, "10.0.0.1"));
+        assert!(!matches(
+            both,
+            &iface("mgmt1", "02:cc:dd:00:00:02"),
+            "10.0.0.1"
+        ));
+        assert!(!matches(
+            both,
+            &iface("eth1", "02:aa:bb:00:00:02"),
+            "10.0.0.1"
+        ));
+
+        // Neither constraint: every interface.
+        assert!(matches("10.0.0.0/8@example.com.@{}", &data, "10.0.0.2"));
+
+        let r: Remapper = both.parse().unwrap();
+        assert_eq!(
+            r.to_string(),
+            "iface=^mgmt:mac=02aabb:10.0.0.0/8@mgmt.example.com.@{}"
+        );
+        for bad in &["iface=(:", "mac=:", "mac=zz:", "mac=02_aa:", "iface=mgmt0"] {
+            let spec = format!("{}10.0.0.0/8@example.com.@{{}}", bad);
+            assert!(spec.parse::<Remapper>().is_err(), "{}", spec);
+        }
+    }
+
+    // Each valid spec and how it's written back, options in their fixed
+    // order, whitespace gone and escapes where they're needed.
+    #[test]
+    fn valid() {
+        let cases = [
+            ("10.0.0.0/8@example.com.@{}", "10.0.0.0/8@example.com.@{}"),
+            (
+                " 10.0.0.0/8 @ example.com. @ {}\n",
+                "10.0.0.0/8@example.com.@{}",
+            ),
+            ("10.0.0.0/8@Example.COM.@{}", "10.0.0.0/8@Example.COM.@{}"),
+            (
+                "10.0.0.0/8@_srv.example.com.@{}",
+                "10.0.0.0/8@_srv.example.com.@{}",
+            ),
+            (
+                "fd00::/8@example.com.@{}@{}-{mac}",
+                "fd00::/8@example.com.@{}@{}-{mac}",
+            ),
+            (
+                "10.0.0.0/8@example.com.@{}.{label}",
+                "10.0.0.0/8@example.com.@{}.{label}",
+            ),
+            (
+                "10.0.0.0/8@example.com.@{label:role}",
+                "10.0.0.0/8@example.com.@{label:role}",
+            ),
+            ("10.0.0.0/8@example.com.@www", "10.0.0.0/8@example.com.@www"),
+            (
+                "10.0.0.0/8@example.com.@{}.example.net.",
+                "10.0.0.0/8@example.com.@{}.example.net.",
+            ),
+            (
+                r"10.0.0.0/8@example.com.@a\@{}",
+                r"10.0.0.0/8@example.com.@a\@{}",
+            ),
+            (
+                r"10.0.0.0/8@example.com.@a\\{}",
+                r"10.0.0.0/8@example.com.@a\\{}",
+            ),
+            (
+                "10.0.0.0/8@{net:16}.example.com.@{}",
+                "10.0.0.0/8@{net:16}.example.com.@{}",
+            ),
+            (
+                "fd00::/8@{net:64}.example.com.@{}",
+                "fd00::/8@{net:64}.example.com.@{}",
+            ),
+            (
+                "suffix:::1/64@{net:48}.example.com.@{}",
+                "suffix:::1/64@{net:48}.example.com.@{}",
+            ),
+            (
+                "ttl=60:vip:label:role=web:10.0.0.0/8@example.com.@vip",
+                "vip:label:role=web:ttl=60:10.0.0.0/8@example.com.@vip",
+            ),
+            (
+                "transform=hash:prefer=static:merge:fd00::/8@example.com.@{}",
+                "merge:prefer=static:transform=hash:fd00::/8@example.com.@{}",
+            ),
+            (
+                "synthesize_a:vlan=100:iface=^eth:fd00::/8@example.com.@{}",
+                "synthesize_a:iface=^eth:vlan=100:fd00::/8@example.com.@{}",
+            ),
+            (
+                "prefer=any:10.0.0.0/8@example.com.@{}",
+                "10.0.0.0/8@example.com.@{}",
+            ),
+        ];
+        for (spec, written) in cases.iter() {
+            let r: Remapper = spec
+                .parse()
+                .unwrap_or_else(|e| panic!("{:?}: {:#}", spec, e));
+            assert_eq!(r.to_string(), *written, "{:?}", spec);
+            let again: Remapper = r.to_string().parse().unwrap();
+            assert_eq!(again.to_string(), *written);
+        }
+
+        let r: Remapper = r"10.0.0.0/8@example.com.@a\@{}@b\\c".parse().unwrap();
+        assert_eq!(r.entry_fmts, ["a@{}", r"b\c"]);
+    }
+
+    // Each invalid spec and what its error has to say, besides the spec
+    // itself.
+    #[test]
+    fn invalid() {
+        let cases = [
+            ("", "expected [options:]net@zone@fmt"),
+            ("10.0.0.0/8", "expected [options:]net@zone@fmt"),
+            ("10.0.0.0/8@example.com.", "expected [options:]net@zone@fmt"),
+            ("10.0.0.0/8@example.com.@", "empty entry format"),
+            ("10.0.0.0/8@example.com.@{}@", "empty entry format"),
+            ("10.0.0.0@example.com.@{}", "invalid net"),
+            ("10.0.0.0/33@example.com.@{}", "invalid net"),
+            ("example.com.@10.0.0.0/8@{}", "invalid net"),
+            ("10.0.0.0/8@example.com@{}", "must end with a dot"),
+            ("10.0.0.0/8@.@{}", "can't be the root"),
+            ("10.0.0.0/8@example..com.@{}", "invalid label ''"),
+            ("10.0.0.0/8@exa mple.com.@{}", "invalid label 'exa mple'"),
+            ("10.0.0.0/8@ex*ample.com.@{}", "invalid label"),
+            (
+                "10.0.0.0/8@aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.com.@{}",
+                "invalid label",
+            ),
+            ("10.0.0.0/8@example.com.@{host}", "unknown placeholder '{host}'"),
+            ("10.0.0.0/8@example.com.@{", "unclosed '{'"),
+            ("10.0.0.0/8@example.com.@}{}", "unmatched '}'"),
+            ("10.0.0.0/8@example.com.@{label:Bad Key}", "in entry format"),
+            (r"10.0.0.0/8@example.com.@a\x", r"unknown escape '\x'"),
+            ("10.0.0.0/8@example.com.@a\\", "trailing backslash"),
+            ("10.0.0.0/8@{net:8}.example.com.@{}", "must be longer than the remapper's /8"),
+            ("10.0.0.0/8@{net:33}.example.com.@{}", "at most /32"),
+            ("10.0.0.0/8@{net:x}.example.com.@{}", "invalid prefix length 'x'"),
+            ("10.0.0.0/8@{net:16.example.com.@{}", "unclosed '{'"),
+            ("10.0.0.0/8@{net:16}.{net:24}.example.com.@{}", "only hold one {net:N}"),
+            ("merge:vip:10.0.0.0/8@example.com.@{}", "don't go together"),
+            ("synthesize_a:10.0.0.0/8@example.com.@{}", "needs an IPv6 net"),
+            ("prefer=newest:10.0.0.0/8@example.com.@{}", "unknown address preference"),
+            ("transform=rot13:10.0.0.0/8@example.com.@{}", "rot13"),
+            ("vlan=4095:10.0.0.0/8@example.com.@{}", "over 4094"),
+            ("label:role:10.0.0.0/8@example.com.@{}", "role"),
+            ("label:role=web", "label: needs a ':'"),
+            ("ttl=300", "ttl= needs a ':'"),
+        ];
+        for (spec, reason) in cases.iter() {
+            let e = match spec.parse::<Remapper>() {
+                Ok(r) => panic!("{:?} parsed as {}", spec, r),
+                Err(e) => format!("{:#}", e),
+            };
+            assert!(
+                e.starts_with(&format!("invalid remapper '{}'", spec)),
+                "{:?}: {}",
+                spec,
+                e
+            );
+            assert!(e.contains(reason), "{:?}: {}", spec, e);
+        }
+    }
+
+    // {net:N} as hex nibbles past the remapper's own net, or through the map.
+    #[test]
+    fn zone_template() {
+        let zone = |spec: &str, addr: &str, map: Option<&NetMap>| {
+            let r: Remapper = spec.parse().unwrap();
+            r.zone_for(&addr.parse().unwrap(), map)
+        };
+
+        let site = "2001:db8::/32@site-{net:48}.infra.example.@{}";
+        let r: Remapper = site.parse().unwrap();
+        assert_eq!(r.zone_net, Some(48));
+        assert_eq!(
+            zone(site, "2001:db8:12::1", None).unwrap(),
+            "site-0012.infra.example."
+        );
+        assert_eq!(
+            zone(site, "2001:db8:abcd:ffff::1", None).unwrap(),
+            "site-abcd.infra.example."
+        );
+        assert_eq!(
+            zone(
+                "2001:db8::/32@{net:64}.example.@{}",
+                "2001:db8:12:3456::1",
+                None
+            )
+            .unwrap(),
+            "00123456.example."
+        );
+        assert_eq!(
+            zone("10.0.0.0/8@{net:16}.example.@{}", "10.5.6.7", None).unwrap(),
+            "05.example."
+        );
+        // Rounded down to a whole nibble.
+        assert_eq!(
+            zone("10.0.0.0/8@{net:14}.example.@{}", "10.255.6.7", None).unwrap(),
+            "f.example."
+        );
+        assert_eq!(
+            zone("10.0.0.0/8@static.example.@{}", "10.5.6.7", None).unwrap(),
+            "static.example."
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zones");
+        std::fs::write(
+            &path,
+            "2001:db8:12::/48 london\n2001:db8:13::/48 paris\n2001:db9:1::/48 elsewhere\n",
+        )
+        .unwrap();
+        let map = NetMap::load(&path).unwrap();
+        assert_eq!(
+            zone(site, "2001:db8:12::1", Some(&map)).unwrap(),
+            "site-london.infra.example."
+        );
+        let e = zone(site, "2001:db8:14::1", Some(&map)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no --zone-net-map entry for the /48 of 2001:db8:14::1"
+        );
+        let mut zones = r.map_zones(Some(&map)).unwrap();
+        zones.sort();
+        assert_eq!(
+            zones,
+            ["site-london.infra.example.", "site-paris.infra.example."]
+        );
+        assert_eq!(r.map_zones(None), None);
+        let fixed: Remapper = "2001:db8::/32@infra.example.@{}".parse().unwrap();
+        assert_eq!(fixed.map_zones(Some(&map)), None);
+    }
+}
@@ -0,0 +1,156 @@
+use tonic::metadata::MetadataMap;
+
+// Same key and format the client library uses.
+pub const METADATA_KEY: &str = "x-request-id";
+
+pub fn mint() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        u16::from_be_bytes([b[4], b[5]]),
+        u16::from_be_bytes([b[6], b[7]]),
+        u16::from_be_bytes([b[8], b[9]]),
+        u64::from_be_bytes([0, 0, b[10], b[11], b[12], b[13], b[14], b[15]])
+    )
+}
+
+// Ids end up in every log line for the request, so anything that isn't a
+// short token is replaced rather than trusted.
+pub fn accept(id: Option<&str>) -> String {
+    match id {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            id.to_owned()
+        }
+        _ => mint(),
+    }
+}
+
+pub fn from_metadata(metadata: &MetadataMap) -> String {
+    accept(metadata.get(METADATA_KEY).and_then(|v| v.to_str().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::strapper::{self, node_state_service_client::NodeStateServiceClient};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use structopt::StructOpt;
+    use tonic::transport::Channel;
+
+    use super::{accept, METADATA_KEY};
+
+    fn is_uuid(id: &str) -> bool {
+        let parts: Vec<_> = id.split('-').map(str::len).collect();
+        parts == [8, 4, 4, 4, 12] && id.as_bytes()[14] == b'4'
+    }
+
+    #[test]
+    fn accepted_or_minted() {
+        assert_eq!(accept(Some("agent-42_a")), "agent-42_a");
+        for id in [None, Some(""), Some("has space"), Some("new\nline")] {
+            assert!(is_uuid(&accept(id)), "{:?}", id);
+        }
+        assert!(is_uuid(&accept(Some(&"x".repeat(65)))));
+    }
+
+    async fn serve() -> SocketAddr {
+        let opt = crate::Opt::from_iter_safe(&["server"]).unwrap();
+        let service = crate::grpc_service(crate::build_server(&opt).await.unwrap());
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(hyper::service::make_service_fn(move |_| {
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(service) }
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn node(hostname: &str) -> strapper::NodeAdvertisement {
+        strapper::NodeAdvertisement {
+            hostname: hostname.to_owned(),
+            interfaces: vec![strapper::Interface {
+                name: "eth0".to_owned(),
+                index: 2,
+                ipaddr: vec!["10.0.0.1".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn advertise(
+        addr: SocketAddr,
+        hostname: &str,
+        id: Option<&str>,
+    ) -> strapper::AdvertiseResult {
+        let mut client: NodeStateServiceClient<Channel> =
+            NodeStateServiceClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+        let mut request = tonic::Request::new(node(hostname));
+        if let Some(id) = id {
+            request
+                .metadata_mut()
+                .insert(METADATA_KEY, id.parse().unwrap());
+        }
+        client.advertise(request).await.unwrap().into_inner()
+    }
+
+    async fn history_ids(addr: SocketAddr, hostname: &str) -> Vec<String> {
+        let mut c = client::StrapperClient::connect(format!("http://{}", addr).parse().unwrap())
+            .await
+            .unwrap();
+        c.history(strapper::HistoryRequest {
+            hostname: hostname.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|e| e.request_id)
+        .collect()
+    }
+
+    // From the agent's metadata to the result and the history, with the
+    // server making one up for agents that don't send it.
+    #[tokio::test]
+    async fn propagated() {
+        let addr = serve().await;
+
+        let result = advertise(addr, "given", Some("agent-chosen-1")).await;
+        assert_eq!(result.request_id, "agent-chosen-1");
+        let ids = history_ids(addr, "given").await;
+        assert!(!ids.is_empty());
+        assert!(ids.iter().all(|id| id == "agent-chosen-1"), "{:?}", ids);
+
+        let result = advertise(addr, "old-agent", None).await;
+        assert!(is_uuid(&result.request_id), "{}", result.request_id);
+        assert_eq!(history_ids(addr, "old-agent").await, [result.request_id]);
+
+        let result = advertise(addr, "garbled", Some("not an id")).await;
+        assert!(is_uuid(&result.request_id), "{}", result.request_id);
+    }
+
+    // The client library's id is the one the server goes by.
+    #[tokio::test]
+    async fn from_the_client() {
+        let addr = serve().await;
+        let mut c = client::StrapperClient::connect(format!("http://{}", addr).parse().unwrap())
+            .await
+            .unwrap();
+        let result = c.advertise(&node("client")).await.unwrap();
+        assert!(is_uuid(&result.request_id), "{}", result.request_id);
+        assert_eq!(history_ids(addr, "client").await, [result.request_id]);
+    }
+}
@@ -0,0 +1,910 @@
+use anyhow::Result;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+
+use proto::{notice, strapper};
+
+use crate::mux::Service;
+use crate::{gate, history, metrics, peer, request_id, sd, NSServer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonInterface {
+    name: String,
+    #[serde(default)]
+    mac: String,
+    #[serde(default)]
+    ipaddr: Vec<String>,
+    index: u32,
+    #[serde(default)]
+    mtu: u32,
+    #[serde(default)]
+    oper_state: i32,
+    #[serde(default)]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vlan_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_index: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wireguard_public_key: Option<String>,
+    #[serde(default)]
+    address_info: Vec<JsonAddressInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonAddressInfo {
+    address: String,
+    #[serde(default = "infinite_lifetime")]
+    preferred_lifetime: u32,
+    #[serde(default = "infinite_lifetime")]
+    valid_lifetime: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    label: String,
+    // static or dynamic; left out when the agent didn't say.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    origin: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    vip: bool,
+}
+
+fn origin_from_json(origin: &str) -> strapper::AddressOrigin {
+    match origin {
+        "static" => strapper::AddressOrigin::Static,
+        "dynamic" => strapper::AddressOrigin::Dynamic,
+        _ => strapper::AddressOrigin::Unknown,
+    }
+}
+
+fn origin_to_json(origin: i32) -> String {
+    match strapper::AddressOrigin::from_i32(origin) {
+        Some(strapper::AddressOrigin::Static) => "static",
+        Some(strapper::AddressOrigin::Dynamic) => "dynamic",
+        _ => "",
+    }
+    .to_owned()
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn infinite_lifetime() -> u32 {
+    u32::MAX
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRoute {
+    gateway: String,
+    #[serde(default)]
+    metric: u32,
+    index: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonService {
+    name: String,
+    protocol: String,
+    port: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonNodeAdvertisement {
+    hostname: String,
+    #[serde(default)]
+    interfaces: Vec<JsonInterface>,
+    #[serde(default)]
+    default_routes: Vec<JsonRoute>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    agent_version: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    agent_start_time: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    sequence: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    observed_ms: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    fqdn: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    services: Vec<JsonService>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    disabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_override: Option<u32>,
+    // Filled in by the server; ignored on advertise.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    effective_hostname: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    source_address: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    self_advertised: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonDeregister {
+    hostname: String,
+}
+
+#[derive(Serialize)]
+struct JsonOwnedRecord {
+    content: String,
+    owner: String,
+}
+
+#[derive(Serialize)]
+struct JsonSharedRrset {
+    zone: String,
+    name: String,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    records: Vec<JsonOwnedRecord>,
+}
+
+#[derive(Serialize)]
+struct JsonQuarantinedRecord {
+    zone: String,
+    name: String,
+    #[serde(rename = "type")]
+    type_: String,
+    failures: u32,
+    last_error: String,
+}
+
+#[derive(Serialize)]
+struct JsonServerStatus {
+    version: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    git_hash: String,
+    uptime_secs: u64,
+    nodes: u32,
+    remappers: u32,
+    pdns_endpoint: String,
+    advertise_succeeded: u64,
+    advertise_failed: u64,
+    pdns_applied: u64,
+    pdns_failures: u64,
+    write_pause: JsonWritePauseState,
+    role: &'static str,
+    missing_zones: Vec<String>,
+    // Left out without --lock-file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    writer_lock: Option<&'static str>,
+    frozen: Vec<JsonFrozenNode>,
+    pdns_gate: &'static str,
+    deletion_guard: JsonDeletionGuard,
+}
+
+#[derive(Serialize)]
+struct JsonDeletionGuard {
+    active: bool,
+    remaining_secs: u64,
+    nodes_seen: u32,
+    min_nodes: u32,
+    held_deletes: u32,
+}
+
+#[derive(Serialize)]
+struct JsonFrozenNode {
+    hostname: String,
+    // Left out for a freeze that lasts until it's lifted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until_ms: Option<u64>,
+}
+
+impl From<strapper::ServerStatus> for JsonServerStatus {
+    fn from(s: strapper::ServerStatus) -> Self {
+        JsonServerStatus {
+            version: s.version,
+            git_hash: s.git_hash,
+            uptime_secs: s.uptime_secs,
+            nodes: s.nodes,
+            remappers: s.remappers,
+            pdns_endpoint: s.pdns_endpoint,
+            advertise_succeeded: s.advertise_succeeded,
+            advertise_failed: s.advertise_failed,
+            pdns_applied: s.pdns_applied,
+            pdns_failures: s.pdns_failures,
+            write_pause: s.write_pause.unwrap_or_default().into(),
+            role: match peer::Role::from_proto(s.role) {
+                Some(peer::Role::Standby) => "standby",
+                _ => "primary",
+            },
+            missing_zones: s.missing_zones,
+            writer_lock: match strapper::WriterLock::from_i32(s.writer_lock) {
+                Some(strapper::WriterLock::Held) => Some("held"),
+                Some(strapper::WriterLock::Waiting) => Some("waiting"),
+                Some(strapper::WriterLock::Lost) => Some("lost"),
+                _ => None,
+            },
+            frozen: s
+                .frozen
+                .into_iter()
+                .map(|f| JsonFrozenNode {
+                    hostname: f.hostname,
+                    until_ms: Some(f.until_ms).filter(|&ms| ms != 0),
+                })
+                .collect(),
+            pdns_gate: match strapper::PdnsGate::from_i32(s.pdns_gate) {
+                Some(strapper::PdnsGate::Queueing) => "queueing",
+                Some(strapper::PdnsGate::Degraded) => "degraded",
+                _ => "open",
+            },
+            deletion_guard: {
+                let g = s.deletion_guard.unwrap_or_default();
+                JsonDeletionGuard {
+                    active: g.active,
+                    remaining_secs: g.remaining_secs,
+                    nodes_seen: g.nodes_seen,
+                    min_nodes: g.min_nodes,
+                    held_deletes: g.held_deletes,
+                }
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRole {
+    role: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonWritePause {
+    #[serde(default)]
+    zone: String,
+    paused: bool,
+}
+
+#[derive(Serialize)]
+struct JsonWritePauseState {
+    all: bool,
+    zones: Vec<String>,
+    held_writes: u64,
+}
+
+impl From<strapper::WritePauseState> for JsonWritePauseState {
+    fn from(s: strapper::WritePauseState) -> Self {
+        JsonWritePauseState {
+            all: s.all,
+            zones: s.zones,
+            held_writes: s.held_writes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+}
+
+impl From<JsonNodeAdvertisement> for strapper::NodeAdvertisement {
+    fn from(a: JsonNodeAdvertisement) -> Self {
+        strapper::NodeAdvertisement {
+            hostname: a.hostname,
+            interfaces: a
+                .interfaces
+                .into_iter()
+                .map(|i| strapper::Interface {
+                    name: i.name,
+                    mac: i.mac,
+                    ipaddr: i.ipaddr,
+                    index: i.index,
+                    mtu: i.mtu,
+                    oper_state: i.oper_state,
+                    kind: i.kind,
+                    vlan_id: i.vlan_id,
+                    parent_index: i.parent_index,
+                    wireguard_public_key: i.wireguard_public_key,
+                    address_info: i
+                        .address_info
+                        .into_iter()
+                        .map(|a| strapper::AddressInfo {
+                            address: a.address,
+                            preferred_lifetime: a.preferred_lifetime,
+                            valid_lifetime: a.valid_lifetime,
+                            label: a.label,
+                            origin: origin_from_json(&a.origin) as i32,
+                            vip: a.vip,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            default_routes: a
+                .default_routes
+                .into_iter()
+                .map(|r| strapper::Route {
+                    gateway: r.gateway,
+                    metric: r.metric,
+                    index: r.index,
+                })
+                .collect(),
+            agent_version: a.agent_version,
+            agent_start_time: a.agent_start_time,
+            sequence: a.sequence,
+            observed_ms: a.observed_ms,
+            fqdn: a.fqdn,
+            services: a
+                .services
+                .into_iter()
+                .map(|s| strapper::Service {
+                    name: s.name,
+                    protocol: s.protocol,
+                    port: s.port,
+                })
+                .collect(),
+            labels: a.labels,
+            disabled: a.disabled,
+            ttl_override: a.ttl_override,
+            effective_hostname: String::new(),
+            source_address: String::new(),
+            self_advertised: false,
+        }
+    }
+}
+
+impl From<strapper::NodeAdvertisement> for JsonNodeAdvertisement {
+    fn from(a: strapper::NodeAdvertisement) -> Self {
+        JsonNodeAdvertisement {
+            hostname: a.hostname,
+            interfaces: a
+                .interfaces
+                .into_iter()
+                .map(|i| JsonInterface {
+                    name: i.name,
+                    mac: i.mac,
+                    ipaddr: i.ipaddr,
+                    index: i.index,
+                    mtu: i.mtu,
+                    oper_state: i.oper_state,
+                    kind: i.kind,
+                    vlan_id: i.vlan_id,
+                    parent_index: i.parent_index,
+                    wireguard_public_key: i.wireguard_public_key,
+                    address_info: i
+                        .address_info
+                        .into_iter()
+                        .map(|a| JsonAddressInfo {
+                            address: a.address,
+                            preferred_lifetime: a.preferred_lifetime,
+                            valid_lifetime: a.valid_lifetime,
+                            label: a.label,
+                            origin: origin_to_json(a.origin),
+                            vip: a.vip,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            default_routes: a
+                .default_routes
+                .into_iter()
+                .map(|r| JsonRoute {
+                    gateway: r.gateway,
+                    metric: r.metric,
+                    index: r.index,
+                })
+                .collect(),
+
+            agent_version: a.agent_version,
+            agent_start_time: a.agent_start_time,
+            sequence: a.sequence,
+            observed_ms: a.observed_ms,
+            fqdn: a.fqdn,
+            services: a
+                .services
+                .into_iter()
+                .map(|s| JsonService {
+                    name: s.name,
+                    protocol: s.protocol,
+                    port: s.port,
+                })
+                .collect(),
+            labels: a.labels,
+            disabled: a.disabled,
+            ttl_override: a.ttl_override,
+            effective_hostname: a.effective_hostname,
+            source_address: a.source_address,
+            self_advertised: a.self_advertised,
+        }
+    }
+}
+
+fn plan_json(plan: &strapper::AdvertisePlan) -> serde_json::Value {
+    serde_json::json!({
+        "changes": plan.changes.iter().map(|c| serde_json::json!({
+            "action": match strapper::ChangeAction::from_i32(c.action) {
+                Some(strapper::ChangeAction::ChangeCreate) => "create",
+                Some(strapper::ChangeAction::ChangeReplace) => "replace",
+                _ => "delete",
+            },
+            "zone": c.zone,
+            "name": c.name,
+            "type": c.r#type,
+            "ttl": c.ttl,
+            "before": c.before,
+            "after": c.after,
+        })).collect::<Vec<_>>(),
+        "conflicts": plan.conflicts.iter().map(|c| serde_json::json!({
+            "zone": c.zone,
+            "name": c.name,
+            "type": c.r#type,
+            "owner": c.owner,
+        })).collect::<Vec<_>>(),
+        "skipped_records": plan.skipped_records.iter().map(|r| serde_json::json!({
+            "zone": r.zone,
+            "name": r.name,
+            "type": r.r#type,
+            "reason": r.reason,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+// A field at a time, as a single json! with every counter in it is past
+// the macro's recursion limit.
+fn metrics_json(server: &NSServer) -> serde_json::Value {
+    let m = &server.metrics;
+    let counters: &[(&str, &AtomicU64)] = &[
+        ("pdns_applied", &m.pdns_applied),
+        ("pdns_failures", &m.pdns_failures),
+        ("pdns_retries", &m.pdns_retries),
+        ("pdns_partial", &m.pdns_partial),
+        ("apply_collapsed", &m.apply_collapsed),
+        ("apply_rejected", &m.apply_rejected),
+        ("audit_failures", &m.audit_failures),
+        ("registry_rejected", &m.registry_rejected),
+        ("advertise_superseded", &m.advertise_superseded),
+        ("deadline_exceeded", &m.deadline_exceeded),
+        ("ownership_conflicts", &m.ownership_conflicts),
+        ("source_mismatch", &m.source_mismatch),
+        ("pdns_zone_throttled", &m.pdns_zone_throttled),
+        ("pdns_connections_opened", &m.pdns_connections_opened),
+        ("pdns_connections_reused", &m.pdns_connections_reused),
+        ("pdns_zone_reads", &m.pdns_zone_reads),
+        ("pdns_name_reads", &m.pdns_name_reads),
+        ("pdns_zone_too_large", &m.pdns_zone_too_large),
+        ("pdns_rectified", &m.pdns_rectified),
+        ("pdns_rectify_failures", &m.pdns_rectify_failures),
+        ("pdns_zone_missing", &m.pdns_zone_missing),
+        ("missing_zone_skipped", &m.missing_zone_skipped),
+        ("history_dropped", &m.history_dropped),
+        ("advertise_no_addresses", &m.advertise_no_addresses),
+        ("advertise_unmatched", &m.advertise_unmatched),
+        ("dns_verified", &m.dns_verified),
+        ("dns_verify_failures", &m.dns_verify_failures),
+        ("writer_lock_lost", &m.writer_lock_lost),
+    ];
+    let mut out: serde_json::Map<String, serde_json::Value> = counters
+        .iter()
+        .map(|(name, counter)| (name.to_string(), metrics::get(counter).into()))
+        .collect();
+    let mut put = |name: &str, value: serde_json::Value| {
+        out.insert(name.to_owned(), value);
+    };
+
+    put(
+        "pdns_targets",
+        server
+            .pdns
+            .targets
+            .iter()
+            .map(|t| {
+                serde_json::json!({
+                    "endpoint": t.endpoint,
+                    "applied": metrics::get(&t.applied),
+                    "failures": metrics::get(&t.failures),
+                })
+            })
+            .collect(),
+    );
+    put("pdns_zone_wait", m.pdns_zone_wait.to_json());
+    put("pdns_request_time", m.pdns_request_time.to_json());
+    put(
+        "pdns_new_connection_request_time",
+        m.pdns_new_connection_request_time.to_json(),
+    );
+    put("pdns_zone_read_time", m.pdns_zone_read_time.to_json());
+    put(
+        "zone_rrsets",
+        serde_json::json!(*m.zone_rrsets.lock().unwrap()),
+    );
+
+    let registry = server.registry.stats();
+    put("registry_nodes", serde_json::json!(registry.nodes));
+    put(
+        "registry_interfaces",
+        serde_json::json!(registry.interfaces),
+    );
+    put("registry_addresses", serde_json::json!(registry.addresses));
+    put(
+        "registry_approx_bytes",
+        serde_json::json!(registry.approx_bytes),
+    );
+    put(
+        "held_writes",
+        serde_json::json!(server.pause.state().held_writes),
+    );
+    put(
+        "writer_lock",
+        serde_json::json!(server.writer_lock.as_ref().map(|l| l.state().as_str())),
+    );
+    put("pdns_gate", server.pdns_gate.state().as_str().into());
+    put(
+        "held_deletes",
+        serde_json::json!(server.deletion_guard.to_proto().held_deletes),
+    );
+    // Entries of each kind in the last drift report; null before the
+    // first.
+    put(
+        "drift",
+        serde_json::json!(server.drift.counts().map(|counts| {
+            counts
+                .iter()
+                .map(|(kind, n)| (kind.to_string(), serde_json::json!(n)))
+                .collect::<serde_json::Map<_, _>>()
+        })),
+    );
+    serde_json::Value::Object(out)
+}
+
+fn history_kind(kind: i32) -> &'static str {
+    match strapper::HistoryKind::from_i32(kind) {
+        Some(strapper::HistoryKind::HistoryRejected) => "rejected",
+        Some(strapper::HistoryKind::HistoryApplied) => "applied",
+        Some(strapper::HistoryKind::HistoryFailed) => "failed",
+        Some(strapper::HistoryKind::HistoryDeregistered) => "deregistered",
+        _ => "advertised",
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, error: String) -> Response<Body> {
+    json_response(status, &JsonError { error })
+}
+
+fn status_response(status: tonic::Status) -> Response<Body> {
+    let code = match status.code() {
+        tonic::Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        tonic::Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        tonic::Code::PermissionDenied => StatusCode::FORBIDDEN,
+        tonic::Code::NotFound => StatusCode::NOT_FOUND,
+        tonic::Code::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+        tonic::Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        tonic::Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        tonic::Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(code, status.message().to_owned())
+}
+
+// Bodies over `limit` bytes are refused before they're all buffered.
+async fn parse_body<T: for<'de> Deserialize<'de>>(
+    req: Request<Body>,
+    limit: usize,
+) -> Result<T, Response<Body>> {
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_response(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body exceeds the limit of {} bytes", limit),
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&buf).map_err(|e| error_response(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+// Which service a path belongs to, for listeners to carry separately; None
+// for paths nothing serves.
+pub fn service(path: &str) -> Option<Service> {
+    match path {
+        "/v1/advertise" | "/v1/plan" | "/v1/deregister" | "/v1/nodes" | "/v1/shared"
+        | "/v1/status" => Some(Service::Rest),
+        "/v1/quarantine" | "/v1/history" | "/v1/config" | "/v1/drift" | "/v1/pause"
+        | "/v1/role" => Some(Service::Admin),
+        "/v1/metrics" | "/sd/targets" => Some(Service::Metrics),
+        "/v1/health" => Some(Service::Health),
+        _ => None,
+    }
+}
+
+pub fn not_found(path: &str) -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, format!("no route for {}", path))
+}
+
+pub async fn handle(
+    server: NSServer,
+    peer: Option<SocketAddr>,
+    req: Request<Body>,
+) -> Response<Body> {
+    debug!("REST {} {}", req.method(), req.uri().path());
+
+    if let Some(tokens) = &server.auth {
+        let authorization = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        if let Err(s) = tokens.check(authorization) {
+            return status_response(s);
+        }
+    }
+
+    let request_id = request_id::accept(
+        req.headers()
+            .get(request_id::METADATA_KEY)
+            .and_then(|v| v.to_str().ok()),
+    );
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/v1/advertise") => {
+            let adv: JsonNodeAdvertisement = match parse_body(req, server.max_body_bytes).await {
+                Ok(a) => a,
+                Err(r) => return r,
+            };
+            match server
+                .handle_advertise(adv.into(), peer, request_id.clone(), None)
+                .await
+            {
+                Ok(summary) => json_response(
+                    StatusCode::OK,
+                    &serde_json::json!({
+                        "request_id": request_id,
+                        "accepted_addresses": summary.accepted,
+                        "skipped_addresses": summary.skipped,
+                        "conflicts": summary.conflicts.iter().map(|c| serde_json::json!({
+                            "zone": c.zone,
+                            "name": c.name,
+                            "type": c.r#type,
+                            "owner": c.owner,
+                        })).collect::<Vec<_>>(),
+                        "suggested_readvertise_secs": server.suggested_readvertise_secs(),
+                        "skipped_records": summary.skipped_records.iter().map(|r| serde_json::json!({
+                            "zone": r.zone,
+                            "name": r.name,
+                            "type": r.r#type,
+                            "reason": r.reason,
+                        })).collect::<Vec<_>>(),
+                        "unmatched_addresses": summary.unmatched,
+                        "unverified_records": summary.unverified.iter().map(|r| serde_json::json!({
+                            "zone": r.zone,
+                            "name": r.name,
+                            "type": r.r#type,
+                            "reason": r.reason,
+                        })).collect::<Vec<_>>(),
+                        "notices": summary.notices.iter().map(|n| serde_json::json!({
+                            "severity": notice::severity_name(n),
+                            "code": n.code,
+                            "message": n.message,
+                        })).collect::<Vec<_>>(),
+                    }),
+                ),
+                Err(s) => status_response(s),
+            }
+        }
+        (&Method::POST, "/v1/plan") => {
+            let adv: JsonNodeAdvertisement = match parse_body(req, server.max_body_bytes).await {
+                Ok(a) => a,
+                Err(r) => return r,
+            };
+            match server.plan_advertise(adv.into(), &request_id).await {
+                Ok(plan) => json_response(StatusCode::OK, &plan_json(&plan)),
+                Err(s) => status_response(s),
+            }
+        }
+        (&Method::POST, "/v1/deregister") => {
+            let dereg: JsonDeregister = match parse_body(req, server.max_body_bytes).await {
+                Ok(d) => d,
+                Err(r) => return r,
+            };
+            match server
+                .handle_deregister(&dereg.hostname, peer, request_id, None)
+                .await
+            {
+                Ok(()) => json_response(StatusCode::OK, &serde_json::json!({})),
+                Err(s) => status_response(s),
+            }
+        }
+        (&Method::GET, "/v1/nodes") => {
+            let nodes: Vec<JsonNodeAdvertisement> =
+                server.list_nodes().into_iter().map(Into::into).collect();
+            json_response(StatusCode::OK, &nodes)
+        }
+        (&Method::GET, "/v1/quarantine") => {
+            let records: Vec<JsonQuarantinedRecord> = server
+                .list_quarantined()
+                .into_iter()
+                .map(|r| JsonQuarantinedRecord {
+                    zone: r.zone,
+                    name: r.name,
+                    type_: r.r#type,
+                    failures: r.failures,
+                    last_error: r.last_error,
+                })
+                .collect();
+            json_response(StatusCode::OK, &records)
+        }
+        (&Method::GET, "/v1/history") => {
+            let mut filter = history::Filter {
+                hostname: String::new(),
+                zone: String::new(),
+                name: String::new(),
+                limit: history::DEFAULT_LIMIT,
+            };
+            for (key, value) in req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+            {
+                match key {
+                    "hostname" => filter.hostname = value.to_owned(),
+                    "zone" => filter.zone = value.to_owned(),
+                    "name" => filter.name = value.to_owned(),
+                    "limit" => match value.parse() {
+                        Ok(n) => filter.limit = n,
+                        Err(_) => {
+                            return error_response(
+                                StatusCode::BAD_REQUEST,
+                                format!("invalid limit '{}'", value),
+                            )
+                        }
+                    },
+                    _ => {
+                        return error_response(
+                            StatusCode::BAD_REQUEST,
+                            format!("unknown parameter '{}'", key),
+                        )
+                    }
+                }
+            }
+            let entries: Vec<_> = server
+                .history
+                .query(&filter)
+                .into_iter()
+                .map(|e| {
+                    serde_json::json!({
+                        "timestamp": humantime::format_rfc3339_millis(
+                            std::time::UNIX_EPOCH + std::time::Duration::from_millis(e.timestamp_ms)
+                        ).to_string(),
+                        "kind": history_kind(e.kind),
+                        "hostname": e.hostname,
+                        "zone": e.zone,
+                        "name": e.name,
+                        "type": e.r#type,
+                        "description": e.description,
+                        "request_id": e.request_id,
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, &entries)
+        }
+        (&Method::GET, "/v1/metrics") => json_response(StatusCode::OK, &metrics_json(&server)),
+        (&Method::GET, "/v1/status") => {
+            let s = server.status();
+            json_response(StatusCode::OK, &JsonServerStatus::from(s))
+        }
+        // For load balancers: a degraded server turns advertisements away,
+        // while a queueing one takes them.
+        (&Method::GET, "/v1/health") => {
+            let gate = server.pdns_gate.state();
+            let status = if gate == gate::GateState::Degraded {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            json_response(status, &serde_json::json!({ "pdns_gate": gate.as_str() }))
+        }
+        (&Method::GET, "/v1/config") => json_response(StatusCode::OK, &server.config.to_json()),
+        // ?refresh=true checks now instead of returning the last report.
+        (&Method::GET, "/v1/drift") => {
+            let refresh = req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .any(|p| p == "refresh" || p == "refresh=true" || p == "refresh=1");
+            json_response(StatusCode::OK, &server.get_drift(refresh).await.to_json())
+        }
+        (&Method::POST, "/v1/pause") => {
+            let pause: JsonWritePause = match parse_body(req, server.max_body_bytes).await {
+                Ok(p) => p,
+                Err(r) => return r,
+            };
+            match server
+                .set_write_pause(&pause.zone, pause.paused, &request_id)
+                .await
+            {
+                Ok(state) => json_response(StatusCode::OK, &JsonWritePauseState::from(state)),
+                Err(s) => status_response(s),
+            }
+        }
+        (&Method::POST, "/v1/role") => {
+            let role: JsonRole = match parse_body(req, server.max_body_bytes).await {
+                Ok(r) => r,
+                Err(r) => return r,
+            };
+            let role: peer::Role = match role.role.parse() {
+                Ok(r) => r,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            match server.set_role(role, &request_id).await {
+                Ok(s) => json_response(StatusCode::OK, &JsonServerStatus::from(s)),
+                Err(s) => status_response(s),
+            }
+        }
+        (&Method::GET, "/v1/shared") => {
+            let rrsets: Vec<JsonSharedRrset> = server
+                .list_shared()
+                .into_iter()
+                .map(|r| JsonSharedRrset {
+                    zone: r.zone,
+                    name: r.name,
+                    type_: r.type_,
+                    records: r
+                        .records
+                        .into_iter()
+                        .map(|(content, owner)| JsonOwnedRecord { content, owner })
+                        .collect(),
+                })
+                .collect();
+            json_response(StatusCode::OK, &rrsets)
+        }
+        (&Method::GET, "/sd/targets") => {
+            let name = req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|p| p.strip_prefix("config="))
+                .unwrap_or(sd::DEFAULT_CONFIG);
+            match server.sd_configs.get(name) {
+                Some(config) => json_response(StatusCode::OK, &server.sd_targets(config)),
+                None => error_response(
+                    StatusCode::NOT_FOUND,
+                    format!("no service discovery config '{}' (see --sd-port)", name),
+                ),
+            }
+        }
+        (_, "/v1/advertise")
+        | (_, "/v1/config")
+        | (_, "/v1/deregister")
+        | (_, "/v1/health")
+        | (_, "/v1/history")
+        | (_, "/v1/nodes")
+        | (_, "/v1/metrics")
+        | (_, "/v1/pause")
+        | (_, "/v1/plan")
+        | (_, "/v1/quarantine")
+        | (_, "/v1/role")
+        | (_, "/v1/shared")
+        | (_, "/v1/status")
+        | (_, "/sd/targets") => error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("{} not allowed", req.method()),
+        ),
+        (_, path) => not_found(path),
+    }
+}
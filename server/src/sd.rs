@@ -0,0 +1,171 @@
+use anyhow::{anyhow, ensure, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use proto::strapper;
+
+// The config a flag value belongs to when it doesn't name one, and the one
+// GET /sd/targets serves without ?config=.
+pub const DEFAULT_CONFIG: &str = "default";
+
+// A `[config=]value` flag, so --sd-port and --sd-remapper can be repeated to
+// describe several scrape configs.
+#[derive(Clone, Debug)]
+pub struct Named<T> {
+    config: String,
+    value: T,
+}
+
+impl<T: FromStr> FromStr for Named<T>
+where
+    T::Err: std::fmt::Display,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (config, value) = match s.split_once('=') {
+            Some((config, value)) => (config, value),
+            None => (DEFAULT_CONFIG, s),
+        };
+        ensure!(!config.is_empty(), "empty config name in '{}'", s);
+        Ok(Named {
+            config: config.to_owned(),
+            value: value
+                .parse()
+                .map_err(|e| anyhow!("invalid value '{}': {}", value, e))?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl FromStr for Family {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(Family::V4),
+            "v6" => Ok(Family::V6),
+            _ => Err(anyhow!(
+                "unknown address family '{}' (expected v4 or v6)",
+                s
+            )),
+        }
+    }
+}
+
+impl Family {
+    fn of(addr: &IpAddr) -> Family {
+        match addr {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Family::V4 => "v4",
+            Family::V6 => "v6",
+        }
+    }
+}
+
+pub struct Config {
+    pub port: u16,
+    // The zones whose remappers' addresses become targets; every remapper's
+    // when empty.
+    pub zones: Vec<String>,
+}
+
+pub fn configs(ports: &[Named<u16>], zones: &[Named<String>]) -> Result<BTreeMap<String, Config>> {
+    let mut configs = BTreeMap::new();
+    for p in ports {
+        let previous = configs.insert(
+            p.config.clone(),
+            Config {
+                port: p.value,
+                zones: vec![],
+            },
+        );
+        ensure!(
+            previous.is_none(),
+            "--sd-port given twice for config '{}'",
+            p.config
+        );
+    }
+    for z in zones {
+        configs
+            .get_mut(&z.config)
+            .ok_or_else(|| {
+                anyhow!(
+                    "--sd-remapper for config '{}', which has no --sd-port",
+                    z.config
+                )
+            })?
+            .zones
+            .push(z.value.clone());
+    }
+    Ok(configs)
+}
+
+// One http_sd target group. Labels apply to a whole group, so a node gets
+// one group for each of its interfaces and address families.
+#[derive(Serialize)]
+pub struct TargetGroup {
+    targets: Vec<String>,
+    labels: BTreeMap<&'static str, String>,
+}
+
+// Target groups for every node with an interface address `matches` accepts,
+// ordered by hostname, interface and family. With a `prefer`red family, a
+// node with addresses of both only gets that one's.
+pub fn target_groups(
+    mut nodes: Vec<strapper::NodeAdvertisement>,
+    port: u16,
+    prefer: Option<Family>,
+    matches: impl Fn(&strapper::NodeAdvertisement, &strapper::Interface, &IpAddr) -> bool,
+) -> Vec<TargetGroup> {
+    nodes.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    let mut groups = vec![];
+    for node in nodes {
+        let mut by_group: BTreeMap<(&str, &'static str), Vec<IpAddr>> = BTreeMap::new();
+        for iface in node.interfaces.iter() {
+            for addr in iface.ipaddr.iter().filter_map(|a| a.parse().ok()) {
+                if matches(&node, iface, &addr) {
+                    by_group
+                        .entry((iface.name.as_str(), Family::of(&addr).label()))
+                        .or_default()
+                        .push(addr);
+                }
+            }
+        }
+        if let Some(prefer) = prefer {
+            if by_group.keys().any(|(_, f)| *f == prefer.label()) {
+                by_group.retain(|(_, f), _| *f == prefer.label());
+            }
+        }
+
+        for ((iface, family), mut addrs) in by_group {
+            addrs.sort();
+            addrs.dedup();
+            let mut labels = BTreeMap::new();
+            labels.insert("hostname", node.hostname.clone());
+            labels.insert("interface", iface.to_owned());
+            labels.insert("family", family.to_owned());
+            groups.push(TargetGroup {
+                targets: addrs
+                    .into_iter()
+                    .map(|a| SocketAddr::new(a, port).to_string())
+                    .collect(),
+                labels,
+            });
+        }
+    }
+    groups
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::SystemTime;
+
+use proto::strapper;
+
+// The server's own host as an advertisement, for --self-advertise: what an
+// agent left to its defaults would send, short of what only netlink tells
+// it (address lifetimes and flags, VLANs, default routes). Addresses come
+// from getifaddrs and the rest from /sys/class/net, and the whole thing is
+// read afresh each time rather than kept up to date from events.
+
+const SYS_NET: &str = "/sys/class/net";
+
+fn sys(name: &str, attr: &str) -> Option<String> {
+    std::fs::read_to_string(Path::new(SYS_NET).join(name).join(attr))
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+
+fn oper_state(s: &str) -> strapper::OperState {
+    match s {
+        "notpresent" => strapper::OperState::NotPresent,
+        "down" => strapper::OperState::Down,
+        "lowerlayerdown" => strapper::OperState::LowerLayerDown,
+        "testing" => strapper::OperState::Testing,
+        "dormant" => strapper::OperState::Dormant,
+        "up" => strapper::OperState::Up,
+        _ => strapper::OperState::Unknown,
+    }
+}
+
+// As the agent's default --address-scope has it: loopback and link-local
+// addresses name nothing another host could reach.
+fn advertisable(addr: &IpAddr) -> bool {
+    let link_local = match addr {
+        IpAddr::V6(a) => a.is_unicast_link_local(),
+        IpAddr::V4(a) => a.is_link_local(),
+    };
+    !(addr.is_loopback() || addr.is_unspecified() || addr.is_multicast() || link_local)
+}
+
+fn interface(name: &str) -> Option<strapper::Interface> {
+    Some(strapper::Interface {
+        name: name.to_owned(),
+        index: sys(name, "ifindex")?.parse().ok()?,
+        mac: sys(name, "address")
+            .filter(|m| m != "00:00:00:00:00:00")
+            .unwrap_or_default(),
+        mtu: sys(name, "mtu").and_then(|m| m.parse().ok()).unwrap_or(0),
+        oper_state: oper_state(&sys(name, "operstate").unwrap_or_default()) as i32,
+        kind: sys(name, "uevent")
+            .and_then(|u| {
+                u.lines()
+                    .find_map(|l| l.strip_prefix("DEVTYPE=").map(str::to_owned))
+            })
+            .unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+// Every interface but loopback, by name, with its advertisable addresses.
+fn interfaces() -> Result<Vec<strapper::Interface>> {
+    let mut ifaces: BTreeMap<String, strapper::Interface> = BTreeMap::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("error listing addresses");
+    }
+    let mut next = ifap;
+    while !next.is_null() {
+        let ifa = unsafe { &*next };
+        next = ifa.ifa_next;
+        if ifa.ifa_flags & libc::IFF_LOOPBACK as u32 != 0 {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let addr = if ifa.ifa_addr.is_null() {
+            None
+        } else {
+            match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        sin.sin_addr.s_addr,
+                    ))))
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+                }
+                _ => None,
+            }
+        };
+        let iface = match ifaces.get_mut(&name) {
+            Some(iface) => iface,
+            None => match interface(&name) {
+                Some(iface) => ifaces.entry(name).or_insert(iface),
+                None => continue,
+            },
+        };
+        if let Some(addr) = addr.filter(advertisable).map(|a| a.to_string()) {
+            if !iface.ipaddr.contains(&addr) {
+                iface.ipaddr.push(addr);
+            }
+        }
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    let mut ifaces: Vec<_> = ifaces.into_values().collect();
+    ifaces.sort_by_key(|i| i.index);
+    Ok(ifaces)
+}
+
+// The advertisement for `hostname` as the host is now. `started` stands in
+// for the agent's start time; the caller keeps the sequence.
+pub fn read(hostname: &str, started: u64) -> Result<strapper::NodeAdvertisement> {
+    Ok(strapper::NodeAdvertisement {
+        hostname: hostname.to_owned(),
+        fqdn: if hostname.contains('.') {
+            hostname.to_owned()
+        } else {
+            String::new()
+        },
+        interfaces: interfaces()?,
+        agent_version: env!("CARGO_PKG_VERSION").to_owned(),
+        agent_start_time: started,
+        observed_ms: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        self_advertised: true,
+        ..Default::default()
+    })
+}
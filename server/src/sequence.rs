@@ -0,0 +1,91 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The newest (agent_start_time, sequence) seen per node. A retried RPC can
+// land after a newer advertisement was processed; applying it would bring
+// back records the newer one removed.
+#[derive(Default)]
+pub struct Sequences {
+    seen: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl Sequences {
+    // Records and admits the advertisement unless it's older than what we
+    // have. A newer start time means the agent restarted and its counter
+    // began again, so any sequence is fine then; an older one is a late
+    // retry from a run that's since ended. Equal sequences are retries of
+    // the same state and get applied again.
+    pub fn admit(&self, hostname: &str, start_time: u64, sequence: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let newer = match seen.get(hostname) {
+            None => true,
+            Some(&(seen_start, seen_seq)) => match start_time.cmp(&seen_start) {
+                Ordering::Greater => true,
+                Ordering::Equal => sequence >= seen_seq,
+                Ordering::Less => false,
+            },
+        };
+        if newer {
+            seen.insert(hostname.to_owned(), (start_time, sequence));
+        }
+        newer
+    }
+
+    pub fn forget(&self, hostname: &str) {
+        self.seen.lock().unwrap().remove(hostname);
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap().len()
+    }
+
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        self.seen.lock().unwrap().retain(|h, _| keep(h));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sequences;
+
+    #[test]
+    fn late_retry() {
+        let s = Sequences::default();
+        assert!(s.admit("node", 100, 1));
+        assert!(s.admit("node", 100, 2));
+        // The retried RPC for 1, landing after 2 was applied.
+        assert!(!s.admit("node", 100, 1));
+        assert!(s.admit("node", 100, 2));
+        assert!(s.admit("node", 100, 3));
+        // Other nodes keep their own.
+        assert!(s.admit("other", 100, 1));
+    }
+
+    #[test]
+    fn restart() {
+        let s = Sequences::default();
+        assert!(s.admit("node", 100, 7));
+        assert!(s.admit("node", 200, 1));
+        assert!(!s.admit("node", 200, 0));
+        // The previous run's retried RPC, landing after the restart: its
+        // sequence is higher, but the run it came from is over.
+        assert!(!s.admit("node", 100, 7));
+        assert!(!s.admit("node", 100, 8));
+        assert!(s.admit("node", 200, 2));
+
+        s.forget("node");
+        assert!(s.admit("node", 150, 0));
+    }
+
+    #[test]
+    fn retain() {
+        let s = Sequences::default();
+        s.admit("a", 1, 1);
+        s.admit("b", 1, 1);
+        s.retain(|h| h == "a");
+        assert_eq!(s.len(), 1);
+        assert!(!s.admit("a", 1, 0));
+        assert!(s.admit("b", 1, 0));
+    }
+}
@@ -0,0 +1,37 @@
+use std::net::IpAddr;
+
+use proto::strapper;
+
+// IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d.
+pub fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+// Whether the advertisement could have come from `source`: one of its
+// addresses, loopback (the agent sharing a host with us), or from a family
+// the node advertises nothing in, which says nothing either way.
+pub fn matches(advertisement: &strapper::NodeAdvertisement, source: IpAddr) -> bool {
+    if source.is_loopback() {
+        return true;
+    }
+    let same_family: Vec<IpAddr> = advertised(advertisement)
+        .filter(|a| a.is_ipv4() == source.is_ipv4())
+        .collect();
+    same_family.is_empty() || same_family.contains(&source)
+}
+
+pub fn advertised(
+    advertisement: &strapper::NodeAdvertisement,
+) -> impl Iterator<Item = IpAddr> + '_ {
+    advertisement
+        .interfaces
+        .iter()
+        .flat_map(|i| i.ipaddr.iter())
+        .filter_map(|a| a.parse().ok())
+}
@@ -0,0 +1,112 @@
+use anyhow::{anyhow, ensure};
+use std::str::FromStr;
+
+use proto::strapper;
+
+// --srv [priority=<n>:][weight=<n>:]<service>@<zone>[@<owner>]: nodes
+// advertising <service> get an SRV record in <zone>, under <owner> or else
+// _<service>._<proto>.<zone>.
+#[derive(Clone)]
+pub struct Mapping {
+    pub service: String,
+    pub zone: String,
+    owner: Option<String>,
+    priority: Option<u16>,
+    weight: Option<u16>,
+}
+
+impl FromStr for Mapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut priority = None;
+        let mut weight = None;
+        let mut rest = s;
+        loop {
+            let (key, after) = match rest.split_once('=') {
+                Some((key, after)) if key == "priority" || key == "weight" => (key, after),
+                _ => break,
+            };
+            let (value, after) = after
+                .split_once(':')
+                .ok_or_else(|| anyhow!("{}= needs a ':' after it", key))?;
+            let value: u16 = value
+                .parse()
+                .map_err(|_| anyhow!("invalid {} '{}' in SRV mapping '{}'", key, value, s))?;
+            if key == "priority" {
+                priority = Some(value);
+            } else {
+                weight = Some(value);
+            }
+            rest = after;
+        }
+
+        let mut parts = rest.split('@');
+        let (service, zone, owner) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(service), Some(zone), owner) if parts.next().is_none() => (service, zone, owner),
+            _ => {
+                return Err(anyhow!(
+                    "invalid SRV mapping '{}' (expected <service>@<zone>[@<owner>])",
+                    s
+                ))
+            }
+        };
+        ensure!(
+            is_label(service),
+            "invalid service '{}' in SRV mapping '{}'",
+            service,
+            s
+        );
+        ensure!(
+            zone.ends_with('.'),
+            "SRV zone '{}' must end with a dot",
+            zone
+        );
+        if let Some(owner) = owner {
+            let in_zone =
+                owner == zone || owner.strip_suffix(zone).is_some_and(|o| o.ends_with('.'));
+            ensure!(in_zone, "SRV owner '{}' isn't in zone {}", owner, zone);
+        }
+        Ok(Mapping {
+            service: service.to_ascii_lowercase(),
+            zone: zone.to_owned(),
+            owner: owner.map(str::to_owned),
+            priority,
+            weight,
+        })
+    }
+}
+
+impl Mapping {
+    pub fn owner(&self, service: &strapper::Service) -> String {
+        match &self.owner {
+            Some(owner) => owner.clone(),
+            None => format!("_{}._{}.{}", service.name, service.protocol, self.zone),
+        }
+    }
+
+    // SRV content as pdns takes it: priority, weight, port and the absolute
+    // target name.
+    pub fn content(&self, defaults: (u16, u16), port: u32, target: &str) -> String {
+        let dot = if target.ends_with('.') { "" } else { "." };
+        format!(
+            "{} {} {} {}{}",
+            self.priority.unwrap_or(defaults.0),
+            self.weight.unwrap_or(defaults.1),
+            port,
+            target,
+            dot
+        )
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('-') && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Whether a service can go in an owner name and record as advertised.
+pub fn is_valid(service: &strapper::Service) -> bool {
+    is_label(&service.name)
+        && is_label(&service.protocol)
+        && (1..=u32::from(u16::MAX)).contains(&service.port)
+}
@@ -0,0 +1,66 @@
+use log::warn;
+
+use proto::strapper;
+
+// A single TXT character-string is length-prefixed with one byte.
+const MAX_STRING_LEN: usize = 255;
+
+// pdns takes TXT content in zone file presentation form.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// One string per interface with a hardware address, e.g.
+// "mac=aa:bb:cc:dd:ee:ff;iface=eth0;agent=strapper/0.1.0". Strings over the TXT limit are dropped
+// rather than split, since tooling reads each string as one interface.
+pub fn node_records(adv: &strapper::NodeAdvertisement) -> Vec<String> {
+    let mut records: Vec<String> = adv
+        .interfaces
+        .iter()
+        .filter(|i| !i.mac.is_empty() && i.mac != "00:00:00:00:00:00")
+        .filter_map(|i| {
+            let mut s = format!("mac={};iface={}", i.mac, i.name);
+            if !adv.agent_version.is_empty() {
+                s.push_str(&format!(";agent=strapper/{}", adv.agent_version));
+            }
+            if s.len() > MAX_STRING_LEN {
+                warn!(
+                    "not publishing TXT metadata for {} interface {}: {} bytes exceeds {}",
+                    adv.hostname,
+                    i.name,
+                    s.len(),
+                    MAX_STRING_LEN
+                );
+                return None;
+            }
+            Some(quote(&s))
+        })
+        .collect();
+    records.sort();
+    records.dedup();
+    records
+}
+
+// "wgkey=<base64>" for each wireguard public key the node has; keys that
+// aren't one were already warned about by validate.
+pub fn wireguard_records(adv: &strapper::NodeAdvertisement) -> Vec<String> {
+    let mut records: Vec<String> = adv
+        .interfaces
+        .iter()
+        .filter_map(|i| i.wireguard_public_key.as_deref())
+        .filter(|k| proto::wireguard::is_public_key(k))
+        .map(|k| quote(&format!("wgkey={}", k)))
+        .collect();
+    records.sort();
+    records.dedup();
+    records
+}
@@ -0,0 +1,234 @@
+use anyhow::anyhow;
+use log::warn;
+use std::str::FromStr;
+
+use proto::node::Problem;
+use proto::{notice, strapper};
+
+use crate::srv;
+
+pub struct Summary {
+    pub accepted: u32,
+    pub skipped: u32,
+    pub conflicts: Vec<strapper::RecordConflict>,
+    pub skipped_records: Vec<strapper::SkippedRecord>,
+    pub unmatched: Vec<String>,
+    pub unverified: Vec<strapper::UnverifiedRecord>,
+    // For the agent to pass on; see proto::notice.
+    pub notices: Vec<strapper::Notice>,
+    // A newer advertisement for the node arrived while this one waited to
+    // be applied, and was applied instead.
+    pub superseded: bool,
+}
+
+// --on-no-match: what becomes of an advertisement that publishes no
+// addresses, either because it has none or because no remapper takes any of
+// them, which is usually a remapper missing for the node's network.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NoMatchPolicy {
+    Ok,
+    Warn,
+    // Fails it with FailedPrecondition, which agents don't retry.
+    Error,
+}
+
+impl FromStr for NoMatchPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(NoMatchPolicy::Ok),
+            "warn" => Ok(NoMatchPolicy::Warn),
+            "error" => Ok(NoMatchPolicy::Error),
+            _ => Err(anyhow!(
+                "unknown no-match policy '{}' (expected ok, warn or error)",
+                s
+            )),
+        }
+    }
+}
+
+// Checks an advertisement before any of it reaches pdns. Problems short of
+// fatal (addresses that don't parse, say) only touch what's skipped later on
+// anyway; here they're logged so agent bugs don't go unnoticed, and under
+// `strict` they (and interfaces without a MAC, and services that can't be
+// published) fail the whole advertisement. Either way the agent hears
+// about them, as notices or the refusal.
+#[allow(clippy::result_large_err)]
+pub fn check(
+    advertisement: &strapper::NodeAdvertisement,
+    strict: bool,
+    request_id: &str,
+) -> Result<Summary, tonic::Status> {
+    let invalid = |message: String| {
+        notice::refusal(
+            tonic::Code::InvalidArgument,
+            notice::INVALID_ADVERTISEMENT,
+            message,
+        )
+    };
+    let problems = advertisement.validate().err().unwrap_or_default();
+    if let Some(p) = problems.iter().find(|p| p.is_fatal()) {
+        return Err(invalid(p.to_string()));
+    }
+    let mut notices = vec![];
+    for p in problems.iter() {
+        warn!("[{}] {} sent {}", request_id, advertisement.hostname, p);
+        if strict {
+            return Err(invalid(p.to_string()));
+        }
+        notices.push(notice::warning(
+            notice::INVALID_ADVERTISEMENT,
+            p.to_string(),
+        ));
+    }
+
+    let skipped = problems
+        .iter()
+        .filter(|p| matches!(p, Problem::BadAddress { .. }))
+        .count() as u32;
+    let total: usize = advertisement
+        .interfaces
+        .iter()
+        .map(|i| i.ipaddr.len())
+        .sum();
+    let mut summary = Summary {
+        accepted: total as u32 - skipped,
+        skipped,
+        conflicts: vec![],
+        skipped_records: vec![],
+        unmatched: vec![],
+        unverified: vec![],
+        notices,
+        superseded: false,
+    };
+    if strict {
+        if let Some(iface) = advertisement.interfaces.iter().find(|i| i.mac.is_empty()) {
+            return Err(invalid(format!("interface {} has no MAC", iface.name)));
+        }
+    }
+    for service in advertisement.services.iter().filter(|s| !srv::is_valid(s)) {
+        warn!(
+            "[{}] {} sent invalid service {}:{}:{}",
+            request_id, advertisement.hostname, service.name, service.protocol, service.port
+        );
+        let message = format!(
+            "invalid service {}:{}:{}",
+            service.name, service.protocol, service.port
+        );
+        if strict {
+            return Err(invalid(message));
+        }
+        summary
+            .notices
+            .push(notice::warning(notice::INVALID_ADVERTISEMENT, message));
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::testing::{iface, node};
+    use proto::{notice, strapper};
+
+    use super::check;
+
+    // What check made of it: accepted and skipped addresses with the
+    // number of notices, or the refusal's status code.
+    type Outcome = Result<(u32, u32, usize), tonic::Code>;
+
+    fn outcome(advertisement: &strapper::NodeAdvertisement, strict: bool) -> Outcome {
+        check(advertisement, strict, "test")
+            .map(|s| (s.accepted, s.skipped, s.notices.len()))
+            .map_err(|status| {
+                assert_eq!(
+                    notice::from_status(&status).map(|n| n.code),
+                    Some(notice::INVALID_ADVERTISEMENT.to_owned())
+                );
+                status.code()
+            })
+    }
+
+    #[test]
+    fn strict_and_lenient() {
+        const MAC: &str = "52:54:00:12:34:56";
+        let invalid = Err(tonic::Code::InvalidArgument);
+        let cases: Vec<(&str, strapper::NodeAdvertisement, Outcome, Outcome)> = vec![
+            (
+                "good",
+                node(vec![iface("eth0", 2, MAC, &["10.0.0.1", "fd00::1"])]),
+                Ok((2, 0, 0)),
+                Ok((2, 0, 0)),
+            ),
+            ("no interfaces", node(vec![]), Ok((0, 0, 0)), Ok((0, 0, 0))),
+            (
+                "empty hostname",
+                strapper::NodeAdvertisement {
+                    hostname: String::new(),
+                    ..node(vec![iface("eth0", 2, MAC, &["10.0.0.1"])])
+                },
+                invalid,
+                invalid,
+            ),
+            (
+                "unparseable address",
+                node(vec![iface("eth0", 2, MAC, &["10.0.0.1", "10.0.0.256"])]),
+                Ok((1, 1, 1)),
+                invalid,
+            ),
+            (
+                "every address unparseable",
+                node(vec![iface("eth0", 2, MAC, &["bogus", "fd00::g"])]),
+                Ok((0, 2, 2)),
+                invalid,
+            ),
+            (
+                "no MAC",
+                node(vec![iface("lo", 2, "", &["10.0.0.1"])]),
+                Ok((1, 0, 0)),
+                invalid,
+            ),
+            (
+                "bad MAC",
+                node(vec![iface("eth0", 2, "52:54:00", &["10.0.0.1"])]),
+                Ok((1, 0, 1)),
+                invalid,
+            ),
+            (
+                "duplicate index",
+                node(vec![
+                    iface("eth0", 2, MAC, &["10.0.0.1"]),
+                    iface("eth1", 2, MAC, &["10.0.0.2"]),
+                ]),
+                Ok((2, 0, 1)),
+                invalid,
+            ),
+            (
+                "invalid service",
+                strapper::NodeAdvertisement {
+                    services: vec![strapper::Service {
+                        name: "http".to_owned(),
+                        protocol: "tcp".to_owned(),
+                        port: 0,
+                    }],
+                    ..node(vec![iface("eth0", 2, MAC, &["10.0.0.1"])])
+                },
+                Ok((1, 0, 1)),
+                invalid,
+            ),
+            (
+                "zero TTL override",
+                strapper::NodeAdvertisement {
+                    ttl_override: Some(0),
+                    ..node(vec![iface("eth0", 2, MAC, &["10.0.0.1"])])
+                },
+                invalid,
+                invalid,
+            ),
+        ];
+        for (name, advertisement, lenient, strict) in cases {
+            assert_eq!(outcome(&advertisement, false), lenient, "{}, lenient", name);
+            assert_eq!(outcome(&advertisement, true), strict, "{}, strict", name);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::PdnsRrsetUpdate;
+
+// How long one lookup may take.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// `ip` or `ip:port`, for --verify-dns.
+pub fn parse_resolver(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid resolver '{}' (expected ip or ip:port)", s))
+}
+
+// Looks each applied rrset up at --verify-dns until the resolver gives what
+// was written (or, for deletes, nothing), waiting --verify-dns-delay-ms
+// before the first try and twice as long again after each miss. A recursive
+// resolver caches a miss for the zone's negative TTL, so the delay should
+// cover how long pdns takes to serve a write; pointing at pdns' own
+// authoritative listener, or a replica of it, avoids that altogether.
+pub struct Verifier {
+    resolver: SocketAddr,
+    delay: Duration,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl Verifier {
+    pub fn new(
+        resolver: SocketAddr,
+        delay: Duration,
+        timeout: Duration,
+        attempts: u32,
+    ) -> Verifier {
+        Verifier {
+            resolver,
+            delay,
+            timeout,
+            attempts: attempts.max(1),
+        }
+    }
+
+    pub fn resolver(&self) -> SocketAddr {
+        self.resolver
+    }
+
+    // Merge-mode deletes drop only this node's share of the rrset, and what
+    // is left isn't known here.
+    pub fn checkable(update: &PdnsRrsetUpdate) -> bool {
+        qtype(update.type_).is_some()
+            && !(update.changetype == "DELETE" && update.merge_owner.is_some())
+    }
+
+    pub async fn verify(&self, update: &PdnsRrsetUpdate) -> Result<(), String> {
+        let qtype = qtype(update.type_).ok_or_else(|| format!("can't look up {}", update.type_))?;
+        let expected = update
+            .records
+            .iter()
+            .filter(|r| !r.disabled)
+            .map(|r| Rdata::parse(update.type_, &r.content))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let start = Instant::now();
+        let mut wait = self.delay;
+        let mut last = String::new();
+        let mut tries = 0;
+        while tries < self.attempts && start.elapsed() + wait < self.timeout {
+            tokio::time::sleep(wait).await;
+            tries += 1;
+            let budget = QUERY_TIMEOUT.min(self.timeout.saturating_sub(start.elapsed()));
+            last = match tokio::time::timeout(budget, self.lookup(&update.name, qtype)).await {
+                Ok(Ok(answers)) => {
+                    let seen = if update.changetype == "DELETE" {
+                        answers.is_empty()
+                    } else {
+                        expected.iter().all(|e| answers.contains(e))
+                    };
+                    if seen {
+                        return Ok(());
+                    }
+                    if answers.is_empty() {
+                        "it had no records".to_owned()
+                    } else {
+                        let answers: Vec<String> = answers.iter().map(Rdata::to_string).collect();
+                        format!("it had {}", answers.join(", "))
+                    }
+                }
+                Ok(Err(e)) => e,
+                Err(_) => "the lookup timed out".to_owned(),
+            };
+            wait *= 2;
+        }
+        Err(format!(
+            "{} {} not {} at {} after {} lookups ({})",
+            update.type_,
+            update.name,
+            if update.changetype == "DELETE" {
+                "gone"
+            } else {
+                "visible"
+            },
+            self.resolver,
+            tries,
+            last
+        ))
+    }
+
+    // Over UDP, and again over TCP if the answer didn't fit.
+    async fn lookup(&self, name: &str, qtype: u16) -> Result<Vec<Rdata>, String> {
+        let id = rand::random();
+        let query = query(id, name, qtype)?;
+        let error = |e: std::io::Error| format!("error querying {}: {}", self.resolver, e);
+
+        let local: SocketAddr = match self.resolver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(error)?;
+        socket.connect(self.resolver).await.map_err(error)?;
+        socket.send(&query).await.map_err(error)?;
+        let mut buf = vec![0; 4096];
+        loop {
+            let n = socket.recv(&mut buf).await.map_err(error)?;
+            match parse(&buf[..n], id, qtype) {
+                // Late answers to some earlier query on the port.
+                Err(Parsed::OtherId) => continue,
+                Err(Parsed::Truncated) => break,
+                Err(Parsed::Failed(e)) => return Err(e),
+                Ok(answers) => return Ok(answers),
+            }
+        }
+
+        let mut stream = TcpStream::connect(self.resolver).await.map_err(error)?;
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend(query);
+        stream.write_all(&framed).await.map_err(error)?;
+        let len = stream.read_u16().await.map_err(error)?;
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.map_err(error)?;
+        parse(&buf, id, qtype).map_err(|e| match e {
+            Parsed::Failed(e) => e,
+            _ => format!("{} sent an unusable answer over TCP", self.resolver),
+        })
+    }
+}
+
+fn qtype(type_: &str) -> Option<u16> {
+    match type_ {
+        "A" => Some(1),
+        "TXT" => Some(16),
+        "AAAA" => Some(28),
+        "SRV" => Some(33),
+        _ => None,
+    }
+}
+
+// Record data as both sides can be brought to it: pdns' presentation form
+// and the wire form the resolver answers in.
+#[derive(PartialEq, Eq, Debug)]
+enum Rdata {
+    Addr(IpAddr),
+    Txt(Vec<Vec<u8>>),
+    Srv(u16, u16, u16, String),
+}
+
+impl Rdata {
+    fn parse(type_: &str, content: &str) -> Result<Rdata, String> {
+        let invalid = || format!("can't compare {} content '{}'", type_, content);
+        match type_ {
+            "A" | "AAAA" => content.parse().map(Rdata::Addr).map_err(|_| invalid()),
+            "TXT" => unquote(content).map(Rdata::Txt).ok_or_else(invalid),
+            "SRV" => {
+                let fields: Vec<&str> = content.split_whitespace().collect();
+                match fields[..] {
+                    [priority, weight, port, target] => Ok(Rdata::Srv(
+                        priority.parse().map_err(|_| invalid())?,
+                        weight.parse().map_err(|_| invalid())?,
+                        port.parse().map_err(|_| invalid())?,
+                        fqdn(target),
+                    )),
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Rdata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rdata::Addr(a) => write!(f, "{}", a),
+            Rdata::Txt(strings) => {
+                let strings: Vec<String> = strings
+                    .iter()
+                    .map(|s| format!("{:?}", String::from_utf8_lossy(s)))
+                    .collect();
+                f.write_str(&strings.join(" "))
+            }
+            Rdata::Srv(priority, weight, port, target) => {
+                write!(f, "{} {} {} {}", priority, weight, port, target)
+            }
+        }
+    }
+}
+
+fn fqdn(name: &str) -> String {
+    let mut name = name.to_ascii_lowercase();
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+    name
+}
+
+// TXT content as txt.rs writes it: one or more quoted strings, with \" and
+// \\ (and \DDD, should someone else have written the rrset) escaped.
+fn unquote(content: &str) -> Option<Vec<Vec<u8>>> {
+    let mut strings = vec![];
+    let mut bytes = content.trim().bytes().peekable();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' => {}
+            b' ' | b'\t' => continue,
+            _ => return None,
+        }
+        let mut s = vec![];
+        loop {
+            match bytes.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = bytes.next()?;
+                    if c.is_ascii_digit() {
+                        let d2 = bytes.next()?;
+                        let d3 = bytes.next()?;
+                        let n = std::str::from_utf8(&[c, d2, d3]).ok()?.parse().ok()?;
+                        s.push(n);
+                    } else {
+                        s.push(c);
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+        strings.push(s);
+    }
+    Some(strings)
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut msg = Vec::with_capacity(512);
+    msg.extend(id.to_be_bytes());
+    // Recursion desired, one question.
+    msg.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("can't look up '{}'", name));
+        }
+        msg.push(label.len() as u8);
+        msg.extend(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend(qtype.to_be_bytes());
+    msg.extend(1u16.to_be_bytes());
+    Ok(msg)
+}
+
+enum Parsed {
+    OtherId,
+    Truncated,
+    Failed(String),
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+// A possibly compressed name starting at `pos`, and where what follows it
+// starts.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 64 {
+                return None;
+            }
+            pos = ((len & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+            continue;
+        }
+        if len == 0 {
+            if name.is_empty() {
+                name.push('.');
+            }
+            return Some((name, end.unwrap_or(pos + 1)));
+        }
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+        name.push('.');
+        pos += 1 + len;
+    }
+}
+
+// The answers of type `qtype`; a CNAME the resolver followed on the way is
+// skipped over. NXDOMAIN is no answers.
+fn parse(msg: &[u8], id: u16, qtype: u16) -> Result<Vec<Rdata>, Parsed> {
+    let malformed = || Parsed::Failed("malformed DNS answer".to_owned());
+    if msg.len() < 12 || u16_at(msg, 0) != Some(id) || msg[2] & 0x80 == 0 {
+        return Err(Parsed::OtherId);
+    }
+    if msg[2] & 0x02 != 0 {
+        return Err(Parsed::Truncated);
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        3 => return Ok(vec![]),
+        2 => return Err(Parsed::Failed("the resolver answered SERVFAIL".to_owned())),
+        5 => return Err(Parsed::Failed("the resolver refused the query".to_owned())),
+        rcode => {
+            return Err(Parsed::Failed(format!(
+                "the resolver answered rcode {}",
+                rcode
+            )))
+        }
+    }
+    let questions = u16_at(msg, 4).unwrap();
+    let answers = u16_at(msg, 6).unwrap();
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos).ok_or_else(malformed)?.1 + 4;
+    }
+    let mut found = vec![];
+    for _ in 0..answers {
+        pos = read_name(msg, pos).ok_or_else(malformed)?.1;
+        let type_ = u16_at(msg, pos).ok_or_else(malformed)?;
+        let len = u16_at(msg, pos + 8).ok_or_else(malformed)? as usize;
+        let start = pos + 10;
+        let data = msg.get(start..start + len).ok_or_else(malformed)?;
+        pos = start + len;
+        if type_ != qtype {
+            continue;
+        }
+        let rdata = match (qtype, data.len()) {
+            (1, 4) => Rdata::Addr(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (28, 16) => Rdata::Addr(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            (16, _) => {
+                let mut strings = vec![];
+                let mut rest = data;
+                while let Some((&n, tail)) = rest.split_first() {
+                    let s = tail.get(..n as usize).ok_or_else(malformed)?;
+                    strings.push(s.to_vec());
+                    rest = &tail[n as usize..];
+                }
+                Rdata::Txt(strings)
+            }
+            (33, n) if n > 6 => Rdata::Srv(
+                u16_at(data, 0).unwrap(),
+                u16_at(data, 2).unwrap(),
+                u16_at(data, 4).unwrap(),
+                // The target may point back into the message.
+                read_name(msg, start + 6).ok_or_else(malformed)?.0,
+            ),
+            _ => return Err(malformed()),
+        };
+        found.push(rdata);
+    }
+    Ok(found)
+}
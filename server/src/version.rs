@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+// Just enough semver for --min-agent-version: MAJOR.MINOR.PATCH with an
+// optional -prerelease, which sorts before the release. Build metadata is
+// ignored, as semver says it should be.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<String>,
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split('+').next().unwrap_or_default();
+        let (core, pre) = match s.split_once('-') {
+            Some((core, pre)) => (core, pre.split('.').map(str::to_owned).collect()),
+            None => (s, vec![]),
+        };
+        let parts = core
+            .split('.')
+            .map(|p| p.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid version '{}'", s))?;
+        match parts[..] {
+            [major, minor, patch] => Ok(Version {
+                major,
+                minor,
+                patch,
+                pre,
+            }),
+            _ => Err(anyhow!(
+                "invalid version '{}' (expected MAJOR.MINOR.PATCH)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+fn cmp_pre_ident(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self
+                    .pre
+                    .iter()
+                    .zip(other.pre.iter())
+                    .map(|(a, b)| cmp_pre_ident(a, b))
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or_else(|| self.pre.len().cmp(&other.pre.len())),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
@@ -0,0 +1,175 @@
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use proto::strapper;
+
+use crate::PdnsRrsetUpdate;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Key {
+    zone: String,
+    name: String,
+    type_: &'static str,
+}
+
+impl Key {
+    fn new(zone: &str, update: &PdnsRrsetUpdate) -> Key {
+        Key {
+            zone: zone.to_owned(),
+            name: update.name.clone(),
+            type_: update.type_,
+        }
+    }
+}
+
+struct Holder {
+    hostname: String,
+    observed_ms: u64,
+}
+
+// When the agent saw the advertisement's addresses, in milliseconds since
+// the epoch; agents too old to say are taken to have seen them just now.
+pub fn observed_ms(adv: &strapper::NodeAdvertisement) -> u64 {
+    if adv.observed_ms != 0 {
+        return adv.observed_ms;
+    }
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub fn advertises_vips(adv: &strapper::NodeAdvertisement) -> bool {
+    adv.interfaces
+        .iter()
+        .flat_map(|i| i.address_info.iter())
+        .any(|i| i.vip)
+}
+
+// Which node each VIP rrset was last written for. The last node to see the
+// address wins, going by when its agent saw it rather than when its
+// advertisement got here, so a handoff settles on the new holder whichever
+// of the two nodes' advertisements arrives first. That's decided per rrset,
+// as a node can be taking one VIP over while giving up another, and relies
+// on the nodes' clocks roughly agreeing. Like Owners, it starts empty.
+#[derive(Default)]
+pub struct Holders {
+    held: Mutex<HashMap<Key, Holder>>,
+    // Taken from planning a VIP write until it's claimed, so a handoff's
+    // delete and its takeover can't reach pdns the wrong way round.
+    turn: tokio::sync::Mutex<()>,
+}
+
+impl Holders {
+    // The turn, for a node with anything to do with VIPs.
+    pub async fn enter(
+        &self,
+        adv: &strapper::NodeAdvertisement,
+    ) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        if !advertises_vips(adv) && !self.holds(&adv.effective_hostname) {
+            return None;
+        }
+        Some(self.turn.lock().await)
+    }
+
+    fn holds(&self, hostname: &str) -> bool {
+        self.held
+            .lock()
+            .unwrap()
+            .values()
+            .any(|h| h.hostname == hostname)
+    }
+
+    // Drops `hostname`'s VIP updates for rrsets another node saw the address
+    // in later, and deletes those it holds but no longer publishes.
+    pub fn check(
+        &self,
+        hostname: &str,
+        observed_ms: u64,
+        updates: Vec<(String, PdnsRrsetUpdate)>,
+    ) -> Vec<(String, PdnsRrsetUpdate)> {
+        let held = self.held.lock().unwrap();
+        let mut checked: Vec<(String, PdnsRrsetUpdate)> = vec![];
+        for (zone, update) in updates {
+            if update.vip {
+                if let Some(h) = held.get(&Key::new(&zone, &update)) {
+                    if h.hostname != hostname && h.observed_ms > observed_ms {
+                        debug!(
+                            "leaving {} {} in {} to {}, which had it later than {}",
+                            update.type_, update.name, zone, h.hostname, hostname
+                        );
+                        continue;
+                    }
+                }
+            }
+            checked.push((zone, update));
+        }
+        let withdrawn: Vec<_> = held
+            .iter()
+            .filter(|(key, h)| {
+                h.hostname == hostname
+                    && !checked
+                        .iter()
+                        .any(|(z, u)| u.vip && Key::new(z, u) == **key)
+            })
+            .map(|(key, _)| delete(key))
+            .collect();
+        checked.extend(withdrawn);
+        checked
+    }
+
+    // Records what `hostname` just wrote.
+    pub fn claim(&self, hostname: &str, observed_ms: u64, updates: &[(String, PdnsRrsetUpdate)]) {
+        let mut held = self.held.lock().unwrap();
+        for (zone, update) in updates.iter().filter(|(_, u)| u.vip) {
+            let key = Key::new(zone, update);
+            if update.changetype != "DELETE" {
+                held.insert(
+                    key,
+                    Holder {
+                        hostname: hostname.to_owned(),
+                        observed_ms,
+                    },
+                );
+            } else if held.get(&key).is_some_and(|h| h.hostname == hostname) {
+                held.remove(&key);
+            }
+        }
+    }
+
+    // Deletes for the rrsets `hostname` holds, for its deregistration.
+    pub fn held(&self, hostname: &str) -> Vec<(String, PdnsRrsetUpdate)> {
+        self.held
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, h)| h.hostname == hostname)
+            .map(|(key, _)| delete(key))
+            .collect()
+    }
+
+    pub fn release(&self, hostname: &str) {
+        self.held
+            .lock()
+            .unwrap()
+            .retain(|_, h| h.hostname != hostname);
+    }
+}
+
+fn delete(key: &Key) -> (String, PdnsRrsetUpdate) {
+    (
+        key.zone.clone(),
+        PdnsRrsetUpdate {
+            name: key.name.clone(),
+            type_: key.type_,
+            ttl: 0,
+            changetype: "DELETE",
+            records: vec![],
+            comments: vec![],
+            merge_owner: None,
+            vip: true,
+        },
+    )
+}
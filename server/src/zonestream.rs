@@ -0,0 +1,146 @@
+use hyper::body::Bytes;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::fmt;
+use std::io::{self, Read};
+use tokio::sync::mpsc;
+
+use crate::PdnsRrset;
+
+// Chunks of the body in flight to the parser, and rrsets parsed ahead of
+// the caller; together they bound what a zone read holds in memory,
+// however big the zone.
+const CHUNKS: usize = 16;
+const RRSETS: usize = 256;
+
+// Hands each rrset of a pdns zone response to `each` as it's parsed, rather
+// than buffering the body and every rrset in it. `each` failing stops the
+// read with its error. Returns how many rrsets there were.
+pub async fn for_each_rrset(
+    mut response: reqwest::Response,
+    mut each: impl FnMut(PdnsRrset) -> Result<(), String>,
+) -> Result<usize, String> {
+    let (chunks_tx, chunks_rx) = mpsc::channel::<Result<Bytes, String>>(CHUNKS);
+    let (rrsets_tx, mut rrsets_rx) = mpsc::channel(RRSETS);
+    // Each side stops when the other hangs up.
+    let feeder = tokio::spawn(async move {
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => Ok(chunk),
+                Ok(None) => return,
+                Err(e) => Err(e.to_string()),
+            };
+            let failed = chunk.is_err();
+            if chunks_tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    let parser = tokio::task::spawn_blocking(move || {
+        let mut de = serde_json::Deserializer::from_reader(ChunkReader {
+            chunks: chunks_rx,
+            chunk: Bytes::new(),
+        });
+        Zone(&rrsets_tx)
+            .deserialize(&mut de)
+            .and_then(|()| de.end())
+            .map_err(|e| e.to_string())
+    });
+
+    let mut count = 0;
+    let mut failed = None;
+    while let Some(rrset) = rrsets_rx.recv().await {
+        count += 1;
+        if let Err(e) = each(rrset) {
+            failed = Some(e);
+            break;
+        }
+    }
+    drop(rrsets_rx);
+    if let Some(e) = failed {
+        feeder.abort();
+        return Err(e);
+    }
+    match parser.await {
+        Ok(Ok(())) => Ok(count),
+        Ok(Err(e)) => Err(format!("error reading zone: {}", e)),
+        Err(e) => Err(format!("zone parser failed: {}", e)),
+    }
+}
+
+// The body as the parser's blocking Read.
+struct ChunkReader {
+    chunks: mpsc::Receiver<Result<Bytes, String>>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+// The zone object, of which only rrsets is kept.
+struct Zone<'a>(&'a mpsc::Sender<PdnsRrset>);
+
+impl<'de> DeserializeSeed<'de> for Zone<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Zone<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a pdns zone")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "rrsets" {
+                map.next_value_seed(Rrsets(self.0))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Rrsets<'a>(&'a mpsc::Sender<PdnsRrset>);
+
+impl<'de> DeserializeSeed<'de> for Rrsets<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Rrsets<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of rrsets")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(rrset) = seq.next_element::<PdnsRrset>()? {
+            if self.0.blocking_send(rrset).is_err() {
+                return Err(de::Error::custom("reader stopped"));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+// The server binary end to end: a real client advertises to it, and a mock
+// pdns records the PATCHes it's sent.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::{json, Value};
+
+use proto::strapper;
+
+const API_KEY: &str = "e2e-key";
+
+// The zone the mock pdns turns every PATCH away from.
+const REJECTING: &str = "bad.example.com.";
+
+#[derive(Debug)]
+struct Patch {
+    path: String,
+    api_key: Option<String>,
+    body: Value,
+}
+
+#[derive(Clone, Default)]
+struct Pdns {
+    patches: Arc<Mutex<Vec<Patch>>>,
+}
+
+impl Pdns {
+    async fn handle(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let api_key = req
+            .headers()
+            .get("X-API-Key")
+            .map(|v| v.to_str().unwrap().to_owned());
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        if method != hyper::Method::PATCH {
+            return Ok(Response::new(Body::from("{}")));
+        }
+        let body = serde_json::from_slice(&body).expect("PATCH body is JSON");
+        let rejected = path.ends_with(REJECTING);
+        self.patches.lock().unwrap().push(Patch {
+            path,
+            api_key,
+            body,
+        });
+        let response = if rejected {
+            Response::builder()
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .body(Body::from(r#"{"error": "RRset conflicts with a CNAME"}"#))
+        } else {
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+        };
+        Ok(response.unwrap())
+    }
+
+    // Everything sent for `zone`, oldest first.
+    fn patches(&self, zone: &str) -> Vec<Patch> {
+        let path = format!("/api/v1/servers/localhost/zones/{}", zone);
+        let mut patches = self.patches.lock().unwrap();
+        let (theirs, rest) = patches.drain(..).partition(|p| p.path == path);
+        *patches = rest;
+        theirs
+    }
+}
+
+async fn mock_pdns() -> (Pdns, SocketAddr) {
+    let pdns = Pdns::default();
+    let handler = pdns.clone();
+    let server =
+        hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handler.clone().handle(req))) }
+        }));
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (pdns, addr)
+}
+
+// The server binary, killed when dropped.
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Server {
+    fn spawn(pdns: SocketAddr, remappers: &[&str]) -> Server {
+        Server::spawn_with(pdns, remappers, &[])
+    }
+
+    fn spawn_with(pdns: SocketAddr, remappers: &[&str], args: &[&str]) -> Server {
+        // Free now; the server takes it moments later.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_server"));
+        cmd.arg("--bind")
+            .arg(addr.to_string())
+            .arg("--pdns-endpoint")
+            .arg(format!("http://{}", pdns))
+            .arg("--pdns-api-key")
+            .arg(API_KEY)
+            .arg("--skip-zone-check");
+        for r in remappers {
+            cmd.arg("-r").arg(r);
+        }
+        cmd.args(args);
+        let child = cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server binary");
+        Server { child, addr }
+    }
+
+    // Once it's listening.
+    async fn client(&mut self) -> client::StrapperClient {
+        let uri: hyper::Uri = format!("http://{}", self.addr).parse().unwrap();
+        let started = Instant::now();
+        loop {
+            match client::StrapperClient::connect(uri.clone()).await {
+                Ok(c) => return c,
+                Err(e) => {
+                    if let Some(status) = self.child.try_wait().unwrap() {
+                        panic!("server exited ({}) before listening", status);
+                    }
+                    assert!(
+                        started.elapsed() < Duration::from_secs(10),
+                        "server never listened: {}",
+                        e
+                    );
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn node(hostname: &str, addrs: &[&str]) -> strapper::NodeAdvertisement {
+    strapper::NodeAdvertisement {
+        hostname: hostname.to_owned(),
+        interfaces: vec![strapper::Interface {
+            name: "eth0".to_owned(),
+            mac: "02:00:00:00:00:01".to_owned(),
+            ipaddr: addrs.iter().map(|a| a.to_string()).collect(),
+            index: 2,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+// The one rrset in a PATCH, without the comments, which carry timestamps.
+fn rrset(patch: &Patch) -> Value {
+    let rrsets = patch.body["rrsets"].as_array().expect("rrsets");
+    assert_eq!(rrsets.len(), 1, "{:?}", patch);
+    let mut rrset = rrsets[0].clone();
+    rrset.as_object_mut().unwrap().remove("comments");
+    rrset
+}
+
+#[tokio::test]
+async fn families_go_to_their_remappers() {
+    let (pdns, pdns_addr) = mock_pdns().await;
+    let mut server = Server::spawn(
+        pdns_addr,
+        &[
+            "10.0.0.0/8@v4.example.com.@{}",
+            "ttl=600:fd00::/8@v6.example.com.@{}",
+        ],
+    );
+    let mut c = server.client().await;
+
+    c.advertise(&node("web1", &["10.1.2.3", "fd00::1234"]))
+        .await
+        .unwrap();
+
+    let v4 = pdns.patches("v4.example.com.");
+    assert_eq!(v4.len(), 1, "{:?}", v4);
+    assert_eq!(v4[0].api_key.as_deref(), Some(API_KEY));
+    assert_eq!(
+        rrset(&v4[0]),
+        json!({
+            "name": "web1.v4.example.com.",
+            "type": "A",
+            "ttl": 3600,
+            "changetype": "REPLACE",
+            "records": [{"content": "10.1.2.3", "disabled": false}],
+        })
+    );
+
+    let v6 = pdns.patches("v6.example.com.");
+    assert_eq!(v6.len(), 1, "{:?}", v6);
+    assert_eq!(v6[0].api_key.as_deref(), Some(API_KEY));
+    assert_eq!(
+        rrset(&v6[0]),
+        json!({
+            "name": "web1.v6.example.com.",
+            "type": "AAAA",
+            "ttl": 600,
+            "changetype": "REPLACE",
+            "records": [{"content": "fd00::1234", "disabled": false}],
+        })
+    );
+}
+
+// An address no remapper takes is left out of pdns, not failed.
+#[tokio::test]
+async fn unmatched_address_is_not_written() {
+    let (pdns, pdns_addr) = mock_pdns().await;
+    let mut server = Server::spawn(pdns_addr, &["10.0.0.0/8@v4.example.com.@{}"]);
+    let mut c = server.client().await;
+
+    c.advertise(&node("web2", &["10.1.2.4", "192.168.7.7"]))
+        .await
+        .unwrap();
+
+    let v4 = pdns.patches("v4.example.com.");
+    assert_eq!(v4.len(), 1, "{:?}", v4);
+    assert_eq!(
+        rrset(&v4[0])["records"],
+        json!([{"content": "10.1.2.4", "disabled": false}])
+    );
+    assert!(pdns.patches.lock().unwrap().is_empty());
+}
+
+// Everything pdns rejects, and won't take on a retry either, fails the
+// advertisement as a precondition rather than as something to retry.
+#[tokio::test]
+async fn pdns_rejection_fails_the_advertisement() {
+    let (pdns, pdns_addr) = mock_pdns().await;
+    let mut server = Server::spawn(pdns_addr, &[&format!("10.0.0.0/8@{}@{{}}", REJECTING)]);
+    let mut c = server.client().await;
+
+    let e = c.advertise(&node("web3", &["10.1.2.5"])).await.unwrap_err();
+    let e = e
+        .downcast_ref::<client::AdvertiseError>()
+        .expect("an advertise error");
+    assert_eq!(
+        e.status.code(),
+        tonic::Code::FailedPrecondition,
+        "{}",
+        e.status
+    );
+
+    let rejected = pdns.patches(REJECTING);
+    assert_eq!(rejected.len(), 1, "{:?}", rejected);
+    assert_eq!(rrset(&rejected[0])["name"], "web3.bad.example.com.");
+}
+
+// Thousands of container veths are turned away whole, naming the limit,
+// before anything reaches pdns.
+#[tokio::test]
+async fn oversized_advertisement_rejected() {
+    let (pdns, pdns_addr) = mock_pdns().await;
+    let mut server = Server::spawn(pdns_addr, &["fd00::/8@v6.example.com.@{}"]);
+    let mut c = server.client().await;
+
+    let mut adv = node("veths", &[]);
+    adv.interfaces = (0..5000u32)
+        .map(|i| strapper::Interface {
+            name: format!("veth{}", i),
+            mac: format!("02:00:00:00:{:02x}:{:02x}", i >> 8, i & 0xff),
+            ipaddr: vec![format!("fd00::{:x}", i)],
+            index: i + 2,
+            ..Default::default()
+        })
+        .collect();
+    let e = c.advertise(&adv).await.unwrap_err();
+    let e = e
+        .downcast_ref::<client::AdvertiseError>()
+        .expect("an advertise error");
+    assert_eq!(
+        e.status.code(),
+        tonic::Code::InvalidArgument,
+        "{}",
+        e.status
+    );
+    assert!(
+        e.status.message().contains("--max-interfaces-per-node"),
+        "{}",
+        e.status
+    );
+    assert!(pdns.patches.lock().unwrap().is_empty());
+
+    // Under the limit, the same node goes through.
+    adv.interfaces.truncate(64);
+    c.advertise(&adv).await.unwrap();
+    assert_eq!(pdns.patches("v6.example.com.").len(), 64);
+}
+
+// An A record synthesize_a: made up from a NAT64 address is published with
+// a comment saying so, and deleted once the address it came from goes.
+#[tokio::test]
+async fn synthesized_a_goes_with_its_aaaa() {
+    let (pdns, pdns_addr) = mock_pdns().await;
+    let mut server = Server::spawn_with(
+        pdns_addr,
+        &["synthesize_a:::/0@v6.example.com.@{}"],
+        &["--nat64-prefix", "64:ff9b::/96"],
+    );
+    let mut c = server.client().await;
+
+    c.advertise(&node("web4", &["64:ff9b::c000:205", "2001:db8::4"]))
+        .await
+        .unwrap();
+    let patches = pdns.patches("v6.example.com.");
+    let comments: Vec<&Value> = patches
+        .iter()
+        .map(|p| &p.body["rrsets"][0]["comments"])
+        .filter(|c| c != &&json!([]))
+        .collect();
+    assert_eq!(
+        comments,
+        [&json!([{
+            "content": "owner=web4 content=192.0.2.5 synthesized-from=64:ff9b::c000:205",
+            "account": "strapper",
+        }])]
+    );
+    let mut added: Vec<Value> = patches.iter().map(rrset).collect();
+    added.sort_by_key(|r| r["records"][0]["content"].as_str().unwrap().to_owned());
+    assert_eq!(
+        added,
+        [
+            json!({
+                "name": "web4.v6.example.com.",
+                "type": "A",
+                "ttl": 3600,
+                "changetype": "REPLACE",
+                "records": [{"content": "192.0.2.5", "disabled": false}],
+            }),
+            json!({
+                "name": "web4.v6.example.com.",
+                "type": "AAAA",
+                "ttl": 3600,
+                "changetype": "REPLACE",
+                "records": [{"content": "2001:db8::4", "disabled": false}],
+            }),
+            json!({
+                "name": "web4.v6.example.com.",
+                "type": "AAAA",
+                "ttl": 3600,
+                "changetype": "REPLACE",
+                "records": [{"content": "64:ff9b::c000:205", "disabled": false}],
+            }),
+        ]
+    );
+
+    c.advertise(&node("web4", &["2001:db8::4"])).await.unwrap();
+    let patches = pdns.patches("v6.example.com.");
+    let deleted: Vec<Value> = patches
+        .iter()
+        .map(rrset)
+        .filter(|r| r["type"] == "A")
+        .collect();
+    assert_eq!(deleted.len(), 1, "{:?}", patches);
+    assert_eq!(deleted[0]["name"], "web4.v6.example.com.");
+    assert_eq!(deleted[0]["changetype"], "DELETE");
+    assert_eq!(deleted[0]["records"], json!([]));
+}
@@ -0,0 +1,938 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+mod plan;
+
+use client::{InterfaceBuilder, NodeAdvertisementBuilder, StrapperClient, Target};
+use proto::strapper;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!(
+                "unknown output format '{}' (expected table or json)",
+                s
+            )),
+        }
+    }
+}
+
+struct Role(strapper::Role);
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Role(strapper::Role::Primary)),
+            "standby" => Ok(Role(strapper::Role::Standby)),
+            _ => Err(anyhow!(
+                "unknown role '{}' (expected primary or standby)",
+                s
+            )),
+        }
+    }
+}
+
+fn role_name(role: i32) -> &'static str {
+    match strapper::Role::from_i32(role) {
+        Some(strapper::Role::Standby) => "standby",
+        _ => "primary",
+    }
+}
+
+// --addr takes an address with or without a prefix length. The prefix is
+// checked but not sent, since advertisements don't carry one.
+struct Address(IpAddr);
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow!("invalid address '{}': {}", s, e))?;
+        if let Some(prefix) = prefix {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix: u8 = prefix
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix length in '{}'", s))?;
+            ensure!(
+                prefix <= max,
+                "prefix length {} is too long for {}",
+                prefix,
+                ip
+            );
+        }
+        Ok(Address(ip))
+    }
+}
+
+#[derive(StructOpt)]
+struct Opt {
+    #[structopt(default_value = "http://leader.infra.ibj.io:55555", long, short)]
+    endpoint: Target,
+
+    #[structopt(long)]
+    auth_token_file: Option<PathBuf>,
+
+    #[structopt(default_value = "table", long)]
+    output: OutputFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    List,
+
+    // Polls ListNodes and prints what changed between polls.
+    Watch {
+        #[structopt(default_value = "5", long)]
+        interval_secs: u64,
+    },
+
+    Advertise {
+        #[structopt(long)]
+        hostname: String,
+
+        #[structopt(default_value = "eth0", long)]
+        iface: String,
+
+        #[structopt(default_value = "1", long)]
+        index: u32,
+
+        #[structopt(long)]
+        mac: Option<String>,
+
+        #[structopt(long = "addr")]
+        addrs: Vec<Address>,
+
+        // Keep the node's records in the zone but disabled.
+        #[structopt(long)]
+        disabled: bool,
+
+        #[structopt(long)]
+        ttl_override: Option<u32>,
+    },
+
+    Deregister {
+        hostname: String,
+    },
+
+    // Shows what a JSON advertisement (a file, or - for stdin) would change
+    // in DNS, without sending it. Fails if any of it would be refused as
+    // another node's.
+    Plan {
+        #[structopt(default_value = "-")]
+        input: String,
+    },
+
+    // What the server recently did for nodes, newest first. --name is a
+    // record name.
+    History {
+        #[structopt(long)]
+        hostname: Option<String>,
+
+        #[structopt(long)]
+        zone: Option<String>,
+
+        #[structopt(long)]
+        name: Option<String>,
+
+        #[structopt(default_value = "50", long)]
+        limit: u32,
+    },
+
+    // Holds DNS writes for --zone, or every zone, until unpaused.
+    Pause {
+        #[structopt(long)]
+        zone: Option<String>,
+    },
+
+    // Applies what was held while paused before returning.
+    Unpause {
+        #[structopt(long)]
+        zone: Option<String>,
+    },
+
+    // Leaves a node's records in DNS as they are, for --duration-secs or
+    // until unfrozen, while the server keeps recording its advertisements.
+    Freeze {
+        hostname: String,
+
+        #[structopt(long)]
+        duration_secs: Option<u64>,
+    },
+
+    // Writes what a frozen node advertised meanwhile before returning.
+    Unfreeze {
+        hostname: String,
+    },
+
+    // Promoting a standby to primary returns once its registry is in pdns.
+    SetRole {
+        role: Role,
+    },
+
+    // The configuration the server is running with, API keys shown only as
+    // fingerprints.
+    Config,
+
+    // Where pdns differs from what the server's registry comes to, as of
+    // its last check or, with --refresh, now.
+    Drift {
+        #[structopt(long)]
+        refresh: bool,
+    },
+}
+
+async fn connect(opt: &Opt) -> Result<StrapperClient> {
+    let client = StrapperClient::connect_target(&opt.endpoint, opt.endpoint.endpoint())
+        .await
+        .with_context(|| format!("error connecting to {}", opt.endpoint))?;
+    let path = match &opt.auth_token_file {
+        Some(path) => path,
+        None => return Ok(client),
+    };
+    let (token, world_readable) = client::read_token_file(path)?;
+    if world_readable {
+        eprintln!(
+            "warning: auth token file {} is world-readable",
+            path.display()
+        );
+    }
+    client.with_auth_token(&token)
+}
+
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<1$}", c, w))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn node_json(n: &strapper::NodeAdvertisement) -> Value {
+    json!({
+        "hostname": n.hostname,
+        "fqdn": n.fqdn,
+        "effective_hostname": n.effective_hostname,
+        "source_address": n.source_address,
+        "self_advertised": n.self_advertised,
+        "agent_version": n.agent_version,
+        "labels": n.labels,
+        "disabled": n.disabled,
+        "ttl_override": n.ttl_override,
+        "interfaces": n.interfaces.iter().map(|i| json!({
+            "name": i.name,
+            "index": i.index,
+            "mac": i.mac,
+            "addresses": i.ipaddr,
+            "origins": i.ipaddr.iter().map(|a| origin(i, a)).collect::<Vec<_>>(),
+            "vlan_id": i.vlan_id,
+            "wireguard_public_key": i.wireguard_public_key,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+// What the agent said about how `addr` was configured, "" if nothing.
+fn origin(i: &strapper::Interface, addr: &str) -> &'static str {
+    let origin = i
+        .address_info
+        .iter()
+        .find(|a| a.address == addr)
+        .and_then(|a| strapper::AddressOrigin::from_i32(a.origin));
+    match origin {
+        Some(strapper::AddressOrigin::Static) => "static",
+        Some(strapper::AddressOrigin::Dynamic) => "dynamic",
+        _ => "",
+    }
+}
+
+fn history_kind(kind: i32) -> &'static str {
+    match strapper::HistoryKind::from_i32(kind) {
+        Some(strapper::HistoryKind::HistoryRejected) => "rejected",
+        Some(strapper::HistoryKind::HistoryApplied) => "applied",
+        Some(strapper::HistoryKind::HistoryFailed) => "failed",
+        Some(strapper::HistoryKind::HistoryDeregistered) => "deregistered",
+        _ => "advertised",
+    }
+}
+
+fn print_history(format: OutputFormat, entries: &[strapper::HistoryEntry]) {
+    let timestamp = |e: &strapper::HistoryEntry| {
+        humantime::format_rfc3339_millis(
+            std::time::UNIX_EPOCH + Duration::from_millis(e.timestamp_ms),
+        )
+        .to_string()
+    };
+    if format == OutputFormat::Json {
+        let out: Vec<Value> = entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "timestamp": timestamp(e),
+                    "kind": history_kind(e.kind),
+                    "hostname": e.hostname,
+                    "zone": e.zone,
+                    "name": e.name,
+                    "type": e.r#type,
+                    "description": e.description,
+                    "request_id": e.request_id,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return;
+    }
+
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|e| {
+            let record = if e.name.is_empty() {
+                String::new()
+            } else {
+                format!("{} {}", e.r#type, e.name)
+            };
+            vec![
+                timestamp(e),
+                history_kind(e.kind).to_owned(),
+                e.hostname.clone(),
+                record,
+                e.description.clone(),
+            ]
+        })
+        .collect();
+    print_table(
+        &["TIME", "KIND", "HOSTNAME", "RECORD", "DESCRIPTION"],
+        &rows,
+    );
+}
+
+fn frozen_until(f: &strapper::FrozenNode) -> String {
+    match f.until_ms {
+        0 => "unfrozen".to_owned(),
+        ms => {
+            humantime::format_rfc3339(std::time::UNIX_EPOCH + Duration::from_millis(ms)).to_string()
+        }
+    }
+}
+
+fn frozen_json(f: &strapper::FrozenNode) -> Value {
+    json!({
+        "hostname": f.hostname,
+        "until_ms": if f.until_ms == 0 { None } else { Some(f.until_ms) },
+    })
+}
+
+fn print_list(format: OutputFormat, list: &strapper::NodeList) {
+    if format == OutputFormat::Json {
+        let quarantined: Vec<Value> = list
+            .quarantined
+            .iter()
+            .map(|q| {
+                json!({
+                    "zone": q.zone,
+                    "name": q.name,
+                    "type": q.r#type,
+                    "failures": q.failures,
+                    "last_error": q.last_error,
+                })
+            })
+            .collect();
+        let out = json!({
+            "nodes": list.nodes.iter().map(node_json).collect::<Vec<_>>(),
+            "quarantined": quarantined,
+            "frozen": list.frozen.iter().map(frozen_json).collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return;
+    }
+
+    let mut rows = vec![];
+    for n in &list.nodes {
+        let mut hostname = if n.effective_hostname.is_empty() || n.effective_hostname == n.hostname
+        {
+            n.hostname.clone()
+        } else {
+            format!("{} ({})", n.effective_hostname, n.hostname)
+        };
+        if n.self_advertised {
+            hostname.push_str(" [server]");
+        }
+        let labels = n
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mode = match (n.disabled, n.ttl_override) {
+            (false, None) => String::new(),
+            (true, None) => "disabled".to_owned(),
+            (false, Some(ttl)) => format!("ttl<={}", ttl),
+            (true, Some(ttl)) => format!("disabled,ttl<={}", ttl),
+        };
+        if n.interfaces.is_empty() {
+            rows.push(vec![
+                hostname.clone(),
+                n.fqdn.clone(),
+                n.source_address.clone(),
+                labels.clone(),
+                mode.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+        for i in &n.interfaces {
+            rows.push(vec![
+                hostname.clone(),
+                n.fqdn.clone(),
+                n.source_address.clone(),
+                labels.clone(),
+                mode.clone(),
+                i.name.clone(),
+                i.mac.clone(),
+                i.ipaddr
+                    .iter()
+                    .map(|a| match origin(i, a) {
+                        "" => a.clone(),
+                        o => format!("{} ({})", a, o),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ]);
+        }
+    }
+    print_table(
+        &[
+            "HOSTNAME",
+            "FQDN",
+            "SOURCE",
+            "LABELS",
+            "MODE",
+            "INTERFACE",
+            "MAC",
+            "ADDRESSES",
+        ],
+        &rows,
+    );
+
+    if !list.quarantined.is_empty() {
+        println!();
+        let rows: Vec<Vec<String>> = list
+            .quarantined
+            .iter()
+            .map(|q| {
+                vec![
+                    q.zone.clone(),
+                    q.name.clone(),
+                    q.r#type.clone(),
+                    q.failures.to_string(),
+                    q.last_error.clone(),
+                ]
+            })
+            .collect();
+        print_table(&["ZONE", "NAME", "TYPE", "FAILURES", "LAST ERROR"], &rows);
+    }
+
+    if !list.frozen.is_empty() {
+        println!();
+        let rows: Vec<Vec<String>> = list
+            .frozen
+            .iter()
+            .map(|f| vec![f.hostname.clone(), frozen_until(f)])
+            .collect();
+        print_table(&["FROZEN", "UNTIL"], &rows);
+    }
+}
+
+fn print_pause(format: OutputFormat, state: &strapper::WritePauseState) {
+    if format == OutputFormat::Json {
+        let out = json!({
+            "all": state.all,
+            "zones": state.zones,
+            "held_writes": state.held_writes,
+        });
+        println!("{}", out);
+        return;
+    }
+
+    match (state.all, state.zones.is_empty()) {
+        (false, true) => println!("writes are not paused"),
+        (true, _) => println!("writes to all zones are paused"),
+        (false, false) => println!("writes to {} are paused", state.zones.join(", ")),
+    }
+    if state.held_writes > 0 {
+        println!("{} writes held", state.held_writes);
+    }
+}
+
+fn print_drift(format: OutputFormat, report: &strapper::DriftReport) {
+    if format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report.to_json()).unwrap()
+        );
+        return;
+    }
+    println!(
+        "checked {} rrsets at {}",
+        report.rrsets_checked,
+        humantime::format_rfc3339(
+            std::time::UNIX_EPOCH + Duration::from_millis(report.checked_at_ms)
+        )
+    );
+    for e in &report.errors {
+        println!("error: {}", e);
+    }
+    if report.entries.is_empty() {
+        return;
+    }
+    println!();
+    let ttl = |ttl: u32| {
+        if ttl == 0 {
+            String::new()
+        } else {
+            ttl.to_string()
+        }
+    };
+    let rows: Vec<Vec<String>> = report
+        .entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.endpoint.clone(),
+                e.zone.clone(),
+                e.name.clone(),
+                e.r#type.clone(),
+                e.kind_name().to_owned(),
+                e.expected.join(" "),
+                e.actual.join(" "),
+                format!("{}/{}", ttl(e.expected_ttl), ttl(e.actual_ttl)),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "PDNS", "ZONE", "NAME", "TYPE", "DRIFT", "EXPECTED", "ACTUAL", "TTL",
+        ],
+        &rows,
+    );
+}
+
+fn print_config(format: OutputFormat, config: &strapper::ServerConfig) {
+    if format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config.to_json()).unwrap()
+        );
+        return;
+    }
+
+    let loaded = humantime::format_rfc3339_millis(
+        std::time::UNIX_EPOCH + Duration::from_millis(config.loaded_at_ms),
+    );
+    println!("loaded from {} at {}", config.source, loaded);
+    println!();
+    let rows: Vec<Vec<String>> = config
+        .pdns_targets
+        .iter()
+        .map(|t| {
+            vec![
+                t.endpoint.clone(),
+                t.server.clone(),
+                t.api_key_fingerprint.clone(),
+            ]
+        })
+        .collect();
+    print_table(&["ENDPOINT", "SERVER", "API KEY"], &rows);
+    println!();
+    let rows: Vec<Vec<String>> = config
+        .remappers
+        .iter()
+        .map(|r| {
+            vec![
+                r.net.clone(),
+                r.zone.clone(),
+                r.entry_formats.join(" "),
+                r.spec.clone(),
+            ]
+        })
+        .collect();
+    print_table(&["NET", "ZONE", "FORMATS", "REMAPPER"], &rows);
+    println!();
+    for listener in &config.listeners {
+        println!("listening on {}", listener);
+    }
+    if !config.listeners.is_empty() {
+        println!();
+    }
+    let optional = |v: Option<String>| v.unwrap_or_else(|| "-".to_owned());
+    let settings = [
+        ("pdns quorum", config.pdns_quorum.clone()),
+        ("pdns proxy", config.pdns_proxy.clone()),
+        (
+            "pdns zone rate",
+            optional(
+                config
+                    .pdns_zone_rate
+                    .map(|r| format!("{}/s, burst {}", r, config.pdns_zone_burst)),
+            ),
+        ),
+        (
+            "advertise rate",
+            format!(
+                "{}/min per node, {} overall",
+                config.max_advertise_per_minute,
+                optional(
+                    config
+                        .max_advertise_global_per_minute
+                        .map(|n| format!("{}/min", n))
+                )
+            ),
+        ),
+        (
+            "ttl",
+            format!(
+                "{} {}s, min {}s, vip {}s",
+                config.ttl_policy, config.record_ttl, config.min_ttl, config.vip_ttl
+            ),
+        ),
+        (
+            "quarantine",
+            format!(
+                "after {} failures, retry after {}s",
+                config.quarantine_after, config.quarantine_retry_secs
+            ),
+        ),
+        (
+            "missing zone ttl",
+            format!("{}s", config.missing_zone_ttl_secs),
+        ),
+        ("ownership conflict", config.ownership_conflict.clone()),
+        ("on no match", config.on_no_match.clone()),
+        ("name source", config.name_source.clone()),
+        (
+            "preserve hostname case",
+            config.preserve_hostname_case.to_string(),
+        ),
+        ("strict", config.strict.to_string()),
+        (
+            "mode",
+            if config.mode == "readonly" {
+                format!(
+                    "{}, checking drift every {}s",
+                    config.mode, config.drift_interval_secs
+                )
+            } else {
+                config.mode.clone()
+            },
+        ),
+        (
+            "zone reads",
+            format!(
+                "by name up to {} names, whole up to {}",
+                config.zone_fetch_max_names,
+                match config.reconcile_max_zone_rrsets {
+                    0 => "any size".to_owned(),
+                    n => format!("{} rrsets", n),
+                }
+            ),
+        ),
+        (
+            "pdns rectify",
+            if config.pdns_rectify == "after-change" {
+                format!(
+                    "{}, debounced {}ms",
+                    config.pdns_rectify, config.pdns_rectify_debounce_ms
+                )
+            } else {
+                config.pdns_rectify.clone()
+            },
+        ),
+        ("nat64 prefix", config.nat64_prefix.clone()),
+        ("self advertise", config.self_advertise.clone()),
+        (
+            "otlp endpoint",
+            if config.otlp_endpoint.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    "{}, sampling {}",
+                    config.otlp_endpoint, config.trace_sample_ratio
+                )
+            },
+        ),
+    ];
+    let rows: Vec<Vec<String>> = settings
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| vec![k.to_string(), v.clone()])
+        .collect();
+    print_table(&["SETTING", "VALUE"], &rows);
+}
+
+// hostname -> "interface address" for every advertised address.
+type Snapshot = BTreeMap<String, BTreeSet<(String, String)>>;
+
+fn snapshot(list: &strapper::NodeList) -> Snapshot {
+    list.nodes
+        .iter()
+        .map(|n| {
+            let addrs = n
+                .interfaces
+                .iter()
+                .flat_map(|i| i.ipaddr.iter().map(move |a| (i.name.clone(), a.clone())))
+                .collect();
+            (n.hostname.clone(), addrs)
+        })
+        .collect()
+}
+
+fn print_change(
+    format: OutputFormat,
+    added: bool,
+    hostname: &str,
+    addr: Option<&(String, String)>,
+) {
+    if format == OutputFormat::Json {
+        let mut event = json!({
+            "event": match (added, addr.is_some()) {
+                (true, false) => "node_added",
+                (false, false) => "node_removed",
+                (true, true) => "address_added",
+                (false, true) => "address_removed",
+            },
+            "hostname": hostname,
+        });
+        if let Some((iface, addr)) = addr {
+            event["interface"] = json!(iface);
+            event["address"] = json!(addr);
+        }
+        println!("{}", event);
+        return;
+    }
+
+    let sign = if added { '+' } else { '-' };
+    match addr {
+        Some((iface, addr)) => println!("{} {} {} {}", sign, hostname, iface, addr),
+        None => println!("{} {}", sign, hostname),
+    }
+}
+
+fn print_diff(format: OutputFormat, old: &Snapshot, new: &Snapshot) {
+    let empty = BTreeSet::new();
+    for (hostname, addrs) in new {
+        let before = match old.get(hostname) {
+            Some(before) => before,
+            None => {
+                print_change(format, true, hostname, None);
+                &empty
+            }
+        };
+        for a in addrs.difference(before) {
+            print_change(format, true, hostname, Some(a));
+        }
+        for a in before.difference(addrs) {
+            print_change(format, false, hostname, Some(a));
+        }
+    }
+    for (hostname, addrs) in old {
+        if !new.contains_key(hostname) {
+            for a in addrs {
+                print_change(format, false, hostname, Some(a));
+            }
+            print_change(format, false, hostname, None);
+        }
+    }
+}
+
+async fn run(opt: &Opt) -> Result<()> {
+    match &opt.command {
+        Command::List => {
+            let list = connect(opt).await?.list_nodes().await?;
+            print_list(opt.output, &list);
+        }
+        Command::Watch { interval_secs } => {
+            let mut client = connect(opt).await?;
+            let mut interval = tokio::time::interval(Duration::from_secs(*interval_secs));
+            let mut last = Snapshot::new();
+            loop {
+                interval.tick().await;
+                let next = snapshot(&client.list_nodes().await?);
+                print_diff(opt.output, &last, &next);
+                last = next;
+            }
+        }
+        Command::Advertise {
+            hostname,
+            iface,
+            index,
+            mac,
+            addrs,
+            disabled,
+            ttl_override,
+        } => {
+            ensure!(!addrs.is_empty(), "at least one --addr is required");
+            let mut builder = InterfaceBuilder::new(iface.as_str(), *index);
+            if let Some(mac) = mac {
+                builder = builder.mac(mac.as_str());
+            }
+            for a in addrs {
+                builder = builder.address(a.0.to_string());
+            }
+            let mut advertisement = NodeAdvertisementBuilder::new(hostname.as_str())
+                .interface(builder.build()?)
+                .disabled(*disabled);
+            if let Some(ttl) = ttl_override {
+                advertisement = advertisement.ttl_override(*ttl);
+            }
+            let advertisement = advertisement.build()?;
+
+            let request_id = connect(opt)
+                .await?
+                .advertise(&advertisement)
+                .await?
+                .request_id;
+            match opt.output {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "hostname": hostname, "request_id": request_id })
+                ),
+                OutputFormat::Table => println!("advertised {} (request {})", hostname, request_id),
+            }
+        }
+        Command::Plan { input } => {
+            let advertisement = plan::read(input)?;
+            let plan = connect(opt).await?.plan_advertise(&advertisement).await?;
+            plan::print(opt.output, &plan);
+            ensure!(
+                plan.conflicts.is_empty(),
+                "{} records would be refused as another node's",
+                plan.conflicts.len()
+            );
+        }
+        Command::History {
+            hostname,
+            zone,
+            name,
+            limit,
+        } => {
+            let request = strapper::HistoryRequest {
+                hostname: hostname.clone().unwrap_or_default(),
+                zone: zone.clone().unwrap_or_default(),
+                name: name.clone().unwrap_or_default(),
+                limit: *limit,
+            };
+            let entries = connect(opt).await?.history(request).await?;
+            print_history(opt.output, &entries);
+        }
+        Command::Deregister { hostname } => {
+            ensure!(!hostname.is_empty(), "hostname is empty");
+            connect(opt).await?.deregister(hostname).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", json!({ "hostname": hostname })),
+                OutputFormat::Table => println!("deregistered {}", hostname),
+            }
+        }
+        Command::Pause { zone } | Command::Unpause { zone } => {
+            let paused = matches!(opt.command, Command::Pause { .. });
+            let zone = zone.as_deref().unwrap_or("");
+            let state = connect(opt).await?.set_write_pause(zone, paused).await?;
+            print_pause(opt.output, &state);
+        }
+        Command::Freeze {
+            hostname,
+            duration_secs,
+        } => {
+            ensure!(!hostname.is_empty(), "hostname is empty");
+            let frozen = connect(opt)
+                .await?
+                .freeze_node(hostname, duration_secs.map(Duration::from_secs))
+                .await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", frozen_json(&frozen)),
+                OutputFormat::Table => {
+                    println!("froze {} until {}", frozen.hostname, frozen_until(&frozen))
+                }
+            }
+        }
+        Command::Unfreeze { hostname } => {
+            ensure!(!hostname.is_empty(), "hostname is empty");
+            connect(opt).await?.unfreeze_node(hostname).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", json!({ "hostname": hostname })),
+                OutputFormat::Table => println!("unfroze {}", hostname),
+            }
+        }
+        Command::Config => {
+            let config = connect(opt).await?.config().await?;
+            print_config(opt.output, &config);
+        }
+        Command::Drift { refresh } => {
+            let report = connect(opt).await?.drift(*refresh).await?;
+            print_drift(opt.output, &report);
+        }
+        Command::SetRole { role } => {
+            let status = connect(opt).await?.set_role(role.0).await?;
+            match opt.output {
+                OutputFormat::Json => println!("{}", json!({ "role": role_name(status.role) })),
+                OutputFormat::Table => println!("server is now {}", role_name(status.role)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Server errors are reported as the gRPC status rather than the whole chain.
+fn describe(e: &anyhow::Error) -> String {
+    match e.chain().find_map(|c| c.downcast_ref::<tonic::Status>()) {
+        Some(s) => format!("{:?}: {}", s.code(), s.message()),
+        None => format!("{:#}", e),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(&opt).await {
+        eprintln!("strapperctl: {}", describe(&e));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use proto::strapper;
+
+use crate::{print_table, OutputFormat};
+
+// An advertisement in the form POST /v1/advertise takes, or a node from
+// `list --output json`. Only what bears on records is read; anything else is
+// ignored rather than rejected.
+#[derive(Deserialize)]
+struct JsonAdvertisement {
+    hostname: String,
+    #[serde(default)]
+    fqdn: String,
+    #[serde(default)]
+    agent_version: String,
+    #[serde(default)]
+    interfaces: Vec<JsonInterface>,
+    #[serde(default)]
+    services: Vec<JsonService>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(default)]
+    ttl_override: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct JsonInterface {
+    name: String,
+    #[serde(default)]
+    index: u32,
+    #[serde(default)]
+    mac: String,
+    #[serde(default, alias = "addresses")]
+    ipaddr: Vec<String>,
+    #[serde(default)]
+    vlan_id: Option<u32>,
+    #[serde(default)]
+    wireguard_public_key: Option<String>,
+    #[serde(default)]
+    address_info: Vec<JsonAddressInfo>,
+}
+
+#[derive(Deserialize)]
+struct JsonAddressInfo {
+    address: String,
+    #[serde(default = "infinite_lifetime")]
+    preferred_lifetime: u32,
+    #[serde(default = "infinite_lifetime")]
+    valid_lifetime: u32,
+    #[serde(default)]
+    vip: bool,
+}
+
+fn infinite_lifetime() -> u32 {
+    u32::MAX
+}
+
+#[derive(Deserialize)]
+struct JsonService {
+    name: String,
+    protocol: String,
+    port: u32,
+}
+
+impl From<JsonAdvertisement> for strapper::NodeAdvertisement {
+    fn from(a: JsonAdvertisement) -> Self {
+        strapper::NodeAdvertisement {
+            hostname: a.hostname,
+            fqdn: a.fqdn,
+            agent_version: a.agent_version,
+            interfaces: a
+                .interfaces
+                .into_iter()
+                .map(|i| strapper::Interface {
+                    name: i.name,
+                    index: i.index,
+                    mac: i.mac,
+                    ipaddr: i.ipaddr,
+                    vlan_id: i.vlan_id,
+                    wireguard_public_key: i.wireguard_public_key,
+                    address_info: i
+                        .address_info
+                        .into_iter()
+                        .map(|a| strapper::AddressInfo {
+                            address: a.address,
+                            preferred_lifetime: a.preferred_lifetime,
+                            valid_lifetime: a.valid_lifetime,
+                            vip: a.vip,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            services: a
+                .services
+                .into_iter()
+                .map(|s| strapper::Service {
+                    name: s.name,
+                    protocol: s.protocol,
+                    port: s.port,
+                })
+                .collect(),
+            labels: a.labels,
+            disabled: a.disabled,
+            ttl_override: a.ttl_override,
+            ..Default::default()
+        }
+    }
+}
+
+// `input` is a path, or - for stdin.
+pub fn read(input: &str) -> Result<strapper::NodeAdvertisement> {
+    let mut text = String::new();
+    if input == "-" {
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("error reading stdin")?;
+    } else {
+        text =
+            std::fs::read_to_string(input).with_context(|| format!("error reading {}", input))?;
+    }
+    let advertisement: JsonAdvertisement = serde_json::from_str(&text)
+        .with_context(|| format!("invalid advertisement in {}", input))?;
+    Ok(advertisement.into())
+}
+
+fn action_name(action: i32) -> &'static str {
+    match strapper::ChangeAction::from_i32(action) {
+        Some(strapper::ChangeAction::ChangeCreate) => "create",
+        Some(strapper::ChangeAction::ChangeReplace) => "replace",
+        _ => "delete",
+    }
+}
+
+pub fn print(format: OutputFormat, plan: &strapper::AdvertisePlan) {
+    if format == OutputFormat::Json {
+        let out = json!({
+            "changes": plan.changes.iter().map(|c| json!({
+                "action": action_name(c.action),
+                "zone": c.zone,
+                "name": c.name,
+                "type": c.r#type,
+                "ttl": c.ttl,
+                "before": c.before,
+                "after": c.after,
+            })).collect::<Vec<_>>(),
+            "conflicts": plan.conflicts.iter().map(|c| json!({
+                "zone": c.zone,
+                "name": c.name,
+                "type": c.r#type,
+                "owner": c.owner,
+            })).collect::<Vec<_>>(),
+            "skipped_records": plan.skipped_records.iter().map(|r| json!({
+                "zone": r.zone,
+                "name": r.name,
+                "type": r.r#type,
+                "reason": r.reason,
+            })).collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return;
+    }
+
+    let contents = |records: &[String]| {
+        if records.is_empty() {
+            "-".to_owned()
+        } else {
+            records.join(",")
+        }
+    };
+    let rows: Vec<Vec<String>> = plan
+        .changes
+        .iter()
+        .map(|c| {
+            let after = if c.action == strapper::ChangeAction::ChangeReplace as i32
+                && c.before == c.after
+            {
+                "(unchanged)".to_owned()
+            } else {
+                contents(&c.after)
+            };
+            vec![
+                action_name(c.action).to_uppercase(),
+                c.zone.clone(),
+                c.name.clone(),
+                c.r#type.clone(),
+                c.ttl.to_string(),
+                contents(&c.before),
+                after,
+            ]
+        })
+        .collect();
+    print_table(
+        &["ACTION", "ZONE", "NAME", "TYPE", "TTL", "BEFORE", "AFTER"],
+        &rows,
+    );
+    for c in &plan.conflicts {
+        println!(
+            "conflict: {} {} in {} is owned by {}",
+            c.r#type, c.name, c.zone, c.owner
+        );
+    }
+    for r in &plan.skipped_records {
+        println!(
+            "skipped: {} {} in {} ({})",
+            r.r#type, r.name, r.zone, r.reason
+        );
+    }
+}